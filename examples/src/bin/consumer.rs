@@ -0,0 +1,79 @@
+use gitdis::prelude::*;
+use std::sync::{mpsc, Arc, RwLock};
+
+/// Keys the consumer reads after each sync.
+const WATCHED_KEYS: [&str; 2] = ["services/api", "features"];
+
+/// A service embedding gitdis: it follows a config repository, such as the
+/// one `generate_config_repo` creates, and reloads its configuration each
+/// time a new commit is loaded.
+///
+///     consumer [path or url]
+fn main() {
+    env_logger::init();
+
+    let url = std::env::args().nth(1).unwrap_or("config-repo".to_string());
+
+    // Local repositories are registered by absolute path.
+    let url = match std::fs::canonicalize(&url) {
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(_) => url,
+    };
+
+    let local_clone_path =
+        std::env::var("GITDIS_LOCAL_CLONE_PATH").unwrap_or("consumer-data".to_string());
+
+    let gitdis = Gitdis::from(GitdisSettings {
+        total_branch_items: 100,
+        local_clone_path,
+        proxy: None,
+        maintenance_interval_millis: None,
+        max_network_operations: None,
+    });
+
+    let settings = BranchSettings {
+        url,
+        branch_name: "main".to_string(),
+        pull_request_interval_millis: 1000,
+        ..BranchSettings::default()
+    };
+    let branch_key = settings.get_repo_key();
+
+    let (sender, receiver) = mpsc::channel();
+    let sender = std::sync::Mutex::new(sender);
+
+    gitdis
+        .get_events()
+        .subscribe(Arc::new(move |event: &BranchEvent| {
+            if let BranchEventKind::SyncCompleted { commit } = &event.event {
+                if let Ok(sender) = sender.lock() {
+                    let _ = sender.send(commit.clone());
+                }
+            }
+        }));
+
+    let mut service = GitdisService::new(RwLock::new(gitdis).into());
+
+    if let Err(err) = service.add_repo(settings) {
+        eprintln!("Failed to register {}: {:?}", branch_key, err);
+        std::process::exit(1);
+    }
+
+    if let Err(err) = service.listen_branch(&branch_key) {
+        eprintln!("Failed to listen to {}: {:?}", branch_key, err);
+        std::process::exit(1);
+    }
+
+    println!("Following {}", branch_key);
+
+    for commit in receiver {
+        println!("Loaded commit {}", commit);
+
+        for key in WATCHED_KEYS {
+            match service.get_object(&branch_key, key) {
+                Ok(value) => println!("{} = {}", key, value.to_json(JsonMode::Inline)),
+                Err(err) => println!("{} unavailable: {:?}", key, err),
+            }
+        }
+    }
+}
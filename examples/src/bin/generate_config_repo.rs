@@ -0,0 +1,101 @@
+use std::process::Command;
+
+/// Files of the sample repository, by path.
+const FILES: [(&str, &str); 3] = [
+    (
+        "services/api.yaml",
+        "replicas: 2\ntimeout_millis: 3000\nlog_level: info\n",
+    ),
+    (
+        "services/web.yaml",
+        "replicas: 3\ncdn: https://cdn.example.com\n",
+    ),
+    (
+        "features.json",
+        "{\n  \"new_checkout\": false,\n  \"dark_mode\": true\n}\n",
+    ),
+];
+
+/// Creates a sample config repository, or with `--bump` commits a change to
+/// an existing one, for the consumer to reload.
+///
+///     generate_config_repo [path] [--bump]
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let bump = args.iter().any(|arg| arg == "--bump");
+    let path = args
+        .iter()
+        .find(|arg| !arg.starts_with("--"))
+        .cloned()
+        .unwrap_or("config-repo".to_string());
+
+    if bump {
+        bump_replicas(&path);
+    } else {
+        generate(&path);
+    }
+}
+
+fn generate(path: &str) {
+    if std::path::Path::new(path).exists() {
+        eprintln!("{} already exists, use --bump to change it", path);
+        std::process::exit(1);
+    }
+
+    for (file, content) in FILES {
+        let file = format!("{}/{}", path, file);
+
+        if let Some(dir) = std::path::Path::new(&file).parent() {
+            std::fs::create_dir_all(dir).expect("Failed to create directory");
+        }
+
+        std::fs::write(&file, content).expect("Failed to write file");
+    }
+
+    git(path, &["init", "-b", "main"]);
+    git(path, &["add", "."]);
+    git(path, &["commit", "-m", "Sample configuration"]);
+
+    println!("Created {}", path);
+}
+
+/// Adds a replica to the api service.
+fn bump_replicas(path: &str) {
+    let file = format!("{}/services/api.yaml", path);
+    let content = std::fs::read_to_string(&file).expect("Failed to read services/api.yaml");
+
+    let content = content
+        .lines()
+        .map(|line| match line.strip_prefix("replicas: ") {
+            Some(replicas) => format!("replicas: {}", replicas.parse::<u32>().unwrap_or(0) + 1),
+            None => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    std::fs::write(&file, content + "\n").expect("Failed to write services/api.yaml");
+
+    git(path, &["commit", "-am", "Add an api replica"]);
+
+    println!("Committed a change to {}", file);
+}
+
+/// Runs git in `dir` with a throwaway identity.
+fn git(dir: &str, args: &[&str]) {
+    let output = Command::new("git")
+        .args([
+            "-c",
+            "user.name=gitdis",
+            "-c",
+            "user.email=gitdis@localhost",
+        ])
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .expect("Failed to execute git");
+
+    if !output.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        std::process::exit(1);
+    }
+}
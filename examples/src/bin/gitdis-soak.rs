@@ -0,0 +1,382 @@
+use gitdis::prelude::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::process::Command;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Poll interval of the soak branches.
+const PULL_INTERVAL_MILLIS: u64 = 1000;
+/// How often the invariants are checked while commits keep coming.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Memory is measured against what is used once every branch loaded.
+const WARMUP: Duration = Duration::from_secs(60);
+
+/// Knobs of a soak run, from `GITDIS_SOAK_*` environment variables.
+struct SoakSettings {
+    duration: Duration,
+    branches: usize,
+    keys: usize,
+    commit_interval: Duration,
+    memory_budget_bytes: u64,
+    max_lag: Duration,
+}
+
+impl SoakSettings {
+    fn from_env() -> Self {
+        Self {
+            duration: Duration::from_secs(env_or("GITDIS_SOAK_DURATION_SECS", 4 * 3600)),
+            branches: env_or("GITDIS_SOAK_BRANCHES", 16) as usize,
+            keys: env_or("GITDIS_SOAK_KEYS", 50) as usize,
+            commit_interval: Duration::from_millis(env_or(
+                "GITDIS_SOAK_COMMIT_INTERVAL_MILLIS",
+                100,
+            )),
+            memory_budget_bytes: env_or("GITDIS_SOAK_MEMORY_BUDGET_MB", 256) * 1024 * 1024,
+            max_lag: Duration::from_millis(env_or("GITDIS_SOAK_MAX_LAG_MILLIS", 15_000)),
+        }
+    }
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// What a fixture branch holds in git, to check the cache against.
+struct BranchTruth {
+    name: String,
+    key: String,
+    values: HashMap<String, String>,
+    deleted: HashSet<String>,
+    /// Commits not loaded yet, oldest first, with when they were made.
+    pending: VecDeque<(String, Instant)>,
+}
+
+/// Drives a fixture repository with continuous commits on many branches
+/// and fails on the first broken invariant: memory growing past its budget,
+/// a key differing from git, or a commit loaded later than the lag budget.
+///
+///     GITDIS_SOAK_DURATION_SECS=3600 gitdis-soak
+fn main() {
+    env_logger::init();
+
+    let settings = SoakSettings::from_env();
+    let root = std::env::temp_dir().join(format!("gitdis-soak-{}", std::process::id()));
+    let root = root.to_string_lossy().to_string();
+    let origin = format!("{}/origin/soak/fixture", root);
+    let mut random = Random::new();
+
+    std::fs::create_dir_all(&origin).expect("Failed to create the fixture");
+    git(&origin, &["init", "-b", "main"]);
+    git(&origin, &["commit", "--allow-empty", "-m", "fixture"]);
+
+    let gitdis = Gitdis::from(GitdisSettings {
+        total_branch_items: settings.keys * 2,
+        local_clone_path: format!("{}/clones", root),
+        proxy: None,
+        maintenance_interval_millis: Some(60_000),
+        max_network_operations: None,
+    });
+
+    // Commits loaded by each branch, as announced by events.
+    let loaded: Arc<Mutex<HashMap<String, String>>> = Arc::default();
+    {
+        let loaded = loaded.clone();
+
+        gitdis
+            .get_events()
+            .subscribe(Arc::new(move |event: &BranchEvent| {
+                if let BranchEventKind::SyncCompleted { commit } = &event.event {
+                    if let Ok(mut loaded) = loaded.lock() {
+                        loaded.insert(event.branch_key.clone(), commit.clone());
+                    }
+                }
+            }));
+    }
+
+    let mut service = GitdisService::new(RwLock::new(gitdis).into());
+    let mut branches = Vec::new();
+
+    for index in 0..settings.branches {
+        let name = format!("soak-{}", index);
+
+        git(&origin, &["checkout", "-q", "-b", &name, "main"]);
+
+        let mut branch = BranchTruth {
+            key: String::new(),
+            name: name.clone(),
+            values: HashMap::new(),
+            deleted: HashSet::new(),
+            pending: VecDeque::new(),
+        };
+
+        for key in 0..settings.keys {
+            write_key(&origin, &mut branch, &format!("keys/k{}", key), &mut random);
+        }
+
+        commit(&origin, &mut branch);
+
+        let branch_settings = BranchSettings {
+            url: format!("file://{}", origin),
+            branch_name: name,
+            pull_request_interval_millis: PULL_INTERVAL_MILLIS,
+            ..BranchSettings::default()
+        };
+        branch.key = branch_settings.get_repo_key();
+
+        service
+            .add_repo(branch_settings)
+            .expect("Failed to register a branch");
+        service
+            .listen_branch(&branch.key)
+            .expect("Failed to listen to a branch");
+
+        branches.push(branch);
+    }
+
+    println!(
+        "Soaking {} branches of {} keys for {:?}",
+        settings.branches, settings.keys, settings.duration
+    );
+
+    let started = Instant::now();
+    let mut baseline_rss = None;
+    let mut next_check = started + CHECK_INTERVAL;
+    let mut commits = 0u64;
+
+    while started.elapsed() < settings.duration {
+        let branch = &mut branches[random.below(settings.branches as u64) as usize];
+
+        git(&origin, &["checkout", "-q", &branch.name]);
+        mutate(&origin, branch, settings.keys, &mut random);
+        commit(&origin, branch);
+        commits += 1;
+
+        if Instant::now() >= next_check {
+            next_check = Instant::now() + CHECK_INTERVAL;
+
+            check_branches(&service, &loaded, &mut branches, settings.max_lag, false);
+
+            let rss = read_rss_bytes();
+
+            if started.elapsed() >= WARMUP && baseline_rss.is_none() {
+                baseline_rss = rss;
+            }
+
+            if let (Some(baseline), Some(rss)) = (baseline_rss, rss) {
+                let growth = rss.saturating_sub(baseline);
+
+                if growth > settings.memory_budget_bytes {
+                    fail(&format!(
+                        "Memory grew by {} bytes past the budget of {}",
+                        growth, settings.memory_budget_bytes
+                    ));
+                }
+            }
+
+            println!(
+                "{:?}: {} commits, rss {} bytes",
+                started.elapsed(),
+                commits,
+                rss.unwrap_or_default()
+            );
+        }
+
+        std::thread::sleep(settings.commit_interval);
+    }
+
+    // Every branch has to catch up with its last commit within the lag
+    // budget once commits stop.
+    let deadline = Instant::now() + settings.max_lag;
+
+    while Instant::now() < deadline
+        && !check_branches(&service, &loaded, &mut branches, settings.max_lag, false)
+    {
+        std::thread::sleep(Duration::from_millis(PULL_INTERVAL_MILLIS));
+    }
+
+    check_branches(&service, &loaded, &mut branches, settings.max_lag, true);
+
+    println!(
+        "Soak passed: {} commits in {:?}",
+        commits,
+        started.elapsed()
+    );
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+/// Checks the branches that loaded their head against git, and that none
+/// waited for a commit longer than `max_lag`, or at all when `settled`.
+/// Returns whether every branch loaded its head.
+fn check_branches(
+    service: &GitdisService,
+    loaded: &Mutex<HashMap<String, String>>,
+    branches: &mut [BranchTruth],
+    max_lag: Duration,
+    settled: bool,
+) -> bool {
+    let loaded = loaded
+        .lock()
+        .map(|loaded| loaded.clone())
+        .unwrap_or_default();
+    let mut all_loaded = true;
+
+    for branch in branches.iter_mut() {
+        // Loading a commit covers the ones before it, which may have been
+        // pulled together.
+        if let Some(commit) = loaded.get(&branch.key) {
+            if let Some(index) = branch.pending.iter().position(|(hash, _)| hash == commit) {
+                branch.pending.drain(..=index);
+            }
+        }
+
+        let (commit, made_at) = match branch.pending.front() {
+            Some(pending) => pending,
+            None => {
+                check_keys(service, branch);
+                continue;
+            }
+        };
+
+        all_loaded = false;
+
+        if settled || made_at.elapsed() > max_lag {
+            fail(&format!(
+                "{} has not loaded {} after {:?}",
+                branch.key,
+                commit,
+                made_at.elapsed()
+            ));
+        }
+    }
+
+    all_loaded
+}
+
+fn check_keys(service: &GitdisService, branch: &BranchTruth) {
+    for (key, expected) in &branch.values {
+        let expected = Value::from(HashMap::from([(
+            "value".to_string(),
+            Value::from(expected.as_str()),
+        )]));
+
+        match service.get_object(&branch.key, key) {
+            Ok(value) if value == expected => (),
+            Ok(value) => fail(&format!(
+                "{} {} is {}, expected {}",
+                branch.key,
+                key,
+                value.to_json(JsonMode::Inline),
+                expected.to_json(JsonMode::Inline)
+            )),
+            Err(err) => fail(&format!("{} {} is missing: {:?}", branch.key, key, err)),
+        }
+    }
+
+    for key in &branch.deleted {
+        if service.get_object(&branch.key, key).is_ok() {
+            fail(&format!(
+                "{} {} was deleted but is still loaded",
+                branch.key, key
+            ));
+        }
+    }
+}
+
+/// Updates, deletes or adds back a random key of the checked out branch.
+fn mutate(origin: &str, branch: &mut BranchTruth, keys: usize, random: &mut Random) {
+    let key = format!("keys/k{}", random.below(keys as u64));
+
+    if branch.values.contains_key(&key) && random.below(10) == 0 {
+        std::fs::remove_file(format!("{}/{}.yaml", origin, key)).expect("Failed to delete a key");
+        branch.values.remove(&key);
+        branch.deleted.insert(key);
+    } else {
+        write_key(origin, branch, &key, random);
+    }
+}
+
+fn write_key(origin: &str, branch: &mut BranchTruth, key: &str, random: &mut Random) {
+    let value = format!("v-{}", random.next_u64());
+    let file = format!("{}/{}.yaml", origin, key);
+
+    if let Some(dir) = std::path::Path::new(&file).parent() {
+        std::fs::create_dir_all(dir).expect("Failed to create a key directory");
+    }
+
+    std::fs::write(&file, format!("value: \"{}\"\n", value)).expect("Failed to write a key");
+
+    branch.deleted.remove(key);
+    branch.values.insert(key.to_string(), value);
+}
+
+fn commit(origin: &str, branch: &mut BranchTruth) {
+    git(origin, &["add", "-A"]);
+    git(origin, &["commit", "-q", "-m", "soak"]);
+
+    let head = git(origin, &["rev-parse", "HEAD"]);
+    branch.pending.push_back((head, Instant::now()));
+}
+
+/// Runs git in `dir` with a throwaway identity, returning its output.
+fn git(dir: &str, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .args([
+            "-c",
+            "user.name=gitdis",
+            "-c",
+            "user.email=gitdis@localhost",
+        ])
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .expect("Failed to execute git");
+
+    if !output.status.success() {
+        fail(&String::from_utf8_lossy(&output.stderr));
+    }
+
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// Resident memory of the process, on Linux.
+fn read_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("Soak failed: {}", message);
+    std::process::exit(1);
+}
+
+/// Xorshift, enough to pick keys without pulling in a crate.
+struct Random(u64);
+
+impl Random {
+    fn new() -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|now| now.as_nanos() as u64)
+            .unwrap_or_default();
+
+        Self(seed | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
@@ -0,0 +1,43 @@
+use std::process::Command;
+
+/// Records the commit and the enabled features of the build for `/version`.
+fn main() {
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or("unknown".to_string());
+
+    let mut features = std::env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect::<Vec<String>>();
+    features.sort();
+
+    println!("cargo:rustc-env=GITDIS_GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=GITDIS_FEATURES={}", features.join(","));
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+
+    // A commit moves the branch HEAD points to, not HEAD itself. Its ref
+    // may be loose or packed; a missing path would rerun every build.
+    let head_ref = std::fs::read_to_string("../.git/HEAD")
+        .ok()
+        .and_then(|head| {
+            head.strip_prefix("ref: ")
+                .map(|name| name.trim().to_string())
+        });
+
+    for path in head_ref
+        .map(|name| format!("../.git/{}", name))
+        .into_iter()
+        .chain(["../.git/packed-refs".to_string()])
+    {
+        if std::path::Path::new(&path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
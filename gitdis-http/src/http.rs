@@ -1,25 +1,90 @@
-use crate::routers::routes;
+use crate::routers::{routes, Capabilities, Registry};
 use gitdis::prelude::GitdisService;
 use log::debug;
+use std::time::Duration;
+
+/// How long listeners get to finish their current sync on shutdown.
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct HttpServer {
     port: String,
     service: GitdisService,
+    capabilities: Capabilities,
+    registry: Registry,
 }
 
 impl HttpServer {
-    pub fn new(port: String, service: GitdisService) -> Self {
-        Self { port, service }
+    pub fn new(
+        port: String,
+        service: GitdisService,
+        capabilities: Capabilities,
+        registry: Registry,
+    ) -> Self {
+        Self {
+            port,
+            service,
+            capabilities,
+            registry,
+        }
     }
 
     pub async fn listen(&self) {
         let port = self.port.clone();
-        let routes = routes(self.service.clone());
+        let routes = routes(
+            self.service.clone(),
+            self.capabilities.clone(),
+            self.registry.clone(),
+        );
 
         let address = format!("0.0.0.0:{}", port);
         let listener = tokio::net::TcpListener::bind(address).await.unwrap();
 
         debug!("Starting gitdis http server");
-        axum::serve(listener, routes).await.unwrap();
+        axum::serve(listener, routes)
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+            .unwrap();
+
+        // Background tasks stop first, so none starts a run on a branch
+        // being stopped.
+        self.capabilities.shutdown();
+
+        let timeout = std::env::var("GITDIS_SHUTDOWN_TIMEOUT_MILLIS")
+            .ok()
+            .and_then(|timeout| timeout.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+
+        let service = self.service.clone();
+        let stopped = tokio::task::spawn_blocking(move || service.shutdown(timeout)).await;
+
+        debug!("Stopped branch listeners: {:?}", stopped);
+    }
+}
+
+/// Resolves on Ctrl+C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
     }
+
+    debug!("Shutting down gitdis http server");
 }
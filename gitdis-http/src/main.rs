@@ -1,11 +1,24 @@
 mod http;
 mod routers;
+#[cfg(test)]
+mod tests;
 
 use gitdis::prelude::*;
 use http::HttpServer;
 use log::debug;
+use routers::{Capabilities, Capability, Registry, DEFAULT_REGISTRY_FILE};
 use std::sync::{Arc, RwLock};
 
+/// How often repos registered with a branch pattern are listed upstream,
+/// unless `GITDIS_DISCOVERY_INTERVAL_MILLIS` says otherwise.
+const DEFAULT_DISCOVERY_INTERVAL_MILLIS: u64 = 60_000;
+/// How often unused keys are posted to `GITDIS_UNUSED_KEYS_WEBHOOK_URL`,
+/// unless `GITDIS_UNUSED_KEYS_INTERVAL_MILLIS` says otherwise.
+const DEFAULT_UNUSED_KEYS_INTERVAL_MILLIS: u64 = 24 * 60 * 60 * 1000;
+/// How often render jobs are checked for branches that moved, unless
+/// `GITDIS_RENDER_JOBS_INTERVAL_MILLIS` says otherwise.
+const DEFAULT_RENDER_JOBS_INTERVAL_MILLIS: u64 = 1000;
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     debug!("Starting gitdis");
@@ -24,15 +37,182 @@ async fn main() -> std::io::Result<()> {
         local_clone_path
     );
 
+    let registry_path = std::env::var("GITDIS_REGISTRY_PATH")
+        .unwrap_or(format!("{}/{}", local_clone_path, DEFAULT_REGISTRY_FILE));
+    let registry = Registry::open(&registry_path).expect("Failed to read the registry");
+
     let gitdis = Gitdis::from(GitdisSettings {
         total_branch_items: 100,
         local_clone_path,
+        proxy: std::env::var("GITDIS_PROXY").ok(),
+        maintenance_interval_millis: std::env::var("GITDIS_MAINTENANCE_INTERVAL_MILLIS")
+            .ok()
+            .and_then(|interval| interval.parse().ok()),
+        max_network_operations: std::env::var("GITDIS_MAX_NETWORK_OPERATIONS")
+            .ok()
+            .and_then(|limit| limit.parse().ok()),
     });
 
-    let service = GitdisService::new(gitdis);
+    let service = GitdisService::new(Arc::new(RwLock::new(gitdis)));
+
+    // Dumps are written as JSON unless `GITDIS_DUMP_CODEC` names another
+    // codec compiled in.
+    let service = match std::env::var("GITDIS_DUMP_PATH") {
+        Ok(dump_path) => match std::env::var("GITDIS_DUMP_CODEC") {
+            Ok(codec) => {
+                let codec = service
+                    .get_codecs()
+                    .get(&codec)
+                    .expect("Unknown GITDIS_DUMP_CODEC");
+
+                service.with_dump_store(DumpStore::new(dump_path).with_codec(codec))
+            }
+            Err(_) => service.with_dumps(dump_path),
+        },
+        Err(_) => service,
+    };
+
+    // Subscribers can be backfilled unless the journal is turned off with 0.
+    let mut service = match std::env::var("GITDIS_EVENT_JOURNAL_CAPACITY")
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+        .unwrap_or(DEFAULT_JOURNAL_CAPACITY)
+    {
+        0 => service,
+        capacity => service.with_journal(capacity),
+    };
+
+    // Branches registered before the restart are registered again before
+    // the clone directory is reconciled, so their clones are adopted.
+    let orphans = match std::env::var("GITDIS_RECONCILE_CLONES").as_deref() {
+        Ok("quarantine") => Some(OrphanPolicy::Quarantine),
+        Ok("delete") => Some(OrphanPolicy::Delete),
+        _ => None,
+    };
+    let report = registry.restore(&mut service, orphans);
+    debug!(
+        "Restored {:?}, reconciled: {:?}",
+        registry.get_keys(),
+        report
+    );
+
+    // Background tasks are registered with their capability, which starts
+    // them unless disabled and restarts them when re-enabled at runtime.
+    let capabilities = Capabilities::from_env();
+
+    if let Some(interval) = std::env::var("GITDIS_AUTO_ARCHIVE_INTERVAL_MILLIS")
+        .ok()
+        .and_then(|interval| interval.parse().ok())
+    {
+        let service = service.clone();
+
+        capabilities.register(Capability::AutoArchive, move || {
+            let service = service.clone();
+
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(std::time::Duration::from_millis(interval));
+
+                loop {
+                    ticker.tick().await;
+
+                    let service = service.clone();
+                    let _ =
+                        tokio::task::spawn_blocking(move || service.archive_idle_branches()).await;
+                }
+            })
+        });
+    }
+
+    if let Ok(url) = std::env::var("GITDIS_UNUSED_KEYS_WEBHOOK_URL") {
+        let interval = std::env::var("GITDIS_UNUSED_KEYS_INTERVAL_MILLIS")
+            .ok()
+            .and_then(|interval| interval.parse().ok())
+            .unwrap_or(DEFAULT_UNUSED_KEYS_INTERVAL_MILLIS);
+        let days = std::env::var("GITDIS_UNUSED_KEYS_DAYS")
+            .ok()
+            .and_then(|days| days.parse().ok())
+            .unwrap_or(DEFAULT_UNUSED_DAYS);
+        let service = service.clone();
+
+        capabilities.register(Capability::UnusedKeysReport, move || {
+            let service = service.clone();
+            let url = url.clone();
+
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(std::time::Duration::from_millis(interval));
+
+                loop {
+                    ticker.tick().await;
+
+                    let service = service.clone();
+                    let url = url.clone();
+                    let posted = tokio::task::spawn_blocking(move || {
+                        service.post_unused_keys(days as u128 * DAY_MILLIS, &url)
+                    })
+                    .await;
+                    debug!("Unused keys posted: {:?}", posted);
+                }
+            })
+        });
+    }
+
+    {
+        let interval = std::env::var("GITDIS_RENDER_JOBS_INTERVAL_MILLIS")
+            .ok()
+            .and_then(|interval| interval.parse().ok())
+            .unwrap_or(DEFAULT_RENDER_JOBS_INTERVAL_MILLIS);
+        let service = service.clone();
+
+        capabilities.register(Capability::RenderJobs, move || {
+            let service = service.clone();
+
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(std::time::Duration::from_millis(interval));
+
+                loop {
+                    ticker.tick().await;
+
+                    let service = service.clone();
+                    let run =
+                        tokio::task::spawn_blocking(move || service.run_due_render_jobs()).await;
+                    debug!("Render jobs run: {:?}", run);
+                }
+            })
+        });
+    }
+
+    // Only repos registered with a branch pattern are listed upstream.
+    let discovery_interval = std::env::var("GITDIS_DISCOVERY_INTERVAL_MILLIS")
+        .ok()
+        .and_then(|interval| interval.parse().ok())
+        .unwrap_or(DEFAULT_DISCOVERY_INTERVAL_MILLIS);
+
+    {
+        let service = service.clone();
+
+        capabilities.register(Capability::Discovery, move || {
+            let service = service.clone();
+
+            tokio::spawn(async move {
+                let mut ticker =
+                    tokio::time::interval(std::time::Duration::from_millis(discovery_interval));
+
+                loop {
+                    ticker.tick().await;
+
+                    let service = service.clone();
+                    let reports =
+                        tokio::task::spawn_blocking(move || service.discover_branches()).await;
+                    debug!("Branch discovery: {:?}", reports);
+                }
+            })
+        });
+    }
+
+    debug!("Enabled capabilities: {:?}", capabilities.get_enabled());
 
-    let server = HttpServer::new(http_port, service);
-    server.listen();
+    let server = HttpServer::new(http_port, service, capabilities, registry);
+    server.listen().await;
 
     Ok(())
 }
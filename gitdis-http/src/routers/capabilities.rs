@@ -0,0 +1,306 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::IntoResponse,
+    Router,
+};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tokio::task::JoinHandle;
+
+use super::routes::coded_error;
+use super::Response;
+
+/// When set, the comma-separated capabilities enabled at startup; all
+/// others start disabled.
+pub const CAPABILITIES_ENV: &str = "GITDIS_CAPABILITIES";
+/// Comma-separated capabilities disabled at startup.
+pub const DISABLED_CAPABILITIES_ENV: &str = "GITDIS_DISABLED_CAPABILITIES";
+
+/// An optional subsystem of the server. Route capabilities gate the
+/// routes they serve, task capabilities run in the background.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    Webhooks,
+    Streams,
+    /// Template rendering and render jobs.
+    Render,
+    /// Ad hoc and saved queries, and views.
+    Queries,
+    /// Memo, serialization and usage metrics.
+    Metrics,
+    Sql,
+    Export,
+    /// Listing branches of repos registered with a branch pattern.
+    Discovery,
+    /// Running render jobs of branches that moved.
+    RenderJobs,
+    AutoArchive,
+    UnusedKeysReport,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 11] = [
+        Capability::Webhooks,
+        Capability::Streams,
+        Capability::Render,
+        Capability::Queries,
+        Capability::Metrics,
+        Capability::Sql,
+        Capability::Export,
+        Capability::Discovery,
+        Capability::RenderJobs,
+        Capability::AutoArchive,
+        Capability::UnusedKeysReport,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Webhooks => "webhooks",
+            Capability::Streams => "streams",
+            Capability::Render => "render",
+            Capability::Queries => "queries",
+            Capability::Metrics => "metrics",
+            Capability::Sql => "sql",
+            Capability::Export => "export",
+            Capability::Discovery => "discovery",
+            Capability::RenderJobs => "render_jobs",
+            Capability::AutoArchive => "auto_archive",
+            Capability::UnusedKeysReport => "unused_keys_report",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|capability| capability.name() == name)
+    }
+
+    /// Whether it runs in the background rather than serving routes.
+    pub fn is_task(&self) -> bool {
+        matches!(
+            self,
+            Capability::Discovery
+                | Capability::RenderJobs
+                | Capability::AutoArchive
+                | Capability::UnusedKeysReport
+        )
+    }
+
+    /// Whether the binary was compiled with it.
+    fn is_compiled(&self) -> bool {
+        !matches!(self, Capability::Sql if !cfg!(feature = "sql"))
+            && !matches!(self, Capability::Export if !cfg!(feature = "export"))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CapabilityError {
+    /// Not compiled in or, for a task, not configured.
+    Unavailable,
+}
+
+type StartHook = Arc<dyn Fn() -> JoinHandle<()> + Send + Sync>;
+
+#[derive(Default)]
+struct CapabilityState {
+    enabled: bool,
+    /// Spawns the task of a task capability; `None` until registered.
+    start: Option<StartHook>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl CapabilityState {
+    /// Task capabilities are only ready once registered.
+    fn is_ready(&self, capability: Capability) -> bool {
+        !capability.is_task() || self.start.is_some()
+    }
+}
+
+/// Which capabilities are enabled, toggled at runtime. Disabling a route
+/// capability answers its routes with 404, disabling a task capability
+/// stops its task at its next await point; a run already handed to a
+/// blocking thread finishes first.
+#[derive(Clone)]
+pub struct Capabilities {
+    states: Arc<RwLock<HashMap<Capability, CapabilityState>>>,
+}
+
+impl Capabilities {
+    /// Reads `GITDIS_CAPABILITIES` and `GITDIS_DISABLED_CAPABILITIES`.
+    /// Unknown names are ignored.
+    pub fn from_env() -> Self {
+        let names = |name: &str| {
+            std::env::var(name).ok().map(|names| {
+                names
+                    .split(',')
+                    .map(|name| name.trim())
+                    .filter(|name| !name.is_empty())
+                    .filter_map(|name| {
+                        let capability = Capability::from_name(name);
+
+                        if capability.is_none() {
+                            debug!("Ignoring unknown capability: {}", name);
+                        }
+
+                        capability
+                    })
+                    .collect::<Vec<Capability>>()
+            })
+        };
+
+        let enabled = names(CAPABILITIES_ENV);
+        let disabled = names(DISABLED_CAPABILITIES_ENV).unwrap_or_default();
+
+        Self::new(|capability| {
+            enabled
+                .as_ref()
+                .is_none_or(|enabled| enabled.contains(&capability))
+                && !disabled.contains(&capability)
+        })
+    }
+
+    fn new(enabled: impl Fn(Capability) -> bool) -> Self {
+        let states = Capability::ALL
+            .into_iter()
+            .map(|capability| {
+                (
+                    capability,
+                    CapabilityState {
+                        enabled: capability.is_compiled() && enabled(capability),
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            states: Arc::new(RwLock::new(states)),
+        }
+    }
+
+    /// Makes a task capability available with the hook spawning its task,
+    /// which is started now if it is enabled and again each time it is
+    /// re-enabled. Tasks left unregistered, e.g. not configured, stay
+    /// unavailable.
+    pub fn register(
+        &self,
+        capability: Capability,
+        start: impl Fn() -> JoinHandle<()> + Send + Sync + 'static,
+    ) {
+        let mut states = self.states.write().unwrap();
+        let state = states.entry(capability).or_default();
+        let start: StartHook = Arc::new(start);
+
+        if state.enabled {
+            state.task = Some(start());
+        }
+
+        state.start = Some(start);
+    }
+
+    pub fn is_enabled(&self, capability: Capability) -> bool {
+        self.states
+            .read()
+            .unwrap()
+            .get(&capability)
+            .is_some_and(|state| state.enabled && state.is_ready(capability))
+    }
+
+    /// Whether `capability` can be enabled.
+    pub fn is_available(&self, capability: Capability) -> bool {
+        capability.is_compiled()
+            && self
+                .states
+                .read()
+                .unwrap()
+                .get(&capability)
+                .is_some_and(|state| state.is_ready(capability))
+    }
+
+    /// Enabled capabilities, in `Capability::ALL` order.
+    pub fn get_enabled(&self) -> Vec<Capability> {
+        Capability::ALL
+            .into_iter()
+            .filter(|capability| self.is_enabled(*capability))
+            .collect()
+    }
+
+    /// Returns whether it was disabled before.
+    pub fn enable(&self, capability: Capability) -> Result<bool, CapabilityError> {
+        if !self.is_available(capability) {
+            return Err(CapabilityError::Unavailable);
+        }
+
+        let mut states = self.states.write().unwrap();
+        let state = states.entry(capability).or_default();
+
+        if state.enabled {
+            return Ok(false);
+        }
+
+        state.enabled = true;
+        state.task = state.start.as_ref().map(|start| start());
+
+        debug!("Enabled capability: {}", capability.name());
+
+        Ok(true)
+    }
+
+    /// Returns whether it was enabled before.
+    pub fn disable(&self, capability: Capability) -> bool {
+        let mut states = self.states.write().unwrap();
+        let state = states.entry(capability).or_default();
+
+        if let Some(task) = state.task.take() {
+            task.abort();
+        }
+
+        let was_enabled = std::mem::replace(&mut state.enabled, false);
+
+        if was_enabled {
+            debug!("Disabled capability: {}", capability.name());
+        }
+
+        was_enabled
+    }
+
+    /// Stops every task, leaving what is enabled as is.
+    pub fn shutdown(&self) {
+        for state in self.states.write().unwrap().values_mut() {
+            if let Some(task) = state.task.take() {
+                task.abort();
+            }
+        }
+    }
+
+    /// Answers the routes of `router` with 404 while `capability` is
+    /// disabled.
+    pub fn gate(&self, capability: Capability, router: Router) -> Router {
+        router.route_layer(middleware::from_fn_with_state(
+            (self.clone(), capability),
+            require_capability,
+        ))
+    }
+}
+
+async fn require_capability(
+    State((capabilities, capability)): State<(Capabilities, Capability)>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    if capabilities.is_enabled(capability) {
+        return next.run(request).await;
+    }
+
+    Response {
+        status: StatusCode::NOT_FOUND,
+        data: coded_error(
+            "capability_disabled",
+            format!("Capability disabled: {}", capability.name()),
+        ),
+    }
+    .into_response()
+}
@@ -1,5 +1,198 @@
-use axum::response::IntoResponse;
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension};
+use gitdis::prelude::*;
+use log::debug;
+use std::collections::HashMap;
+
+use super::capabilities::{Capabilities, Capability, CapabilityError};
+use super::routes::coded_error;
+use super::versioning::API_VERSIONS;
+use super::{MessageError, Response};
 
 pub async fn health_check() -> impl IntoResponse {
     "OK"
 }
+
+/// Unhealthy while a managed clone was edited in place, since the config
+/// served for it may have diverged from git.
+pub async fn clones_health_check(
+    Extension(service): Extension<GitdisService>,
+) -> impl IntoResponse {
+    let dirty_branches = match service.get_dirty_branches() {
+        Ok(dirty_branches) => dirty_branches,
+        Err(_) => {
+            return Response {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                data: Value::Null,
+            }
+        }
+    };
+
+    let status = match dirty_branches.is_empty() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    Response {
+        status,
+        data: Value::from(HashMap::from([(
+            "dirty_branches".to_string(),
+            Value::from(
+                dirty_branches
+                    .into_iter()
+                    .map(Value::from)
+                    .collect::<Vec<Value>>(),
+            ),
+        )])),
+    }
+}
+
+pub async fn get_version(
+    Extension(service): Extension<GitdisService>,
+    Extension(capabilities): Extension<Capabilities>,
+) -> impl IntoResponse {
+    let mut data = HashMap::new();
+
+    data.insert(
+        "version".to_string(),
+        Value::from(env!("CARGO_PKG_VERSION")),
+    );
+    data.insert("git_sha".to_string(), Value::from(env!("GITDIS_GIT_SHA")));
+    data.insert(
+        "features".to_string(),
+        Value::from(
+            env!("GITDIS_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(Value::from)
+                .collect::<Vec<Value>>(),
+        ),
+    );
+    data.insert(
+        "api_versions".to_string(),
+        Value::from(
+            API_VERSIONS
+                .iter()
+                .map(|version| Value::from(*version))
+                .collect::<Vec<Value>>(),
+        ),
+    );
+
+    data.insert(
+        "capabilities".to_string(),
+        Value::from(
+            capabilities
+                .get_enabled()
+                .iter()
+                .map(|capability| Value::from(capability.name()))
+                .collect::<Vec<Value>>(),
+        ),
+    );
+    data.insert(
+        "codecs".to_string(),
+        Value::from(
+            service
+                .get_codecs()
+                .get_names()
+                .into_iter()
+                .map(Value::from)
+                .collect::<Vec<Value>>(),
+        ),
+    );
+    data.insert("wire_version".to_string(), Value::from(WIRE_VERSION as u64));
+
+    Response {
+        status: StatusCode::OK,
+        data: Value::from(data),
+    }
+}
+
+/// Every capability, whether it is available and whether it is enabled.
+pub async fn get_capabilities(
+    Extension(capabilities): Extension<Capabilities>,
+) -> impl IntoResponse {
+    let data = Capability::ALL
+        .iter()
+        .map(|capability| {
+            let mut object = HashMap::new();
+
+            object.insert("name".to_string(), Value::from(capability.name()));
+            object.insert(
+                "kind".to_string(),
+                Value::from(match capability.is_task() {
+                    true => "task",
+                    false => "routes",
+                }),
+            );
+            object.insert(
+                "available".to_string(),
+                Value::from(capabilities.is_available(*capability)),
+            );
+            object.insert(
+                "enabled".to_string(),
+                Value::from(capabilities.is_enabled(*capability)),
+            );
+
+            Value::from(object)
+        })
+        .collect::<Vec<Value>>();
+
+    Response {
+        status: StatusCode::OK,
+        data: Value::from(data),
+    }
+}
+
+fn unknown_capability(name: &str) -> Response<Value> {
+    Response {
+        status: StatusCode::NOT_FOUND,
+        data: coded_error(
+            "unknown_capability",
+            format!("Unknown capability: {}", name),
+        ),
+    }
+}
+
+pub async fn enable_capability(
+    Extension(capabilities): Extension<Capabilities>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    debug!("Enabling capability router");
+
+    let capability = match Capability::from_name(&name) {
+        Some(capability) => capability,
+        None => return unknown_capability(&name),
+    };
+
+    match capabilities.enable(capability) {
+        Ok(_) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("Capability enabled".to_string()).to_value(),
+        },
+        Err(CapabilityError::Unavailable) => Response {
+            status: StatusCode::CONFLICT,
+            data: coded_error(
+                "capability_unavailable",
+                format!("Capability not compiled in or not configured: {}", name),
+            ),
+        },
+    }
+}
+
+pub async fn disable_capability(
+    Extension(capabilities): Extension<Capabilities>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    debug!("Disabling capability router");
+
+    let capability = match Capability::from_name(&name) {
+        Some(capability) => capability,
+        None => return unknown_capability(&name),
+    };
+
+    capabilities.disable(capability);
+
+    Response {
+        status: StatusCode::OK,
+        data: MessageError::new("Capability disabled".to_string()).to_value(),
+    }
+}
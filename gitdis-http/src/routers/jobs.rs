@@ -0,0 +1,255 @@
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension};
+use gitdis::prelude::*;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::routes::resolve_errors;
+use super::validation::{FieldErrors, Validate, Validated};
+use super::{MessageError, Response};
+
+/// Directory file targets are written under. Without it, file targets are
+/// refused.
+pub const RENDER_FILE_ROOT_ENV: &str = "GITDIS_RENDER_FILE_ROOT";
+
+#[derive(Deserialize, Debug)]
+pub struct RenderJobParams {
+    owner: String,
+    repo: String,
+    branch: String,
+    name: String,
+}
+
+impl RenderJobParams {
+    fn get_branch_key(&self) -> String {
+        get_branch_key(&self.owner, &self.repo, &self.branch)
+    }
+}
+
+/// See `PublishTarget`.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RenderTargetBody {
+    /// `file`, `s3` or `http_put`.
+    #[serde(rename = "type")]
+    kind: String,
+    /// For `file`, relative to `GITDIS_RENDER_FILE_ROOT`.
+    path: Option<String>,
+    bucket: Option<String>,
+    key: Option<String>,
+    region: Option<String>,
+    /// For `s3`, an S3-compatible store instead of AWS.
+    endpoint: Option<String>,
+    url: Option<String>,
+}
+
+impl RenderTargetBody {
+    fn validate(&self, errors: &mut FieldErrors) {
+        let mut require = |field: &str, value: &Option<String>| {
+            if value.as_deref().is_none_or(|value| value.trim().is_empty()) {
+                errors.add(&format!("target.{}", field), "Missing");
+            }
+        };
+
+        match self.kind.as_str() {
+            "file" => require("path", &self.path),
+            "s3" => {
+                require("bucket", &self.bucket);
+                require("key", &self.key);
+                require("region", &self.region);
+            }
+            "http_put" => require("url", &self.url),
+            _ => errors.add("target.type", "Must be file, s3 or http_put"),
+        }
+
+        if self.kind == "file" {
+            if std::env::var(RENDER_FILE_ROOT_ENV).is_err() {
+                errors.add(
+                    "target.type",
+                    &format!("File targets need {}", RENDER_FILE_ROOT_ENV),
+                );
+            }
+
+            if self.path.as_deref().is_some_and(|path| {
+                path.starts_with('/') || path.split('/').any(|part| part == "..")
+            }) {
+                errors.add("target.path", "Must be a relative path without ..");
+            }
+        }
+
+        for (field, url) in [("endpoint", &self.endpoint), ("url", &self.url)] {
+            if url
+                .as_deref()
+                .is_some_and(|url| !url.starts_with("http://") && !url.starts_with("https://"))
+            {
+                errors.add(&format!("target.{}", field), "Must be an http(s) URL");
+            }
+        }
+    }
+
+    /// Call after `validate`.
+    fn to_target(&self) -> PublishTarget {
+        match self.kind.as_str() {
+            "s3" => PublishTarget::S3 {
+                bucket: self.bucket.clone().unwrap_or_default(),
+                key: self.key.clone().unwrap_or_default(),
+                region: self.region.clone().unwrap_or_default(),
+                endpoint: self.endpoint.clone(),
+            },
+            "http_put" => PublishTarget::HttpPut {
+                url: self.url.clone().unwrap_or_default(),
+            },
+            _ => PublishTarget::File {
+                path: format!(
+                    "{}/{}",
+                    std::env::var(RENDER_FILE_ROOT_ENV)
+                        .unwrap_or_default()
+                        .trim_end_matches('/'),
+                    self.path.as_deref().unwrap_or_default()
+                ),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RenderJobBody {
+    /// The template itself; give either it or `path`.
+    template: Option<String>,
+    /// A template file of the repository, relative to its root.
+    path: Option<String>,
+    /// Only this prefix of the branch as context.
+    prefix: Option<String>,
+    target: RenderTargetBody,
+    interval_millis: Option<u64>,
+}
+
+impl Validate for RenderJobBody {
+    const FIELDS: &'static [&'static str] =
+        &["template", "path", "prefix", "target", "interval_millis"];
+
+    fn validate(&self, errors: &mut FieldErrors) {
+        match (&self.template, &self.path) {
+            (Some(_), Some(_)) => errors.add(".", "Needs a template or a path, not both"),
+            (None, None) => errors.add("template", "Missing template or path"),
+            (None, Some(path)) if path.trim_matches('/').is_empty() => {
+                errors.add("path", "Must not be empty")
+            }
+            _ => (),
+        }
+
+        self.target.validate(errors);
+    }
+}
+
+impl RenderJobBody {
+    fn to_job(&self) -> RenderJob {
+        RenderJob {
+            template: match (&self.template, &self.path) {
+                (Some(template), _) => TemplateSource::Inline(template.clone()),
+                (None, path) => TemplateSource::File(path.clone().unwrap_or_default()),
+            },
+            prefix: self.prefix.clone().unwrap_or_default(),
+            target: self.target.to_target(),
+            interval_millis: self.interval_millis.unwrap_or(DEFAULT_JOB_INTERVAL_MILLIS),
+        }
+    }
+}
+
+fn job_to_value(branch_key: &str, name: &str, job: &RenderJob, status: &RenderJobStatus) -> Value {
+    let mut object = HashMap::new();
+
+    object.insert("branch".to_string(), Value::from(branch_key));
+    object.insert("name".to_string(), Value::from(name));
+    object.insert("job".to_string(), job.to_value());
+    object.insert("status".to_string(), status.to_value());
+
+    Value::from(object)
+}
+
+pub async fn save_render_job(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<RenderJobParams>,
+    Validated(payload): Validated<RenderJobBody>,
+) -> impl IntoResponse {
+    debug!("Saving render job router");
+
+    match service.save_render_job(&params.get_branch_key(), &params.name, payload.to_job()) {
+        Ok(replaced) => Response {
+            status: match replaced {
+                true => StatusCode::OK,
+                false => StatusCode::CREATED,
+            },
+            data: MessageError::new("Render job saved".to_string()).to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn get_render_job(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<RenderJobParams>,
+) -> impl IntoResponse {
+    debug!("Getting render job router");
+
+    let branch_key = params.get_branch_key();
+
+    match service.get_render_job(&branch_key, &params.name) {
+        Ok((job, status)) => Response {
+            status: StatusCode::OK,
+            data: job_to_value(&branch_key, &params.name, &job, &status),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn remove_render_job(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<RenderJobParams>,
+) -> impl IntoResponse {
+    debug!("Removing render job router");
+
+    match service.remove_render_job(&params.get_branch_key(), &params.name) {
+        Ok(_) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("Render job removed".to_string()).to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+/// Runs a job now, whether or not its branch moved, answering its status.
+pub async fn run_render_job(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<RenderJobParams>,
+) -> impl IntoResponse {
+    debug!("Running render job router");
+
+    let branch_key = params.get_branch_key();
+
+    match tokio::task::spawn_blocking(move || service.run_render_job(&branch_key, &params.name))
+        .await
+    {
+        Ok(Ok(status)) => Response {
+            status: StatusCode::OK,
+            data: status.to_value(),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+/// Every render job with its status.
+pub async fn list_render_jobs(Extension(service): Extension<GitdisService>) -> impl IntoResponse {
+    debug!("Listing render jobs router");
+
+    let jobs = service
+        .get_render_jobs()
+        .iter()
+        .map(|(branch_key, name, job, status)| job_to_value(branch_key, name, job, status))
+        .collect::<Vec<Value>>();
+
+    Response {
+        status: StatusCode::OK,
+        data: Value::from(jobs),
+    }
+}
@@ -0,0 +1,162 @@
+use axum::{
+    extract::{Request, State},
+    http::{header::RETRY_AFTER, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use log::debug;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+
+use super::routes::coded_error;
+use super::versioning::API_VERSIONS;
+use super::Response;
+
+/// Budget of a request unless its route is listed below.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Budget of routes that run git or move whole branches around.
+const DEFAULT_LONG_TIMEOUT: Duration = Duration::from_secs(120);
+const DEFAULT_MAX_IN_FLIGHT: usize = 256;
+const DEFAULT_RETRY_AFTER_SECS: u64 = 1;
+/// Routes, without their version prefix, given the long budget: the
+/// method (any when `None`), then the start and end of the path.
+const LONG_ROUTES: &[(Option<Method>, &str, &str)] = &[
+    (None, "/dumps/", ""),
+    (None, "/archives/", ""),
+    (None, "/migrations/", ""),
+    (None, "/clones/", ""),
+    (None, "/webhooks/", ""),
+    (None, "/exports/", ""),
+    (None, "/queries/", ""),
+    (Some(Method::GET), "/repos/", "/run"),
+    (Some(Method::POST), "/repos/", "/commit"),
+    // Object patches are written back to git.
+    (Some(Method::PATCH), "/repos/", ""),
+];
+/// Routes that keep their response open. They have no budget and do not
+/// count as in flight, or a few subscribers would use up the limit.
+const OPEN_ROUTES: &[&str] = &["/streams/"];
+
+/// Per-route time budgets and a bound on requests in flight, so a slow
+/// request or a burst of exports cannot starve the others. Health checks
+/// are mounted outside of it.
+#[derive(Clone)]
+pub struct RouteLimits {
+    timeout: Duration,
+    long_timeout: Duration,
+    in_flight: Arc<Semaphore>,
+    retry_after_secs: u64,
+}
+
+impl RouteLimits {
+    /// Reads `GITDIS_ROUTE_TIMEOUT_MILLIS`, `GITDIS_LONG_ROUTE_TIMEOUT_MILLIS`,
+    /// `GITDIS_MAX_IN_FLIGHT` and `GITDIS_RETRY_AFTER_SECS`.
+    pub fn from_env() -> Self {
+        let env = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        };
+
+        Self::new(
+            env("GITDIS_ROUTE_TIMEOUT_MILLIS")
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_TIMEOUT),
+            env("GITDIS_LONG_ROUTE_TIMEOUT_MILLIS")
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_LONG_TIMEOUT),
+            env("GITDIS_MAX_IN_FLIGHT")
+                .map(|max: u64| max as usize)
+                .unwrap_or(DEFAULT_MAX_IN_FLIGHT),
+            env("GITDIS_RETRY_AFTER_SECS").unwrap_or(DEFAULT_RETRY_AFTER_SECS),
+        )
+    }
+
+    pub fn new(
+        timeout: Duration,
+        long_timeout: Duration,
+        max_in_flight: usize,
+        retry_after_secs: u64,
+    ) -> Self {
+        Self {
+            timeout,
+            long_timeout,
+            in_flight: Arc::new(Semaphore::new(max_in_flight)),
+            retry_after_secs,
+        }
+    }
+
+    fn get_timeout(&self, method: &Method, route: &str) -> Option<Duration> {
+        if OPEN_ROUTES.iter().any(|prefix| route.starts_with(prefix)) {
+            return None;
+        }
+
+        let is_long = LONG_ROUTES.iter().any(|(long_method, prefix, suffix)| {
+            long_method
+                .as_ref()
+                .is_none_or(|long_method| long_method == method)
+                && route.starts_with(prefix)
+                && route.ends_with(suffix)
+        });
+
+        match is_long {
+            true => Some(self.long_timeout),
+            false => Some(self.timeout),
+        }
+    }
+
+    fn unavailable(&self, code: &str, message: &str) -> axum::response::Response {
+        let mut response = Response {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            data: coded_error(code, message.to_string()),
+        }
+        .into_response();
+
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(self.retry_after_secs));
+
+        response
+    }
+}
+
+/// Sheds requests once the in-flight limit is reached and cuts those over
+/// their budget, both with 503 and `Retry-After`. Only the async part of a
+/// request is cut: work a handler moved to `spawn_blocking`, such as git
+/// commands, runs to its end, bounded by the git timeout instead.
+pub async fn enforce_limits(
+    State(limits): State<RouteLimits>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let route = strip_version(request.uri().path()).to_string();
+
+    let timeout = match limits.get_timeout(request.method(), &route) {
+        Some(timeout) => timeout,
+        None => return next.run(request).await,
+    };
+
+    let _permit = match limits.in_flight.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            debug!("Shedding request to {}", route);
+            return limits.unavailable("overloaded", "Too many requests in flight");
+        }
+    };
+
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            debug!("Request to {} timed out after {:?}", route, timeout);
+            limits.unavailable("timeout", "Request took too long")
+        }
+    }
+}
+
+fn strip_version(path: &str) -> &str {
+    API_VERSIONS
+        .iter()
+        .find_map(|version| path.strip_prefix(&format!("/v{}", version)))
+        .filter(|route| route.starts_with('/'))
+        .unwrap_or(path)
+}
@@ -1,18 +1,50 @@
+mod capabilities;
 mod extras;
+mod jobs;
+mod limits;
+mod queries;
+mod registry;
 mod routes;
+mod stream;
+mod validation;
+mod versioning;
+mod webhooks;
 use axum::{
     body::Body,
     http::{self, StatusCode},
+    middleware,
     response::IntoResponse,
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Extension, Router,
 };
-use extras::health_check;
-use gitdis::prelude::*;
+pub use capabilities::{Capabilities, Capability};
+use extras::{
+    clones_health_check, disable_capability, enable_capability, get_capabilities, get_version,
+    health_check,
+};
 use gitdis::prelude::*;
-use routes::create_repo;
+use jobs::{get_render_job, list_render_jobs, remove_render_job, run_render_job, save_render_job};
+pub(crate) use limits::{enforce_limits, RouteLimits};
+#[cfg(feature = "sql")]
+use queries::query_sql;
+use queries::{
+    get_view, materialize_view, query_branch, remove_saved_query, remove_view, run_saved_query,
+    save_query,
+};
+pub use registry::{Registry, DEFAULT_REGISTRY_FILE};
+use routes::{
+    archive_branch, commit_changes, compact_dumps, create_repo, disable_branch, dump_branch,
+    enable_branch, evict_object, get_client_usage, get_errors, get_history, get_lint,
+    get_memo_stats, get_metadata, get_object, get_serialization_stats, get_status, get_unused_keys,
+    get_usage, migrate_legacy, override_sync_limits, patch_object, pause_branch, reconcile_clones,
+    remove_branch, render_template, restore_branch, resume_branch,
+};
 use serde::Serialize;
-use tokio::sync::mpsc::{self, Receiver};
+#[cfg(feature = "export")]
+use stream::export_branch;
+use stream::{backfill_subscriber, snapshot_branch, stream_branch, stream_query};
+use versioning::negotiate_api_version;
+use webhooks::git_webhook;
 
 #[derive(Serialize, ToValue)]
 pub struct MessageError {
@@ -47,10 +79,133 @@ impl MessageError {
     }
 }
 
-pub fn routes(service: GitdisService) -> Router {
+/// Routes served under each API version prefix and, for older clients,
+/// without one. Routes of optional subsystems are gated by their
+/// capability.
+fn api_routes(capabilities: &Capabilities) -> Router {
+    let router = Router::new();
+
+    #[cfg(feature = "sql")]
+    let router = router.merge(capabilities.gate(
+        Capability::Sql,
+        Router::new().route("/repos/:owner/:repo/:branch/sql", post(query_sql)),
+    ));
+
+    #[cfg(feature = "export")]
+    let router = router.merge(capabilities.gate(
+        Capability::Export,
+        Router::new().route("/exports/:owner/:repo/:branch", get(export_branch)),
+    ));
+
+    let webhooks = Router::new().route("/webhooks/git", post(git_webhook));
+
+    let streams = Router::new()
+        .route("/streams/:owner/:repo/:branch", get(stream_branch))
+        .route("/streams/:owner/:repo/:branch/queries", post(stream_query))
+        .route("/subscribers/:id/backfill", post(backfill_subscriber));
+
+    let render = Router::new()
+        .route("/repos/:owner/:repo/:branch/render", post(render_template))
+        .route(
+            "/repos/:owner/:repo/:branch/render/jobs/:name",
+            get(get_render_job)
+                .put(save_render_job)
+                .delete(remove_render_job),
+        )
+        .route(
+            "/repos/:owner/:repo/:branch/render/jobs/:name/run",
+            post(run_render_job),
+        )
+        .route("/jobs/render", get(list_render_jobs));
+
+    let queries = Router::new()
+        .route(
+            "/repos/:owner/:repo/:branch/queries/:name",
+            put(save_query).delete(remove_saved_query),
+        )
+        .route(
+            "/repos/:owner/:repo/:branch/queries/:name/run",
+            get(run_saved_query),
+        )
+        .route(
+            "/repos/:owner/:repo/:branch/views/:name",
+            get(get_view).put(materialize_view).delete(remove_view),
+        )
+        .route("/queries/:owner/:repo/:branch", post(query_branch));
+
+    let metrics = Router::new()
+        .route("/metrics/memo", get(get_memo_stats))
+        .route("/metrics/serialization", get(get_serialization_stats))
+        .route("/usage/:owner/:repo/:branch", get(get_usage))
+        .route("/usage/:owner/:repo/:branch/clients", get(get_client_usage))
+        .route("/usage/:owner/:repo/:branch/unused", get(get_unused_keys));
+
+    router
+        .merge(capabilities.gate(Capability::Webhooks, webhooks))
+        .merge(capabilities.gate(Capability::Streams, streams))
+        .merge(capabilities.gate(Capability::Render, render))
+        .merge(capabilities.gate(Capability::Queries, queries))
+        .merge(capabilities.gate(Capability::Metrics, metrics))
+        .route("/errors/:owner/:repo/:branch", get(get_errors))
+        .route("/dumps/:owner/:repo/:branch", post(dump_branch))
+        .route("/dumps/:owner/:repo/:branch/compact", post(compact_dumps))
+        .route("/snapshots/:owner/:repo/:branch", get(snapshot_branch))
+        .route("/pauses/:owner/:repo/:branch", post(pause_branch))
+        .route("/pauses/:owner/:repo/:branch/resume", post(resume_branch))
+        .route(
+            "/sync-limits/:owner/:repo/:branch/override",
+            post(override_sync_limits),
+        )
+        .route("/standby/:owner/:repo/:branch", post(disable_branch))
+        .route("/standby/:owner/:repo/:branch/enable", post(enable_branch))
+        .route("/archives/:owner/:repo/:branch", post(archive_branch))
+        .route(
+            "/archives/:owner/:repo/:branch/restore",
+            post(restore_branch),
+        )
+        .route("/repos", post(create_repo))
+        .route("/repos/:owner/:repo/:branch", delete(remove_branch))
+        .route("/repos/:owner/:repo/:branch/commit", post(commit_changes))
+        .route(
+            "/repos/:owner/:repo/:branch/*object_key",
+            get(get_object).patch(patch_object),
+        )
+        .route(
+            "/metadata/:owner/:repo/:branch/*object_key",
+            get(get_metadata),
+        )
+        .route(
+            "/history/:owner/:repo/:branch/*object_key",
+            get(get_history),
+        )
+        .route(
+            "/evictions/:owner/:repo/:branch/*object_key",
+            post(evict_object),
+        )
+        .route("/migrations/legacy", post(migrate_legacy))
+        .route("/clones/reconcile", post(reconcile_clones))
+        .route("/status/:owner/:repo/:branch", get(get_status))
+        .route("/lint/:owner/:repo/:branch", get(get_lint))
+}
+
+pub fn routes(service: GitdisService, capabilities: Capabilities, registry: Registry) -> Router {
     Router::new()
+        .merge(api_routes(&capabilities))
+        .nest("/v1", api_routes(&capabilities))
+        .nest("/v2", api_routes(&capabilities))
+        // Added after the limits so health checks are never shed.
+        .layer(middleware::from_fn_with_state(
+            RouteLimits::from_env(),
+            enforce_limits,
+        ))
         .route("/health", get(health_check))
-        // .route("/repos", post(create_repo))
-        // .route("/repos/:owner/:repo/:branch/*object_key", get(get_object))
+        .route("/health/clones", get(clones_health_check))
+        .route("/version", get(get_version))
+        .route("/capabilities", get(get_capabilities))
+        .route("/capabilities/:name/enable", post(enable_capability))
+        .route("/capabilities/:name/disable", post(disable_capability))
         .layer(Extension(service))
+        .layer(Extension(capabilities))
+        .layer(Extension(registry))
+        .layer(middleware::from_fn(negotiate_api_version))
 }
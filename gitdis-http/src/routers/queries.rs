@@ -0,0 +1,519 @@
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension,
+};
+use gitdis::prelude::*;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::routes::{resolve_errors, BranchParams, GenerationQuery};
+use super::validation::{FieldErrors, Validate, Validated};
+use super::{MessageError, Response};
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct QueryCondition {
+    /// A JSON pointer into the entry, or `$key`.
+    path: String,
+    op: String,
+    value: Option<JsonValue>,
+    /// Binds the value from a run parameter; saved queries only.
+    param: Option<String>,
+}
+
+impl QueryCondition {
+    pub fn validate(&self, field: &str, allow_params: bool, errors: &mut FieldErrors) {
+        let op = match ConditionOp::parse(&self.op) {
+            Ok(op) => op,
+            Err(err) => {
+                errors.add(&format!("{}.op", field), &err.to_string());
+                return;
+            }
+        };
+
+        if let Err(err) = Condition::new(&self.path, op, Value::Null) {
+            errors.add(&format!("{}.path", field), &err.to_string());
+        }
+
+        match (&self.value, &self.param) {
+            (Some(_), Some(_)) => errors.add(field, "Needs a value or a param, not both"),
+            (None, Some(_)) if !allow_params => errors.add(
+                &format!("{}.param", field),
+                "Parameters are bound in saved queries only",
+            ),
+            (None, None) if op != ConditionOp::Exists => {
+                errors.add(&format!("{}.value", field), "Missing value")
+            }
+            _ => (),
+        }
+    }
+
+    /// Call after `validate`.
+    fn to_saved(&self) -> Option<SavedCondition> {
+        let arg = match (&self.value, &self.param) {
+            (_, Some(param)) => QueryArg::Param(param.clone()),
+            (Some(value), None) => QueryArg::Value(to_value(value)),
+            (None, None) => QueryArg::Value(Value::Null),
+        };
+
+        SavedCondition::new(&self.path, ConditionOp::parse(&self.op).ok()?, arg).ok()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct QueryBody {
+    prefix: Option<String>,
+    #[serde(default)]
+    conditions: Vec<QueryCondition>,
+    limit: Option<usize>,
+    /// Lower the server limits for this query; they cannot be raised.
+    max_scanned: Option<usize>,
+    max_duration_millis: Option<u64>,
+}
+
+impl Validate for QueryBody {
+    const FIELDS: &'static [&'static str] = &[
+        "prefix",
+        "conditions",
+        "limit",
+        "max_scanned",
+        "max_duration_millis",
+    ];
+
+    fn validate(&self, errors: &mut FieldErrors) {
+        for (index, condition) in self.conditions.iter().enumerate() {
+            condition.validate(&format!("conditions[{}]", index), false, errors);
+        }
+
+        if self.limit == Some(0) {
+            errors.add("limit", "Must be greater than 0");
+        }
+    }
+}
+
+impl QueryBody {
+    fn to_query(&self) -> ConditionQuery {
+        ConditionQuery {
+            prefix: self.prefix.clone().unwrap_or_default(),
+            conditions: to_conditions(&self.conditions),
+            limit: self.limit,
+        }
+    }
+
+    fn get_limits(&self) -> QueryLimits {
+        let limits = server_limits();
+
+        QueryLimits {
+            max_scanned: self
+                .max_scanned
+                .map_or(limits.max_scanned, |max| max.min(limits.max_scanned)),
+            max_cost: limits.max_cost,
+            max_duration: self
+                .max_duration_millis
+                .map(Duration::from_millis)
+                .map_or(limits.max_duration, |max| max.min(limits.max_duration)),
+        }
+    }
+}
+
+/// A read-only SQL query, see `gitdis::sql::SqlMirror`.
+#[cfg(feature = "sql")]
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SqlBody {
+    sql: String,
+    /// Lower the server limits for this query; they cannot be raised.
+    max_rows: Option<usize>,
+    max_duration_millis: Option<u64>,
+}
+
+#[cfg(feature = "sql")]
+impl Validate for SqlBody {
+    const FIELDS: &'static [&'static str] = &["sql", "max_rows", "max_duration_millis"];
+
+    fn validate(&self, errors: &mut FieldErrors) {
+        if self.sql.trim().is_empty() {
+            errors.add("sql", "Must not be empty");
+        }
+
+        if self.max_rows == Some(0) {
+            errors.add("max_rows", "Must be greater than 0");
+        }
+    }
+}
+
+#[cfg(feature = "sql")]
+impl SqlBody {
+    /// Reads `GITDIS_SQL_MAX_ROWS` and `GITDIS_SQL_MAX_DURATION_MILLIS`
+    /// over the defaults.
+    fn get_limits(&self) -> SqlLimits {
+        let env = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+        let defaults = SqlLimits::default();
+        let max_rows = env("GITDIS_SQL_MAX_ROWS").map_or(defaults.max_rows, |max| max as usize);
+        let max_duration = env("GITDIS_SQL_MAX_DURATION_MILLIS")
+            .map_or(defaults.max_duration, Duration::from_millis);
+
+        SqlLimits {
+            max_rows: self.max_rows.map_or(max_rows, |max| max.min(max_rows)),
+            max_duration: self
+                .max_duration_millis
+                .map(Duration::from_millis)
+                .map_or(max_duration, |max| max.min(max_duration)),
+        }
+    }
+}
+
+/// A clause streamed as it keeps matching, see `stream::stream_query`.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ContinuousQueryBody {
+    prefix: Option<String>,
+    #[serde(default)]
+    conditions: Vec<QueryCondition>,
+}
+
+impl Validate for ContinuousQueryBody {
+    const FIELDS: &'static [&'static str] = &["prefix", "conditions"];
+
+    fn validate(&self, errors: &mut FieldErrors) {
+        for (index, condition) in self.conditions.iter().enumerate() {
+            condition.validate(&format!("conditions[{}]", index), false, errors);
+        }
+    }
+}
+
+impl ContinuousQueryBody {
+    /// Call after `validate`: invalid conditions are left out.
+    pub fn to_query(&self) -> ConditionQuery {
+        ConditionQuery {
+            prefix: self.prefix.clone().unwrap_or_default(),
+            conditions: to_conditions(&self.conditions),
+            limit: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct QueryOrderBody {
+    path: String,
+    descending: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SavedQueryBody {
+    prefix: Option<String>,
+    #[serde(default)]
+    conditions: Vec<QueryCondition>,
+    /// JSON pointers returned of each match.
+    #[serde(default)]
+    projection: Vec<String>,
+    order_by: Option<QueryOrderBody>,
+    limit: Option<usize>,
+}
+
+impl Validate for SavedQueryBody {
+    const FIELDS: &'static [&'static str] =
+        &["prefix", "conditions", "projection", "order_by", "limit"];
+
+    fn validate(&self, errors: &mut FieldErrors) {
+        for (index, condition) in self.conditions.iter().enumerate() {
+            condition.validate(&format!("conditions[{}]", index), true, errors);
+        }
+
+        for (index, path) in self.projection.iter().enumerate() {
+            if !path.starts_with('/') {
+                errors.add(&format!("projection[{}]", index), "Must be a JSON pointer");
+            }
+        }
+
+        if let Some(order) = &self.order_by {
+            if let Err(err) = Condition::new(&order.path, ConditionOp::Exists, Value::Null) {
+                errors.add("order_by.path", &err.to_string());
+            }
+        }
+
+        if self.limit == Some(0) {
+            errors.add("limit", "Must be greater than 0");
+        }
+    }
+}
+
+impl SavedQueryBody {
+    /// Call after `validate`: invalid conditions are left out.
+    fn to_saved(&self) -> SavedQuery {
+        SavedQuery {
+            prefix: self.prefix.clone().unwrap_or_default(),
+            conditions: self
+                .conditions
+                .iter()
+                .filter_map(QueryCondition::to_saved)
+                .collect(),
+            projection: self.projection.clone(),
+            order_by: self.order_by.as_ref().map(|order| QueryOrder {
+                path: order.path.clone(),
+                descending: order.descending.unwrap_or(false),
+            }),
+            limit: self.limit,
+        }
+    }
+}
+
+/// Materializes a saved query, with its parameters bound for good.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ViewBody {
+    query: String,
+    #[serde(default)]
+    params: HashMap<String, JsonValue>,
+}
+
+impl Validate for ViewBody {
+    const FIELDS: &'static [&'static str] = &["query", "params"];
+
+    fn validate(&self, errors: &mut FieldErrors) {
+        if self.query.is_empty() {
+            errors.add("query", "Must name a saved query");
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SavedQueryParams {
+    owner: String,
+    repo: String,
+    branch: String,
+    name: String,
+}
+
+impl SavedQueryParams {
+    fn get_branch_key(&self) -> String {
+        get_branch_key(&self.owner, &self.repo, &self.branch)
+    }
+}
+
+/// Call after `validate`: invalid conditions are left out.
+pub fn to_conditions(conditions: &[QueryCondition]) -> Vec<Condition> {
+    conditions
+        .iter()
+        .filter_map(|condition| {
+            let value = condition.value.as_ref().map_or(Value::Null, to_value);
+            let op = ConditionOp::parse(&condition.op).ok()?;
+
+            Condition::new(&condition.path, op, value).ok()
+        })
+        .collect()
+}
+
+pub fn to_value(json: &JsonValue) -> Value {
+    Value::json_to_value(&json.to_string()).unwrap_or(Value::Null)
+}
+
+/// A run parameter from the query string: JSON when it parses, so `3` and
+/// `true` compare as a number and a boolean, else a string.
+fn to_param(raw: &str) -> Value {
+    match serde_json::from_str::<JsonValue>(raw) {
+        Ok(json) => to_value(&json),
+        Err(_) => Value::from(raw),
+    }
+}
+
+/// Reads `GITDIS_QUERY_MAX_SCANNED`, `GITDIS_QUERY_MAX_COST` and
+/// `GITDIS_QUERY_MAX_DURATION_MILLIS` over the defaults.
+fn server_limits() -> QueryLimits {
+    let env = |name: &str| {
+        std::env::var(name)
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+    let defaults = QueryLimits::default();
+
+    QueryLimits {
+        max_scanned: env("GITDIS_QUERY_MAX_SCANNED")
+            .map_or(defaults.max_scanned, |max| max as usize),
+        max_cost: env("GITDIS_QUERY_MAX_COST").unwrap_or(defaults.max_cost),
+        max_duration: env("GITDIS_QUERY_MAX_DURATION_MILLIS")
+            .map_or(defaults.max_duration, Duration::from_millis),
+    }
+}
+
+pub async fn query_branch(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+    Query(generation): Query<GenerationQuery>,
+    Validated(payload): Validated<QueryBody>,
+) -> impl IntoResponse {
+    debug!("Querying branch router");
+
+    let branch_key = params.get_branch_key();
+    let query = payload.to_query();
+    let limits = payload.get_limits();
+
+    let run = move || match generation.get_selector() {
+        Some(selector) => service
+            .get_generation(&branch_key, &selector)
+            .map(|generation| query.execute_generation(&generation, &limits)),
+        None => service.query(&branch_key, &query, &limits),
+    };
+
+    match tokio::task::spawn_blocking(run).await {
+        Ok(Ok(result)) => Response {
+            status: StatusCode::OK,
+            data: result.to_value(),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+#[cfg(feature = "sql")]
+pub async fn query_sql(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+    Validated(payload): Validated<SqlBody>,
+) -> impl IntoResponse {
+    debug!("SQL query router");
+
+    let branch_key = params.get_branch_key();
+    let limits = payload.get_limits();
+
+    let run = move || service.query_sql(&branch_key, &payload.sql, &limits);
+
+    match tokio::task::spawn_blocking(run).await {
+        Ok(Ok(result)) => Response {
+            status: StatusCode::OK,
+            data: result.to_value(),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+pub async fn save_query(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<SavedQueryParams>,
+    Validated(payload): Validated<SavedQueryBody>,
+) -> impl IntoResponse {
+    debug!("Saving query router");
+
+    match service.save_query(&params.get_branch_key(), &params.name, payload.to_saved()) {
+        Ok(replaced) => Response {
+            status: match replaced {
+                true => StatusCode::OK,
+                false => StatusCode::CREATED,
+            },
+            data: MessageError::new("Query saved".to_string()).to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn remove_saved_query(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<SavedQueryParams>,
+) -> impl IntoResponse {
+    debug!("Removing saved query router");
+
+    match service.remove_saved_query(&params.get_branch_key(), &params.name) {
+        Ok(_) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("Query removed".to_string()).to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+/// Runs a saved query with the query string bound as its parameters, within
+/// the server limits.
+pub async fn run_saved_query(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<SavedQueryParams>,
+    Query(query): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    debug!("Running saved query router");
+
+    let branch_key = params.get_branch_key();
+    let bindings = query
+        .iter()
+        .map(|(name, raw)| (name.clone(), to_param(raw)))
+        .collect::<HashMap<String, Value>>();
+    let limits = server_limits();
+
+    match tokio::task::spawn_blocking(move || {
+        service.run_saved_query(&branch_key, &params.name, &bindings, &limits)
+    })
+    .await
+    {
+        Ok(Ok(result)) => Response {
+            status: StatusCode::OK,
+            data: result.to_value(),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+pub async fn materialize_view(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<SavedQueryParams>,
+    Validated(payload): Validated<ViewBody>,
+) -> impl IntoResponse {
+    debug!("Materializing view router");
+
+    let branch_key = params.get_branch_key();
+    let bindings = payload
+        .params
+        .iter()
+        .map(|(name, value)| (name.clone(), to_value(value)))
+        .collect::<HashMap<String, Value>>();
+
+    match tokio::task::spawn_blocking(move || {
+        service.materialize_view(&branch_key, &params.name, &payload.query, &bindings)
+    })
+    .await
+    {
+        Ok(Ok(replaced)) => Response {
+            status: match replaced {
+                true => StatusCode::OK,
+                false => StatusCode::CREATED,
+            },
+            data: MessageError::new("View materialized".to_string()).to_value(),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+pub async fn get_view(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<SavedQueryParams>,
+) -> impl IntoResponse {
+    debug!("Getting view router");
+
+    match service.get_view(&params.get_branch_key(), &params.name) {
+        Ok(result) => Response {
+            status: StatusCode::OK,
+            data: result.to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn remove_view(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<SavedQueryParams>,
+) -> impl IntoResponse {
+    debug!("Removing view router");
+
+    match service.remove_view(&params.get_branch_key(), &params.name) {
+        Ok(_) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("View removed".to_string()).to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
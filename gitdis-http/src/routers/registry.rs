@@ -0,0 +1,131 @@
+use gitdis::prelude::*;
+use log::{debug, error};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use super::routes::{register, CreateRepo};
+
+/// Where registrations are kept unless `GITDIS_REGISTRY_PATH` says
+/// otherwise, relative to the clone path.
+pub const DEFAULT_REGISTRY_FILE: &str = "registry.json";
+
+/// The branches registered through the API, by key, kept in a JSON file so
+/// they are registered again when the server restarts. Inline tokens and
+/// proxies are never written to it, see `CreateRepo`; branches relying on
+/// them come back without them, so prefer `token_env` or a helper.
+#[derive(Clone)]
+pub struct Registry {
+    path: PathBuf,
+    entries: Arc<Mutex<BTreeMap<String, CreateRepo>>>,
+}
+
+impl Registry {
+    /// Reads the registrations kept in `path`, none if it does not exist.
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+
+        let entries = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(Self {
+            path,
+            entries: Arc::new(Mutex::new(entries)),
+        })
+    }
+
+    /// Keeps a registration, replacing the one with the same key.
+    pub fn record(&self, key: &str, repo: &CreateRepo) {
+        self.update(|entries| {
+            entries.insert(key.to_string(), repo.clone());
+        });
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.update(|entries| {
+            entries.remove(key);
+        });
+    }
+
+    pub fn get_keys(&self) -> Vec<String> {
+        match self.entries.lock() {
+            Ok(entries) => entries.keys().cloned().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Failing to write only loses the change on restart, so it is logged
+    /// rather than failing the request that made it.
+    fn update(&self, change: impl FnOnce(&mut BTreeMap<String, CreateRepo>)) {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        change(&mut entries);
+
+        if let Err(err) = self.save(&entries) {
+            error!(
+                "Failed to write the registry {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+
+    fn save(&self, entries: &BTreeMap<String, CreateRepo>) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let bytes = serde_json::to_vec_pretty(entries)?;
+        let temp_path = self.path.with_extension("json.tmp");
+
+        // Written aside and renamed, so a crash never leaves half a file.
+        std::fs::write(&temp_path, bytes)?;
+        std::fs::rename(&temp_path, &self.path)
+    }
+
+    /// Registers the kept branches again, reconciles the clone directory
+    /// with them when `orphans` is set, then starts the listeners of those
+    /// registered with `listen`. Clones are reconciled before any listener
+    /// runs, so the clones of kept branches are adopted and none is moved
+    /// while being cloned.
+    pub fn restore(
+        &self,
+        service: &mut GitdisService,
+        orphans: Option<OrphanPolicy>,
+    ) -> Option<ReconcileReport> {
+        let entries = match self.entries.lock() {
+            Ok(entries) => entries.clone(),
+            Err(_) => BTreeMap::new(),
+        };
+
+        let mut listen = Vec::new();
+
+        for (key, repo) in entries {
+            let listens = repo.listen;
+
+            match register(service, repo.into(), false) {
+                Ok(_) if listens => listen.push(key),
+                Ok(_) => (),
+                Err(err) => error!("Failed to restore {}: {:?}", key, err),
+            }
+        }
+
+        let report = orphans.map(|policy| service.reconcile_clones(policy));
+        debug!("Reconciled clones: {:?}", report);
+
+        for key in listen {
+            if let Err(err) = service.listen_branch(&key) {
+                error!("Failed to listen to {}: {:?}", key, err);
+            }
+        }
+
+        report.and_then(|report| report.ok())
+    }
+}
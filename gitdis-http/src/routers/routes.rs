@@ -1,97 +1,1764 @@
-use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension, Json};
+use axum::{
+    body::Bytes,
+    extract::{Path, Query},
+    http::{
+        header::{CONTENT_TYPE, IF_MATCH},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response as HttpResponse},
+    Extension,
+};
 use gitdis::prelude::valu3::prelude::ToValueBehavior;
 use gitdis::prelude::*;
 use log::debug;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use valu3::value::Value;
 
-use crate::http;
+use super::queries::{to_conditions, to_value, QueryCondition};
+use super::registry::Registry;
+use super::validation::{is_git_url, FieldErrors, Validate, Validated};
+use super::{MessageError, Response};
 
-use super::{ArcGitdisService, MessageError, Response};
+const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+/// Branch sequence number the response is at least as recent as.
+pub const SEQUENCE_HEADER: &str = "X-Gitdis-Sequence";
+/// Commit that last touched the object read, see `get_metadata`.
+pub const COMMIT_HEADER: &str = "X-Gitdis-Commit";
+/// Identifies the consumer of a read, see `get_client_usage`.
+pub const CLIENT_HEADER: &str = "X-Gitdis-Client";
+/// Set to `true` to refuse reads without `X-Gitdis-Client`.
+const REQUIRE_CLIENT_ENV: &str = "GITDIS_REQUIRE_CLIENT_ID";
+const MAX_CLIENT_LENGTH: usize = 128;
+/// Past generations a branch may keep in memory.
+const MAX_GENERATIONS: usize = 100;
+/// Past values a branch may keep in memory for each key.
+const MAX_KEY_VERSIONS: usize = 100;
+/// Longest a repo creation may wait for the first sync of the branch.
+const MAX_WAIT_READY_MILLIS: u64 = 60_000;
 
 #[derive(Deserialize, Serialize, Clone)]
+pub struct CreateRepoCredentials {
+    username: Option<String>,
+    #[serde(skip_serializing)]
+    token: Option<String>,
+    token_env: Option<String>,
+    /// A git credential helper name or absolute path, or `system` for the
+    /// helpers configured on the server.
+    helper: Option<String>,
+    use_http_path: Option<bool>,
+}
+
+impl From<CreateRepoCredentials> for Option<BranchCredentials> {
+    fn from(credentials: CreateRepoCredentials) -> Self {
+        let username = credentials.username.unwrap_or_default();
+
+        match (credentials.token, credentials.token_env, credentials.helper) {
+            (Some(token), _, _) => Some(BranchCredentials::Token { username, token }),
+            (None, Some(token_env), _) => Some(BranchCredentials::TokenEnv {
+                username,
+                token_env,
+            }),
+            (None, None, Some(helper)) => Some(BranchCredentials::Helper {
+                helper: match helper.as_str() {
+                    SYSTEM_CREDENTIAL_HELPER => None,
+                    _ => Some(helper),
+                },
+                use_http_path: credentials.use_http_path.unwrap_or(false),
+            }),
+            (None, None, None) => None,
+        }
+    }
+}
+
+const SYSTEM_CREDENTIAL_HELPER: &str = "system";
+
+/// Helpers are run by git, so only names of installed helpers and absolute
+/// paths are taken, never `!` shell snippets.
+fn is_credential_helper(helper: &str) -> bool {
+    let name = helper.strip_prefix('/').unwrap_or(helper);
+
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+        && !name.split('/').any(|part| part.is_empty() || part == "..")
+}
+
+const LINT_LEVELS: [&str; 3] = ["error", "warning", "off"];
+
+/// A lint level, `off` for none, or `default` when not given.
+fn to_lint_level(level: Option<&str>, default: Option<LintLevel>) -> Option<LintLevel> {
+    match level {
+        Some(level) => LintLevel::parse(level),
+        None => default,
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CreateRepoLintRule {
+    name: String,
+    /// `error` or `warning`, the default.
+    level: Option<String>,
+    prefix: Option<String>,
+    #[serde(default)]
+    conditions: Vec<QueryCondition>,
+    message: Option<String>,
+}
+
+/// See `LintSettings`. Built-in rules take `error`, `warning` or `off`.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CreateRepoLint {
+    duplicate_key: Option<String>,
+    empty_value: Option<String>,
+    long_string: Option<String>,
+    max_string_length: Option<usize>,
+    non_normalized_boolean: Option<String>,
+    #[serde(default)]
+    rules: Vec<CreateRepoLintRule>,
+    #[serde(default)]
+    block_on_error: bool,
+}
+
+impl CreateRepoLint {
+    fn validate(&self, errors: &mut FieldErrors) {
+        for (field, level) in [
+            ("duplicate_key", &self.duplicate_key),
+            ("empty_value", &self.empty_value),
+            ("long_string", &self.long_string),
+            ("non_normalized_boolean", &self.non_normalized_boolean),
+        ] {
+            if level
+                .as_deref()
+                .is_some_and(|level| !LINT_LEVELS.contains(&level))
+            {
+                errors.add(&format!("lint.{}", field), "Must be error, warning or off");
+            }
+        }
+
+        if self.max_string_length == Some(0) {
+            errors.add("lint.max_string_length", "Must be greater than 0");
+        }
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            let field = format!("lint.rules[{}]", index);
+
+            if rule.name.trim().is_empty() {
+                errors.add(&format!("{}.name", field), "Must not be empty");
+            }
+
+            if rule
+                .level
+                .as_deref()
+                .is_some_and(|level| LintLevel::parse(level).is_none())
+            {
+                errors.add(&format!("{}.level", field), "Must be error or warning");
+            }
+
+            for (position, condition) in rule.conditions.iter().enumerate() {
+                condition.validate(
+                    &format!("{}.conditions[{}]", field, position),
+                    false,
+                    errors,
+                );
+            }
+        }
+    }
+}
+
+impl From<CreateRepoLint> for LintSettings {
+    fn from(lint: CreateRepoLint) -> Self {
+        let defaults = LintSettings::default();
+
+        LintSettings {
+            duplicate_key: to_lint_level(lint.duplicate_key.as_deref(), defaults.duplicate_key),
+            empty_value: to_lint_level(lint.empty_value.as_deref(), defaults.empty_value),
+            long_string: to_lint_level(lint.long_string.as_deref(), defaults.long_string),
+            max_string_length: lint.max_string_length.unwrap_or(defaults.max_string_length),
+            non_normalized_boolean: to_lint_level(
+                lint.non_normalized_boolean.as_deref(),
+                defaults.non_normalized_boolean,
+            ),
+            rules: lint
+                .rules
+                .into_iter()
+                .map(|rule| LintRule {
+                    level: rule
+                        .level
+                        .as_deref()
+                        .and_then(LintLevel::parse)
+                        .unwrap_or(LintLevel::Warning),
+                    query: ConditionQuery {
+                        prefix: rule.prefix.unwrap_or_default(),
+                        conditions: to_conditions(&rule.conditions),
+                        limit: None,
+                    },
+                    message: rule
+                        .message
+                        .unwrap_or_else(|| format!("Matches rule {}", rule.name)),
+                    name: rule.name,
+                })
+                .collect(),
+            block_on_error: lint.block_on_error,
+        }
+    }
+}
+
+/// See `SchemaRule`.
+/// See `KeyFormat`; omitted characters take its defaults.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CreateRepoKeyFormat {
+    separator: Option<char>,
+    escape: Option<char>,
+}
+
+impl From<CreateRepoKeyFormat> for KeyFormat {
+    fn from(format: CreateRepoKeyFormat) -> Self {
+        let default = KeyFormat::default();
+
+        KeyFormat {
+            separator: format.separator.unwrap_or(default.separator),
+            escape: format.escape.unwrap_or(default.escape),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CreateRepoSchema {
+    schema: String,
+    files: Vec<String>,
+}
+
+impl CreateRepoSchema {
+    fn validate(&self, field: &str, errors: &mut FieldErrors) {
+        let schema = self.schema.trim_start_matches('/');
+
+        if schema.is_empty() || schema.split('/').any(|part| part == "..") {
+            errors.add(
+                &format!("{}.schema", field),
+                "Must be a path inside the repository",
+            );
+        }
+
+        if self.files.is_empty() {
+            errors.add(&format!("{}.files", field), "Must not be empty");
+        } else if let Err(err) = build_globs(&self.files) {
+            errors.add(&format!("{}.files", field), &err);
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
 pub struct CreateRepo {
     url: String,
     branch_name: Option<String>,
     pull_request_interval_millis: Option<u64>,
+    poll_jitter_percent: Option<u8>,
+    poll_jitter_millis: Option<u64>,
+    credentials: Option<CreateRepoCredentials>,
+    webhook_secret_env: Option<String>,
+    labels: Option<HashMap<String, String>>,
+    clone_depth: Option<u32>,
+    partial_clone: Option<bool>,
+    encoding: Option<String>,
+    path_target: Option<String>,
+    multi_document: Option<String>,
+    lenient_json: Option<bool>,
+    interpolate_env: Option<bool>,
+    interpolate_allow: Option<Vec<String>>,
+    ref_type: Option<String>,
+    require_signed_commits: Option<bool>,
+    gpg_home: Option<String>,
+    ssh_allowed_signers: Option<String>,
+    max_sync_retries: Option<u32>,
+    #[serde(skip_serializing)]
+    proxy: Option<String>,
+    #[serde(alias = "exclude")]
+    ignore: Option<Vec<String>>,
+    include: Option<Vec<String>>,
+    binary_files: Option<Vec<String>>,
+    prewarm_bundle_uri: Option<String>,
+    prewarm_tarball: Option<String>,
+    reset_dirty: Option<bool>,
+    adopt_existing_clone: Option<bool>,
+    bare: Option<bool>,
+    generations: Option<usize>,
+    key_versions: Option<usize>,
+    git_timeout_millis: Option<u64>,
+    /// A mirror HEAD is checked against before each sync, see
+    /// `BranchSettings::mirror_url`.
+    mirror_url: Option<String>,
+    mirror_credentials: Option<CreateRepoCredentials>,
+    lint: Option<CreateRepoLint>,
+    schemas: Option<Vec<CreateRepoSchema>>,
+    key_format: Option<CreateRepoKeyFormat>,
+    max_file_bytes: Option<u64>,
+    max_sync_files: Option<usize>,
+    max_sync_parse_millis: Option<u64>,
+    /// Registers the branch in standby when false, see `enable_branch`.
+    enabled: Option<bool>,
+    /// Starts listening to the branch right away. Branch patterns always
+    /// do, through discovery.
+    #[serde(default)]
+    pub(crate) listen: bool,
+    /// With `listen`, answers once the branch loaded its first commit, or
+    /// after this long with 202 Accepted.
+    wait_ready_ms: Option<u64>,
+}
+
+impl Validate for CreateRepo {
+    const FIELDS: &'static [&'static str] = &[
+        "url",
+        "branch_name",
+        "pull_request_interval_millis",
+        "poll_jitter_percent",
+        "poll_jitter_millis",
+        "credentials",
+        "webhook_secret_env",
+        "labels",
+        "clone_depth",
+        "partial_clone",
+        "encoding",
+        "path_target",
+        "multi_document",
+        "lenient_json",
+        "interpolate_env",
+        "interpolate_allow",
+        "ref_type",
+        "require_signed_commits",
+        "gpg_home",
+        "ssh_allowed_signers",
+        "max_sync_retries",
+        "proxy",
+        "ignore",
+        "exclude",
+        "include",
+        "binary_files",
+        "prewarm_bundle_uri",
+        "prewarm_tarball",
+        "reset_dirty",
+        "adopt_existing_clone",
+        "bare",
+        "generations",
+        "key_versions",
+        "git_timeout_millis",
+        "mirror_url",
+        "mirror_credentials",
+        "lint",
+        "schemas",
+        "key_format",
+        "max_file_bytes",
+        "max_sync_files",
+        "max_sync_parse_millis",
+        "enabled",
+        "listen",
+        "wait_ready_ms",
+    ];
+
+    fn validate(&self, errors: &mut FieldErrors) {
+        if !is_git_url(&self.url) {
+            errors.add(
+                "url",
+                "Must be a git URL, e.g. https://host/owner/repo.git, or an absolute path",
+            );
+        }
+
+        if self
+            .branch_name
+            .as_ref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            errors.add("branch_name", "Must not be empty");
+        }
+
+        if self.pull_request_interval_millis == Some(0) {
+            errors.add("pull_request_interval_millis", "Must be greater than 0");
+        }
+
+        if self.max_sync_files == Some(0) {
+            errors.add("max_sync_files", "Must be greater than 0");
+        }
+
+        if self.max_sync_parse_millis == Some(0) {
+            errors.add("max_sync_parse_millis", "Must be greater than 0");
+        }
+
+        if let Some(Err(err)) = self.path_target.as_deref().map(validate_relative_path) {
+            errors.add("path_target", &err);
+        }
+
+        if self
+            .poll_jitter_percent
+            .is_some_and(|percent| percent > 100)
+        {
+            errors.add("poll_jitter_percent", "Must be at most 100");
+        }
+
+        if self.poll_jitter_percent.is_some() && self.poll_jitter_millis.is_some() {
+            errors.add(
+                "poll_jitter_percent",
+                "Only one of poll_jitter_percent and poll_jitter_millis",
+            );
+        }
+
+        if self.clone_depth == Some(0) {
+            errors.add("clone_depth", "Must be greater than 0");
+        }
+
+        if self.git_timeout_millis == Some(0) {
+            errors.add("git_timeout_millis", "Must be greater than 0");
+        }
+
+        if self
+            .generations
+            .is_some_and(|generations| generations > MAX_GENERATIONS)
+        {
+            errors.add(
+                "generations",
+                &format!("Must be at most {}", MAX_GENERATIONS),
+            );
+        }
+
+        if self
+            .key_versions
+            .is_some_and(|key_versions| key_versions > MAX_KEY_VERSIONS)
+        {
+            errors.add(
+                "key_versions",
+                &format!("Must be at most {}", MAX_KEY_VERSIONS),
+            );
+        }
+
+        if self
+            .mirror_url
+            .as_deref()
+            .is_some_and(|url| !is_git_url(url))
+        {
+            errors.add("mirror_url", "Must be a git URL or an absolute path");
+        }
+
+        if self.mirror_credentials.is_some() && self.mirror_url.is_none() {
+            errors.add("mirror_credentials", "Requires mirror_url");
+        }
+
+        for (field, credentials) in [
+            ("credentials", &self.credentials),
+            ("mirror_credentials", &self.mirror_credentials),
+        ] {
+            let credentials = match credentials {
+                Some(credentials) => credentials,
+                None => continue,
+            };
+            let has_token = credentials.token.is_some() || credentials.token_env.is_some();
+
+            match (has_token, &credentials.helper) {
+                (false, None) => errors.add(field, "Needs a token, a token_env or a helper"),
+                (true, Some(_)) => errors.add(field, "Only one of a token and a helper"),
+                (true, None) if credentials.username.is_none() => {
+                    errors.add(&format!("{}.username", field), "Required with a token")
+                }
+                (false, Some(helper)) if !is_credential_helper(helper) => errors.add(
+                    &format!("{}.helper", field),
+                    "Must be a helper name, an absolute path or system",
+                ),
+                _ => (),
+            }
+        }
+
+        if let Some(lint) = &self.lint {
+            lint.validate(errors);
+        }
+
+        for (index, schema) in self.schemas.iter().flatten().enumerate() {
+            schema.validate(&format!("schemas[{}]", index), errors);
+        }
+
+        if let Some(Err(err)) = self
+            .key_format
+            .clone()
+            .map(|format| KeyFormat::from(format).validate())
+        {
+            errors.add("key_format", &err);
+        }
+
+        if let Some(mode) = &self.multi_document {
+            if !["indexed", "merged"].contains(&mode.as_str()) {
+                errors.add("multi_document", "Must be indexed or merged");
+            }
+        }
+
+        if let Some(wait_ready_ms) = self.wait_ready_ms {
+            if self.branch_name.as_deref().is_some_and(is_branch_pattern) {
+                errors.add(
+                    "wait_ready_ms",
+                    "Not supported when branch_name is a pattern",
+                );
+            } else if !self.listen {
+                errors.add("wait_ready_ms", "Requires listen");
+            } else if self.enabled == Some(false) {
+                errors.add("wait_ready_ms", "Not supported when the branch is disabled");
+            }
+
+            if wait_ready_ms > MAX_WAIT_READY_MILLIS {
+                errors.add(
+                    "wait_ready_ms",
+                    &format!("Must be at most {}", MAX_WAIT_READY_MILLIS),
+                );
+            }
+        }
+
+        if let Some(ref_type) = &self.ref_type {
+            if self.branch_name.as_deref().is_some_and(is_branch_pattern) && ref_type != "branch" {
+                errors.add("ref_type", "Must be branch when branch_name is a pattern");
+            }
+
+            if !["branch", "tag", "commit"].contains(&ref_type.as_str()) {
+                errors.add("ref_type", "Must be branch, tag or commit");
+            }
+        }
+
+        if self.bare == Some(true) && self.partial_clone == Some(true) {
+            errors.add("partial_clone", "Not supported for bare clones");
+        }
+
+        if self.bare == Some(true) && self.prewarm_tarball.is_some() {
+            errors.add(
+                "prewarm_tarball",
+                "Bare clones cannot be prewarmed from a tarball",
+            );
+        }
+
+        if self.gpg_home.is_some() && self.ssh_allowed_signers.is_some() {
+            errors.add("gpg_home", "Only one of gpg_home and ssh_allowed_signers");
+        }
+
+        if self.prewarm_bundle_uri.is_some() && self.prewarm_tarball.is_some() {
+            errors.add(
+                "prewarm_bundle_uri",
+                "Only one of prewarm_bundle_uri and prewarm_tarball",
+            );
+        }
+
+        for (field, patterns) in [
+            ("ignore", &self.ignore),
+            ("include", &self.include),
+            ("binary_files", &self.binary_files),
+        ] {
+            if let Some(Err(err)) = patterns.as_ref().map(|patterns| build_globs(patterns)) {
+                errors.add(field, &err);
+            }
+        }
+    }
 }
 
-impl Into<BranchSettings> for CreateRepo {
-    fn into(self) -> BranchSettings {
+impl From<CreateRepo> for BranchSettings {
+    fn from(repo: CreateRepo) -> Self {
         BranchSettings {
-            url: self.url,
-            branch_name: self.branch_name.unwrap_or("main".to_string()),
-            pull_request_interval_millis: self.pull_request_interval_millis.unwrap_or(3000),
+            url: repo.url,
+            branch_name: repo.branch_name.unwrap_or("main".to_string()),
+            pull_request_interval_millis: repo.pull_request_interval_millis.unwrap_or(3000),
+            poll_jitter: match (repo.poll_jitter_percent, repo.poll_jitter_millis) {
+                (Some(percent), _) => Some(PollJitter::Percent(percent)),
+                (None, Some(millis)) => Some(PollJitter::Millis(millis)),
+                (None, None) => None,
+            },
+            credentials: repo.credentials.and_then(|credentials| credentials.into()),
+            webhook_secret_env: repo.webhook_secret_env,
+            labels: repo.labels.unwrap_or_default(),
+            clone_depth: repo.clone_depth,
+            partial_clone: repo.partial_clone,
+            encoding: repo.encoding,
+            path_target: repo.path_target,
+            multi_document: repo.multi_document.and_then(|mode| match mode.as_str() {
+                "indexed" => Some(MultiDocument::Indexed),
+                "merged" => Some(MultiDocument::Merged),
+                _ => None,
+            }),
+            lenient_json: repo.lenient_json,
+            interpolate_env: repo.interpolate_env,
+            interpolate_allow: repo.interpolate_allow,
+            ref_type: repo.ref_type.and_then(|ref_type| match ref_type.as_str() {
+                "branch" => Some(RefType::Branch),
+                "tag" => Some(RefType::Tag),
+                "commit" => Some(RefType::Commit),
+                _ => None,
+            }),
+            require_signed_commits: repo.require_signed_commits,
+            signing_keyring: match (repo.gpg_home, repo.ssh_allowed_signers) {
+                (Some(home), _) => Some(SigningKeyring::Gpg { home }),
+                (None, Some(allowed_signers)) => Some(SigningKeyring::Ssh { allowed_signers }),
+                (None, None) => None,
+            },
+            retry: repo.max_sync_retries.map(|max_retries| RetryPolicy {
+                max_retries,
+                ..RetryPolicy::default()
+            }),
+            proxy: repo.proxy,
+            ignore: repo.ignore.unwrap_or_default(),
+            include: repo.include.unwrap_or_default(),
+            binary_files: repo.binary_files.unwrap_or_default(),
+            prewarm: match (repo.prewarm_bundle_uri, repo.prewarm_tarball) {
+                (Some(uri), _) => Some(Prewarm::Bundle { uri }),
+                (None, Some(location)) => Some(Prewarm::Tarball { location }),
+                (None, None) => None,
+            },
+            reset_dirty: repo.reset_dirty,
+            adopt_existing_clone: repo.adopt_existing_clone,
+            bare: repo.bare,
+            generations: repo.generations,
+            key_versions: repo.key_versions,
+            enabled: repo.enabled,
+            git_timeout_millis: repo.git_timeout_millis,
+            mirror_url: repo.mirror_url,
+            mirror_credentials: repo
+                .mirror_credentials
+                .and_then(|credentials| credentials.into()),
+            lint: repo.lint.map(|lint| lint.into()),
+            schemas: repo
+                .schemas
+                .unwrap_or_default()
+                .into_iter()
+                .map(|schema| SchemaRule {
+                    schema: schema.schema,
+                    files: schema.files,
+                })
+                .collect(),
+            key_format: repo.key_format.map(|format| format.into()),
+            max_file_bytes: repo.max_file_bytes,
+            max_sync_files: repo.max_sync_files,
+            max_sync_parse_millis: repo.max_sync_parse_millis,
         }
     }
 }
 
-fn resolve_errors(err: GitdisServiceError) -> Response<Value> {
+/// Error body of API v2: the message plus a stable, machine-readable code.
+/// v1 clients get the message only, see `versioning`.
+pub fn coded_error(code: &str, message: String) -> Value {
+    let mut data = HashMap::new();
+
+    data.insert("code".to_string(), Value::from(code));
+    data.insert("message".to_string(), Value::from(message));
+
+    Value::from(data)
+}
+
+pub fn resolve_errors(err: GitdisServiceError) -> Response<Value> {
     match err {
         GitdisServiceError::RepoAlreadyExists => Response {
             status: StatusCode::CONFLICT,
-            data: MessageError::new("Repo already exists".to_string()).to_value(),
+            data: coded_error("repo_already_exists", "Repo already exists".to_string()),
         },
         GitdisServiceError::BranchNotFound => Response {
             status: StatusCode::NOT_FOUND,
-            data: MessageError::new("Branch not found".to_string()).to_value(),
+            data: coded_error("branch_not_found", "Branch not found".to_string()),
         },
         GitdisServiceError::InternalError(err) => Response {
             status: StatusCode::INTERNAL_SERVER_ERROR,
-            data: MessageError::new(err).to_value(),
+            data: coded_error("internal_error", err),
+        },
+        GitdisServiceError::RepoNotCreated => Response {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            data: coded_error("repo_not_created", "Repo not created".to_string()),
+        },
+        GitdisServiceError::ObjectNotFound => Response {
+            status: StatusCode::NOT_FOUND,
+            data: coded_error("object_not_found", "Object not found".to_string()),
+        },
+        GitdisServiceError::InvalidPatch(err) => Response {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            data: coded_error("invalid_patch", err),
+        },
+        GitdisServiceError::InvalidSettings(err) => Response {
+            status: StatusCode::BAD_REQUEST,
+            data: coded_error("invalid_settings", err),
+        },
+        GitdisServiceError::Dump(err) => Response {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            data: coded_error("dump_failed", err),
+        },
+        GitdisServiceError::QueryNotFound => Response {
+            status: StatusCode::NOT_FOUND,
+            data: coded_error("query_not_found", "Query not found".to_string()),
+        },
+        GitdisServiceError::ViewNotFound => Response {
+            status: StatusCode::NOT_FOUND,
+            data: coded_error("view_not_found", "View not found".to_string()),
+        },
+        GitdisServiceError::GenerationNotFound => Response {
+            status: StatusCode::NOT_FOUND,
+            data: coded_error(
+                "generation_not_found",
+                "Generation not found or no longer kept".to_string(),
+            ),
         },
+        GitdisServiceError::InvalidQuery(err) => Response {
+            status: StatusCode::BAD_REQUEST,
+            data: coded_error("invalid_query", err),
+        },
+        GitdisServiceError::InvalidTemplate(err) => Response {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            data: coded_error("invalid_template", err),
+        },
+        GitdisServiceError::RenderJobNotFound => Response {
+            status: StatusCode::NOT_FOUND,
+            data: coded_error("render_job_not_found", "Render job not found".to_string()),
+        },
+        GitdisServiceError::SyncFailed(err) => Response {
+            status: StatusCode::BAD_GATEWAY,
+            data: coded_error("sync_failed", err),
+        },
+        GitdisServiceError::NotReady => Response {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            data: coded_error("not_ready", "Branch has not loaded yet".to_string()),
+        },
+        GitdisServiceError::SubscriberNotFound => Response {
+            status: StatusCode::NOT_FOUND,
+            data: coded_error("subscriber_not_found", "Subscriber not found".to_string()),
+        },
+        GitdisServiceError::BackfillUnavailable(err) => Response {
+            status: StatusCode::GONE,
+            data: coded_error("backfill_unavailable", err),
+        },
+        GitdisServiceError::InvalidKey(err) => Response {
+            status: StatusCode::BAD_REQUEST,
+            data: coded_error("invalid_key", err),
+        },
+        GitdisServiceError::UnsupportedWriteBack(err) => Response {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            data: coded_error("unsupported_write_back", err),
+        },
+        GitdisServiceError::GitTimeout(err) => Response {
+            status: StatusCode::GATEWAY_TIMEOUT,
+            data: coded_error("git_timeout", err),
+        },
+        GitdisServiceError::PreconditionFailed(commit) => Response {
+            status: StatusCode::PRECONDITION_FAILED,
+            data: coded_error(
+                "precondition_failed",
+                format!("Branch moved, current commit is {}", commit),
+            ),
+        },
+    }
+}
+
+/// The created branch with the first commit it loaded.
+fn with_commit(info: &BranchInfo, commit: String) -> Value {
+    let mut object = HashMap::new();
+
+    object.insert("key".to_string(), Value::from(info.get_key()));
+    object.insert("create_at".to_string(), Value::from(info.get_create_at()));
+    object.insert("commit".to_string(), Value::from(commit));
+
+    Value::from(object)
+}
+
+/// Registers a branch, or the discovery of a branch pattern, starting to
+/// listen to the branch when `listen`. Branch patterns always do, through
+/// discovery.
+pub fn register(
+    service: &mut GitdisService,
+    settings: BranchSettings,
+    listen: bool,
+) -> Result<BranchInfo, GitdisServiceError> {
+    let repo_key = settings.get_repo_key();
+
+    match is_branch_pattern(&settings.branch_name) {
+        true => service.add_discovery(settings),
+        false => service.add_repo(settings).and_then(|info| match listen {
+            true => service.listen_branch(&repo_key).map(|_| info),
+            false => Ok(info),
+        }),
     }
 }
 
 pub async fn create_repo(
-    Extension(gitdis): Extension<ArcGitdisService>,
-    Json(payload): Json<CreateRepo>,
+    Extension(mut service): Extension<GitdisService>,
+    Extension(registry): Extension<Registry>,
+    Validated(payload): Validated<CreateRepo>,
 ) -> impl IntoResponse {
     debug!("Creating new repo router");
-    let mut services = gitdis.write().unwrap();
+    let listen = payload.listen;
+    let wait_ready = payload.wait_ready_ms.map(std::time::Duration::from_millis);
+    let settings: BranchSettings = payload.clone().into();
+    let repo_key = settings.get_repo_key();
 
-    match services.add_repo(payload.into()) {
-        Ok(data) => Response {
+    let info = match register(&mut service, settings, listen) {
+        Ok(info) => info,
+        Err(err) => return resolve_errors(err),
+    };
+
+    registry.record(&repo_key, &payload);
+
+    let timeout = match wait_ready {
+        Some(timeout) => timeout,
+        None => {
+            return Response {
+                status: StatusCode::CREATED,
+                data: info.to_value(),
+            }
+        }
+    };
+
+    match service.wait_ready(&repo_key, timeout).await {
+        Ok(commit) => Response {
             status: StatusCode::CREATED,
-            data: data.to_value(),
+            data: with_commit(&info, commit),
         },
+        // Registered and listening, the data is still loading.
+        Err(GitdisServiceError::NotReady) => Response {
+            status: StatusCode::ACCEPTED,
+            data: info.to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ObjectParams {
+    owner: String,
+    repo: String,
+    branch: String,
+    object_key: String,
+}
+
+impl ObjectParams {
+    fn get_branch_key(&self) -> String {
+        get_branch_key(&self.owner, &self.repo, &self.branch)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BranchParams {
+    owner: String,
+    repo: String,
+    branch: String,
+}
+
+impl BranchParams {
+    pub fn get_branch_key(&self) -> String {
+        get_branch_key(&self.owner, &self.repo, &self.branch)
+    }
+}
+
+/// Sends the response of a branch read with the branch sequence, read
+/// before the data so the data is never older than the header says.
+fn with_sequence(sequence: u64, response: Response<Value>) -> HttpResponse {
+    ([(SEQUENCE_HEADER, sequence.to_string())], response).into_response()
+}
+
+/// Like `with_sequence`, for a body already serialized to JSON.
+fn json_with_sequence(sequence: u64, body: Bytes) -> HttpResponse {
+    (
+        [
+            (SEQUENCE_HEADER, sequence.to_string()),
+            (CONTENT_TYPE.as_str(), "application/json".to_string()),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+pub async fn get_errors(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    let branch_key = params.get_branch_key();
+
+    let sequence = match service.get_branch_sequence(&branch_key) {
+        Ok(sequence) => sequence,
+        Err(err) => return resolve_errors(err).into_response(),
+    };
+
+    match service.get_errors(&branch_key) {
+        Ok(errors) => with_sequence(
+            sequence,
+            Response {
+                status: StatusCode::OK,
+                data: errors.to_value(),
+            },
+        ),
+        Err(err) => resolve_errors(err).into_response(),
+    }
+}
+
+/// Reads from a past generation of the branch instead of the current one,
+/// by sequence number or commit.
+#[derive(Deserialize, Debug)]
+pub struct GenerationQuery {
+    generation: Option<String>,
+}
+
+impl GenerationQuery {
+    pub fn get_selector(&self) -> Option<GenerationSelector> {
+        self.generation
+            .as_deref()
+            .map(|generation| GenerationSelector::parse(generation.trim()))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MetaQuery {
+    /// Wrap the object as `{"value": ..., "meta": {"commit", "owners"}}`.
+    /// Ignored when reading a past generation.
+    #[serde(default)]
+    include_meta: bool,
+}
+
+/// The commit that last touched `object_key` and the CODEOWNERS owners of
+/// its file.
+fn get_object_meta(service: &GitdisService, branch_key: &str, object_key: &str) -> Value {
+    let commit = service
+        .get_metadata(branch_key, object_key)
+        .map(|metadata| metadata.to_value())
+        .unwrap_or(Value::Null);
+    let owners = service
+        .get_owners(branch_key, object_key)
+        .unwrap_or_default()
+        .iter()
+        .map(|owner| Value::from(owner.as_str()))
+        .collect::<Vec<Value>>();
+
+    Value::from(HashMap::from([
+        ("commit".to_string(), commit),
+        ("owners".to_string(), Value::from(owners)),
+    ]))
+}
+
+/// The `X-Gitdis-Client` of a read, refused when malformed, or when
+/// missing and `GITDIS_REQUIRE_CLIENT_ID` is `true`.
+fn get_client(headers: &HeaderMap) -> Result<Option<String>, Response<Value>> {
+    let client = match headers.get(CLIENT_HEADER) {
+        Some(client) => client.to_str().ok().map(str::trim),
+        None if std::env::var(REQUIRE_CLIENT_ENV).is_ok_and(|require| require == "true") => {
+            return Err(Response {
+                status: StatusCode::BAD_REQUEST,
+                data: coded_error(
+                    "client_required",
+                    format!("The {} header is required", CLIENT_HEADER),
+                ),
+            })
+        }
+        None => return Ok(None),
+    };
+
+    match client {
+        Some(client)
+            if !client.is_empty()
+                && client.len() <= MAX_CLIENT_LENGTH
+                && client.chars().all(|c| c.is_ascii_graphic()) =>
+        {
+            Ok(Some(client.to_string()))
+        }
+        _ => Err(Response {
+            status: StatusCode::BAD_REQUEST,
+            data: coded_error(
+                "invalid_client",
+                format!(
+                    "{} must be 1 to {} visible ASCII characters",
+                    CLIENT_HEADER, MAX_CLIENT_LENGTH
+                ),
+            ),
+        }),
+    }
+}
+
+pub async fn get_object(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<ObjectParams>,
+    Query(query): Query<GenerationQuery>,
+    Query(meta): Query<MetaQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let client = match get_client(&headers) {
+        Ok(client) => client,
+        Err(response) => return response.into_response(),
+    };
+
+    // An archived branch is restored by the read, which clones it again.
+    let read_service = service.clone();
+    let (params, response) = match tokio::task::spawn_blocking(move || {
+        let response = read_object(&read_service, &params, query, meta);
+        (params, response)
+    })
+    .await
+    {
+        Ok(read) => read,
         Err(err) => {
-            let response = resolve_errors(err);
-            response
-        }
-    }
-}
-
-// #[derive(Deserialize, Debug)]
-// pub struct ObjectParams {
-//     owner: String,
-//     repo: String,
-//     branch: String,
-//     object_key: String,
-// }
-
-// impl ObjectParams {
-//     fn get_branch_key(&self) -> String {
-//         format!("{}/{}/{}", self.owner, self.repo, self.branch)
-//     }
-// }
-
-// pub async fn get_object(
-//     Extension(gitdis): Extension<ArcGitdisService>,
-//     Path(params): Path<ObjectParams>,
-// ) -> impl IntoResponse {
-//     let services: std::sync::RwLockReadGuard<GitdisService> = gitdis.read().unwrap();
-
-//     let branch_key = params.get_branch_key();
-
-//     match services.get_data(&branch_key, &params.object_key) {
-//         Ok(value) => Ok(Response {
-//             status: StatusCode::OK,
-//             data: match value {
-//                 Some(value) => Some(value),
-//                 None => None,
-//             },
-//         }),
-//         Err(err) => Err(resolve_errors(err)),
-//     }
-// }
+            return resolve_errors(GitdisServiceError::InternalError(err.to_string()))
+                .into_response()
+        }
+    };
+
+    if let Some(client) = client {
+        if response.status().is_success() {
+            service.record_client_read(&params.get_branch_key(), &params.object_key, &client);
+        }
+    }
+
+    response
+}
+
+fn read_object(
+    service: &GitdisService,
+    params: &ObjectParams,
+    query: GenerationQuery,
+    meta: MetaQuery,
+) -> HttpResponse {
+    let branch_key = params.get_branch_key();
+
+    if let Some(selector) = query.get_selector() {
+        let generation = match service.get_generation(&branch_key, &selector) {
+            Ok(generation) => generation,
+            Err(err) => return resolve_errors(err).into_response(),
+        };
+
+        return match generation.get_object(&params.object_key) {
+            Some(value) => with_sequence(
+                generation.sequence,
+                Response {
+                    status: StatusCode::OK,
+                    data: value,
+                },
+            ),
+            None => resolve_errors(GitdisServiceError::ObjectNotFound).into_response(),
+        };
+    }
+
+    let sequence = match service.get_branch_sequence(&branch_key) {
+        Ok(sequence) => sequence,
+        Err(err) => return resolve_errors(err).into_response(),
+    };
+
+    if meta.include_meta {
+        return match service.get_object(&branch_key, &params.object_key) {
+            Ok(value) => with_sequence(
+                sequence,
+                Response {
+                    status: StatusCode::OK,
+                    data: Value::from(HashMap::from([
+                        ("value".to_string(), value),
+                        (
+                            "meta".to_string(),
+                            get_object_meta(service, &branch_key, &params.object_key),
+                        ),
+                    ])),
+                },
+            ),
+            Err(err) => resolve_errors(err).into_response(),
+        };
+    }
+
+    let mut response = match service.get_object_json(&branch_key, &params.object_key) {
+        Ok(body) => json_with_sequence(sequence, body),
+        Err(err) => return resolve_errors(err).into_response(),
+    };
+
+    if let Ok(metadata) = service.get_metadata(&branch_key, &params.object_key) {
+        if let Ok(commit) = HeaderValue::from_str(&metadata.commit) {
+            response.headers_mut().insert(COMMIT_HEADER, commit);
+        }
+    }
+
+    response
+}
+
+pub async fn get_metadata(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<ObjectParams>,
+) -> impl IntoResponse {
+    match service.get_metadata(&params.get_branch_key(), &params.object_key) {
+        Ok(metadata) => Response {
+            status: StatusCode::OK,
+            data: metadata.to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn get_history(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<ObjectParams>,
+) -> impl IntoResponse {
+    match service.get_history(&params.get_branch_key(), &params.object_key) {
+        Ok(versions) => Response {
+            status: StatusCode::OK,
+            data: Value::from(
+                versions
+                    .iter()
+                    .map(|version| version.to_value())
+                    .collect::<Vec<Value>>(),
+            ),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn evict_object(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<ObjectParams>,
+) -> impl IntoResponse {
+    debug!("Evicting object router");
+
+    match service.evict_key(&params.get_branch_key(), &params.object_key) {
+        Ok(true) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("Object evicted".to_string()).to_value(),
+        },
+        Ok(false) => resolve_errors(GitdisServiceError::ObjectNotFound),
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn dump_branch(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    debug!("Dumping branch router");
+
+    match tokio::task::spawn_blocking(move || service.dump_branch(&params.get_branch_key())).await {
+        Ok(Ok(Some(file))) => Response {
+            status: StatusCode::CREATED,
+            data: HashMap::from([("file".to_string(), file)]).to_value(),
+        },
+        Ok(Ok(None)) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("No changes since the last dump".to_string()).to_value(),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RemoveBranchQuery {
+    #[serde(default)]
+    remove_clone: bool,
+}
+
+pub async fn remove_branch(
+    Extension(service): Extension<GitdisService>,
+    Extension(registry): Extension<Registry>,
+    Path(params): Path<BranchParams>,
+    Query(query): Query<RemoveBranchQuery>,
+) -> impl IntoResponse {
+    debug!("Removing branch router");
+
+    let branch_key = params.get_branch_key();
+    let removed_key = branch_key.clone();
+
+    match tokio::task::spawn_blocking(move || {
+        service.remove_branch(&branch_key, query.remove_clone)
+    })
+    .await
+    {
+        Ok(Ok(_)) => {
+            registry.remove(&removed_key);
+
+            Response {
+                status: StatusCode::OK,
+                data: MessageError::new("Branch removed".to_string()).to_value(),
+            }
+        }
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReconcileQuery {
+    /// `quarantine` (default) or `delete`.
+    orphans: Option<String>,
+}
+
+pub async fn reconcile_clones(
+    Extension(service): Extension<GitdisService>,
+    Query(query): Query<ReconcileQuery>,
+) -> impl IntoResponse {
+    debug!("Reconciling clones router");
+
+    let policy = match query.orphans.as_deref() {
+        None | Some("quarantine") => OrphanPolicy::Quarantine,
+        Some("delete") => OrphanPolicy::Delete,
+        Some(_) => {
+            return Response {
+                status: StatusCode::BAD_REQUEST,
+                data: MessageError::new("orphans must be quarantine or delete".to_string())
+                    .to_value(),
+            }
+        }
+    };
+
+    match tokio::task::spawn_blocking(move || service.reconcile_clones(policy)).await {
+        Ok(Ok(report)) => Response {
+            status: StatusCode::OK,
+            data: report.to_value(),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+pub async fn pause_branch(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    debug!("Pausing branch router");
+
+    match service.pause_branch(&params.get_branch_key()) {
+        Ok(_) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("Branch paused".to_string()).to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn override_sync_limits(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    debug!("Overriding sync limits router");
+
+    match service.override_sync_limits(&params.get_branch_key()) {
+        Ok(_) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("Sync limits overridden for the next sync".to_string())
+                .to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn resume_branch(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    debug!("Resuming branch router");
+
+    match service.resume_branch(&params.get_branch_key()) {
+        Ok(_) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("Branch resumed".to_string()).to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn enable_branch(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    debug!("Enabling branch router");
+
+    match service.set_enabled(&params.get_branch_key(), true) {
+        Ok(_) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("Branch enabled".to_string()).to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn disable_branch(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    debug!("Disabling branch router");
+
+    match service.set_enabled(&params.get_branch_key(), false) {
+        Ok(_) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("Branch in standby".to_string()).to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn archive_branch(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    debug!("Archiving branch router");
+
+    match tokio::task::spawn_blocking(move || service.archive_branch(&params.get_branch_key()))
+        .await
+    {
+        Ok(Ok(_)) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("Branch archived".to_string()).to_value(),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+pub async fn restore_branch(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    debug!("Restoring branch router");
+
+    match tokio::task::spawn_blocking(move || service.restore_branch(&params.get_branch_key()))
+        .await
+    {
+        Ok(Ok(_)) => Response {
+            status: StatusCode::OK,
+            data: MessageError::new("Branch restored".to_string()).to_value(),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+pub async fn get_usage(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    Response {
+        status: StatusCode::OK,
+        data: service.get_usage(&params.get_branch_key()).to_value(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ClientUsageQuery {
+    /// Only the clients depending on this key, e.g. before deleting it.
+    prefix: Option<String>,
+}
+
+/// Reads attributed to each `X-Gitdis-Client`, by key read.
+pub async fn get_client_usage(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+    Query(query): Query<ClientUsageQuery>,
+) -> impl IntoResponse {
+    let clients = service
+        .get_client_usage(
+            &params.get_branch_key(),
+            query.prefix.as_deref().unwrap_or_default(),
+        )
+        .into_iter()
+        .map(|(client, usage)| (client, usage.to_value()))
+        .collect::<HashMap<String, Value>>();
+
+    Response {
+        status: StatusCode::OK,
+        data: Value::from(clients),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UnusedKeysQuery {
+    /// Days without a read, 30 by default.
+    days: Option<u64>,
+}
+
+/// Keys not read within the window, as possibly dead config.
+pub async fn get_unused_keys(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+    Query(query): Query<UnusedKeysQuery>,
+) -> impl IntoResponse {
+    let days = query.days.unwrap_or(DEFAULT_UNUSED_DAYS);
+
+    match service.get_unused_keys(&params.get_branch_key(), days as u128 * DAY_MILLIS) {
+        Ok(report) => Response {
+            status: StatusCode::OK,
+            data: report.to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn get_status(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    match service.get_branch_status(&params.get_branch_key()) {
+        Ok(status) => Response {
+            status: StatusCode::OK,
+            data: status.to_value(),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn get_lint(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    match service.get_lint_report(&params.get_branch_key()) {
+        Ok(Some(report)) => Response {
+            status: StatusCode::OK,
+            data: report.to_value(),
+        },
+        Ok(None) => Response {
+            status: StatusCode::NOT_FOUND,
+            data: coded_error("lint_not_found", "Branch has not been linted".to_string()),
+        },
+        Err(err) => resolve_errors(err),
+    }
+}
+
+pub async fn compact_dumps(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+) -> impl IntoResponse {
+    debug!("Compacting dumps router");
+
+    match tokio::task::spawn_blocking(move || service.compact_dumps(&params.get_branch_key())).await
+    {
+        Ok(Ok(file)) => Response {
+            status: StatusCode::OK,
+            data: HashMap::from([("file".to_string(), file)]).to_value(),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+/// A registration as the old `gcs` server accepted it on `POST /repos`.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct LegacyRepo {
+    url: String,
+    branch_name: String,
+    pull_request_interval_millis: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct LegacyMigration {
+    /// Clone directory of the old server, e.g. its `data` directory.
+    clone_path: Option<String>,
+    #[serde(default)]
+    registrations: Vec<LegacyRepo>,
+}
+
+impl Validate for LegacyMigration {
+    const FIELDS: &'static [&'static str] = &["clone_path", "registrations"];
+
+    fn validate(&self, errors: &mut FieldErrors) {
+        if self.clone_path.is_none() && self.registrations.is_empty() {
+            errors.add(".", "Needs a clone_path or registrations");
+        }
+
+        for (index, registration) in self.registrations.iter().enumerate() {
+            if !is_git_url(&registration.url) {
+                errors.add(
+                    &format!("registrations[{}].url", index),
+                    "Must be a git URL",
+                );
+            }
+        }
+    }
+}
+
+impl CreateRepo {
+    /// What a migrated branch is kept in the registry as: its remote,
+    /// branch and poll interval, the only settings the old server had,
+    /// listened to like it did.
+    fn from_migrated(settings: &BranchSettings) -> Self {
+        CreateRepo {
+            url: settings.url.clone(),
+            branch_name: Some(settings.branch_name.clone()),
+            pull_request_interval_millis: Some(settings.pull_request_interval_millis),
+            listen: true,
+            ..CreateRepo::default()
+        }
+    }
+}
+
+pub async fn migrate_legacy(
+    Extension(service): Extension<GitdisService>,
+    Extension(registry): Extension<Registry>,
+    Validated(payload): Validated<LegacyMigration>,
+) -> impl IntoResponse {
+    debug!("Migrating legacy router");
+
+    let registrations = payload
+        .registrations
+        .into_iter()
+        .map(|repo| LegacyRegistration {
+            url: repo.url,
+            branch_name: repo.branch_name,
+            pull_request_interval_millis: repo.pull_request_interval_millis,
+        })
+        .collect();
+
+    let migrated_service = service.clone();
+
+    match tokio::task::spawn_blocking(move || {
+        migrated_service.migrate_legacy(payload.clone_path.as_deref(), registrations)
+    })
+    .await
+    {
+        Ok(Ok(report)) => {
+            for key in &report.migrated {
+                if let Ok(settings) = service.get_branch_settings(key) {
+                    registry.record(key, &CreateRepo::from_migrated(&settings));
+                }
+            }
+
+            Response {
+                status: StatusCode::OK,
+                data: report.to_value(),
+            }
+        }
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+pub async fn get_memo_stats(Extension(service): Extension<GitdisService>) -> impl IntoResponse {
+    let stats = service.get_memo_stats();
+    let mut data = stats.to_value();
+
+    if let Value::Object(object) = &mut data {
+        object.insert("hit_rate".to_string(), Value::from(stats.hit_rate()));
+    }
+
+    Response {
+        status: StatusCode::OK,
+        data,
+    }
+}
+
+pub async fn get_serialization_stats(
+    Extension(service): Extension<GitdisService>,
+) -> impl IntoResponse {
+    let stats = service.get_serialization_stats();
+    let mut data = stats.to_value();
+
+    if let Value::Object(object) = &mut data {
+        object.insert("hit_rate".to_string(), Value::from(stats.hit_rate()));
+    }
+
+    Response {
+        status: StatusCode::OK,
+        data,
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CommitChangeBody {
+    key: String,
+    /// The new value of the key; give either it or `delete`.
+    value: Option<serde_json::Value>,
+    #[serde(default)]
+    delete: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CommitBody {
+    message: String,
+    changes: Vec<CommitChangeBody>,
+}
+
+impl Validate for CommitBody {
+    const FIELDS: &'static [&'static str] = &["message", "changes"];
+
+    fn validate(&self, errors: &mut FieldErrors) {
+        if self.message.trim().is_empty() {
+            errors.add("message", "Must not be empty");
+        }
+
+        if self.changes.is_empty() {
+            errors.add("changes", "Must not be empty");
+        }
+
+        for (index, change) in self.changes.iter().enumerate() {
+            let field = format!("changes[{}]", index);
+
+            if change.key.trim_matches('/').is_empty() {
+                errors.add(&format!("{}.key", field), "Must not be empty");
+            }
+
+            if let Err(err) = validate_relative_path(&change.key) {
+                errors.add(&format!("{}.key", field), &err);
+            }
+
+            match (&change.value, change.delete) {
+                (Some(_), true) => errors.add(&field, "Needs a value or delete, not both"),
+                (None, false) => errors.add(&format!("{}.value", field), "Missing value"),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl CommitBody {
+    /// Call after `validate`.
+    fn to_changes(&self) -> Vec<KeyWrite> {
+        self.changes
+            .iter()
+            .map(|change| {
+                let key = change.key.trim_matches('/').to_string();
+
+                match &change.value {
+                    Some(value) if !change.delete => KeyWrite::Update {
+                        key,
+                        value: to_value(value),
+                    },
+                    _ => KeyWrite::Delete { key },
+                }
+            })
+            .collect()
+    }
+}
+
+/// Writes several keys back as one commit, pushed as a whole or not at
+/// all. `If-Match` works as for `patch_object`.
+pub async fn commit_changes(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+    headers: HeaderMap,
+    Validated(payload): Validated<CommitBody>,
+) -> impl IntoResponse {
+    debug!("Committing changes router");
+
+    let if_match = headers
+        .get(IF_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim_matches('"').to_string());
+    let changes = payload.to_changes();
+
+    match tokio::task::spawn_blocking(move || {
+        service.commit_changes(
+            &params.get_branch_key(),
+            changes,
+            &payload.message,
+            if_match,
+        )
+    })
+    .await
+    {
+        Ok(Ok(commit)) => Response {
+            status: StatusCode::OK,
+            data: Value::from(HashMap::from([("commit".to_string(), Value::from(commit))])),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RenderBody {
+    /// The template itself; give either it or `path`.
+    template: Option<String>,
+    /// A template file of the repository, relative to its root.
+    path: Option<String>,
+    /// Only this prefix of the branch as context.
+    prefix: Option<String>,
+}
+
+impl Validate for RenderBody {
+    const FIELDS: &'static [&'static str] = &["template", "path", "prefix"];
+
+    fn validate(&self, errors: &mut FieldErrors) {
+        match (&self.template, &self.path) {
+            (Some(_), Some(_)) => errors.add(".", "Needs a template or a path, not both"),
+            (None, None) => errors.add("template", "Missing template or path"),
+            (None, Some(path)) if path.trim_matches('/').is_empty() => {
+                errors.add("path", "Must not be empty")
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Renders a template with the branch data as context, answering the
+/// rendered text.
+pub async fn render_template(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+    Validated(payload): Validated<RenderBody>,
+) -> HttpResponse {
+    debug!("Rendering template router");
+
+    let source = match (payload.template, payload.path) {
+        (Some(template), _) => TemplateSource::Inline(template),
+        (None, path) => TemplateSource::File(path.unwrap_or_default()),
+    };
+    let prefix = payload.prefix.unwrap_or_default();
+
+    match tokio::task::spawn_blocking(move || {
+        service.render_template(&params.get_branch_key(), &prefix, source)
+    })
+    .await
+    {
+        Ok(Ok(text)) => {
+            ([(CONTENT_TYPE.as_str(), "text/plain; charset=utf-8")], text).into_response()
+        }
+        Ok(Err(err)) => resolve_errors(err).into_response(),
+        Err(err) => {
+            resolve_errors(GitdisServiceError::InternalError(err.to_string())).into_response()
+        }
+    }
+}
+
+pub async fn patch_object(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<ObjectParams>,
+    headers: HeaderMap,
+    body: String,
+) -> impl IntoResponse {
+    debug!("Patching object router");
+
+    if let Err(err) = validate_relative_path(&params.object_key) {
+        return resolve_errors(GitdisServiceError::InvalidKey(err));
+    }
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+
+    let payload = match Value::json_to_value(&body) {
+        Ok(payload) => payload,
+        Err(_) => {
+            return Response {
+                status: StatusCode::BAD_REQUEST,
+                data: MessageError::new("Invalid JSON body".to_string()).to_value(),
+            }
+        }
+    };
+
+    let patch = if content_type.starts_with(MERGE_PATCH_CONTENT_TYPE) {
+        ObjectPatch::Merge(payload)
+    } else if content_type.starts_with(JSON_PATCH_CONTENT_TYPE) {
+        ObjectPatch::Json(payload)
+    } else {
+        return Response {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            data: MessageError::new(format!(
+                "Content-Type must be {} or {}",
+                MERGE_PATCH_CONTENT_TYPE, JSON_PATCH_CONTENT_TYPE
+            ))
+            .to_value(),
+        };
+    };
+
+    let if_match = headers
+        .get(IF_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim_matches('"').to_string());
+
+    // The commit and push block on git.
+    match tokio::task::spawn_blocking(move || {
+        service.patch_object(
+            &params.get_branch_key(),
+            &params.object_key,
+            patch,
+            if_match,
+        )
+    })
+    .await
+    {
+        Ok(Ok(value)) => Response {
+            status: StatusCode::OK,
+            data: value,
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
@@ -0,0 +1,455 @@
+#[cfg(feature = "export")]
+use axum::body::Body;
+use axum::{
+    extract::{Path, Query},
+    http::{header, StatusCode},
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Extension,
+};
+use gitdis::prelude::*;
+use log::debug;
+use serde::Deserialize;
+use std::{collections::BTreeMap, convert::Infallible};
+use tokio::sync::{
+    broadcast::{error::RecvError, Receiver},
+    mpsc::Sender,
+};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+use super::queries::ContinuousQueryBody;
+use super::routes::{resolve_errors, BranchParams};
+use super::validation::Validated;
+use super::Response;
+
+/// Frames of a stream in flight before its client is waited for.
+const STREAM_FRAMES_IN_FLIGHT: usize = 64;
+/// Bytes buffered before a chunk of an export is sent.
+#[cfg(feature = "export")]
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+/// Chunks of an export in flight before the writer waits for the client.
+#[cfg(feature = "export")]
+const EXPORT_CHUNKS_IN_FLIGHT: usize = 16;
+
+#[derive(Deserialize, Debug)]
+pub struct StreamQuery {
+    prefix: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BackfillQuery {
+    /// A sequence number, or a commit loaded by a sync.
+    since: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SnapshotQuery {
+    /// Codecs the client reads, most preferred first, `json` by default.
+    codecs: Option<String>,
+}
+
+#[cfg(feature = "export")]
+#[derive(Deserialize, Debug)]
+pub struct ExportQuery {
+    prefix: Option<String>,
+    /// `arrow`, the default, or `parquet`.
+    format: Option<String>,
+}
+
+/// Sends what is written to it to the response body, waiting while the
+/// client is behind.
+#[cfg(feature = "export")]
+struct ChunkWriter {
+    sender: tokio::sync::mpsc::Sender<std::io::Result<Vec<u8>>>,
+}
+
+#[cfg(feature = "export")]
+impl std::io::Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.sender
+            .blocking_send(Ok(buf.to_vec()))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `subscription` is the id to backfill the stream with, if it can be.
+fn snapshot_frame(
+    sequence: u64,
+    entries: Vec<(String, Value)>,
+    subscription: Option<u64>,
+) -> SseEvent {
+    let entries = entries.into_iter().collect::<BTreeMap<String, Value>>();
+    let mut frame = BTreeMap::new();
+    frame.insert("sequence".to_string(), Value::from(sequence));
+
+    if let Some(subscription) = subscription {
+        frame.insert("subscription".to_string(), Value::from(subscription));
+    }
+
+    frame.insert("entries".to_string(), Value::from(entries));
+
+    SseEvent::default()
+        .event("snapshot")
+        .id(sequence.to_string())
+        .data(Value::from(frame).to_json(JsonMode::Inline))
+}
+
+fn change_frame(event: BranchEvent) -> SseEvent {
+    let mut frame = BTreeMap::new();
+    frame.insert("sequence".to_string(), Value::from(event.sequence));
+
+    match event.event {
+        BranchEventKind::Cache(Event::Insert(data)) => {
+            frame.insert("type".to_string(), Value::from("insert"));
+            frame.insert("key".to_string(), Value::from(data.key));
+            frame.insert("value".to_string(), data.value);
+        }
+        BranchEventKind::Cache(Event::Remove(data)) => {
+            frame.insert("type".to_string(), Value::from("remove"));
+            frame.insert("key".to_string(), Value::from(data.key));
+        }
+        BranchEventKind::Evicted { key, reason } => {
+            frame.insert("type".to_string(), Value::from("evicted"));
+            frame.insert("key".to_string(), Value::from(key));
+            frame.insert("reason".to_string(), Value::from(reason.as_str()));
+        }
+        BranchEventKind::Cache(Event::Clear) => {
+            frame.insert("type".to_string(), Value::from("clear"));
+        }
+        BranchEventKind::Rejected { commit, reason } => {
+            frame.insert("type".to_string(), Value::from("rejected"));
+            frame.insert("commit".to_string(), Value::from(commit));
+            frame.insert("reason".to_string(), Value::from(reason));
+        }
+        BranchEventKind::Invalid { file, errors } => {
+            frame.insert("type".to_string(), Value::from("invalid"));
+            frame.insert("file".to_string(), Value::from(file));
+            frame.insert(
+                "errors".to_string(),
+                Value::from(errors.into_iter().map(Value::from).collect::<Vec<Value>>()),
+            );
+        }
+        BranchEventKind::ParseError {
+            file,
+            commit,
+            error,
+        } => {
+            frame.insert("type".to_string(), Value::from("parse_error"));
+            frame.insert("file".to_string(), Value::from(file));
+            frame.insert("commit".to_string(), Value::from(commit));
+            frame.insert("error".to_string(), Value::from(error));
+        }
+        BranchEventKind::Dirty {
+            files,
+            local_commits,
+        } => {
+            frame.insert("type".to_string(), Value::from("dirty"));
+            frame.insert(
+                "files".to_string(),
+                Value::from(files.into_iter().map(Value::from).collect::<Vec<Value>>()),
+            );
+            frame.insert("local_commits".to_string(), Value::from(local_commits));
+        }
+        BranchEventKind::Archived { reason } => {
+            frame.insert("type".to_string(), Value::from("archived"));
+            frame.insert("reason".to_string(), Value::from(reason));
+        }
+        BranchEventKind::Restored => {
+            frame.insert("type".to_string(), Value::from("restored"));
+        }
+        BranchEventKind::Removed => {
+            frame.insert("type".to_string(), Value::from("removed"));
+        }
+        BranchEventKind::SyncCompleted { commit } => {
+            frame.insert("type".to_string(), Value::from("sync_completed"));
+            frame.insert("commit".to_string(), Value::from(commit));
+        }
+        BranchEventKind::SyncFailed { error } => {
+            frame.insert("type".to_string(), Value::from("sync_failed"));
+            frame.insert("error".to_string(), Value::from(error));
+        }
+    }
+
+    SseEvent::default()
+        .event("change")
+        .id(event.sequence.to_string())
+        .data(Value::from(frame).to_json(JsonMode::Inline))
+}
+
+fn match_frame(sequence: u64, change: MatchChange) -> SseEvent {
+    let mut frame = BTreeMap::new();
+    frame.insert("sequence".to_string(), Value::from(sequence));
+
+    match change {
+        MatchChange::Entered { key, value } => {
+            frame.insert("type".to_string(), Value::from("entered"));
+            frame.insert("key".to_string(), Value::from(key));
+            frame.insert("value".to_string(), value);
+        }
+        MatchChange::Left { key } => {
+            frame.insert("type".to_string(), Value::from("left"));
+            frame.insert("key".to_string(), Value::from(key));
+        }
+        MatchChange::Updated { key, value } => {
+            frame.insert("type".to_string(), Value::from("updated"));
+            frame.insert("key".to_string(), Value::from(key));
+            frame.insert("value".to_string(), value);
+        }
+    }
+
+    SseEvent::default()
+        .event("change")
+        .id(sequence.to_string())
+        .data(Value::from(frame).to_json(JsonMode::Inline))
+}
+
+/// Replays journaled events after `since` to a subscriber: a branch stream,
+/// by the `subscription` of its snapshot frame, or a key watch by name.
+pub async fn backfill_subscriber(
+    Extension(service): Extension<GitdisService>,
+    Path(subscriber): Path<String>,
+    Query(query): Query<BackfillQuery>,
+) -> impl IntoResponse {
+    let since = JournalPosition::parse(&query.since);
+
+    match tokio::task::spawn_blocking(move || service.backfill(&subscriber, &since)).await {
+        Ok(Ok(replayed)) => Response {
+            status: StatusCode::OK,
+            data: Value::from(BTreeMap::from([(
+                "replayed".to_string(),
+                Value::from(replayed as u64),
+            )])),
+        },
+        Ok(Err(err)) => resolve_errors(err),
+        Err(err) => resolve_errors(GitdisServiceError::InternalError(err.to_string())),
+    }
+}
+
+/// Server-sent events: one `snapshot` frame with the matching entries, then
+/// a `change` frame per update, both carrying sequence numbers. Backfilled
+/// events come as `change` frames with older sequence numbers.
+pub async fn stream_branch(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+    Query(query): Query<StreamQuery>,
+) -> impl IntoResponse {
+    let prefix = query.prefix.unwrap_or_default();
+
+    let subscription = match service.subscribe(&params.get_branch_key(), &prefix) {
+        Ok(subscription) => subscription,
+        Err(err) => return resolve_errors(err).into_response(),
+    };
+
+    debug!("Streaming subscription {}", subscription.id);
+
+    let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_FRAMES_IN_FLIGHT);
+    let _ = sender.try_send(snapshot_frame(
+        subscription.sequence,
+        subscription.snapshot,
+        Some(subscription.id),
+    ));
+
+    bridge(
+        service,
+        subscription.id,
+        subscription.receiver,
+        sender,
+        change_frame,
+    );
+
+    let stream = ReceiverStream::new(receiver).map(Ok::<SseEvent, Infallible>);
+
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Server-sent events for a clause: one `snapshot` frame with the matching
+/// entries, then a `change` frame per entry entering, leaving or updated in
+/// the set.
+pub async fn stream_query(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+    Validated(payload): Validated<ContinuousQueryBody>,
+) -> impl IntoResponse {
+    let subscription = match service.subscribe_query(&params.get_branch_key(), payload.to_query()) {
+        Ok(subscription) => subscription,
+        Err(err) => return resolve_errors(err).into_response(),
+    };
+
+    debug!("Streaming query subscription {}", subscription.id);
+
+    let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_FRAMES_IN_FLIGHT);
+    let _ = sender.try_send(snapshot_frame(
+        subscription.sequence,
+        subscription.matches,
+        None,
+    ));
+
+    bridge(
+        service,
+        subscription.id,
+        subscription.receiver,
+        sender,
+        |(sequence, change)| match_frame(sequence, change),
+    );
+
+    let stream = ReceiverStream::new(receiver).map(Ok::<SseEvent, Infallible>);
+
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// The keys of a branch under `prefix` as an Arrow IPC stream or a Parquet
+/// file, see `gitdis::export`, sent as it is written.
+#[cfg(feature = "export")]
+pub async fn export_branch(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+    Query(query): Query<ExportQuery>,
+) -> impl IntoResponse {
+    let format = match query.format.as_deref() {
+        None => ExportFormat::Arrow,
+        Some(format) => match ExportFormat::parse(format) {
+            Some(format) => format,
+            None => {
+                return resolve_errors(GitdisServiceError::InvalidQuery(format!(
+                    "Unknown export format: {}",
+                    format
+                )))
+                .into_response()
+            }
+        },
+    };
+
+    let branch_key = params.get_branch_key();
+    let prefix = query.prefix.unwrap_or_default();
+
+    let rows = match tokio::task::spawn_blocking({
+        let branch_key = branch_key.clone();
+        move || service.export_branch(&branch_key, &prefix)
+    })
+    .await
+    {
+        Ok(Ok(rows)) => rows,
+        Ok(Err(err)) => return resolve_errors(err).into_response(),
+        Err(err) => {
+            return resolve_errors(GitdisServiceError::InternalError(err.to_string()))
+                .into_response()
+        }
+    };
+
+    debug!("Exporting {} keys of {}", rows.len(), branch_key);
+
+    let (sender, receiver) = tokio::sync::mpsc::channel(EXPORT_CHUNKS_IN_FLIGHT);
+
+    tokio::task::spawn_blocking(move || {
+        let writer = std::io::BufWriter::with_capacity(
+            EXPORT_CHUNK_BYTES,
+            ChunkWriter {
+                sender: sender.clone(),
+            },
+        );
+
+        if let Err(err) = write_export(&rows, format, writer) {
+            debug!("Export of {} stopped: {}", branch_key, err);
+            let _ = sender.blocking_send(Err(std::io::Error::other(err.to_string())));
+        }
+    });
+
+    let filename = format!(
+        "{}.{}",
+        params.get_branch_key().replace('/', "-"),
+        format.get_extension()
+    );
+
+    (
+        [
+            (header::CONTENT_TYPE, format.get_content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(receiver)),
+    )
+        .into_response()
+}
+
+/// The whole branch as a wire frame, see `gitdis::codec`, in the first of
+/// the codecs offered this node has. The codec chosen is also sent in the
+/// `X-Gitdis-Codec` header.
+pub async fn snapshot_branch(
+    Extension(service): Extension<GitdisService>,
+    Path(params): Path<BranchParams>,
+    Query(query): Query<SnapshotQuery>,
+) -> impl IntoResponse {
+    let offered = query
+        .codecs
+        .unwrap_or(JSON_CODEC.to_string())
+        .split(',')
+        .map(|codec| codec.trim().to_string())
+        .collect::<Vec<String>>();
+    let branch_key = params.get_branch_key();
+
+    match tokio::task::spawn_blocking(move || service.snapshot_branch(&branch_key, &offered)).await
+    {
+        Ok(Ok((codec, bytes))) => (
+            [
+                (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                (header::HeaderName::from_static("x-gitdis-codec"), codec),
+            ],
+            bytes,
+        )
+            .into_response(),
+        Ok(Err(err)) => resolve_errors(err).into_response(),
+        Err(err) => {
+            resolve_errors(GitdisServiceError::InternalError(err.to_string())).into_response()
+        }
+    }
+}
+
+/// Bridges the events of a subscription to the response, stopping once the
+/// client has gone away. A client too far behind to catch up is cut off, to
+/// reconnect from a new snapshot.
+fn bridge<T: Clone + Send + 'static>(
+    service: GitdisService,
+    id: u64,
+    mut events: Receiver<T>,
+    sender: Sender<SseEvent>,
+    to_frame: fn(T) -> SseEvent,
+) {
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = sender.closed() => break,
+            };
+
+            match event {
+                Ok(event) => {
+                    if sender.send(to_frame(event)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("Subscription {} missed {} events", id, missed);
+                    break;
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        debug!("Closing subscription {}", id);
+        service.unsubscribe(id);
+    });
+}
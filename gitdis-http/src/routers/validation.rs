@@ -0,0 +1,143 @@
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response as HttpResponse},
+};
+use gitdis::prelude::*;
+use serde::de::DeserializeOwned;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+
+use super::Response;
+
+/// When `true`, bodies with fields a route does not know are rejected
+/// instead of the fields being ignored.
+const STRICT_BODIES_ENV: &str = "GITDIS_STRICT_BODIES";
+
+/// Problems found in a request body, per field. Nested fields are joined
+/// with dots, e.g. `credentials.username`.
+#[derive(Default)]
+pub struct FieldErrors {
+    errors: Vec<(String, String)>,
+}
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &str, message: &str) {
+        self.errors.push((field.to_string(), message.to_string()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn into_response(self) -> HttpResponse {
+        let fields = self
+            .errors
+            .into_iter()
+            .map(|(field, message)| {
+                let mut object = HashMap::new();
+                object.insert("field".to_string(), Value::from(field));
+                object.insert("message".to_string(), Value::from(message));
+                Value::from(object)
+            })
+            .collect::<Vec<Value>>();
+
+        let mut data = HashMap::new();
+        data.insert("code".to_string(), Value::from("invalid_body"));
+        data.insert("message".to_string(), Value::from("Invalid request body"));
+        data.insert("fields".to_string(), Value::from(fields));
+
+        Response {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            data: Value::from(data),
+        }
+        .into_response()
+    }
+}
+
+/// A request body with checks beyond what deserializing it covers.
+pub trait Validate {
+    /// Top-level fields of the body, to reject unknown ones in strict mode.
+    const FIELDS: &'static [&'static str];
+
+    fn validate(&self, errors: &mut FieldErrors);
+}
+
+/// JSON body extractor answering `422 Unprocessable Entity` with the failing
+/// fields when the body does not deserialize or does not validate.
+pub struct Validated<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for Validated<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned + Validate,
+{
+    type Rejection = HttpResponse;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let bytes = Bytes::from_request(request, state)
+            .await
+            .map_err(|rejection| rejection.into_response())?;
+
+        let mut errors = FieldErrors::default();
+
+        let json = match serde_json::from_slice::<JsonValue>(&bytes) {
+            Ok(json) => json,
+            Err(err) => {
+                errors.add(".", &format!("Invalid JSON: {}", err));
+                return Err(errors.into_response());
+            }
+        };
+
+        if is_strict() {
+            if let JsonValue::Object(object) = &json {
+                for field in object.keys() {
+                    if !T::FIELDS.contains(&field.as_str()) {
+                        errors.add(field, "Unknown field");
+                    }
+                }
+            }
+        }
+
+        let payload = match serde_path_to_error::deserialize::<_, T>(json) {
+            Ok(payload) => payload,
+            Err(err) => {
+                errors.add(&err.path().to_string(), &err.inner().to_string());
+                return Err(errors.into_response());
+            }
+        };
+
+        payload.validate(&mut errors);
+
+        if !errors.is_empty() {
+            return Err(errors.into_response());
+        }
+
+        Ok(Validated(payload))
+    }
+}
+
+fn is_strict() -> bool {
+    std::env::var(STRICT_BODIES_ENV).is_ok_and(|strict| strict == "true")
+}
+
+/// Whether `url` has a form git can clone from: `scheme://host/path`,
+/// scp-like `user@host:path` or an absolute path on the server. Relative
+/// paths are refused, as they depend on where the server was started.
+pub fn is_git_url(url: &str) -> bool {
+    if url.starts_with('/') {
+        return url.len() > 1;
+    }
+
+    match url.split_once("://") {
+        Some((scheme, rest)) => {
+            ["http", "https", "ssh", "git", "file"].contains(&scheme) && !rest.is_empty()
+        }
+        None => url
+            .split_once(':')
+            .is_some_and(|(host, path)| host.contains('@') && !path.is_empty()),
+    }
+}
@@ -0,0 +1,132 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header::CONTENT_TYPE, HeaderValue, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use gitdis::prelude::*;
+use log::debug;
+
+use super::{MessageError, Response};
+
+pub const API_VERSION_HEADER: &str = "X-Gitdis-Api-Version";
+/// API versions this build serves, oldest first.
+pub const API_VERSIONS: &[&str] = &["1", "2"];
+/// Version served on unprefixed paths when no version is asked for, so
+/// clients written before versioning keep their response shapes.
+const UNPREFIXED_API_VERSION: &str = "1";
+/// Versions still served but due to be removed.
+const DEPRECATED_API_VERSIONS: &[&str] = &["1"];
+/// HTTP date after which v1 may be removed, e.g.
+/// `Sat, 01 May 2027 00:00:00 GMT`. Without it no `Sunset` header is sent.
+const SUNSET_ENV: &str = "GITDIS_API_V1_SUNSET";
+/// Bodies above this size are passed through without being downgraded.
+const MAX_DOWNGRADE_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Resolves the API version of a request from its `/v1` or `/v2` prefix, or
+/// from `X-Gitdis-Api-Version` on unprefixed paths. Handlers always answer
+/// in the latest shape; older versions are downgraded here.
+pub async fn negotiate_api_version(request: Request, next: Next) -> axum::response::Response {
+    let prefixed = API_VERSIONS
+        .iter()
+        .find(|version| {
+            let prefix = format!("/v{}", version);
+            let path = request.uri().path();
+
+            path == prefix || path.starts_with(&format!("{}/", prefix))
+        })
+        .map(|version| version.to_string());
+
+    let requested = request
+        .headers()
+        .get(API_VERSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string());
+
+    let version = match (prefixed, requested) {
+        (Some(prefixed), Some(requested)) if prefixed != requested => {
+            return unsupported(format!(
+                "Path is API version {} but {} asks for {}",
+                prefixed, API_VERSION_HEADER, requested
+            ));
+        }
+        (Some(prefixed), _) => prefixed,
+        (None, Some(requested)) if !API_VERSIONS.contains(&requested.as_str()) => {
+            return unsupported(format!(
+                "Unsupported API version {}, supported: {}",
+                requested,
+                API_VERSIONS.join(", ")
+            ));
+        }
+        (None, Some(requested)) => requested,
+        (None, None) => UNPREFIXED_API_VERSION.to_string(),
+    };
+
+    let mut response = next.run(request).await;
+
+    if version == "1" {
+        response = downgrade_to_v1(response).await;
+    }
+
+    if DEPRECATED_API_VERSIONS.contains(&version.as_str()) {
+        response
+            .headers_mut()
+            .insert("Deprecation", HeaderValue::from_static("true"));
+
+        if let Some(sunset) = std::env::var(SUNSET_ENV)
+            .ok()
+            .and_then(|sunset| HeaderValue::from_str(&sunset).ok())
+        {
+            response.headers_mut().insert("Sunset", sunset);
+        }
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&version) {
+        response.headers_mut().insert(API_VERSION_HEADER, value);
+    }
+
+    response
+}
+
+fn unsupported(message: String) -> axum::response::Response {
+    Response {
+        status: StatusCode::BAD_REQUEST,
+        data: MessageError::new(message).to_value(),
+    }
+    .into_response()
+}
+
+/// v1 error bodies carry the message only.
+async fn downgrade_to_v1(response: axum::response::Response) -> axum::response::Response {
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+
+    if !is_json || !(response.status().is_client_error() || response.status().is_server_error()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+
+    let bytes = match to_bytes(body, MAX_DOWNGRADE_BODY_BYTES).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            debug!("Error reading response body: {}", err);
+            return axum::response::Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    let body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(serde_json::Value::Object(mut object)) => {
+            object.remove("code");
+            serde_json::to_vec(&object).unwrap_or(bytes.to_vec())
+        }
+        _ => bytes.to_vec(),
+    };
+
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+
+    axum::response::Response::from_parts(parts, Body::from(body))
+}
@@ -0,0 +1,156 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
+use gitdis::prelude::valu3::prelude::ToValueBehavior;
+use gitdis::prelude::*;
+use gitdis::repo_url::RepoUrl;
+use hmac::{Hmac, Mac};
+use log::debug;
+use serde_json::Value as JsonValue;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use super::{MessageError, Response};
+
+const BRANCH_REF_PREFIX: &str = "refs/heads/";
+/// Secret of the branches without `webhook_secret_env`.
+const WEBHOOK_SECRET_ENV: &str = "GITDIS_WEBHOOK_SECRET";
+/// HMAC-SHA256 of the body, hex encoded after `sha256=`, sent by GitHub.
+const GITHUB_SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+const GITHUB_SIGNATURE_PREFIX: &str = "sha256=";
+/// The secret itself, sent by GitLab.
+const GITLAB_TOKEN_HEADER: &str = "X-Gitlab-Token";
+
+/// Extracts the branch key (`host~owner/repo/branch`) from a GitHub or
+/// GitLab push payload. Returns `None` for pushes that are not to a branch.
+fn get_branch_key(payload: &JsonValue) -> Option<String> {
+    let branch = payload
+        .get("ref")?
+        .as_str()?
+        .strip_prefix(BRANCH_REF_PREFIX)?;
+
+    // GitHub sends `repository.html_url`, GitLab `project.web_url`; both
+    // are keyed like the clone URLs, host and nested groups included.
+    let web_url = payload
+        .get("repository")
+        .and_then(|repository| repository.get("html_url"))
+        .or_else(|| {
+            payload
+                .get("project")
+                .and_then(|project| project.get("web_url"))
+        })?
+        .as_str()?;
+    let url = RepoUrl::parse(web_url).filter(|url| !url.local)?;
+
+    Some(gitdis::repo_url::get_branch_key(
+        &url.get_owner(),
+        &url.name,
+        branch,
+    ))
+}
+
+/// The secret webhooks for `branch_key` are signed with: the one in the
+/// branch's `webhook_secret_env`, or else `GITDIS_WEBHOOK_SECRET`.
+fn get_secret(service: &GitdisService, branch_key: Option<&str>) -> Option<String> {
+    let secret_env = branch_key
+        .and_then(|branch_key| service.get_branch_settings(branch_key).ok())
+        .and_then(|settings| settings.webhook_secret_env);
+
+    std::env::var(secret_env.as_deref().unwrap_or(WEBHOOK_SECRET_ENV))
+        .ok()
+        .filter(|secret| !secret.is_empty())
+}
+
+/// Whether the request carries a valid GitHub signature of `body`, or the
+/// GitLab token, for `secret`. Both are compared in constant time.
+fn is_signed(headers: &HeaderMap, body: &[u8], secret: &str) -> bool {
+    if let Some(signature) = headers
+        .get(GITHUB_SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+    {
+        let signature = match signature
+            .strip_prefix(GITHUB_SIGNATURE_PREFIX)
+            .and_then(|signature| hex::decode(signature).ok())
+        {
+            Some(signature) => signature,
+            None => return false,
+        };
+        let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+            Ok(mac) => mac,
+            Err(_) => return false,
+        };
+
+        mac.update(body);
+
+        return mac.verify_slice(&signature).is_ok();
+    }
+
+    match headers.get(GITLAB_TOKEN_HEADER) {
+        Some(token) => token.as_bytes().ct_eq(secret.as_bytes()).into(),
+        None => false,
+    }
+}
+
+/// Triggers a sync of the branch a GitHub or GitLab push is for. Requests
+/// are rejected unless signed with the branch's secret, see `get_secret`;
+/// with no secret configured every request is.
+pub async fn git_webhook(
+    Extension(service): Extension<GitdisService>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    debug!("Webhook received");
+
+    let payload: JsonValue = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(err) => {
+            return Response {
+                status: StatusCode::BAD_REQUEST,
+                data: MessageError::new(format!("Invalid payload: {}", err)).to_value(),
+            }
+        }
+    };
+    let branch_key = get_branch_key(&payload);
+
+    let verified = match get_secret(&service, branch_key.as_deref()) {
+        Some(secret) => is_signed(&headers, &body, &secret),
+        None => false,
+    };
+
+    if !verified {
+        return Response {
+            status: StatusCode::UNAUTHORIZED,
+            data: MessageError::new("Invalid or missing webhook signature".to_string()).to_value(),
+        };
+    }
+
+    let branch_key = match branch_key {
+        Some(branch_key) => branch_key,
+        None => {
+            return Response {
+                status: StatusCode::ACCEPTED,
+                data: MessageError::new("Ignored: not a branch push".to_string()).to_value(),
+            }
+        }
+    };
+
+    debug!("Webhook for branch: {}", branch_key);
+
+    match service.trigger_sync(&branch_key) {
+        Ok(_) => Response {
+            status: StatusCode::ACCEPTED,
+            data: MessageError::new(format!("Sync triggered for {}", branch_key)).to_value(),
+        },
+        Err(GitdisServiceError::BranchNotFound) => Response {
+            status: StatusCode::NOT_FOUND,
+            data: MessageError::new(format!("Branch not registered: {}", branch_key)).to_value(),
+        },
+        Err(_) => Response {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            data: MessageError::new("Error triggering sync".to_string()).to_value(),
+        },
+    }
+}
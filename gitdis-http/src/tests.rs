@@ -0,0 +1,658 @@
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    Router,
+};
+use gitdis::prelude::*;
+use std::sync::{Arc, RwLock};
+use std::{fs, time::Duration};
+use tower::ServiceExt;
+
+use crate::routers::{enforce_limits, routes, Capabilities, Capability, Registry, RouteLimits};
+
+/// Runs git in `dir` with a throwaway identity, returning its output.
+fn git(dir: &str, args: &[&str]) -> String {
+    let output = std::process::Command::new("git")
+        .args([
+            "-c",
+            "user.name=gitdis",
+            "-c",
+            "user.email=gitdis@localhost",
+        ])
+        .args(["-c", "commit.gpgsign=false"])
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// A scratch directory holding an `owner/repo` origin with one commit, and
+/// the hash of that commit.
+fn create_origin(name: &str) -> (String, String, String) {
+    let root = std::env::temp_dir().join(format!("gitdis-http-{}-{}", name, std::process::id()));
+    let root = root.to_str().unwrap().to_string();
+    let origin = format!("{}/origin/owner/repo", root);
+
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&origin).unwrap();
+    fs::write(format!("{}/config.json", origin), "{}").unwrap();
+    git(&origin, &["init", "-b", "main"]);
+    git(&origin, &["add", "."]);
+    git(&origin, &["commit", "-m", "origin"]);
+    let head = git(&origin, &["rev-parse", "HEAD"]);
+
+    (root, origin, head)
+}
+
+fn create_service(root: &str) -> GitdisService {
+    let gitdis = Gitdis::from(GitdisSettings {
+        total_branch_items: 100,
+        local_clone_path: format!("{}/clones", root),
+        proxy: None,
+        maintenance_interval_millis: None,
+        max_network_operations: None,
+    });
+
+    GitdisService::new(Arc::new(RwLock::new(gitdis)))
+}
+
+fn create_router(service: &GitdisService, registry: &Registry) -> Router {
+    routes(service.clone(), Capabilities::from_env(), registry.clone())
+}
+
+fn open_registry(root: &str) -> Registry {
+    Registry::open(format!("{}/registry.json", root)).unwrap()
+}
+
+fn post_json(uri: &str, body: String) -> Request<Body> {
+    Request::post(uri)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_create_repo_listen_and_wait_ready() {
+    let (root, origin, head) = create_origin("create");
+    let service = create_service(&root);
+    let router = create_router(&service, &open_registry(&root));
+    let body = format!(
+        r#"{{"url": "{}", "branch_name": "main", "listen": true, "wait_ready_ms": 10000}}"#,
+        origin
+    );
+
+    let response = router
+        .clone()
+        .oneshot(post_json("/repos", body.clone()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(
+        service
+            .get_branch_status("owner/repo/main")
+            .unwrap()
+            .last_commit,
+        Some(head)
+    );
+
+    let response = router
+        .clone()
+        .oneshot(post_json("/repos", body))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // Waiting needs a listener.
+    let body = format!(r#"{{"url": "{}", "wait_ready_ms": 1000}}"#, origin);
+    let response = router.oneshot(post_json("/repos", body)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    assert!(service.shutdown(Duration::from_secs(10)));
+    let _ = fs::remove_dir_all(root);
+}
+
+#[tokio::test]
+async fn test_registry_survives_restart() {
+    let (root, origin, head) = create_origin("registry");
+    let service = create_service(&root);
+    let registry = open_registry(&root);
+    let body = format!(
+        r#"{{"url": "{}", "branch_name": "main", "listen": true, "wait_ready_ms": 10000}}"#,
+        origin
+    );
+
+    let response = create_router(&service, &registry)
+        .oneshot(post_json("/repos", body))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert!(service.shutdown(Duration::from_secs(10)));
+
+    // A repository no registration accounts for.
+    let orphan = format!("{}/clones/owner/other", root);
+    fs::create_dir_all(format!("{}/main", orphan)).unwrap();
+
+    let mut service = create_service(&root);
+    let registry = open_registry(&root);
+    assert_eq!(registry.get_keys(), vec!["owner/repo/main".to_string()]);
+
+    let report = registry
+        .restore(&mut service, Some(OrphanPolicy::Quarantine))
+        .unwrap();
+    let clone = format!("{}/clones/owner/repo/main", root);
+    assert!(report
+        .actions
+        .iter()
+        .any(|action| matches!(action, ReconcileAction::Adopted { path } if *path == clone)));
+    assert!(report.actions.iter().any(
+        |action| matches!(action, ReconcileAction::Quarantined { path, .. } if *path == orphan)
+    ));
+    assert!(fs::metadata(&orphan).is_err());
+
+    service
+        .wait_ready("owner/repo/main", Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(
+        service
+            .get_branch_status("owner/repo/main")
+            .unwrap()
+            .last_commit,
+        Some(head)
+    );
+
+    let request = Request::delete("/repos/owner/repo/main")
+        .body(Body::empty())
+        .unwrap();
+    let response = create_router(&service, &registry)
+        .oneshot(request)
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    assert!(open_registry(&root).get_keys().is_empty());
+
+    assert!(service.shutdown(Duration::from_secs(10)));
+    let _ = fs::remove_dir_all(root);
+}
+
+fn post_webhook(body: &str, header: Option<(&str, String)>) -> Request<Body> {
+    let mut request = Request::post("/webhooks/git").header("Content-Type", "application/json");
+
+    if let Some((name, value)) = header {
+        request = request.header(name, value);
+    }
+
+    request.body(Body::from(body.to_string())).unwrap()
+}
+
+#[tokio::test]
+async fn test_git_webhook_signature() {
+    use hmac::{Hmac, Mac};
+
+    let root = std::env::temp_dir().join(format!("gitdis-http-webhook-{}", std::process::id()));
+    let root = root.to_str().unwrap().to_string();
+    let secret_env = format!("GITDIS_TEST_WEBHOOK_SECRET_{}", std::process::id());
+    std::env::set_var(&secret_env, "s3cret");
+
+    let mut service = create_service(&root);
+    service
+        .add_repo(BranchSettings {
+            url: "https://github.com/owner/repo.git".to_string(),
+            branch_name: "main".to_string(),
+            webhook_secret_env: Some(secret_env),
+            ..BranchSettings::default()
+        })
+        .unwrap();
+    let router = create_router(&service, &open_registry(&root));
+
+    let body = r#"{"ref": "refs/heads/main", "repository": {"full_name": "owner/repo", "html_url": "https://github.com/owner/repo"}}"#;
+    let sign = |secret: &str| {
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    };
+
+    let cases = [
+        (
+            Some(("X-Hub-Signature-256", sign("s3cret"))),
+            StatusCode::ACCEPTED,
+        ),
+        (
+            Some(("X-Gitlab-Token", "s3cret".to_string())),
+            StatusCode::ACCEPTED,
+        ),
+        (
+            Some(("X-Hub-Signature-256", sign("other"))),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            Some(("X-Hub-Signature-256", "sha256=zz".to_string())),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            Some(("X-Gitlab-Token", "other".to_string())),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (None, StatusCode::UNAUTHORIZED),
+    ];
+
+    for (header, status) in cases {
+        let response = router
+            .clone()
+            .oneshot(post_webhook(body, header.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{:?}", header);
+    }
+
+    assert!(service.shutdown(Duration::from_secs(10)));
+    let _ = fs::remove_dir_all(root);
+}
+
+#[tokio::test]
+async fn test_patch_object_unsupported_write_back() {
+    let (root, origin, _) = create_origin("patch");
+    fs::write(
+        format!("{}/config.json", origin),
+        "{\n  // kept by hand\n  \"port\": 1\n}\n",
+    )
+    .unwrap();
+    git(&origin, &["commit", "-qam", "comment"]);
+    let service = create_service(&root);
+    let router = create_router(&service, &open_registry(&root));
+    let body = format!(
+        r#"{{"url": "{}", "branch_name": "main", "lenient_json": true, "listen": true, "wait_ready_ms": 10000}}"#,
+        origin
+    );
+
+    let response = router
+        .clone()
+        .oneshot(post_json("/repos", body))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // Rewriting the file would drop its comment.
+    let request = Request::patch("/repos/owner/repo/main/config")
+        .header("Content-Type", "application/merge-patch+json")
+        .body(Body::from(r#"{"port": 2}"#))
+        .unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    assert!(service.shutdown(Duration::from_secs(10)));
+    let _ = fs::remove_dir_all(root);
+}
+
+#[tokio::test]
+async fn test_mixed_case_path() {
+    let (root, origin, _) = create_origin("case");
+    let service = create_service(&root);
+    let router = create_router(&service, &open_registry(&root));
+    let body = format!(
+        r#"{{"url": "{}", "branch_name": "main", "listen": true, "wait_ready_ms": 10000}}"#,
+        origin
+    );
+
+    let response = router
+        .clone()
+        .oneshot(post_json("/repos", body))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    for uri in [
+        "/repos/owner/repo/main/config",
+        "/repos/Owner/REPO/main/config",
+    ] {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+    }
+
+    // Branch names are case-sensitive in git.
+    let request = Request::get("/repos/owner/repo/MAIN/config")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    assert!(service.shutdown(Duration::from_secs(10)));
+    let _ = fs::remove_dir_all(root);
+}
+
+/// Reads the body until a frame of `event` arrives.
+async fn next_frame(body: &mut axum::body::BodyDataStream, event: &str) {
+    use tokio_stream::StreamExt;
+
+    let expected = format!("event: {}\n", event);
+    let read = async {
+        let mut frames = String::new();
+
+        while let Some(chunk) = body.next().await {
+            frames.push_str(&String::from_utf8_lossy(&chunk.unwrap()));
+
+            if frames.contains(&expected) {
+                return;
+            }
+        }
+
+        panic!("Stream ended without a {} frame: {}", event, frames);
+    };
+
+    tokio::time::timeout(Duration::from_secs(10), read)
+        .await
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_stream_branch_changes() {
+    let (root, origin, _) = create_origin("stream");
+    let service = create_service(&root);
+    let router = create_router(&service, &open_registry(&root));
+    let body = format!(
+        r#"{{"url": "{}", "branch_name": "main", "listen": true, "wait_ready_ms": 10000}}"#,
+        origin
+    );
+
+    let response = router
+        .clone()
+        .oneshot(post_json("/repos", body))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let mut streams = Vec::new();
+
+    for _ in 0..2 {
+        let request = Request::get("/streams/owner/repo/main")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut body = response.into_body().into_data_stream();
+        next_frame(&mut body, "snapshot").await;
+        streams.push(body);
+    }
+
+    // A client going away leaves the others streaming.
+    streams.pop();
+
+    fs::write(format!("{}/service.json", origin), r#"{"port": 1}"#).unwrap();
+    git(&origin, &["add", "."]);
+    git(&origin, &["commit", "-m", "service"]);
+    service.trigger_sync("owner/repo/main").unwrap();
+
+    next_frame(&mut streams[0], "change").await;
+
+    drop(streams);
+    assert!(service.shutdown(Duration::from_secs(10)));
+    let _ = fs::remove_dir_all(root);
+}
+
+async fn read_json(response: axum::response::Response) -> serde_json::Value {
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+async fn get_enabled_capabilities(router: &Router) -> Vec<String> {
+    let request = Request::get("/version").body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    serde_json::from_value(read_json(response).await["capabilities"].clone()).unwrap()
+}
+
+#[tokio::test]
+async fn test_capability_toggle() {
+    let root = std::env::temp_dir().join(format!("gitdis-http-capability-{}", std::process::id()));
+    let root = root.to_str().unwrap().to_string();
+    let service = create_service(&root);
+    let router = create_router(&service, &open_registry(&root));
+    let get_memo_stats = || Request::get("/metrics/memo").body(Body::empty()).unwrap();
+
+    let response = router.clone().oneshot(get_memo_stats()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(get_enabled_capabilities(&router)
+        .await
+        .contains(&"metrics".to_string()));
+
+    let request = Request::post("/capabilities/metrics/disable")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = router.clone().oneshot(get_memo_stats()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let request = Request::get("/v2/metrics/memo")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(read_json(response).await["code"], "capability_disabled");
+    assert!(!get_enabled_capabilities(&router)
+        .await
+        .contains(&"metrics".to_string()));
+
+    let request = Request::post("/capabilities/metrics/enable")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = router.clone().oneshot(get_memo_stats()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let request = Request::post("/capabilities/nope/enable")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let _ = fs::remove_dir_all(root);
+}
+
+#[tokio::test]
+async fn test_capability_tasks() {
+    let root = std::env::temp_dir().join(format!("gitdis-http-tasks-{}", std::process::id()));
+    let root = root.to_str().unwrap().to_string();
+    let service = create_service(&root);
+    let capabilities = Capabilities::from_env();
+    let router = routes(service.clone(), capabilities.clone(), open_registry(&root));
+    let enable_discovery = || {
+        Request::post("/capabilities/discovery/enable")
+            .header("X-Gitdis-Api-Version", "2")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // Not registered, e.g. not configured.
+    assert!(!capabilities.is_available(Capability::Discovery));
+    assert!(capabilities.enable(Capability::Discovery).is_err());
+    let response = router.clone().oneshot(enable_discovery()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(read_json(response).await["code"], "capability_unavailable");
+    assert!(!get_enabled_capabilities(&router)
+        .await
+        .contains(&"discovery".to_string()));
+
+    let started = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let hook_started = started.clone();
+    capabilities.register(Capability::Discovery, move || {
+        hook_started.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        tokio::spawn(std::future::pending())
+    });
+    assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert!(get_enabled_capabilities(&router)
+        .await
+        .contains(&"discovery".to_string()));
+
+    // Enabling what is enabled starts nothing more.
+    let response = router.clone().oneshot(enable_discovery()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    assert!(capabilities.disable(Capability::Discovery));
+    assert!(!get_enabled_capabilities(&router)
+        .await
+        .contains(&"discovery".to_string()));
+
+    let response = router.clone().oneshot(enable_discovery()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+    capabilities.shutdown();
+    let _ = fs::remove_dir_all(root);
+}
+
+#[tokio::test]
+async fn test_api_versions() {
+    let root = std::env::temp_dir().join(format!("gitdis-http-versions-{}", std::process::id()));
+    let root = root.to_str().unwrap().to_string();
+    let service = create_service(&root);
+    let router = create_router(&service, &open_registry(&root));
+    std::env::set_var("GITDIS_API_V1_SUNSET", "Sat, 01 May 2027 00:00:00 GMT");
+
+    let get = |uri: &str, version: Option<&str>| {
+        let mut request = Request::get(uri);
+
+        if let Some(version) = version {
+            request = request.header("X-Gitdis-Api-Version", version);
+        }
+
+        request.body(Body::empty()).unwrap()
+    };
+    let header = |response: &axum::response::Response, name: &str| {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap().to_string())
+    };
+
+    let cases = [
+        ("/metrics/memo", None, "1"),
+        ("/metrics/memo", Some("2"), "2"),
+        ("/v1/metrics/memo", None, "1"),
+        ("/v1/metrics/memo", Some("1"), "1"),
+        ("/v2/metrics/memo", None, "2"),
+    ];
+
+    for (uri, requested, version) in cases {
+        let response = router.clone().oneshot(get(uri, requested)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        assert_eq!(
+            header(&response, "X-Gitdis-Api-Version").as_deref(),
+            Some(version)
+        );
+
+        match version {
+            "1" => {
+                assert_eq!(header(&response, "Deprecation").as_deref(), Some("true"));
+                assert_eq!(
+                    header(&response, "Sunset").as_deref(),
+                    Some("Sat, 01 May 2027 00:00:00 GMT")
+                );
+            }
+            _ => {
+                assert_eq!(header(&response, "Deprecation"), None);
+                assert_eq!(header(&response, "Sunset"), None);
+            }
+        }
+    }
+
+    // The prefix and the header disagree, or the version is not served.
+    for (uri, requested) in [("/v1/metrics/memo", "2"), ("/metrics/memo", "3")] {
+        let response = router
+            .clone()
+            .oneshot(get(uri, Some(requested)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+    }
+
+    // Only v2 error bodies carry a code.
+    let response = router
+        .clone()
+        .oneshot(get("/v2/status/owner/repo/main", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = read_json(response).await;
+    assert_eq!(body["code"], "branch_not_found");
+    assert!(body["message"].is_string());
+
+    let response = router
+        .oneshot(get("/v1/status/owner/repo/main", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = read_json(response).await;
+    assert!(body.get("code").is_none());
+    assert!(body["message"].is_string());
+
+    std::env::remove_var("GITDIS_API_V1_SUNSET");
+    let _ = fs::remove_dir_all(root);
+}
+
+#[tokio::test]
+async fn test_route_limits() {
+    let limits = RouteLimits::new(Duration::from_millis(100), Duration::from_secs(5), 1, 7);
+    let router = Router::new()
+        .route(
+            "/status/:owner/:repo/:branch",
+            axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                "status"
+            }),
+        )
+        .route(
+            "/dumps/:owner/:repo/:branch",
+            axum::routing::post(|| async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                "dumped"
+            }),
+        )
+        .layer(axum::middleware::from_fn_with_state(limits, enforce_limits));
+    let dump = || {
+        Request::post("/dumps/owner/repo/main")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // A request over its budget is cut.
+    let response = router
+        .clone()
+        .oneshot(
+            Request::get("/status/owner/repo/main")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "7");
+    assert_eq!(read_json(response).await["code"], "timeout");
+
+    // Long routes get the long budget, and hold the only slot meanwhile.
+    let running = tokio::spawn(router.clone().oneshot(dump()));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = router.clone().oneshot(dump()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "7");
+    assert_eq!(read_json(response).await["code"], "overloaded");
+
+    let response = running.await.unwrap().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The slot is given back once the request is done.
+    let response = router.oneshot(dump()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
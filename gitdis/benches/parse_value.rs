@@ -0,0 +1,52 @@
+// Parsing time of JSON files as a sync loads them. Compare the simd-json
+// path against the regular parser with:
+//
+//     cargo bench -p gitdis --bench parse_value -- --save-baseline plain
+//     cargo bench -p gitdis --bench parse_value --features simd -- --baseline plain
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use gitdis::payload::{parse_value, ParseOptions};
+
+/// A JSON document of about `bytes` bytes, shaped like a service registry.
+fn document(bytes: usize) -> String {
+    let mut content = String::from("[");
+    let mut index = 0;
+
+    while content.len() < bytes {
+        if index > 0 {
+            content.push(',');
+        }
+
+        content.push_str(&format!(
+            r#"{{"name":"service-{index}","port":{port},"weight":{weight},"enabled":{enabled},"tags":["api","zone-{zone}"],"limits":{{"cpu":"500m","memory":"{memory}Mi"}}}}"#,
+            port = 8000 + index % 1000,
+            weight = index as f64 / 7.0,
+            enabled = index % 3 != 0,
+            zone = index % 4,
+            memory = 128 * (1 + index % 8),
+        ));
+        index += 1;
+    }
+
+    content.push(']');
+    content
+}
+
+fn bench_parse_value(c: &mut Criterion) {
+    let options = ParseOptions::default();
+    let mut group = c.benchmark_group("parse_value");
+
+    // The smallest size stays below `SIMD_MIN_BYTES`, on the regular parser.
+    for size in [64 * 1024, 1024 * 1024, 8 * 1024 * 1024] {
+        let content = document(size);
+
+        group.throughput(Throughput::Bytes(content.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &content, |b, content| {
+            b.iter(|| parse_value("services.json", content, &options).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse_value);
+criterion_main!(benches);
@@ -0,0 +1,46 @@
+use crate::sync::{AtomicBool, Ordering, RwLock};
+
+/// Runs `restore` on the data of an archived branch and marks it restored,
+/// both under the write lock of `data`, so a read that finds the branch no
+/// longer archived also finds its data. Returns `false`, running nothing,
+/// when the branch is not archived, e.g. another restore got there first.
+pub(crate) fn finish_restore<T>(
+    data: &RwLock<T>,
+    archived: &AtomicBool,
+    restore: impl FnOnce(&mut T),
+) -> bool {
+    let mut data = match data.write() {
+        Ok(data) => data,
+        Err(_) => return false,
+    };
+
+    if !archived.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    restore(&mut data);
+    archived.store(false, Ordering::SeqCst);
+
+    true
+}
+
+/// Runs `clear` on the data of a branch being archived, unless a restore
+/// brought it back meanwhile. Returns whether it ran.
+pub(crate) fn finish_archive<T>(
+    data: &RwLock<T>,
+    archived: &AtomicBool,
+    clear: impl FnOnce(&mut T),
+) -> bool {
+    let mut data = match data.write() {
+        Ok(data) => data,
+        Err(_) => return false,
+    };
+
+    if !archived.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    clear(&mut data);
+
+    true
+}
@@ -1,15 +1,108 @@
-use crate::cache::ArcCache;
+use crate::cache::{
+    list_prefix, ArcBranchErrors, ArcBranchHistory, ArcBranchStatus, ArcCache, ArcKeyMetadata,
+    ArcKeyOwners, ArcKeyVersions, ArcLintReport, ArcSyncFailures, ArcSyncReceiver,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::events::{BranchEventKind, EventHub};
+use crate::filter::{build_globs, FileFilter};
+use crate::gitdis::{
+    canonicalize_repo_url, resolve_repo_url, validate_relative_path, BranchCredentials,
+    BranchSettings, Prewarm, RefType, SigningKeyring, SyncSignal, DEFAULT_GIT_TIMEOUT_MILLIS,
+};
+use crate::history::BranchHistory;
+use crate::keys::KeyFormat;
+use crate::limiter::NetworkLimiter;
+use crate::lint::{self, LintLevel, LintReport, LintSettings};
+use crate::matrix::{Matrix, MATRIX_FILE};
+use crate::metadata::{parse_log_record, CommitMetadata, LOG_FORMAT, RECORD_SEPARATOR};
+use crate::owners::{CodeOwners, CODEOWNERS_FILES};
+use crate::payload::{self, ParseOptions, PayloadParsers};
+use crate::policy::{ArcBranchPolicy, ResolvedPolicy};
+use crate::process::{ProcessRunner, SystemProcessRunner};
+use crate::retry::{PollJitter, RetryPolicy};
+use crate::schema::{BranchSchemas, SchemaRule};
+use crate::status::BranchStatus;
+use crate::versions::KeyVersions;
+use crate::walk;
+use encoding_rs::{Encoding, UTF_8};
+use globset::GlobSet;
 use log::debug;
 use quickleaf::valu3::prelude::*;
-use std::{collections::HashMap, process::Command};
+use quickleaf::Cache;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    process::{Command, Output},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError, TryRecvError},
+        Arc, Mutex, RwLock,
+    },
+};
+
+/// Bare mirror shared by the branches of a repository, in `owner/repo`.
+pub(crate) const STORE_DIR: &str = ".store.git";
+
+/// Key of the branch errors a sync stopped by its limits is listed under.
+const SYNC_LIMIT_ERROR_KEY: &str = "sync-limits";
+
+/// Errors of `git pull --ff-only` when upstream history was rewritten, as
+/// printed in the C locale it is run in.
+const HISTORY_REWRITTEN_ERRORS: [&str; 2] = ["Not possible to fast-forward", "unrelated histories"];
+
+/// Key under which a failed signature check is listed in the branch errors.
+const SIGNATURE_ERROR_KEY: &str = "HEAD";
+
+/// Appended to a clone path for a prewarm tarball being downloaded.
+const PREWARM_DOWNLOAD_SUFFIX: &str = ".prewarm.tar.gz";
+
+/// Appended to a clone path for the lock file guarding it. The file sits
+/// next to the clone so it survives the clone being removed.
+const LOCK_FILE_SUFFIX: &str = ".lock";
+
+// Serializes creation of the shared object stores, which several branch
+// handlers of the same repository may attempt at once.
+static STORE_LOCK: Mutex<()> = Mutex::new(());
 
 const EXT_JSON: &str = ".json";
 const EXT_YML: &str = ".yml";
 const EXT_YAML: &str = ".yaml";
+const EXT_JSONC: &str = ".jsonc";
+const EXT_JSON5: &str = ".json5";
+const EXT_NDJSON: &str = ".ndjson";
+const EXT_JSONL: &str = ".jsonl";
+const EXT_CSV: &str = ".csv";
+/// Extensions of the files loaded, in the order keys are looked up.
+const EXTENSIONS: [&str; 8] = [
+    EXT_JSON, EXT_YML, EXT_YAML, EXT_JSONC, EXT_JSON5, EXT_NDJSON, EXT_JSONL, EXT_CSV,
+];
 
 #[derive(Debug, PartialEq)]
 pub enum BranchHandlerError {
     GitError((Option<i32>, String)),
+    MissingCredentials(String),
+    FileNotFound(String),
+    PinnedRef(String),
+    UnsupportedWriteBack(String),
+    CommitMismatch(String),
+    UnverifiedCommit((String, String)),
+    CloneLock(String),
+    DirtyWorkingTree((Vec<String>, u64)),
+    BareClone(String),
+    /// The directory is not a clone the branch can reuse, and why.
+    AdoptRejected((String, String)),
+    /// The process, e.g. `git fetch`, was killed after running this long.
+    GitTimeout((String, std::time::Duration)),
+    /// The commit fetched, and the one the mirror has for the ref if any.
+    MirrorMismatch((String, Option<String>)),
+    /// The commit, and its error-level lint findings.
+    LintFailed((String, usize)),
+    /// The file, and why it could not be written or removed.
+    WriteFailed((String, String)),
+    /// The key or file resolves outside the repository, through `..` or a
+    /// symbolic link.
+    PathEscape(String),
+    /// The sync parsed too many files or for too long, and which limit.
+    SyncLimitExceeded(String),
 }
 
 impl std::fmt::Display for BranchHandlerError {
@@ -18,7 +111,163 @@ impl std::fmt::Display for BranchHandlerError {
             BranchHandlerError::GitError((code, error)) => {
                 write!(f, "Git error: code: {:?}, error: {}", code, error)
             }
+            BranchHandlerError::MissingCredentials(env) => {
+                write!(f, "Missing credentials: env var {} is not set", env)
+            }
+            BranchHandlerError::FileNotFound(key) => write!(f, "File not found for key: {}", key),
+            BranchHandlerError::PinnedRef(reference) => {
+                write!(f, "Ref {} is pinned and cannot be written to", reference)
+            }
+            BranchHandlerError::UnsupportedWriteBack(file) => {
+                write!(
+                    f,
+                    "Write-back is only supported for plain JSON files: {}",
+                    file
+                )
+            }
+            BranchHandlerError::CommitMismatch(commit) => {
+                write!(f, "Branch moved, current commit is {}", commit)
+            }
+            BranchHandlerError::UnverifiedCommit((commit, reason)) => {
+                write!(
+                    f,
+                    "Commit {} failed signature verification: {}",
+                    commit, reason
+                )
+            }
+            BranchHandlerError::CloneLock(error) => {
+                write!(f, "Failed to lock the clone: {}", error)
+            }
+            BranchHandlerError::DirtyWorkingTree((files, local_commits)) => {
+                write!(
+                    f,
+                    "Clone has local changes: {} edited files ({}), {} local commits",
+                    files.len(),
+                    files.join(", "),
+                    local_commits
+                )
+            }
+            BranchHandlerError::BareClone(path) => {
+                write!(
+                    f,
+                    "Write-back needs a working tree, {} is a bare clone",
+                    path
+                )
+            }
+            BranchHandlerError::AdoptRejected((path, reason)) => {
+                write!(f, "Cannot adopt the clone at {}: {}", path, reason)
+            }
+            BranchHandlerError::GitTimeout((operation, timeout)) => {
+                write!(f, "{} timed out after {:?}", operation, timeout)
+            }
+            BranchHandlerError::MirrorMismatch((commit, Some(mirror_commit))) => {
+                write!(
+                    f,
+                    "Commit {} differs from the mirror, which has {}",
+                    commit, mirror_commit
+                )
+            }
+            BranchHandlerError::MirrorMismatch((commit, None)) => {
+                write!(f, "Commit {} is missing from the mirror", commit)
+            }
+            BranchHandlerError::LintFailed((commit, errors)) => {
+                write!(f, "Commit {} has {} lint errors", commit, errors)
+            }
+            BranchHandlerError::WriteFailed((file, error)) => {
+                write!(f, "Failed to write {}: {}", file, error)
+            }
+            BranchHandlerError::PathEscape(path) => {
+                write!(f, "Path resolves outside the repository: {}", path)
+            }
+            BranchHandlerError::SyncLimitExceeded(limit) => write!(
+                f,
+                "Sync stopped after {}, override the sync limits to load it",
+                limit
+            ),
+        }
+    }
+}
+
+/// One change of a `BranchHandler::commit_changes` transaction.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyWrite {
+    /// Serializes `value` back into the file behind `key`.
+    Update { key: String, value: Value },
+    /// Removes the file behind `key`.
+    Delete { key: String },
+}
+
+impl KeyWrite {
+    pub fn get_key(&self) -> &str {
+        match self {
+            KeyWrite::Update { key, .. } | KeyWrite::Delete { key } => key,
+        }
+    }
+}
+
+/// Limits on the files one sync parses, see `BranchSettings::max_sync_files`
+/// and `BranchSettings::max_sync_parse_millis`.
+#[derive(Debug, Default)]
+pub(crate) struct SyncBudget {
+    max_files: Option<usize>,
+    max_parse_time: Option<std::time::Duration>,
+    /// When the sync under way started and the files it parsed so far, while
+    /// one runs within the limits.
+    running: Option<(std::time::Instant, usize)>,
+}
+
+impl SyncBudget {
+    pub(crate) fn new(
+        max_files: Option<usize>,
+        max_parse_time: Option<std::time::Duration>,
+    ) -> Self {
+        Self {
+            max_files,
+            max_parse_time,
+            running: None,
+        }
+    }
+
+    pub(crate) fn start(&mut self, now: std::time::Instant) {
+        self.running = Some((now, 0));
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.running = None;
+    }
+
+    /// Counts `files` more files to parse, failing once the sync under way
+    /// goes over a limit. Free when no sync is under way.
+    pub(crate) fn charge(
+        &mut self,
+        files: usize,
+        now: std::time::Instant,
+    ) -> Result<(), BranchHandlerError> {
+        let (started, parsed) = match &mut self.running {
+            Some(running) => running,
+            None => return Ok(()),
+        };
+
+        *parsed += files;
+
+        if let Some(max_files) = self.max_files.filter(|max_files| *parsed > *max_files) {
+            return Err(BranchHandlerError::SyncLimitExceeded(format!(
+                "{} files, over the limit of {}",
+                parsed, max_files
+            )));
         }
+
+        if let Some(max_parse_time) = self
+            .max_parse_time
+            .filter(|max_parse_time| now.duration_since(*started) > *max_parse_time)
+        {
+            return Err(BranchHandlerError::SyncLimitExceeded(format!(
+                "parsing for over {:?}",
+                max_parse_time
+            )));
+        }
+
+        Ok(())
     }
 }
 
@@ -55,345 +304,3396 @@ impl std::fmt::Display for Status {
 }
 
 pub struct BranchHandler {
+    branch_key: String,
     clone_path: String,
     url: String,
     branch_name: String,
+    ref_type: RefType,
     cache: ArcCache,
     ignore: Vec<String>,
+    filter: FileFilter,
+    /// Files loaded as base64 rather than parsed, see
+    /// `BranchSettings::binary_files`.
+    binary_files: GlobSet,
+    /// The globs of `binary_files`, for the sparse checkout.
+    binary_patterns: Vec<String>,
     repo_path: String,
+    store_path: String,
+    path_target: Option<String>,
+    data_root: String,
     current_commit_hash: String,
     pull_request_interval_millis: u64,
+    poll_jitter: Option<PollJitter>,
+    credentials: Option<BranchCredentials>,
+    proxy: Option<String>,
+    clone_depth: Option<u32>,
+    partial_clone: bool,
+    prewarm: Option<Prewarm>,
+    encoding: &'static Encoding,
+    errors: ArcBranchErrors,
+    failures: ArcSyncFailures,
+    status: ArcBranchStatus,
+    history: ArcBranchHistory,
+    metadata: ArcKeyMetadata,
+    versions: ArcKeyVersions,
+    code_owners: CodeOwners,
+    owners: ArcKeyOwners,
+    sync_receiver: ArcSyncReceiver,
+    retry: RetryPolicy,
+    parse_options: ParseOptions,
+    key_format: Option<KeyFormat>,
+    /// Included file (canonical path) -> files that include it.
+    include_dependents: RefCell<HashMap<String, HashSet<String>>>,
+    matrix: Option<Matrix>,
+    matrix_keys: HashSet<String>,
+    events: EventHub,
+    require_signed_commits: bool,
+    signing_keyring: Option<SigningKeyring>,
+    mirror_url: Option<String>,
+    mirror_credentials: Option<BranchCredentials>,
+    lint: Option<LintSettings>,
+    lint_report: ArcLintReport,
+    schema_rules: Vec<SchemaRule>,
+    /// Compiled from the schema files of the loaded commit.
+    schemas: RefCell<BranchSchemas>,
+    reset_dirty: bool,
+    adopt_existing_clone: bool,
+    /// The clone has no working tree, files are read from `HEAD`.
+    bare: bool,
+    sync_budget: RefCell<SyncBudget>,
+    /// Commit `within_sync_limits` is loading, for the parse errors it
+    /// reports.
+    loading_commit_hash: Option<String>,
+    /// Last commit reported as rejected, so it is reported only once.
+    rejected_commit_hash: Option<String>,
+    maintenance_interval: Option<std::time::Duration>,
+    last_maintenance: std::time::Instant,
+    policy: ArcBranchPolicy,
+    /// Start of the syncs run in the last minute, for the rate limit of
+    /// the branch policy.
+    recent_syncs: VecDeque<std::time::Instant>,
+    clock: Arc<dyn Clock>,
+    runner: Arc<dyn ProcessRunner>,
+    git_timeout: std::time::Duration,
+    network: NetworkLimiter,
+}
+
+/// Directory of a branch working tree inside its `owner/repo` directory.
+/// `%` and `/` are percent-encoded, so no two branch names share one, and
+/// none clashes with the store since git refs never start with a dot.
+pub(crate) fn get_branch_dir(branch_name: &str) -> String {
+    branch_name.replace('%', "%25").replace('/', "%2F")
 }
 
 impl BranchHandler {
     pub fn new(
         data_path: String,
-        url: String,
-        branch_name: String,
+        settings: BranchSettings,
         cache: ArcCache,
-        pull_request_interval_millis: u64,
+        errors: ArcBranchErrors,
+        failures: ArcSyncFailures,
+        sync_receiver: ArcSyncReceiver,
+        events: EventHub,
     ) -> Self {
-        let repo_name = url.split("/").last().unwrap().replace(".git", "");
-        let repo_path = format!("{}/{}", data_path, repo_name);
+        // Each branch gets its own working tree under `owner/repo/branch`,
+        // all of them borrowing objects from one mirror of the repository.
+        let branch_key = settings.get_repo_key();
+        let repo_dir = format!("{}/{}", data_path, settings.get_repo_name_key());
+        let repo_path = format!("{}/{}", repo_dir, get_branch_dir(&settings.branch_name));
+        let store_path = format!("{}/{}", repo_dir, STORE_DIR);
+        let path_target = settings
+            .path_target
+            .map(|path| path.trim_matches('/').to_string())
+            .filter(|path| !path.is_empty());
+        let data_root = match &path_target {
+            Some(path) => format!("{}/{}", repo_path, path),
+            None => repo_path.clone(),
+        };
+        let encoding = settings
+            .encoding
+            .as_ref()
+            .and_then(|label| Encoding::for_label(label.as_bytes()))
+            .unwrap_or(UTF_8);
 
         Self {
+            branch_key,
             clone_path: data_path,
-            url,
-            branch_name,
+            url: resolve_repo_url(&settings.url),
+            branch_name: settings.branch_name,
+            ref_type: settings.ref_type.unwrap_or_default(),
             cache,
             ignore: vec!["/.git/".to_string()],
+            filter: FileFilter::new(&settings.include, &settings.ignore).unwrap_or_default(),
+            binary_files: build_globs(&settings.binary_files).unwrap_or_else(|_| GlobSet::empty()),
+            binary_patterns: settings.binary_files.clone(),
             repo_path,
+            store_path,
+            path_target,
+            data_root,
             current_commit_hash: "".to_string(),
-            pull_request_interval_millis,
+            pull_request_interval_millis: settings.pull_request_interval_millis,
+            poll_jitter: settings.poll_jitter,
+            credentials: settings.credentials,
+            proxy: settings.proxy,
+            clone_depth: settings.clone_depth,
+            partial_clone: settings.partial_clone.unwrap_or(false)
+                && !settings.bare.unwrap_or(false),
+            prewarm: settings.prewarm,
+            encoding,
+            errors,
+            failures,
+            status: Arc::new(RwLock::new(BranchStatus::default())),
+            history: Arc::new(RwLock::new(BranchHistory::default())),
+            metadata: Arc::new(RwLock::new(HashMap::new())),
+            versions: Arc::new(RwLock::new(KeyVersions::default())),
+            code_owners: CodeOwners::default(),
+            owners: Arc::new(RwLock::new(HashMap::new())),
+            sync_receiver,
+            retry: settings.retry.unwrap_or_default(),
+            parse_options: ParseOptions {
+                multi_document: settings.multi_document.unwrap_or_default(),
+                lenient_json: settings.lenient_json.unwrap_or(false),
+                interpolate_env: settings.interpolate_env.unwrap_or(false),
+                interpolate_allow: settings.interpolate_allow.clone().unwrap_or_default(),
+                max_file_bytes: settings.max_file_bytes,
+                parsers: PayloadParsers::new(),
+            },
+            key_format: settings.key_format.clone(),
+            include_dependents: RefCell::new(HashMap::new()),
+            matrix: None,
+            matrix_keys: HashSet::new(),
+            events,
+            require_signed_commits: settings.require_signed_commits.unwrap_or(false),
+            signing_keyring: settings.signing_keyring,
+            mirror_url: settings.mirror_url.as_deref().map(resolve_repo_url),
+            mirror_credentials: settings.mirror_credentials,
+            lint: settings.lint,
+            lint_report: Arc::new(RwLock::new(None)),
+            schema_rules: settings.schemas,
+            schemas: RefCell::new(BranchSchemas::default()),
+            reset_dirty: settings.reset_dirty.unwrap_or(false),
+            adopt_existing_clone: settings.adopt_existing_clone.unwrap_or(false),
+            bare: settings.bare.unwrap_or(false),
+            sync_budget: RefCell::new(SyncBudget::new(
+                settings.max_sync_files,
+                settings
+                    .max_sync_parse_millis
+                    .map(std::time::Duration::from_millis),
+            )),
+            loading_commit_hash: None,
+            rejected_commit_hash: None,
+            maintenance_interval: None,
+            last_maintenance: std::time::Instant::now(),
+            policy: Arc::new(RwLock::new(ResolvedPolicy::default())),
+            recent_syncs: VecDeque::new(),
+            clock: Arc::new(SystemClock),
+            runner: Arc::new(SystemProcessRunner),
+            git_timeout: std::time::Duration::from_millis(
+                settings
+                    .git_timeout_millis
+                    .unwrap_or(DEFAULT_GIT_TIMEOUT_MILLIS),
+            ),
+            network: NetworkLimiter::default(),
         }
     }
 
+    /// Shares the branch status kept by this handler with its owner.
+    pub fn with_status(mut self, status: ArcBranchStatus) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Shares the history of generations kept for the branch with its owner.
+    pub fn with_history(mut self, history: ArcBranchHistory) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Shares the commit metadata of the keys with the branch owner.
+    pub fn with_metadata(mut self, metadata: ArcKeyMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Shares the versions kept of each key with the branch owner.
+    pub fn with_versions(mut self, versions: ArcKeyVersions) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    /// Shares the CODEOWNERS owners of the keys with the branch owner.
+    pub fn with_owners(mut self, owners: ArcKeyOwners) -> Self {
+        self.owners = owners;
+        self
+    }
+
+    /// Shares the lint report of the last commit with the branch owner.
+    pub fn with_lint_report(mut self, lint_report: ArcLintReport) -> Self {
+        self.lint_report = lint_report;
+        self
+    }
+
+    /// Follows the policy resolved for the branch, for its freeze windows
+    /// and rate limit.
+    pub fn with_policy(mut self, policy: ArcBranchPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Reads the time and waits between retries on `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last_maintenance = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Runs git and the other processes of the handler through `runner`.
+    pub fn with_process_runner(mut self, runner: Arc<dyn ProcessRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Also loads the files of the formats in `parsers`.
+    pub fn with_parsers(mut self, parsers: PayloadParsers) -> Self {
+        self.parse_options.parsers = parsers;
+        self
+    }
+
+    /// Runs clones, fetches, pulls and pushes within `network`'s limit.
+    pub fn with_network_limiter(mut self, network: NetworkLimiter) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Garbage collects the clone every `interval_millis`, after a sync.
+    pub fn with_maintenance(mut self, interval_millis: Option<u64>) -> Self {
+        self.maintenance_interval = interval_millis.map(std::time::Duration::from_millis);
+        self
+    }
+
     /// Get the data from the repository instantly
     pub fn clone_and_get_data(&self) -> Result<HashMap<String, Value>, BranchHandlerError> {
         if !std::path::Path::new(&self.clone_path).exists() {
             std::fs::create_dir(&self.clone_path).expect("Failed to create repo directory");
         }
 
+        let _lock = self.lock_clone()?;
+
         self.git_clone()?;
-        self.get_initial_data()
+
+        if self.require_signed_commits || self.mirror_url.is_some() || self.lint.is_some() {
+            let commit_hash = self.git_get_commit_hash()?;
+            self.git_verify_head(commit_hash.trim())?;
+            self.lint_commit(commit_hash.trim())?;
+        }
+
+        self.load_schemas();
+        self.get_initial_data(true)
     }
 
     pub fn listen(&mut self) -> Result<(), BranchHandlerError> {
-        self.setup()?;
+        while self.is_standby() {
+            debug!("Branch {} is in standby, not cloning", self.branch_key);
 
-        loop {
-            std::thread::sleep(std::time::Duration::from_millis(
-                self.pull_request_interval_millis,
-            ));
-            self.update()?;
+            if !self.wait_next_sync() {
+                return Ok(());
+            }
         }
-    }
 
-    fn setup(&mut self) -> Result<(), BranchHandlerError> {
-        if !std::path::Path::new(&self.clone_path).exists() {
-            std::fs::create_dir(&self.clone_path).expect("Failed to create repo directory");
+        self.with_retry(Self::setup)?;
+
+        if self.ref_type.is_pinned() {
+            debug!("Ref {} is pinned, not polling", self.branch_name);
+            return Ok(());
         }
 
-        self.git_clone()?;
-        self.load_initial_data()?;
-        self.current_commit_hash = self.git_get_commit_hash()?;
+        while self.wait_next_sync() {
+            if self.is_paused() || self.is_standby() {
+                debug!(
+                    "Branch {} is paused or in standby, skipping sync",
+                    self.branch_key
+                );
+                continue;
+            }
 
-        debug!("Initial commit hash: {}", self.current_commit_hash);
+            if self.is_held_by_policy() {
+                continue;
+            }
+
+            self.with_retry(Self::sync)?;
+        }
+
+        debug!("Listener for {} stopped", self.branch_key);
 
         Ok(())
     }
 
-    fn update(&mut self) -> Result<(), BranchHandlerError> {
-        self.git_pull()?;
+    /// One step of `listen` for the sync scheduler: attempt number `attempt`
+    /// of the setup, or of a sync once `ready`. Returns the delay before the
+    /// next attempt, `None` on success, or the error once retries are
+    /// exhausted.
+    pub(crate) fn sync_step(
+        &mut self,
+        ready: bool,
+        attempt: u32,
+    ) -> Result<Option<std::time::Duration>, BranchHandlerError> {
+        if !ready {
+            return self.try_operation(Self::setup, attempt);
+        }
 
-        let current_commit_hash = self.git_get_commit_hash()?;
+        if self.is_paused() {
+            debug!("Branch {} is paused, skipping sync", self.branch_key);
+            return Ok(None);
+        }
 
-        debug!("Current commit hash: {}", current_commit_hash);
+        // Retries of a sync already let through are not held back.
+        if attempt == 1 && self.is_held_by_policy() {
+            return Ok(None);
+        }
 
-        if self.current_commit_hash == current_commit_hash {
-            debug!("No changes");
-            return Ok(());
+        self.try_operation(Self::sync, attempt)
+    }
+
+    /// Whether the listener was asked to stop or its branch is gone, for
+    /// the sync scheduler. Pending sync requests are dropped, as the
+    /// scheduler only asks right before a sync.
+    pub(crate) fn is_stop_requested(&self) -> bool {
+        match self.sync_receiver.lock() {
+            Ok(receiver) => match receiver.try_recv() {
+                Ok(SyncSignal::Sync) => !Self::collapse_sync_requests(&receiver),
+                Ok(SyncSignal::Stop) => true,
+                Err(TryRecvError::Empty) => false,
+                Err(TryRecvError::Disconnected) => true,
+            },
+            Err(_) => false,
         }
+    }
 
-        debug!("Changes detected");
+    pub(crate) fn is_pinned(&self) -> bool {
+        self.ref_type.is_pinned()
+    }
 
-        self.current_commit_hash = current_commit_hash;
+    /// The wait before the next poll, jittered anew on every call.
+    pub(crate) fn get_interval(&self) -> std::time::Duration {
+        let interval = std::time::Duration::from_millis(self.pull_request_interval_millis);
 
-        let output = self.git_diff_stat()?;
+        match &self.poll_jitter {
+            Some(jitter) => jitter.apply(interval),
+            None => interval,
+        }
+    }
 
-        debug!("Diff stat: {}", output);
+    /// Runs `operation`, retrying with backoff while it fails, up to the
+    /// retry policy's limit. Failures are counted for `Gitdis`.
+    fn with_retry(
+        &mut self,
+        operation: fn(&mut Self) -> Result<(), BranchHandlerError>,
+    ) -> Result<(), BranchHandlerError> {
+        let mut attempt = 0;
 
-        let mut chars = output.split('\0');
+        loop {
+            attempt += 1;
 
-        while let Some(char) = chars.next() {
-            if char.is_empty() {
-                break;
+            match self.try_operation(operation, attempt)? {
+                Some(delay) => self.clock.sleep(delay),
+                None => return Ok(()),
             }
+        }
+    }
 
-            let status = match char {
-                "A" => Status::Added,
-                "D" => Status::Deleted,
-                _ => {
-                    if char.starts_with("R") {
-                        Status::Moved
-                    } else if char.starts_with("C") {
-                        Status::Copied
-                    } else if char.starts_with("M") {
-                        Status::Modified
-                    } else {
-                        continue;
-                    }
+    /// Runs attempt number `attempt` (starting at 1) of `operation` under
+    /// the clone lock and records its outcome. Returns the delay before the
+    /// next attempt, `None` on success, or the error once retries are
+    /// exhausted.
+    fn try_operation(
+        &mut self,
+        operation: fn(&mut Self) -> Result<(), BranchHandlerError>,
+        attempt: u32,
+    ) -> Result<Option<std::time::Duration>, BranchHandlerError> {
+        let result = match self.lock_clone() {
+            Ok(_lock) => operation(self),
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(_) => {
+                if let Ok(mut failures) = self.failures.write() {
+                    failures.consecutive = 0;
                 }
-            };
 
-            match chars.next() {
-                Some(file) => {
-                    let file = format!("{}/{}", self.repo_path, file);
+                self.update_status(None);
+                self.record_generation();
+                self.record_versions();
 
-                    if self.is_ignore(&file) || !self.is_valid_file(&file) {
-                        continue;
-                    }
+                Ok(None)
+            }
+            Err(err) => {
+                if let Ok(mut failures) = self.failures.write() {
+                    failures.consecutive += 1;
+                    failures.total += 1;
+                    failures.last_error = Some(err.to_string());
+                }
 
-                    debug!("File: {}, Status: {}", file, status);
+                self.update_status(Some(err.to_string()));
+
+                if attempt > self.retry.max_retries {
+                    debug!(
+                        "Giving up after {} retries: {}",
+                        self.retry.max_retries, err
+                    );
+                    self.events.publish(
+                        &self.branch_key,
+                        BranchEventKind::SyncFailed {
+                            error: err.to_string(),
+                        },
+                    );
+                    return Err(err);
+                }
 
-                    match status {
-                        Status::Added | Status::Modified | Status::Copied => {
-                            let content = self.get_file_content(&file);
-                            let value = match Value::payload_to_value(&content) {
-                                Ok(value) => value,
-                                Err(_) => Value::Undefined,
-                            };
+                let delay = self.retry.get_delay(attempt);
 
-                            match self.cache.write() {
-                                Ok(mut cache) => cache.insert(self.fix_key(&file), value),
-                                Err(_) => continue,
-                            };
-                        }
-                        Status::Deleted => {
-                            match self.cache.write() {
-                                Ok(mut cache) => match cache.remove(&self.fix_key(&file)) {
-                                    Ok(_) => (),
-                                    Err(_) => (),
-                                },
-                                Err(_) => continue,
-                            };
-                        }
-                        Status::Moved => match chars.next() {
-                            Some(new_file) => {
-                                let new_file = format!("{}/{}", self.repo_path, new_file);
-                                let content = self.get_file_content(&new_file);
-                                let value = match Value::payload_to_value(&content) {
-                                    Ok(value) => value,
-                                    Err(_) => Value::Undefined,
-                                };
-
-                                match self.cache.write() {
-                                    Ok(mut cache) => {
-                                        cache.insert(self.fix_key(&new_file), value);
-                                        cache.remove(&self.fix_key(&file)).unwrap();
-                                    }
-                                    Err(_) => continue,
-                                };
-                            }
-                            None => break,
-                        },
-                    }
+                debug!("Sync failed, retrying in {:?}: {}", delay, err);
+
+                Ok(Some(delay))
+            }
+        }
+    }
+
+    /// Records the outcome of a sync: a success also refreshes the commit,
+    /// the sync time and the count of loaded files, and announces the
+    /// commit when it changed.
+    fn update_status(&self, error: Option<String>) {
+        let files_loaded = match (&error, self.cache.read()) {
+            (None, Ok(cache)) => Some(
+                list_prefix(&cache, "")
+                    .iter()
+                    .filter(|(key, _)| !self.matrix_keys.contains(key))
+                    .count(),
+            ),
+            _ => None,
+        };
+
+        let mut loaded = None;
+
+        if let Ok(mut status) = self.status.write() {
+            if let Some(files_loaded) = files_loaded {
+                let commit = Some(self.current_commit_hash.trim().to_string())
+                    .filter(|commit| !commit.is_empty());
+
+                if commit.is_some() && commit != status.last_commit {
+                    loaded = commit.clone();
                 }
-                None => break,
+
+                status.last_commit = commit;
+                status.last_sync_at = Some(self.clock.now_millis());
+                status.files_loaded = files_loaded;
             }
+
+            status.last_error = error;
         }
 
-        Ok(())
+        if let Some(commit) = loaded {
+            self.events
+                .publish(&self.branch_key, BranchEventKind::SyncCompleted { commit });
+        }
     }
 
-    fn fix_key(&self, key: &str) -> String {
-        key.replace(&format!("{}/", &self.repo_path), "")
-            .split(".")
-            .next()
-            .unwrap()
-            .to_string()
+    /// Records the cache as a new generation once a commit other than the
+    /// last recorded one is loaded.
+    fn record_generation(&self) {
+        let commit = self.current_commit_hash.trim();
+
+        if commit.is_empty() {
+            return;
+        }
+
+        let mut history = match self.history.write() {
+            Ok(history) => history,
+            Err(_) => return,
+        };
+
+        if !history.is_enabled() || history.get_last_commit() == Some(commit) {
+            return;
+        }
+
+        if let Ok(cache) = self.cache.read() {
+            history.record(
+                &cache,
+                commit,
+                self.events.get_branch_sequence(&self.branch_key),
+                self.clock.now_millis(),
+            );
+        }
     }
 
-    fn get_initial_data(&self) -> Result<HashMap<String, Value>, BranchHandlerError> {
-        let files = self.list_all_files(&self.clone_path);
-        let mut data = HashMap::new();
+    /// Records the keys a newly loaded commit changed as new versions.
+    fn record_versions(&self) {
+        let commit = self.current_commit_hash.trim();
 
-        for file in files {
-            let content = self.get_file_content(&file);
-            let value = match Value::payload_to_value(&content) {
-                Ok(value) => value,
-                Err(_) => Value::Undefined,
-            };
+        if commit.is_empty() {
+            return;
+        }
 
-            data.insert(self.fix_key(&file), value);
+        let mut versions = match self.versions.write() {
+            Ok(versions) => versions,
+            Err(_) => return,
+        };
+
+        if !versions.is_enabled() || versions.get_last_commit() == Some(commit) {
+            return;
         }
 
-        println!("{:#?}", data);
+        if let Ok(cache) = self.cache.read() {
+            versions.record(&cache, commit, self.clock.now_millis());
+        }
+    }
 
-        Ok(data)
+    /// Waits for the poll interval, returning early when a sync is requested.
+    /// Returns `false` once the listener is asked to stop or its branch is
+    /// gone.
+    fn wait_next_sync(&self) -> bool {
+        let interval = self.get_interval();
+
+        match self.sync_receiver.lock() {
+            Ok(receiver) => match receiver.recv_timeout(interval) {
+                Ok(SyncSignal::Sync) => Self::collapse_sync_requests(&receiver),
+                Ok(SyncSignal::Stop) => false,
+                Err(RecvTimeoutError::Timeout) => true,
+                Err(RecvTimeoutError::Disconnected) => false,
+            },
+            Err(_) => {
+                std::thread::sleep(interval);
+                true
+            }
+        }
     }
 
-    fn load_initial_data(&mut self) -> Result<(), BranchHandlerError> {
-        let data = self.get_initial_data()?;
+    /// Collapses the sync requests that piled up while waiting. Returns
+    /// `false` if a stop was requested among them.
+    fn collapse_sync_requests(receiver: &Receiver<SyncSignal>) -> bool {
+        debug!("Sync requested");
 
-        match self.cache.write() {
-            Ok(mut cache) => {
-                for (key, value) in data {
-                    cache.insert(self.fix_key(&key), value);
-                }
+        while let Ok(signal) = receiver.try_recv() {
+            if signal == SyncSignal::Stop {
+                return false;
             }
-            Err(_) => (),
         }
 
-        Ok(())
+        true
     }
 
-    fn get_file_content(&self, path: &str) -> String {
-        debug!("Reading file: {}", path);
-        std::fs::read_to_string(path).unwrap()
+    fn is_paused(&self) -> bool {
+        self.status.read().is_ok_and(|status| status.paused)
     }
 
-    fn is_valid_file(&self, path: &str) -> bool {
-        path.ends_with(EXT_JSON) || path.ends_with(EXT_YML) || path.ends_with(EXT_YAML)
-    }
+    /// Whether the branch policy skips the next sync: the branch is in a
+    /// freeze window, or ran as many syncs as its rate limit allows in the
+    /// last minute. A sync let through counts towards the limit.
+    fn is_held_by_policy(&mut self) -> bool {
+        let (frozen, rate_limit) = match self.policy.read() {
+            Ok(policy) => (
+                policy.is_frozen(self.clock.now_millis()),
+                policy.rate_limit_per_minute,
+            ),
+            Err(_) => (false, None),
+        };
+
+        if frozen {
+            debug!("Branch {} is frozen, skipping sync", self.branch_key);
+            return true;
+        }
 
-    fn is_ignore(&self, key: &str) -> bool {
-        for ignore in &self.ignore {
-            if key.contains(ignore) {
-                return true;
+        let limit = match rate_limit {
+            Some(limit) => limit as usize,
+            None => {
+                self.recent_syncs.clear();
+                return false;
             }
+        };
+        let now = self.clock.now();
+
+        while self
+            .recent_syncs
+            .front()
+            .is_some_and(|start| now.duration_since(*start) >= std::time::Duration::from_secs(60))
+        {
+            self.recent_syncs.pop_front();
+        }
+
+        if self.recent_syncs.len() >= limit {
+            debug!("Branch {} is rate limited, skipping sync", self.branch_key);
+            return true;
         }
 
+        self.recent_syncs.push_back(now);
+
         false
     }
 
-    fn list_all_files(&self, path: &str) -> Vec<String> {
-        let mut files = Vec::new();
+    /// Whether the branch is disabled, see `BranchSettings::enabled`.
+    pub(crate) fn is_standby(&self) -> bool {
+        self.status.read().is_ok_and(|status| status.standby)
+    }
 
-        for entry in std::fs::read_dir(path).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            let path_str = path.to_str().unwrap();
+    /// Takes the lock of the branch clone, waiting while another handler,
+    /// e.g. the listener or a write-back, holds it. Released when dropped.
+    fn lock_clone(&self) -> Result<std::fs::File, BranchHandlerError> {
+        self.lock_clone_file()
+            .map_err(|err| BranchHandlerError::CloneLock(err.to_string()))
+    }
 
-            if self.is_ignore(path_str) {
-                continue;
-            }
+    fn lock_clone_file(&self) -> std::io::Result<std::fs::File> {
+        let lock_path = format!("{}{}", self.repo_path, LOCK_FILE_SUFFIX);
 
-            if path.is_dir() {
-                files.append(&mut self.list_all_files(path_str));
-            } else if self.is_valid_file(path_str) {
-                let full_path = path_str.to_string();
+        if let Some(parent) = std::path::Path::new(&lock_path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
 
-                files.push(full_path);
-            }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)?;
+        file.lock()?;
+
+        Ok(file)
+    }
+
+    /// Deletes the working tree of the branch. The shared object store is
+    /// kept for the other branches of the repository.
+    pub fn remove_clone(&self) -> std::io::Result<()> {
+        if !std::path::Path::new(&self.repo_path).exists() {
+            return Ok(());
         }
 
-        files
+        let _lock = self.lock_clone_file()?;
+
+        debug!("Removing clone {}", self.repo_path);
+
+        std::fs::remove_dir_all(&self.repo_path)
     }
 
-    fn git_clone(&self) -> Result<(), BranchHandlerError> {
-        debug!("Cloning repository");
+    fn setup(&mut self) -> Result<(), BranchHandlerError> {
+        if !std::path::Path::new(&self.clone_path).exists() {
+            std::fs::create_dir(&self.clone_path).expect("Failed to create repo directory");
+        }
 
-        if std::path::Path::new(&self.repo_path).exists() {
-            return self.git_pull();
+        if self.adopt_existing_clone && std::path::Path::new(&self.repo_path).exists() {
+            self.adopt_clone()?;
         }
 
-        let output = Command::new("git")
-            .arg("clone")
-            .arg("--branch")
-            .arg(&self.branch_name)
-            .arg(&self.url)
-            .current_dir(&self.clone_path)
-            .output()
-            .expect("Failed to execute git clone");
+        // A clone left by a previous run may have been edited since.
+        if std::path::Path::new(&self.repo_path).exists() && !self.check_working_tree()? {
+            debug!("Discarding local changes in {}", self.repo_path);
+            self.git_reset_to_remote()?;
+        }
 
-        if !output.status.success() {
-            let code = output.status.code();
-            let error = String::from_utf8_lossy(&output.stderr);
-            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        self.git_clone()?;
+
+        let commit_hash = self.git_get_commit_hash()?;
+
+        // An unverified checkout is left unloaded; the first verified commit
+        // pulled later is then loaded in full by `update`.
+        if self.verify_commit(&commit_hash)
+            && self.within_sync_limits(&commit_hash, Self::load_initial_data)?
+        {
+            self.current_commit_hash = commit_hash;
         }
 
+        debug!("Initial commit hash: {}", self.current_commit_hash);
+
         Ok(())
     }
 
-    fn git_pull(&self) -> Result<(), BranchHandlerError> {
-        debug!("Pulling changes");
+    /// Pulls and loads changes, then runs maintenance when it is due.
+    fn sync(&mut self) -> Result<(), BranchHandlerError> {
+        self.update()?;
 
-        let output = Command::new("git")
-            .arg("pull")
-            .current_dir(&self.repo_path)
-            .output()
-            .expect("Failed to execute git pull");
-
-        if !output.status.success() {
-            let code = output.status.code();
-            let error = String::from_utf8_lossy(&output.stderr);
-            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        if let Some(interval) = self.maintenance_interval {
+            if self.clock.now().duration_since(self.last_maintenance) >= interval {
+                self.run_maintenance();
+            }
         }
 
         Ok(())
     }
 
-    fn git_diff_stat(&mut self) -> Result<String, BranchHandlerError> {
-        debug!("Getting diff stat");
+    /// Prunes stale remote refs and garbage collects the clone, recording
+    /// the space reclaimed. Objects borrowed from the shared store are left
+    /// alone, as other branches may still need them. A failure is only
+    /// logged; maintenance is tried again after the next interval.
+    fn run_maintenance(&mut self) {
+        self.last_maintenance = self.clock.now();
+
+        let git_dir = match self.bare {
+            true => self.repo_path.clone(),
+            false => format!("{}/.git", self.repo_path),
+        };
+        let size_before = dir_size(std::path::Path::new(&git_dir));
+
+        if let Err(err) = self.git_gc() {
+            debug!("Maintenance of {} failed: {}", self.branch_key, err);
+            return;
+        }
 
-        let output = Command::new("git")
-            .arg("diff")
-            .arg("-z")
-            .arg("--name-status")
-            .arg("HEAD^")
-            .arg("HEAD")
-            .current_dir(&self.repo_path)
-            .output()
-            .expect("Failed to execute git diff --stat");
+        let reclaimed_bytes = size_before.saturating_sub(dir_size(std::path::Path::new(&git_dir)));
 
-        if !output.status.success() {
-            let code = output.status.code();
-            let error = String::from_utf8_lossy(&output.stderr);
-            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        debug!(
+            "Maintenance of {} reclaimed {} bytes",
+            self.branch_key, reclaimed_bytes
+        );
+
+        if let Ok(mut status) = self.status.write() {
+            status.last_maintenance_at = Some(self.clock.now_millis());
+            status.reclaimed_bytes = reclaimed_bytes;
         }
+    }
 
-        let output = String::from_utf8_lossy(&output.stdout).to_string();
+    fn update(&mut self) -> Result<(), BranchHandlerError> {
+        if !self.check_working_tree()? {
+            debug!("Discarding local changes in {}", self.repo_path);
+            return self.recover_rewritten_history();
+        }
 
-        Ok(output)
-    }
+        match self.git_pull() {
+            Ok(_) => (),
+            Err(BranchHandlerError::GitError((_, error)))
+                if HISTORY_REWRITTEN_ERRORS
+                    .iter()
+                    .any(|message| error.contains(message)) =>
+            {
+                debug!("Upstream history was rewritten: {}", error);
+                return self.recover_rewritten_history();
+            }
+            Err(err) => return Err(err),
+        }
 
-    fn git_get_commit_hash(&mut self) -> Result<String, BranchHandlerError> {
-        let output = Command::new("git")
-            .arg("rev-parse")
-            .arg("HEAD")
-            .current_dir(&self.repo_path)
-            .output()
-            .expect("Failed to execute git rev-parse HEAD");
+        let current_commit_hash = self.git_get_commit_hash()?;
 
-        if !output.status.success() {
-            let code = output.status.code();
-            let error = String::from_utf8_lossy(&output.stderr);
-            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        debug!("Current commit hash: {}", current_commit_hash);
+
+        if self.current_commit_hash == current_commit_hash {
+            debug!("No changes");
+            return Ok(());
         }
 
-        let output = String::from_utf8_lossy(&output.stdout).to_string();
+        debug!("Changes detected");
 
-        Ok(output)
-    }
+        if !self.verify_commit(&current_commit_hash) {
+            return Ok(());
+        }
+
+        if self.current_commit_hash.is_empty() {
+            if self.within_sync_limits(&current_commit_hash, Self::load_initial_data)? {
+                self.current_commit_hash = current_commit_hash;
+            }
+
+            return Ok(());
+        }
+
+        let previous_commit_hash =
+            std::mem::replace(&mut self.current_commit_hash, current_commit_hash.clone());
+
+        // A stopped sync is diffed from the same commit once overridden.
+        if !self.within_sync_limits(&current_commit_hash, |handler| {
+            handler.apply_diff(&previous_commit_hash)
+        })? {
+            self.current_commit_hash = previous_commit_hash;
+        }
+
+        Ok(())
+    }
+
+    /// Loads the changes between `previous_commit_hash` and `HEAD`.
+    fn apply_diff(&mut self, previous_commit_hash: &str) -> Result<(), BranchHandlerError> {
+        let output = self.git_diff_stat(previous_commit_hash)?;
+
+        debug!("Diff stat: {}", output);
+
+        // Before the files are applied, so keys of deleted files are
+        // dropped again as they are unloaded.
+        self.record_metadata(&format!("{}..HEAD", previous_commit_hash.trim()), None);
+
+        if output
+            .split('\0')
+            .any(|file| self.schemas.borrow().is_schema(file))
+        {
+            debug!("Schemas of {} changed, reloading", self.branch_key);
+            return self.reload_all_data();
+        }
+
+        if output
+            .split('\0')
+            .any(|file| CODEOWNERS_FILES.contains(&file))
+        {
+            self.load_code_owners();
+        }
+
+        let mut chars = output.split('\0');
+        let mut changed_files = Vec::new();
+        let mut changes = Vec::new();
+
+        while let Some(char) = chars.next() {
+            if char.is_empty() {
+                break;
+            }
+
+            let status = match char {
+                "A" => Status::Added,
+                "D" => Status::Deleted,
+                _ => {
+                    if char.starts_with("R") {
+                        Status::Moved
+                    } else if char.starts_with("C") {
+                        Status::Copied
+                    } else if char.starts_with("M") {
+                        Status::Modified
+                    } else {
+                        continue;
+                    }
+                }
+            };
+
+            let file = match chars.next() {
+                Some(file) => file,
+                None => break,
+            };
+
+            // Renames and copies list the source, then the destination.
+            let new_file = match status {
+                Status::Moved | Status::Copied => match chars.next() {
+                    Some(new_file) => Some(format!("{}/{}", self.repo_path, new_file)),
+                    None => break,
+                },
+                _ => None,
+            };
+
+            changed_files.push(file.to_string());
+
+            if let Some(new_file) = &new_file {
+                changed_files.push(self.get_repo_relative_path(new_file).to_string());
+            }
+
+            changes.push((status, format!("{}/{}", self.repo_path, file), new_file));
+        }
+
+        // Counted up front, so a commit over the limit applies nothing.
+        self.charge_sync_budget(changes.len())?;
+
+        // Files are parsed into `staged` first, by path, and only applied
+        // once all of them are, so a sync stopped halfway through by the
+        // parse time limit leaves the cache as it was. `None` unloads.
+        let mut staged: BTreeMap<String, Option<Value>> = BTreeMap::new();
+
+        for (status, file, new_file) in changes {
+            // Only the parse time is left to check.
+            self.charge_sync_budget(0)?;
+
+            debug!("File: {}, Status: {}", file, status);
+
+            let (unloaded, loaded) = match (status, new_file) {
+                (Status::Added, _) | (Status::Modified, _) => (None, Some(file)),
+                (Status::Deleted, _) => (Some(file), None),
+                (Status::Moved, Some(new_file)) => (Some(file), Some(new_file)),
+                (Status::Copied, Some(new_file)) => (None, Some(new_file)),
+                _ => (None, None),
+            };
+
+            if let Some(file) = unloaded {
+                staged.insert(file, None);
+            }
+
+            if let Some(file) = loaded {
+                if let Some(value) = self.read_loadable_file(&file) {
+                    staged.insert(file, Some(value));
+                }
+            }
+        }
+
+        self.charge_sync_budget(0)?;
+
+        // Unloads go first, so a file renamed to another extension keeps
+        // its key.
+        for (file, _) in staged.iter().filter(|(_, value)| value.is_none()) {
+            self.unload_file(file);
+        }
+
+        for (file, value) in staged {
+            if let Some(value) = value {
+                self.store_file(&file, value);
+            }
+        }
+
+        self.refresh_includers(&changed_files);
+
+        let matrix_changed = changed_files.iter().any(|file| match &self.matrix {
+            Some(matrix) => matrix.is_affected_by(file),
+            None => file == MATRIX_FILE,
+        });
+
+        if matrix_changed {
+            self.load_matrix();
+        }
+
+        Ok(())
+    }
+
+    /// Looks for edits and commits made inside the clone, which make pulls
+    /// fail. They are recorded in the branch status and published as a
+    /// `Dirty` event when they change. Returns `false` if they are to be
+    /// discarded, or fails if they are to be kept.
+    fn check_working_tree(&mut self) -> Result<bool, BranchHandlerError> {
+        if self.bare {
+            return Ok(true);
+        }
+
+        let files = self.git_dirty_files()?;
+        let local_commits = self.git_count_local_commits();
+
+        let changed = match self.status.write() {
+            Ok(mut status) => {
+                let changed = status.dirty_files != files || status.local_commits != local_commits;
+                status.dirty_files = files.clone();
+                status.local_commits = local_commits;
+                changed
+            }
+            Err(_) => false,
+        };
+
+        if files.is_empty() && local_commits == 0 {
+            return Ok(true);
+        }
+
+        if changed {
+            debug!(
+                "Clone {} is dirty: {:?}, {} local commits",
+                self.repo_path, files, local_commits
+            );
+
+            self.events.publish(
+                &self.branch_key,
+                BranchEventKind::Dirty {
+                    files: files.clone(),
+                    local_commits,
+                },
+            );
+        }
+
+        match self.reset_dirty {
+            true => Ok(false),
+            false => Err(BranchHandlerError::DirtyWorkingTree((files, local_commits))),
+        }
+    }
+
+    /// Moves the clone to the rewritten remote branch (e.g. after a
+    /// force-push) and rebuilds the cache from a full scan, since the last
+    /// commit seen may not be related to the new one. Also discards local
+    /// changes, see `check_working_tree`.
+    fn recover_rewritten_history(&mut self) -> Result<(), BranchHandlerError> {
+        self.git_reset_to_remote()?;
+
+        let commit_hash = self.git_get_commit_hash()?;
+
+        if !self.verify_commit(&commit_hash)
+            || !self.within_sync_limits(&commit_hash, Self::reload_all_data)?
+        {
+            return Ok(());
+        }
+
+        self.current_commit_hash = commit_hash;
+
+        Ok(())
+    }
+
+    /// Reloads every file, removing the keys whose file is gone.
+    fn reload_all_data(&mut self) -> Result<(), BranchHandlerError> {
+        self.include_dependents.borrow_mut().clear();
+        self.load_schemas();
+        self.clear_parse_errors();
+
+        let data = self.get_initial_data(true)?;
+
+        self.load_code_owners();
+
+        if let Ok(mut cache) = self.cache.write() {
+            for (key, _) in list_prefix(&cache, "") {
+                if !data.contains_key(&key) && !self.matrix_keys.contains(&key) {
+                    self.remove_key(&mut cache, &key);
+                }
+            }
+
+            for (key, value) in data.iter() {
+                cache.insert(key, value.clone());
+            }
+        }
+
+        if let Ok(mut metadata) = self.metadata.write() {
+            metadata.retain(|key, _| data.contains_key(key));
+        }
+
+        self.record_metadata("HEAD", Some(data.into_keys().collect()));
+        self.load_matrix();
+
+        Ok(())
+    }
+
+    /// Checks the signature of `commit` when the branch requires signed
+    /// commits, that its mirror agrees on it when it has one, and lints it
+    /// when the branch is linted. Rejections are listed in the branch errors
+    /// and published as a `Rejected` event, once per commit. The last
+    /// verified commit stays current, so the next verified one is diffed
+    /// against it.
+    fn verify_commit(&mut self, commit_hash: &str) -> bool {
+        if !self.require_signed_commits && self.mirror_url.is_none() && self.lint.is_none() {
+            return true;
+        }
+
+        let commit_hash = commit_hash.trim();
+
+        match self
+            .git_verify_head(commit_hash)
+            .and_then(|_| self.lint_commit(commit_hash))
+        {
+            Ok(_) => {
+                self.rejected_commit_hash = None;
+
+                if let Ok(mut errors) = self.errors.write() {
+                    errors.remove(SIGNATURE_ERROR_KEY);
+                }
+
+                true
+            }
+            Err(err) => {
+                if self.rejected_commit_hash.as_deref() == Some(commit_hash) {
+                    return false;
+                }
+
+                debug!("Rejecting commit {}: {}", commit_hash, err);
+
+                if let Ok(mut errors) = self.errors.write() {
+                    errors.insert(SIGNATURE_ERROR_KEY.to_string(), err.to_string());
+                }
+
+                self.events.publish(
+                    &self.branch_key,
+                    BranchEventKind::Rejected {
+                        commit: commit_hash.to_string(),
+                        reason: err.to_string(),
+                    },
+                );
+                self.rejected_commit_hash = Some(commit_hash.to_string());
+
+                false
+            }
+        }
+    }
+
+    /// Runs `load` for `commit_hash` within the sync limits of the branch.
+    /// A load going over them is stopped: the commit is marked blocked in
+    /// the status, listed in the branch errors and published as a
+    /// `Rejected` event, and is not loaded again until the limits are
+    /// overridden, see `Gitdis::override_sync_limits`. Returns whether the
+    /// commit was loaded.
+    fn within_sync_limits<F>(
+        &mut self,
+        commit_hash: &str,
+        load: F,
+    ) -> Result<bool, BranchHandlerError>
+    where
+        F: FnOnce(&mut Self) -> Result<(), BranchHandlerError>,
+    {
+        let commit_hash = commit_hash.trim();
+        let (blocked, overridden) = match self.status.read() {
+            Ok(status) => (
+                status.blocked_commit.as_deref() == Some(commit_hash),
+                status.override_sync_limits,
+            ),
+            Err(_) => (false, false),
+        };
+
+        if blocked && !overridden {
+            return Ok(false);
+        }
+
+        if !overridden {
+            self.sync_budget.borrow_mut().start(self.clock.now());
+        }
+
+        self.loading_commit_hash = Some(commit_hash.to_string());
+
+        let result = load(self);
+
+        self.loading_commit_hash = None;
+        self.sync_budget.borrow_mut().stop();
+
+        match result {
+            Ok(_) => {
+                if let Ok(mut status) = self.status.write() {
+                    status.blocked_commit = None;
+                    status.blocked_reason = None;
+                    status.override_sync_limits = false;
+                }
+
+                if let Ok(mut errors) = self.errors.write() {
+                    errors.remove(SYNC_LIMIT_ERROR_KEY);
+                }
+
+                Ok(true)
+            }
+            Err(err @ BranchHandlerError::SyncLimitExceeded(_)) => {
+                debug!("Stopping the sync of {}: {}", commit_hash, err);
+
+                if let Ok(mut status) = self.status.write() {
+                    status.blocked_commit = Some(commit_hash.to_string());
+                    status.blocked_reason = Some(err.to_string());
+                }
+
+                if let Ok(mut errors) = self.errors.write() {
+                    errors.insert(SYNC_LIMIT_ERROR_KEY.to_string(), err.to_string());
+                }
+
+                self.events.publish(
+                    &self.branch_key,
+                    BranchEventKind::Rejected {
+                        commit: commit_hash.to_string(),
+                        reason: err.to_string(),
+                    },
+                );
+
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn charge_sync_budget(&self, files: usize) -> Result<(), BranchHandlerError> {
+        self.sync_budget
+            .borrow_mut()
+            .charge(files, self.clock.now())
+    }
+
+    /// Lints the files of `commit_hash`, which is checked out, and keeps
+    /// the report in the branch. Fails on error-level findings when the
+    /// branch blocks on them.
+    fn lint_commit(&self, commit_hash: &str) -> Result<(), BranchHandlerError> {
+        let settings = match &self.lint {
+            Some(settings) => settings,
+            None => return Ok(()),
+        };
+
+        let mut sources: HashMap<String, Vec<String>> = HashMap::new();
+
+        for file in self.list_data_files()? {
+            sources
+                .entry(self.fix_key(&file))
+                .or_default()
+                .push(self.get_repo_relative_path(&file).to_string());
+        }
+
+        let data = self.get_initial_data(false)?;
+        let findings = lint::lint(&sources, &data, settings);
+        let errors = findings
+            .iter()
+            .filter(|finding| finding.level == LintLevel::Error)
+            .count();
+        let blocked = settings.block_on_error && errors > 0;
+
+        debug!(
+            "Lint of {}: {} findings, {} errors",
+            commit_hash,
+            findings.len(),
+            errors
+        );
+
+        if let Ok(mut report) = self.lint_report.write() {
+            *report = Some(LintReport {
+                commit: commit_hash.to_string(),
+                findings,
+                blocked,
+            });
+        }
+
+        match blocked {
+            true => Err(BranchHandlerError::LintFailed((
+                commit_hash.to_string(),
+                errors,
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    /// Expands `.gitdis/matrix.yaml`, replacing every key published by the
+    /// previous expansion.
+    fn load_matrix(&mut self) {
+        let matrix_file = format!("{}/{}", self.repo_path, MATRIX_FILE);
+
+        self.matrix = if self.file_exists(&matrix_file) {
+            match Matrix::parse(&self.get_file_content(&matrix_file)) {
+                Ok(matrix) => {
+                    self.clear_error(&matrix_file);
+                    Some(matrix)
+                }
+                Err(err) => {
+                    self.flag_error(&matrix_file, err.to_string());
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut data = HashMap::new();
+
+        if let Some(matrix) = &self.matrix {
+            for template in &matrix.templates {
+                let template_file = format!("{}/{}", self.repo_path, template);
+
+                if !self.file_exists(&template_file) {
+                    self.flag_error(&matrix_file, format!("Template not found: {}", template));
+                    continue;
+                }
+
+                let content = self.get_file_content(&template_file);
+                let template_key = self.fix_key(&template_file);
+
+                for entry in &matrix.environments {
+                    let rendered = matrix.render(&content, entry);
+                    let key = Matrix::get_key(entry, &template_key);
+
+                    match payload::parse_value(&template_file, &rendered, &self.parse_options) {
+                        Ok(value) => {
+                            data.insert(key, value);
+                        }
+                        Err(err) => {
+                            debug!("Matrix error: {}: {}", key, err);
+                            self.flag_error(&template_file, format!("{}: {}", key, err));
+                        }
+                    }
+                }
+            }
+        }
+
+        debug!("Matrix keys: {:?}", data.keys());
+
+        if let Ok(mut cache) = self.cache.write() {
+            let previous_keys: Vec<String> = self.matrix_keys.drain().collect();
+
+            for key in previous_keys {
+                if !data.contains_key(&key) {
+                    self.remove_key(&mut cache, &key);
+                }
+            }
+
+            for (key, value) in data {
+                self.matrix_keys.insert(key.clone());
+                cache.insert(key, value);
+            }
+        }
+    }
+
+    /// Re-parses files that include any of `changed_files`, which are paths
+    /// relative to the repository.
+    fn refresh_includers(&self, changed_files: &[String]) {
+        let repo_path = match std::path::Path::new(&self.repo_path).canonicalize() {
+            Ok(repo_path) => repo_path,
+            Err(_) => return,
+        };
+
+        let mut includers = HashSet::new();
+
+        {
+            let include_dependents = self.include_dependents.borrow();
+
+            for file in changed_files {
+                let file = repo_path.join(file).to_string_lossy().to_string();
+
+                if let Some(dependents) = include_dependents.get(&file) {
+                    includers.extend(dependents.iter().cloned());
+                }
+            }
+        }
+
+        for file in includers {
+            if !std::path::Path::new(&file).exists() {
+                continue;
+            }
+
+            debug!("Refreshing includer: {}", file);
+
+            let value = match self.read_data_file(&file) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            if let Ok(mut cache) = self.cache.write() {
+                cache.insert(self.fix_key(&file), value);
+            }
+        }
+    }
+
+    /// Serializes `value` back into the file behind `key`, then commits and
+    /// pushes it. When `expected_commit` is given, the write only happens if
+    /// the branch head still matches it. Returns the new commit hash.
+    pub fn write_back(
+        &mut self,
+        key: &str,
+        value: &Value,
+        expected_commit: Option<&str>,
+    ) -> Result<String, BranchHandlerError> {
+        if self.ref_type.is_pinned() {
+            return Err(BranchHandlerError::PinnedRef(self.branch_name.clone()));
+        }
+
+        if self.bare {
+            return Err(BranchHandlerError::BareClone(self.repo_path.clone()));
+        }
+
+        let _lock = self.lock_clone()?;
+
+        self.git_clone()?;
+
+        let current_commit_hash = self.git_get_commit_hash()?.trim().to_string();
+
+        if let Some(expected_commit) = expected_commit {
+            if current_commit_hash != expected_commit.trim() {
+                return Err(BranchHandlerError::CommitMismatch(current_commit_hash));
+            }
+        }
+
+        let file = self.find_writable_file(key)?;
+
+        debug!("Writing back file: {}", file);
+
+        let mut content = value.to_json(JsonMode::Indented);
+        content.push('\n');
+
+        // As in `commit_changes`, a failed write, commit or push leaves the
+        // clone where it was rather than ahead of its remote.
+        let result = std::fs::write(&file, content)
+            .map_err(|err| BranchHandlerError::WriteFailed((file.clone(), err.to_string())))
+            .and_then(|_| {
+                self.git_commit(
+                    std::slice::from_ref(&file),
+                    &format!("Update {} via gitdis", key),
+                )
+            })
+            .and_then(|_| self.git_push());
+
+        if let Err(err) = result {
+            if let Err(reset) = self.git_reset_hard(&current_commit_hash) {
+                debug!(
+                    "Failed to roll back {} to {}: {}",
+                    self.branch_key, current_commit_hash, reset
+                );
+            }
+
+            return Err(err);
+        }
+
+        let commit_hash = self.git_get_commit_hash()?;
+        self.current_commit_hash = commit_hash.clone();
+
+        Ok(commit_hash.trim().to_string())
+    }
+
+    /// Applies every change in `changes` and pushes them as a single commit
+    /// with `message`. Files are all resolved before any is touched, and if
+    /// a write, the commit or the push fails the clone is reset to where it
+    /// was, so either every change lands or none does. `expected_commit`
+    /// works as in `write_back`. Returns the new commit hash.
+    pub fn commit_changes(
+        &mut self,
+        changes: &[KeyWrite],
+        message: &str,
+        expected_commit: Option<&str>,
+    ) -> Result<String, BranchHandlerError> {
+        if self.ref_type.is_pinned() {
+            return Err(BranchHandlerError::PinnedRef(self.branch_name.clone()));
+        }
+
+        if self.bare {
+            return Err(BranchHandlerError::BareClone(self.repo_path.clone()));
+        }
+
+        let _lock = self.lock_clone()?;
+
+        self.git_clone()?;
+
+        let current_commit_hash = self.git_get_commit_hash()?.trim().to_string();
+
+        if let Some(expected_commit) = expected_commit {
+            if current_commit_hash != expected_commit.trim() {
+                return Err(BranchHandlerError::CommitMismatch(current_commit_hash));
+            }
+        }
+
+        let files = changes
+            .iter()
+            .map(|change| match change {
+                KeyWrite::Update { key, .. } => self.find_writable_file(key),
+                KeyWrite::Delete { key } => self.find_file(key),
+            })
+            .collect::<Result<Vec<String>, BranchHandlerError>>()?;
+
+        debug!("Committing {} changes", changes.len());
+
+        let result = changes
+            .iter()
+            .zip(&files)
+            .try_for_each(|(change, file)| {
+                match change {
+                    KeyWrite::Update { value, .. } => {
+                        let mut content = value.to_json(JsonMode::Indented);
+                        content.push('\n');
+                        std::fs::write(file, content)
+                    }
+                    KeyWrite::Delete { .. } if !std::path::Path::new(file).exists() => Ok(()),
+                    KeyWrite::Delete { .. } => std::fs::remove_file(file),
+                }
+                .map_err(|err| BranchHandlerError::WriteFailed((file.clone(), err.to_string())))
+            })
+            .and_then(|_| self.git_commit(&files, message))
+            .and_then(|_| self.git_push());
+
+        if let Err(err) = result {
+            if let Err(reset) = self.git_reset_hard(&current_commit_hash) {
+                debug!(
+                    "Failed to roll back {} to {}: {}",
+                    self.branch_key, current_commit_hash, reset
+                );
+            }
+
+            return Err(err);
+        }
+
+        let commit_hash = self.git_get_commit_hash()?;
+        self.current_commit_hash = commit_hash.clone();
+
+        Ok(commit_hash.trim().to_string())
+    }
+
+    /// Reads a file of the repository as of `HEAD`, e.g. a template, also
+    /// when a bare or partial clone has not checked it out. Symlinks are not
+    /// followed and paths leaving the repository are refused.
+    pub fn read_repo_file(&self, path: &str) -> Result<String, BranchHandlerError> {
+        let path = path.trim_start_matches('/');
+
+        if path.is_empty() || path.split('/').any(|part| part == "..") {
+            return Err(BranchHandlerError::FileNotFound(path.to_string()));
+        }
+
+        match self.git_show_file(path) {
+            Some(bytes) => Ok(String::from_utf8_lossy(&bytes).to_string()),
+            None => Err(BranchHandlerError::FileNotFound(path.to_string())),
+        }
+    }
+
+    /// The file behind `key`, if gitdis can serialize a value back into it.
+    fn find_writable_file(&self, key: &str) -> Result<String, BranchHandlerError> {
+        let file = self.find_file(key)?;
+
+        if !file.ends_with(EXT_JSON) {
+            return Err(BranchHandlerError::UnsupportedWriteBack(file));
+        }
+
+        // Rewriting a file read with `lenient_json` would drop its comments.
+        if self.parse_options.lenient_json
+            && !payload::is_strict_json(&self.get_file_content(&file))
+        {
+            return Err(BranchHandlerError::UnsupportedWriteBack(file));
+        }
+
+        // And one with placeholders would commit their expanded values.
+        if self.parse_options.interpolate_env
+            && payload::has_placeholders(&self.get_file_content(&file))
+        {
+            return Err(BranchHandlerError::UnsupportedWriteBack(file));
+        }
+
+        Ok(file)
+    }
+
+    fn find_file(&self, key: &str) -> Result<String, BranchHandlerError> {
+        let path = match &self.key_format {
+            Some(format) => format.to_path(key),
+            None => key.to_string(),
+        };
+
+        if validate_relative_path(&path).is_err() {
+            return Err(BranchHandlerError::PathEscape(key.to_string()));
+        }
+
+        for ext in EXTENSIONS {
+            let file = format!("{}/{}{}", self.data_root, path, ext);
+
+            if !std::path::Path::new(&file).exists() {
+                continue;
+            }
+
+            if !self.is_within_repo(&file) {
+                return Err(BranchHandlerError::PathEscape(file));
+            }
+
+            return Ok(file);
+        }
+
+        Err(BranchHandlerError::FileNotFound(key.to_string()))
+    }
+
+    fn fix_key(&self, key: &str) -> String {
+        let path = key.replace(&format!("{}/", &self.data_root), "");
+
+        match &self.key_format {
+            Some(format) => format.from_path(&path),
+            None => path.split(".").next().unwrap().to_string(),
+        }
+    }
+
+    /// Loadable files under the data root.
+    fn list_data_files(&self) -> Result<Vec<String>, BranchHandlerError> {
+        Ok(self.walk_data_files()?.collect())
+    }
+
+    /// Loadable files under the data root, walked on another thread while
+    /// they are consumed unless the clone is bare.
+    fn walk_data_files(&self) -> Result<Box<dyn Iterator<Item = String>>, BranchHandlerError> {
+        if self.bare {
+            return Ok(Box::new(self.git_list_files()?.into_iter()));
+        }
+
+        let ignore = self.ignore.clone();
+        let filter = self.filter.clone();
+        let binary_files = self.binary_files.clone();
+        let parsers = self.parse_options.parsers.clone();
+        let repo_path = self.repo_path.clone();
+
+        let files = walk::walk(&self.data_root, &self.repo_path, move |path, is_dir| {
+            if is_dir {
+                // With a trailing slash too, so `/.git/` prunes the directory.
+                return !is_ignored(&ignore, &filter, &repo_path, path)
+                    && !is_ignored(&ignore, &filter, &repo_path, &format!("{}/", path));
+            }
+
+            let relative = get_repo_relative_path(&repo_path, path);
+
+            !is_ignored(&ignore, &filter, &repo_path, path)
+                && (is_valid_file(path)
+                    || binary_files.is_match(relative)
+                    || parsers.find(path).is_some())
+                && filter.is_match(relative)
+        });
+
+        Ok(Box::new(files))
+    }
+
+    /// Parses every loadable file. With `check_schemas`, files failing
+    /// their schemas keep the value cached for their key, if any.
+    fn get_initial_data(
+        &self,
+        check_schemas: bool,
+    ) -> Result<HashMap<String, Value>, BranchHandlerError> {
+        let mut data = HashMap::new();
+
+        for file in self.walk_data_files()? {
+            self.charge_sync_budget(1)?;
+
+            let key = self.fix_key(&file);
+            let value = self.read_data_file(&file);
+
+            // Skipped and unparsable files keep their cached value, as
+            // invalid ones do.
+            let value = match value {
+                Some(value) if !check_schemas || self.check_schemas(&file, &value) => value,
+                _ => {
+                    let cached = self
+                        .cache
+                        .read()
+                        .ok()
+                        .and_then(|cache| cache.get(&key).cloned());
+
+                    if let Some(cached) = cached {
+                        data.insert(key, cached);
+                    }
+
+                    continue;
+                }
+            };
+
+            data.insert(key, value);
+        }
+
+        debug!("Read {} keys from {}", data.len(), self.repo_path);
+
+        Ok(data)
+    }
+
+    fn load_initial_data(&mut self) -> Result<(), BranchHandlerError> {
+        self.load_schemas();
+        self.clear_parse_errors();
+
+        let data = self.get_initial_data(true)?;
+        let keys = data.keys().cloned().collect();
+
+        self.load_code_owners();
+
+        match self.cache.write() {
+            Ok(mut cache) => {
+                for (key, value) in data {
+                    cache.insert(key, value);
+                }
+            }
+            Err(_) => (),
+        }
+
+        self.record_metadata("HEAD", Some(keys));
+        self.load_matrix();
+
+        Ok(())
+    }
+
+    pub(crate) fn get_file_content(&self, path: &str) -> String {
+        debug!("Reading file: {}", path);
+
+        if !self.is_within_repo(path) {
+            self.flag_path_escape(path);
+            return String::new();
+        }
+
+        let bytes = match self.bare {
+            true => self.git_show_file(path).unwrap_or_default(),
+            false => std::fs::read(path).unwrap_or_else(|err| {
+                debug!("Failed to read {}: {}", path, err);
+                Vec::new()
+            }),
+        };
+
+        self.decode_content(path, &bytes)
+    }
+
+    /// Whether `path`, inside the repository, exists in the checkout, or in
+    /// `HEAD` for bare clones.
+    fn file_exists(&self, path: &str) -> bool {
+        match self.bare {
+            true => {
+                let object = format!("HEAD:{}", self.get_repo_relative_path(path));
+                self.git_read(&self.repo_path, &["cat-file", "-e", &object])
+                    .is_some()
+            }
+            false => std::path::Path::new(path).exists(),
+        }
+    }
+
+    /// Decodes file bytes to UTF-8. A BOM wins, then plain UTF-8, then the
+    /// branch default encoding. Files that still contain undecodable bytes
+    /// are flagged in the branch errors.
+    pub(crate) fn decode_content(&self, path: &str, bytes: &[u8]) -> String {
+        let (encoding, bytes) = match Encoding::for_bom(bytes) {
+            Some((encoding, bom_length)) => (encoding, &bytes[bom_length..]),
+            None => match std::str::from_utf8(bytes) {
+                Ok(content) => {
+                    self.clear_error(path);
+                    return content.to_string();
+                }
+                Err(_) => (self.encoding, bytes),
+            },
+        };
+
+        debug!("Decoding {} as {}", path, encoding.name());
+
+        let (content, had_errors) = encoding.decode_without_bom_handling(bytes);
+
+        if had_errors {
+            self.flag_error(path, format!("Undecodable content as {}", encoding.name()));
+        } else {
+            self.clear_error(path);
+        }
+
+        content.into_owned()
+    }
+
+    /// Whether `path`, once symbolic links are resolved, is inside the
+    /// repository. Bare clones read from `HEAD`, where links are not
+    /// followed.
+    fn is_within_repo(&self, path: &str) -> bool {
+        if self.bare {
+            return true;
+        }
+
+        match (
+            std::fs::canonicalize(path),
+            std::fs::canonicalize(&self.repo_path),
+        ) {
+            (Ok(path), Ok(repo_path)) => path.starts_with(repo_path),
+            // Missing files are reported where they are read.
+            _ => true,
+        }
+    }
+
+    fn flag_path_escape(&self, path: &str) {
+        debug!("Skipping {}, it resolves outside the repository", path);
+        self.flag_error(
+            path,
+            BranchHandlerError::PathEscape(path.to_string()).to_string(),
+        );
+    }
+
+    /// Size of `path` in the checkout, or in `HEAD` for bare clones.
+    fn get_file_size(&self, path: &str) -> Option<u64> {
+        match self.bare {
+            true => {
+                let object = format!("HEAD:{}", self.get_repo_relative_path(path));
+                self.git_read(&self.repo_path, &["cat-file", "-s", &object])?
+                    .trim()
+                    .parse()
+                    .ok()
+            }
+            false => std::fs::metadata(path).ok().map(|metadata| metadata.len()),
+        }
+    }
+
+    /// Reads and parses a data file. Files over `max_file_bytes` are not
+    /// read: they are flagged in the branch errors and `None` is returned,
+    /// as it is for files failing to parse. Large files in a format that
+    /// allows it are parsed as they are read.
+    fn read_data_file(&self, path: &str) -> Option<Value> {
+        if !self.is_within_repo(path) {
+            self.flag_path_escape(path);
+            return None;
+        }
+
+        let size = self.get_file_size(path);
+
+        if let (Some(limit), Some(size)) = (self.parse_options.max_file_bytes, size) {
+            if size > limit {
+                debug!("Skipping {}: {} bytes is over {}", path, size, limit);
+                self.flag_error(path, payload::PayloadError::FileTooLarge(limit).to_string());
+                return None;
+            }
+        }
+
+        if self.is_binary_file(path) {
+            return self.read_binary_file(path);
+        }
+
+        let streams = !self.bare
+            && size.is_some_and(|size| size >= payload::STREAM_MIN_BYTES)
+            && payload::can_stream(path, &self.parse_options);
+
+        if streams {
+            debug!("Streaming file: {}", path);
+
+            let parsed = std::fs::File::open(path)
+                .map_err(|err| payload::PayloadError::Parse(err.to_string()))
+                .and_then(|file| payload::parse_reader(path, file, &self.parse_options));
+
+            self.clear_error(path);
+
+            return self.resolve_parsed(path, parsed);
+        }
+
+        let content = self.get_file_content(path);
+
+        self.parse_content(path, &content)
+    }
+
+    /// Reads a file as binary, flagging it in the branch errors when it
+    /// cannot be read.
+    fn read_binary_file(&self, path: &str) -> Option<Value> {
+        debug!("Reading binary file: {}", path);
+
+        let bytes = match self.bare {
+            true => self
+                .git_show_file(path)
+                .ok_or_else(|| "Not in HEAD".to_string()),
+            false => std::fs::read(path).map_err(|err| err.to_string()),
+        };
+
+        match bytes {
+            Ok(bytes) => {
+                self.clear_error(path);
+                Some(payload::binary_value(path, &bytes))
+            }
+            Err(err) => {
+                self.flag_error(path, format!("Unreadable binary file: {}", err));
+                None
+            }
+        }
+    }
+
+    fn parse_content(&self, path: &str, content: &str) -> Option<Value> {
+        self.resolve_parsed(
+            path,
+            payload::parse_value(path, content, &self.parse_options),
+        )
+    }
+
+    /// Resolves the includes of a parsed file, recording which files it
+    /// depends on. Errors are flagged and reported, see `report_parse_error`,
+    /// and give `None`.
+    fn resolve_parsed(
+        &self,
+        path: &str,
+        parsed: Result<Value, payload::PayloadError>,
+    ) -> Option<Value> {
+        let parsed = parsed.and_then(|value| match self.bare {
+            true => Ok((value, Vec::new())),
+            false => payload::resolve_includes(value, path, &self.repo_path, &self.parse_options),
+        });
+
+        match parsed {
+            Ok((value, included)) => {
+                let mut include_dependents = self.include_dependents.borrow_mut();
+
+                for dependents in include_dependents.values_mut() {
+                    dependents.remove(path);
+                }
+
+                for file in included {
+                    include_dependents
+                        .entry(file)
+                        .or_default()
+                        .insert(path.to_string());
+                }
+
+                self.clear_parse_error(path);
+
+                Some(value)
+            }
+            Err(err) => {
+                self.flag_error(path, err.to_string());
+                self.report_parse_error(path, &err);
+                None
+            }
+        }
+    }
+
+    /// Lists a file failing to parse in the branch status and publishes a
+    /// `ParseError` event, when it fails while a commit is loaded rather
+    /// than, e.g., linted.
+    fn report_parse_error(&self, path: &str, err: &payload::PayloadError) {
+        let commit = match &self.loading_commit_hash {
+            Some(commit) => commit.clone(),
+            None => return,
+        };
+        let file = self.get_repo_relative_path(path).to_string();
+
+        if let Ok(mut status) = self.status.write() {
+            status.parse_errors.insert(file.clone(), err.to_string());
+        }
+
+        self.events.publish(
+            &self.branch_key,
+            BranchEventKind::ParseError {
+                file,
+                commit,
+                error: err.to_string(),
+            },
+        );
+    }
+
+    /// Before every file is parsed again, dropping those since deleted.
+    fn clear_parse_errors(&self) {
+        if let Ok(mut status) = self.status.write() {
+            status.parse_errors.clear();
+        }
+    }
+
+    fn clear_parse_error(&self, path: &str) {
+        let file = self.get_repo_relative_path(path);
+
+        let failed = self
+            .status
+            .read()
+            .is_ok_and(|status| status.parse_errors.contains_key(file));
+
+        if !failed {
+            return;
+        }
+
+        if let Ok(mut status) = self.status.write() {
+            status.parse_errors.remove(file);
+        }
+    }
+
+    /// Compiles the schema rules of the branch from the schema files of the
+    /// loaded commit, read through git so they need not be checked out.
+    fn load_schemas(&self) {
+        if self.schema_rules.is_empty() {
+            return;
+        }
+
+        let schemas = BranchSchemas::load(&self.schema_rules, |schema| {
+            self.read_repo_file(schema).ok()
+        });
+
+        *self.schemas.borrow_mut() = schemas;
+    }
+
+    /// Validates the value of `path` against the schemas applying to it.
+    /// Invalid files are flagged in the branch errors and published as an
+    /// `Invalid` event. Files that failed to parse are already flagged and
+    /// not validated.
+    fn check_schemas(&self, path: &str, value: &Value) -> bool {
+        if let Value::Undefined = value {
+            return true;
+        }
+
+        let file = self.get_repo_relative_path(path);
+        let errors = self.schemas.borrow().validate(file, value);
+
+        if errors.is_empty() {
+            return true;
+        }
+
+        self.flag_error(path, errors.join("; "));
+        self.events.publish(
+            &self.branch_key,
+            BranchEventKind::Invalid {
+                file: file.to_string(),
+                errors,
+            },
+        );
+
+        false
+    }
+
+    fn flag_error(&self, path: &str, error: String) {
+        debug!("File error: {}: {}", path, error);
+
+        if let Ok(mut errors) = self.errors.write() {
+            errors.insert(self.fix_key(path), error);
+        }
+    }
+
+    fn clear_error(&self, path: &str) {
+        if let Ok(mut errors) = self.errors.write() {
+            errors.remove(&self.fix_key(path));
+        }
+    }
+
+    fn is_valid_file(&self, path: &str) -> bool {
+        is_valid_file(path)
+            || self.is_binary_file(path)
+            || self.parse_options.parsers.find(path).is_some()
+    }
+
+    fn is_binary_file(&self, path: &str) -> bool {
+        self.binary_files
+            .is_match(self.get_repo_relative_path(path))
+    }
+
+    fn is_in_target(&self, path: &str) -> bool {
+        path.starts_with(&format!("{}/", self.data_root))
+    }
+
+    fn is_ignore(&self, key: &str) -> bool {
+        is_ignored(&self.ignore, &self.filter, &self.repo_path, key)
+    }
+
+    fn get_repo_relative_path<'a>(&self, path: &'a str) -> &'a str {
+        get_repo_relative_path(&self.repo_path, path)
+    }
+
+    fn is_included(&self, path: &str) -> bool {
+        self.filter.is_match(self.get_repo_relative_path(path))
+    }
+
+    fn is_loadable(&self, path: &str) -> bool {
+        !self.is_ignore(path)
+            && self.is_valid_file(path)
+            && self.is_in_target(path)
+            && self.is_included(path)
+            && !self
+                .schemas
+                .borrow()
+                .is_schema(self.get_repo_relative_path(path))
+    }
+
+    /// The value of `path` to store, if it is to be loaded.
+    fn read_loadable_file(&self, path: &str) -> Option<Value> {
+        if !self.is_loadable(path) {
+            return None;
+        }
+
+        let value = self.read_data_file(path)?;
+
+        match self.check_schemas(path, &value) {
+            true => Some(value),
+            false => None,
+        }
+    }
+
+    fn store_file(&self, path: &str, value: Value) {
+        self.record_owners(path);
+
+        if let Ok(mut cache) = self.cache.write() {
+            cache.insert(self.fix_key(path), value);
+        }
+    }
+
+    fn unload_file(&self, path: &str) {
+        if !self.is_loadable(path) {
+            return;
+        }
+
+        if let Ok(mut cache) = self.cache.write() {
+            self.remove_key(&mut cache, &self.fix_key(path));
+        }
+
+        self.clear_parse_error(path);
+
+        if let Ok(mut metadata) = self.metadata.write() {
+            metadata.remove(&self.fix_key(path));
+        }
+
+        if let Ok(mut owners) = self.owners.write() {
+            owners.remove(&self.fix_key(path));
+        }
+    }
+
+    /// Reads the first CODEOWNERS found, see `CODEOWNERS_FILES`, and records
+    /// the owners of the file of every key.
+    fn load_code_owners(&mut self) {
+        self.code_owners = CODEOWNERS_FILES
+            .iter()
+            .map(|file| format!("{}/{}", self.repo_path, file))
+            .find(|file| self.file_exists(file))
+            .map(|file| CodeOwners::parse(&self.get_file_content(&file)))
+            .unwrap_or_default();
+
+        let files = match self.list_data_files() {
+            Ok(files) => files,
+            Err(err) => {
+                debug!("Failed to list the files of {}: {}", self.branch_key, err);
+                return;
+            }
+        };
+
+        let owners = files
+            .iter()
+            .map(|file| {
+                (
+                    self.fix_key(file),
+                    self.code_owners
+                        .get_owners(self.get_repo_relative_path(file)),
+                )
+            })
+            .filter(|(_, owners)| !owners.is_empty())
+            .collect();
+
+        if let Ok(mut current) = self.owners.write() {
+            *current = owners;
+        }
+    }
+
+    /// Records the owners of the key of `path`, before it is loaded so key
+    /// watches see them.
+    fn record_owners(&self, path: &str) {
+        let key = self.fix_key(path);
+        let owners = self
+            .code_owners
+            .get_owners(self.get_repo_relative_path(path));
+
+        if let Ok(mut current) = self.owners.write() {
+            match owners.is_empty() {
+                true => current.remove(&key),
+                false => current.insert(key, owners),
+            };
+        }
+    }
+
+    /// Records the commit that last touched the file of each key, from the
+    /// commits of `range`. With `keys`, the walk stops once each of them is
+    /// found, so a full load does not read the whole history. Failures are
+    /// only logged, the keys keep their previous metadata.
+    fn record_metadata(&self, range: &str, keys: Option<HashSet<String>>) {
+        let touched = match self.git_log_keys(range, keys) {
+            Ok(touched) => touched,
+            Err(err) => {
+                debug!("Failed to read commit metadata of {}: {}", range, err);
+                return;
+            }
+        };
+
+        if let Ok(mut metadata) = self.metadata.write() {
+            metadata.extend(touched);
+        }
+    }
+
+    /// Removes the key of something deleted in git. Removals made any other
+    /// way are published as evictions.
+    fn remove_key(&self, cache: &mut Cache, key: &str) {
+        if cache.get(key).is_none() {
+            return;
+        }
+
+        self.events.expect_removal(&self.branch_key, key, None);
+        let _ = cache.remove(key);
+
+        if let Ok(mut versions) = self.versions.write() {
+            versions.mark_removed(key);
+        }
+    }
+
+    /// Runs `command` to completion, killing it past the git timeout.
+    fn run_process(&self, command: &mut Command) -> Result<Output, BranchHandlerError> {
+        run_with_timeout(self.runner.as_ref(), command, self.git_timeout)
+    }
+
+    /// Runs a command reaching the remote once the network limiter lets it.
+    fn run_network(&self, command: &mut Command) -> Result<Output, BranchHandlerError> {
+        let _permit = self.network.acquire();
+
+        self.run_process(command)
+    }
+
+    /// Runs a command, returning its stderr if it fails.
+    fn run_command(&self, command: &mut Command) -> Result<(), String> {
+        let output = self.run_process(command).map_err(|err| err.to_string())?;
+
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).to_string());
+        }
+
+        Ok(())
+    }
+
+    fn git_remote_command(&self) -> Result<Command, BranchHandlerError> {
+        git_remote_command(self.proxy.as_deref(), self.credentials.as_ref())
+    }
+
+    fn git_clone(&self) -> Result<(), BranchHandlerError> {
+        debug!("Cloning repository");
+
+        self.migrate_legacy_clone();
+
+        if let Some(Prewarm::Tarball { location }) = &self.prewarm {
+            if !self.bare && !std::path::Path::new(&self.repo_path).exists() {
+                self.prewarm_from_tarball(location);
+            }
+        }
+
+        // The clone was made before the branch switched to or from bare.
+        if std::path::Path::new(&self.repo_path).exists() && self.is_bare_clone() != self.bare {
+            debug!("Recloning {} with bare={}", self.repo_path, self.bare);
+
+            if let Err(err) = std::fs::remove_dir_all(&self.repo_path) {
+                return Err(BranchHandlerError::GitError((None, err.to_string())));
+            }
+        }
+
+        if std::path::Path::new(&self.repo_path).exists() {
+            if self.ref_type.is_pinned() {
+                return Ok(());
+            }
+
+            return self.git_pull();
+        }
+
+        let repo_dir = std::path::Path::new(&self.repo_path)
+            .parent()
+            .expect("Repo path has no parent");
+        std::fs::create_dir_all(repo_dir).expect("Failed to create repo directory");
+
+        let mut command = self.git_remote_command()?;
+        command.arg("clone");
+
+        // Shallow and partial clones are already cheap and cannot borrow
+        // from a full mirror, so only full clones use the shared store. They
+        // borrow its objects rather than copying them, which is safe as the
+        // store is never garbage collected; without a store they clone on
+        // their own.
+        if self.clone_depth.is_none() && !self.partial_clone {
+            self.git_prepare_store()?;
+            command.arg("--reference-if-able").arg(&self.store_path);
+        }
+
+        if let Some(Prewarm::Bundle { uri }) = &self.prewarm {
+            debug!("Prewarming from bundle {}", uri);
+            command.arg(format!("--bundle-uri={}", uri));
+        }
+
+        if self.bare {
+            command.arg("--bare");
+        }
+
+        if self.partial_clone {
+            debug!("Partial clone without blobs");
+            command.arg("--filter=blob:none");
+        }
+
+        // A commit cannot be cloned directly: clone without checking out
+        // and check the commit out afterwards.
+        if self.ref_type != RefType::Commit {
+            command.arg("--branch").arg(&self.branch_name);
+        }
+
+        // With a target path or a partial clone, check out nothing yet: the
+        // sparse-checkout below materializes only the files loaded. Bare
+        // clones check out nothing at all, though commits still need `HEAD`
+        // moved to them.
+        let checkout_later = match self.bare {
+            true => self.ref_type == RefType::Commit,
+            false => {
+                self.path_target.is_some() || self.partial_clone || self.ref_type == RefType::Commit
+            }
+        };
+
+        if checkout_later && !self.bare {
+            command.arg("--no-checkout");
+        }
+
+        if let Some(depth) = self.clone_depth {
+            debug!("Shallow clone with depth {}", depth);
+
+            // For commits the default branch is only a placeholder, the
+            // commit itself is fetched with the requested depth later.
+            let depth = match self.ref_type {
+                RefType::Commit => 1,
+                _ => depth,
+            };
+
+            command
+                .arg("--depth")
+                .arg(depth.to_string())
+                .arg("--single-branch");
+        }
+
+        let output = self.run_network(command.arg(&self.url).arg(&self.repo_path))?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        if self.partial_clone {
+            self.git_sparse_checkout_files()?;
+        } else if let Some(path_target) = self.path_target.as_ref().filter(|_| !self.bare) {
+            self.git_sparse_checkout(path_target)?;
+        }
+
+        if checkout_later {
+            self.git_checkout()?;
+        }
+
+        Ok(())
+    }
+
+    /// Seeds the clone from a tarball of one. On failure the partial clone
+    /// is removed and the branch is cloned as usual.
+    fn prewarm_from_tarball(&self, location: &str) {
+        debug!("Prewarming {} from {}", self.repo_path, location);
+
+        if let Err(err) = self.extract_tarball(location) {
+            debug!("Prewarm from {} failed: {}", location, err);
+            let _ = std::fs::remove_dir_all(&self.repo_path);
+        }
+    }
+
+    fn extract_tarball(&self, location: &str) -> Result<(), String> {
+        std::fs::create_dir_all(&self.repo_path).map_err(|err| err.to_string())?;
+
+        let is_remote = location.starts_with("http://") || location.starts_with("https://");
+        let archive = match is_remote {
+            true => format!("{}{}", self.repo_path, PREWARM_DOWNLOAD_SUFFIX),
+            false => location.to_string(),
+        };
+
+        if is_remote {
+            // curl is not run through the git timeout, so it gets its own.
+            let mut command = Command::new("curl");
+            command
+                .args(["-fsSL", "-o", &archive])
+                .arg("--max-time")
+                .arg(self.git_timeout.as_secs().max(1).to_string());
+
+            if let Some(proxy) = &self.proxy {
+                command.arg("--proxy").arg(proxy);
+            }
+
+            let _permit = self.network.acquire();
+            self.run_command(command.arg(location))?;
+        }
+
+        let extracted = self.check_tarball(&archive).and_then(|_| {
+            self.run_command(
+                Command::new("tar")
+                    .arg("-xzf")
+                    .arg(&archive)
+                    .arg("-C")
+                    .arg(&self.repo_path),
+            )
+        });
+
+        if is_remote {
+            let _ = std::fs::remove_file(&archive);
+        }
+
+        extracted?;
+
+        // Without this check git would look for a repository further up.
+        if !std::path::Path::new(&format!("{}/.git", self.repo_path)).is_dir() {
+            return Err("tarball does not hold a clone at its root".to_string());
+        }
+
+        // The tarball may have been made from a mirror or another remote.
+        self.run_command(
+            Command::new("git")
+                .args(["remote", "set-url", "origin", &self.url])
+                .current_dir(&self.repo_path),
+        )?;
+
+        // Its checkout may be stale, edited or of another branch; only its
+        // objects are kept.
+        self.git_reset_to_remote().map_err(|err| err.to_string())
+    }
+
+    /// Rejects archives with entries extracting outside the clone.
+    fn check_tarball(&self, archive: &str) -> Result<(), String> {
+        let output = self
+            .run_process(Command::new("tar").arg("-tzf").arg(archive))
+            .map_err(|err| err.to_string())?;
+
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).to_string());
+        }
+
+        let entries = String::from_utf8_lossy(&output.stdout);
+
+        match entries.lines().find(|entry| !is_contained_path(entry)) {
+            Some(entry) => Err(format!("tarball entry {} is outside the clone", entry)),
+            None => Ok(()),
+        }
+    }
+
+    /// Clones used to live in `data/<repo>`, so two owners with a repository
+    /// of the same name shared a directory. Moves such a clone to its
+    /// `owner/repo/branch` path when it belongs to this branch. Clones of
+    /// another remote or branch are left alone.
+    fn migrate_legacy_clone(&self) {
+        if std::path::Path::new(&self.repo_path).exists() {
+            return;
+        }
+
+        let repo_name = self.url.split('/').next_back().unwrap_or("");
+        let repo_name = repo_name.strip_suffix(".git").unwrap_or(repo_name);
+
+        if repo_name.is_empty() {
+            return;
+        }
+
+        let legacy_path = format!("{}/{}", self.clone_path, repo_name);
+
+        if !std::path::Path::new(&format!("{}/.git", legacy_path)).is_dir() {
+            return;
+        }
+
+        let remote_url = self.git_read(&legacy_path, &["config", "--get", "remote.origin.url"]);
+        let branch_name = self.git_read(&legacy_path, &["rev-parse", "--abbrev-ref", "HEAD"]);
+
+        if remote_url.as_deref() != Some(self.url.as_str())
+            || branch_name.as_deref() != Some(self.branch_name.as_str())
+        {
+            debug!("Leaving unrelated clone at {}", legacy_path);
+            return;
+        }
+
+        debug!("Migrating clone {} to {}", legacy_path, self.repo_path);
+
+        // The legacy directory may be the new owner directory itself, so it
+        // is moved aside before the new layout is created.
+        let moving_path = format!("{}.migrating", legacy_path);
+
+        if let Err(err) = std::fs::rename(&legacy_path, &moving_path) {
+            debug!("Failed to migrate {}: {}", legacy_path, err);
+            return;
+        }
+
+        let moved = std::path::Path::new(&self.repo_path)
+            .parent()
+            .map(|parent| std::fs::create_dir_all(parent).is_ok())
+            .unwrap_or(false)
+            && std::fs::rename(&moving_path, &self.repo_path).is_ok();
+
+        if !moved {
+            debug!("Failed to migrate {}, restoring it", legacy_path);
+            let _ = std::fs::rename(&moving_path, &legacy_path);
+        }
+    }
+
+    /// Runs a read-only git command in `path`, returning its trimmed output.
+    fn git_read(&self, path: &str, args: &[&str]) -> Option<String> {
+        let output = self
+            .run_process(Command::new("git").args(args).current_dir(path))
+            .ok()?;
+
+        if !output.status.success() {
+            return None;
+        }
+
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Checks that the clone found in the branch directory is one of the
+    /// branch repository, at its ref and as bare as the branch, then points
+    /// its remote at the branch URL so pulls use the branch settings.
+    fn adopt_clone(&self) -> Result<(), BranchHandlerError> {
+        debug!("Adopting clone {}", self.repo_path);
+
+        let reject =
+            |reason: String| BranchHandlerError::AdoptRejected((self.repo_path.clone(), reason));
+
+        // Checked before asking git, which would otherwise answer for a
+        // repository containing the directory.
+        let is_git_dir = match self.bare {
+            true => std::path::Path::new(&format!("{}/HEAD", self.repo_path)).is_file(),
+            false => !self.is_bare_clone(),
+        };
+
+        if !is_git_dir {
+            return Err(reject(match self.bare {
+                true => "not a bare clone".to_string(),
+                false => "not a clone with a working tree".to_string(),
+            }));
+        }
+
+        let remote_url = self
+            .git_read(&self.repo_path, &["config", "--get", "remote.origin.url"])
+            .ok_or_else(|| reject("no origin remote".to_string()))?;
+
+        if canonicalize_repo_url(&remote_url) != canonicalize_repo_url(&self.url) {
+            return Err(reject(format!("origin is {}", remote_url)));
+        }
+
+        let (found, expected) = match self.ref_type {
+            RefType::Branch => (
+                self.git_read(&self.repo_path, &["rev-parse", "--abbrev-ref", "HEAD"]),
+                Some(self.branch_name.clone()),
+            ),
+            RefType::Tag | RefType::Commit => (
+                self.git_read(&self.repo_path, &["rev-parse", "HEAD"]),
+                self.git_read(
+                    &self.repo_path,
+                    &["rev-parse", &format!("{}^{{commit}}", self.branch_name)],
+                ),
+            ),
+        };
+
+        if found.is_none() || found != expected {
+            return Err(reject(format!(
+                "HEAD is at {}, expected {}",
+                found.unwrap_or_default(),
+                self.branch_name
+            )));
+        }
+
+        let output = self.run_process(
+            Command::new("git")
+                .arg("remote")
+                .arg("set-url")
+                .arg("origin")
+                .arg(&self.url)
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the clone on disk has no working tree.
+    fn is_bare_clone(&self) -> bool {
+        !std::path::Path::new(&format!("{}/.git", self.repo_path)).exists()
+    }
+
+    /// Loadable files of `HEAD` under the data root, as paths inside the
+    /// repository directory like those of a checkout.
+    fn git_list_files(&self) -> Result<Vec<String>, BranchHandlerError> {
+        let mut command = Command::new("git");
+        command
+            .arg("ls-tree")
+            .arg("-r")
+            .arg("-z")
+            .arg("--name-only")
+            .arg("HEAD");
+
+        if let Some(path_target) = &self.path_target {
+            command.arg("--").arg(path_target);
+        }
+
+        let output = self.run_process(command.current_dir(&self.repo_path))?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .split('\0')
+            .filter(|file| !file.is_empty())
+            .map(|file| format!("{}/{}", self.repo_path, file))
+            .filter(|file| {
+                !self.is_ignore(file) && self.is_valid_file(file) && self.is_included(file)
+            })
+            .collect())
+    }
+
+    /// Contents of `path`, inside the repository, at `HEAD`.
+    fn git_show_file(&self, path: &str) -> Option<Vec<u8>> {
+        let object = format!("HEAD:{}", self.get_repo_relative_path(path));
+
+        let output = self
+            .run_process(
+                Command::new("git")
+                    .arg("cat-file")
+                    .arg("blob")
+                    .arg(&object)
+                    .current_dir(&self.repo_path),
+            )
+            .ok()?;
+
+        if !output.status.success() {
+            debug!("Failed to read {}", object);
+            return None;
+        }
+
+        Some(output.stdout)
+    }
+
+    /// Creates or refreshes the bare mirror shared by every branch of the
+    /// repository. Objects fetched here are reused by the branch clones,
+    /// so the store never collects garbage: an object dropped from it, e.g.
+    /// after a force-push, may still be one a clone borrows.
+    fn git_prepare_store(&self) -> Result<(), BranchHandlerError> {
+        let _lock = STORE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+
+        let mut command = self.git_remote_command()?;
+        command.arg("-c").arg("gc.auto=0");
+
+        if std::path::Path::new(&self.store_path).exists() {
+            debug!("Refreshing shared store");
+            command
+                .arg("--git-dir")
+                .arg(&self.store_path)
+                .arg("fetch")
+                .arg("--prune");
+        } else {
+            debug!("Creating shared store");
+            command
+                .arg("clone")
+                .arg("--mirror")
+                .arg("--config")
+                .arg("gc.auto=0")
+                .arg(&self.url)
+                .arg(&self.store_path);
+        }
+
+        let output = self.run_network(&mut command)?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        Ok(())
+    }
+
+    fn git_sparse_checkout(&self, path_target: &str) -> Result<(), BranchHandlerError> {
+        debug!("Sparse checkout of {}", path_target);
+
+        let output = self.run_network(
+            self.git_remote_command()?
+                .arg("sparse-checkout")
+                .arg("set")
+                .arg("--cone")
+                .arg(path_target)
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        Ok(())
+    }
+
+    /// Restricts the checkout of a partial clone to the files that may be
+    /// loaded, so pulls and checkouts fetch no other blobs. Those it checks
+    /// out are fetched, so it runs as a remote command. Extensions
+    /// match at any depth, so files added later are checked out too.
+    fn git_sparse_checkout_files(&self) -> Result<(), BranchHandlerError> {
+        let prefix = match &self.path_target {
+            Some(path_target) => format!("/{}/**/", path_target),
+            None => "".to_string(),
+        };
+        // Binary globs are relative to the repository root, as
+        // `is_binary_file` matches them, so they already name the target.
+        let extensions = self.parse_options.parsers.get_extensions();
+        let patterns = EXTENSIONS
+            .iter()
+            .chain(
+                extensions
+                    .iter()
+                    .filter(|extension| !EXTENSIONS.contains(extension)),
+            )
+            .map(|ext| format!("{}*{}", prefix, ext))
+            .chain(
+                self.binary_patterns
+                    .iter()
+                    .map(|glob| format!("/{}", glob.trim_start_matches('/'))),
+            )
+            .chain(CODEOWNERS_FILES.iter().map(|file| format!("/{}", file)))
+            .collect::<Vec<String>>();
+
+        debug!("Sparse checkout of {:?}", patterns);
+
+        let output = self.run_network(
+            self.git_remote_command()?
+                .arg("sparse-checkout")
+                .arg("set")
+                .arg("--no-cone")
+                .args(&patterns)
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        Ok(())
+    }
+
+    fn git_checkout(&self) -> Result<(), BranchHandlerError> {
+        debug!("Checking out {}", self.branch_name);
+
+        if self.ref_type == RefType::Commit {
+            if let Some(depth) = self.clone_depth {
+                let output = self.run_network(
+                    self.git_remote_command()?
+                        .arg("fetch")
+                        .arg("--depth")
+                        .arg(depth.to_string())
+                        .arg("origin")
+                        .arg(&self.branch_name)
+                        .current_dir(&self.repo_path),
+                )?;
+
+                if !output.status.success() {
+                    let code = output.status.code();
+                    let error = String::from_utf8_lossy(&output.stderr);
+                    return Err(BranchHandlerError::GitError((code, error.to_string())));
+                }
+            }
+        }
+
+        // Partial clones fetch the blobs they check out.
+        let mut command = self.git_remote_command()?;
+
+        // Bare clones have nothing to check out, `HEAD` is moved instead.
+        if self.bare {
+            command.arg("update-ref").arg("--no-deref").arg("HEAD");
+        } else if self.ref_type == RefType::Commit {
+            command.arg("checkout").arg("--detach");
+        } else {
+            command.arg("checkout");
+        }
+
+        let output =
+            self.run_network(command.arg(&self.branch_name).current_dir(&self.repo_path))?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        Ok(())
+    }
+
+    fn git_pull(&self) -> Result<(), BranchHandlerError> {
+        debug!("Pulling changes");
+
+        let mut command = self.git_remote_command()?;
+
+        if self.bare {
+            // Nothing is checked out, so the branch is simply moved to the
+            // remote one, rewritten history included.
+            command
+                .arg("fetch")
+                .arg("origin")
+                .arg(format!("+refs/heads/{0}:refs/heads/{0}", self.branch_name));
+        } else {
+            // The clone never has commits of its own that are not pushed, so
+            // anything but a fast-forward means upstream rewrote history.
+            // That is told from the error, so it must not be translated.
+            command.env("LC_ALL", "C").arg("pull").arg("--ff-only");
+        }
+
+        if let Some(depth) = self.clone_depth {
+            command.arg("--depth").arg(depth.to_string());
+        }
+
+        let output = self.run_network(command.current_dir(&self.repo_path))?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        Ok(())
+    }
+
+    /// Tracked files edited in the working tree or the index, and untracked
+    /// files that a full load would pick up.
+    fn git_dirty_files(&self) -> Result<Vec<String>, BranchHandlerError> {
+        let output = self.run_process(
+            Command::new("git")
+                .arg("status")
+                .arg("--porcelain")
+                .arg("-z")
+                .arg("--untracked-files=all")
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        let output = String::from_utf8_lossy(&output.stdout).to_string();
+        let mut entries = output.split('\0');
+        let mut files = Vec::new();
+
+        while let Some(entry) = entries.next() {
+            if entry.len() < 4 {
+                continue;
+            }
+
+            // Renames are followed by their source path.
+            if entry.starts_with('R') || entry.starts_with('C') {
+                entries.next();
+            }
+
+            let file = &entry[3..];
+
+            if entry.starts_with("??") && !self.is_loadable(&format!("{}/{}", self.repo_path, file))
+            {
+                continue;
+            }
+
+            files.push(file.to_string());
+        }
+
+        Ok(files)
+    }
+
+    /// Commits on `HEAD` that the last fetched remote branch does not have.
+    fn git_count_local_commits(&self) -> u64 {
+        let range = format!("origin/{}..HEAD", self.branch_name);
+
+        self.git_read(&self.repo_path, &["rev-list", "--count", &range])
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0)
+    }
+
+    fn git_reset_to_remote(&self) -> Result<(), BranchHandlerError> {
+        debug!("Resetting to origin/{}", self.branch_name);
+
+        if self.bare {
+            return self.git_pull();
+        }
+
+        let mut command = self.git_remote_command()?;
+        command.arg("fetch");
+
+        if let Some(depth) = self.clone_depth {
+            command.arg("--depth").arg(depth.to_string());
+        }
+
+        let output = self.run_network(
+            command
+                .arg("origin")
+                .arg(&self.branch_name)
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        let output = self.run_process(
+            Command::new("git")
+                .arg("reset")
+                .arg("--hard")
+                .arg("FETCH_HEAD")
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        // Left are the untracked files that would be loaded.
+        let untracked = self.git_dirty_files()?;
+
+        if !untracked.is_empty() {
+            let output = self.run_process(
+                Command::new("git")
+                    .arg("clean")
+                    .arg("-f")
+                    .arg("-q")
+                    .arg("--")
+                    .args(&untracked)
+                    .current_dir(&self.repo_path),
+            )?;
+
+            if !output.status.success() {
+                let code = output.status.code();
+                let error = String::from_utf8_lossy(&output.stderr);
+                return Err(BranchHandlerError::GitError((code, error.to_string())));
+            }
+        }
+
+        if let Ok(mut status) = self.status.write() {
+            status.dirty_files.clear();
+            status.local_commits = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Stages `files`, removals included, and commits them.
+    fn git_commit(&self, files: &[String], message: &str) -> Result<(), BranchHandlerError> {
+        debug!("Committing changes");
+
+        let output = self.run_process(
+            Command::new("git")
+                .arg("add")
+                .arg("--all")
+                .arg("--")
+                .args(files)
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        let output = self.run_process(
+            Command::new("git")
+                .arg("-c")
+                .arg("user.name=gitdis")
+                .arg("-c")
+                .arg("user.email=gitdis@localhost")
+                .arg("commit")
+                .arg("-m")
+                .arg(message)
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        Ok(())
+    }
+
+    /// Drops the commits and changes made in the clone since `commit`.
+    fn git_reset_hard(&self, commit: &str) -> Result<(), BranchHandlerError> {
+        debug!("Resetting to {}", commit);
+
+        let output = self.run_process(
+            Command::new("git")
+                .arg("reset")
+                .arg("--hard")
+                .arg(commit)
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        Ok(())
+    }
+
+    fn git_push(&self) -> Result<(), BranchHandlerError> {
+        debug!("Pushing changes");
+
+        let output = self.run_network(
+            self.git_remote_command()?
+                .arg("push")
+                .arg("origin")
+                .arg(&self.branch_name)
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        Ok(())
+    }
+
+    /// Diffs against the last commit seen rather than `HEAD^`, which is both
+    /// correct when several commits arrive at once and available in shallow
+    /// clones where the parent of `HEAD` was never fetched.
+    fn git_diff_stat(&mut self, from_commit: &str) -> Result<String, BranchHandlerError> {
+        debug!("Getting diff stat");
+
+        let output = self.run_process(
+            Command::new("git")
+                .arg("diff")
+                .arg("-z")
+                .arg("--name-status")
+                .arg(from_commit.trim())
+                .arg("HEAD")
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        let output = String::from_utf8_lossy(&output.stdout).to_string();
+
+        Ok(output)
+    }
+
+    fn git_verify_head(&self, commit_hash: &str) -> Result<(), BranchHandlerError> {
+        if self.require_signed_commits {
+            self.git_verify_commit(commit_hash)?;
+        }
+
+        self.git_verify_mirror(commit_hash)
+    }
+
+    /// Looks the ref up on the mirror, peeling annotated tags, and compares
+    /// it with `commit_hash`.
+    fn git_verify_mirror(&self, commit_hash: &str) -> Result<(), BranchHandlerError> {
+        let mirror_url = match &self.mirror_url {
+            Some(mirror_url) => mirror_url,
+            None => return Ok(()),
+        };
+
+        let reference = match self.ref_type {
+            RefType::Branch => format!("refs/heads/{}", self.branch_name),
+            RefType::Tag => format!("refs/tags/{}", self.branch_name),
+            RefType::Commit => return Ok(()),
+        };
+
+        debug!("Checking commit {} against {}", commit_hash, mirror_url);
+
+        let mut command =
+            git_remote_command(self.proxy.as_deref(), self.mirror_credentials.as_ref())?;
+        let output = self.run_network(
+            command
+                .arg("ls-remote")
+                .arg(mirror_url)
+                .arg(&reference)
+                .arg(format!("{}^{{}}", reference)),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let refs = stdout
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .collect::<Vec<(&str, &str)>>();
+        let peeled = format!("{}^{{}}", reference);
+        let mirror_commit = refs
+            .iter()
+            .find(|(_, name)| *name == peeled)
+            .or_else(|| refs.iter().find(|(_, name)| *name == reference))
+            .map(|(hash, _)| hash.to_string());
+
+        match mirror_commit {
+            Some(mirror_commit) if mirror_commit == commit_hash => Ok(()),
+            mirror_commit => Err(BranchHandlerError::MirrorMismatch((
+                commit_hash.to_string(),
+                mirror_commit,
+            ))),
+        }
+    }
+
+    fn git_verify_commit(&self, commit_hash: &str) -> Result<(), BranchHandlerError> {
+        debug!("Verifying commit {}", commit_hash);
+
+        let mut command = Command::new("git");
+
+        match &self.signing_keyring {
+            Some(SigningKeyring::Gpg { home }) => {
+                command.env("GNUPGHOME", home);
+            }
+            Some(SigningKeyring::Ssh { allowed_signers }) => {
+                command
+                    .arg("-c")
+                    .arg(format!("gpg.ssh.allowedSignersFile={}", allowed_signers));
+            }
+            None => (),
+        }
+
+        let output = self.run_process(
+            command
+                .arg("verify-commit")
+                .arg(commit_hash)
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            let reason = if error.is_empty() {
+                "commit is not signed".to_string()
+            } else {
+                error
+            };
+
+            return Err(BranchHandlerError::UnverifiedCommit((
+                commit_hash.to_string(),
+                reason,
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn git_gc(&self) -> Result<(), BranchHandlerError> {
+        debug!("Collecting garbage in {}", self.repo_path);
+
+        let mut command = self.git_remote_command()?;
+        command
+            .arg("remote")
+            .arg("prune")
+            .arg("origin")
+            .current_dir(&self.repo_path);
+
+        let permit = self.network.acquire();
+        self.run_command(&mut command)
+            .map_err(|error| BranchHandlerError::GitError((None, error)))?;
+        drop(permit);
+
+        let mut command = Command::new("git");
+        command
+            .arg("gc")
+            .arg("--quiet")
+            .arg("--prune=now")
+            .current_dir(&self.repo_path);
+
+        self.run_command(&mut command)
+            .map_err(|error| BranchHandlerError::GitError((None, error)))
+    }
+
+    /// The newest commit of `range` touching the file of each loadable key,
+    /// reading `git log` until every key of `wanted`, if given, is found.
+    fn git_log_keys(
+        &self,
+        range: &str,
+        mut wanted: Option<HashSet<String>>,
+    ) -> Result<HashMap<String, Arc<CommitMetadata>>, BranchHandlerError> {
+        let mut command = Command::new("git");
+        command
+            .arg("-c")
+            .arg("core.quotePath=false")
+            .arg("log")
+            .arg("--name-only")
+            .arg(format!("--format={}", LOG_FORMAT))
+            .arg(range);
+
+        if let Some(path_target) = &self.path_target {
+            command.arg("--").arg(path_target);
+        }
+
+        // A range git does not know lists nothing, as before any commit.
+        let output = self.run_process(command.current_dir(&self.repo_path))?;
+        let mut keys = HashMap::new();
+
+        for record in output.stdout.split(|byte| *byte == RECORD_SEPARATOR) {
+            // Stops once every wanted key was found.
+            if wanted.as_ref().is_some_and(|wanted| wanted.is_empty()) {
+                break;
+            }
+
+            let record = String::from_utf8_lossy(record);
+
+            let (commit, files) = match parse_log_record(&record) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+
+            for file in files {
+                let path = format!("{}/{}", self.repo_path, file);
+
+                if !self.is_loadable(&path) {
+                    continue;
+                }
+
+                let key = self.fix_key(&path);
+
+                if let Some(wanted) = wanted.as_mut() {
+                    if !wanted.remove(&key) {
+                        continue;
+                    }
+                }
+
+                // Newest first, so the first commit seen is the last to
+                // touch the file.
+                keys.entry(key).or_insert_with(|| commit.clone());
+            }
+        }
+
+        Ok(keys)
+    }
+
+    fn git_get_commit_hash(&self) -> Result<String, BranchHandlerError> {
+        let output = self.run_process(
+            Command::new("git")
+                .arg("rev-parse")
+                .arg("HEAD")
+                .current_dir(&self.repo_path),
+        )?;
+
+        if !output.status.success() {
+            let code = output.status.code();
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(BranchHandlerError::GitError((code, error.to_string())));
+        }
+
+        let output = String::from_utf8_lossy(&output.stdout).to_string();
+
+        Ok(output)
+    }
+}
+
+/// Runs `command` through `runner`, failing with `GitTimeout` once it ran
+/// for `timeout`.
+fn run_with_timeout(
+    runner: &dyn ProcessRunner,
+    command: &mut Command,
+    timeout: std::time::Duration,
+) -> Result<Output, BranchHandlerError> {
+    runner
+        .output_timeout(command, timeout)
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::TimedOut => {
+                BranchHandlerError::GitTimeout((get_operation(command), timeout))
+            }
+            _ => BranchHandlerError::GitError((None, err.to_string())),
+        })
+}
+
+/// What `command` runs: the program, with the subcommand for git, e.g.
+/// `git fetch`.
+fn get_operation(command: &Command) -> String {
+    let program = command.get_program().to_string_lossy().to_string();
+
+    if program != "git" {
+        return program;
+    }
+
+    let mut args = command.get_args();
+
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-c") => {
+                args.next();
+            }
+            Some(arg) if !arg.starts_with('-') => return format!("{} {}", program, arg),
+            _ => (),
+        }
+    }
+
+    program
+}
+
+/// Whether an archive entry stays under the directory it is extracted to:
+/// relative, with no `..` component.
+pub(crate) fn is_contained_path(path: &str) -> bool {
+    !path.starts_with('/') && !path.split('/').any(|part| part == "..")
+}
+
+/// Total size of the files under `path`, symlinks not followed.
+fn dir_size(path: &std::path::Path) -> u64 {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Builds a git command for operations that talk to the remote. When the
+/// branch has credentials, they are handed to git through a one-off
+/// credential helper reading from the process environment, so the token
+/// never shows up in the command line, the remote URL or the logs. The
+/// proxy is passed the same way, as it may carry credentials too.
+/// Branches delegating to credential helpers never prompt: a helper
+/// without an answer fails the command instead of blocking the listener.
+pub(crate) fn git_remote_command(
+    proxy: Option<&str>,
+    credentials: Option<&BranchCredentials>,
+) -> Result<Command, BranchHandlerError> {
+    let mut command = Command::new("git");
+
+    // Passed in the environment so it stays out of the process list.
+    if let Some(proxy) = proxy {
+        add_env_config(&mut command, "http.proxy", proxy);
+    }
+
+    match credentials {
+        Some(BranchCredentials::Helper {
+            helper,
+            use_http_path,
+        }) => {
+            debug!(
+                "Using git credential helper {}",
+                helper.as_deref().unwrap_or("from the git config")
+            );
+
+            if let Some(helper) = helper {
+                command
+                    .arg("-c")
+                    .arg("credential.helper=")
+                    .arg("-c")
+                    .arg(format!("credential.helper={}", helper));
+            }
+
+            if *use_http_path {
+                command.arg("-c").arg("credential.useHttpPath=true");
+            }
+
+            command.env("GIT_TERMINAL_PROMPT", "0");
+        }
+        Some(credentials) => {
+            let token = match credentials.get_token() {
+                Some(token) => token,
+                None => {
+                    let env = match credentials {
+                        BranchCredentials::TokenEnv { token_env, .. } => token_env.clone(),
+                        _ => "".to_string(),
+                    };
+                    return Err(BranchHandlerError::MissingCredentials(env));
+                }
+            };
+            let username = credentials.get_username().unwrap_or_default();
+
+            debug!("Using credentials for user {}", username);
+
+            command
+                .arg("-c")
+                .arg("credential.helper=")
+                .arg("-c")
+                .arg(
+                    "credential.helper=!f() { echo \"username=${GITDIS_GIT_USERNAME}\"; echo \"password=${GITDIS_GIT_TOKEN}\"; }; f",
+                )
+                .env("GITDIS_GIT_USERNAME", username)
+                .env("GITDIS_GIT_TOKEN", token)
+                .env("GIT_TERMINAL_PROMPT", "0");
+        }
+        None => (),
+    }
+
+    Ok(command)
+}
+
+/// Adds a config entry to the ones `command` passes git through
+/// `GIT_CONFIG_COUNT`, its own or else those of the environment.
+pub(crate) fn add_env_config(command: &mut Command, key: &str, value: &str) {
+    let count = command
+        .get_envs()
+        .find(|(name, _)| *name == "GIT_CONFIG_COUNT")
+        .map(|(_, count)| count.map(|count| count.to_os_string()))
+        .unwrap_or_else(|| std::env::var_os("GIT_CONFIG_COUNT"));
+    let index = count
+        .and_then(|count| count.to_str().and_then(|count| count.parse::<usize>().ok()))
+        .unwrap_or(0);
+
+    command
+        .env("GIT_CONFIG_COUNT", (index + 1).to_string())
+        .env(format!("GIT_CONFIG_KEY_{}", index), key)
+        .env(format!("GIT_CONFIG_VALUE_{}", index), value);
+}
+
+fn is_valid_file(path: &str) -> bool {
+    EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+fn is_ignored(ignore: &[String], filter: &FileFilter, repo_path: &str, path: &str) -> bool {
+    ignore.iter().any(|ignore| path.contains(ignore.as_str()))
+        || filter.is_excluded(get_repo_relative_path(repo_path, path))
+}
+
+fn get_repo_relative_path<'a>(repo_path: &str, path: &'a str) -> &'a str {
+    path.strip_prefix(repo_path)
+        .map(|path| path.trim_start_matches('/'))
+        .unwrap_or(path)
+}
+
+/// Names of the branches of the remote at `url`, as `git ls-remote --heads`
+/// lists them.
+pub fn list_remote_branches(
+    url: &str,
+    proxy: Option<&str>,
+    credentials: Option<&BranchCredentials>,
+    runner: &dyn ProcessRunner,
+    timeout: std::time::Duration,
+) -> Result<Vec<String>, BranchHandlerError> {
+    let mut command = git_remote_command(proxy, credentials)?;
+    command
+        .arg("ls-remote")
+        .arg("--heads")
+        .arg(resolve_repo_url(url));
+
+    let output = run_with_timeout(runner, &mut command, timeout)?;
+
+    if !output.status.success() {
+        let code = output.status.code();
+        let error = String::from_utf8_lossy(&output.stderr);
+        return Err(BranchHandlerError::GitError((code, error.to_string())));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter_map(|(_, reference)| reference.strip_prefix("refs/heads/"))
+        .map(String::from)
+        .collect())
 }
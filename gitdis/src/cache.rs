@@ -1,2 +1,57 @@
 use quickleaf::Cache;
-pub type ArcCache = std::sync::Arc<std::sync::RwLock<Cache>>;
+pub type ArcCache = std::sync::Arc<crate::sync::RwLock<Cache>>;
+pub type ArcBranchErrors =
+    std::sync::Arc<std::sync::RwLock<std::collections::HashMap<String, String>>>;
+pub type ArcSyncReceiver =
+    std::sync::Arc<std::sync::Mutex<std::sync::mpsc::Receiver<crate::gitdis::SyncSignal>>>;
+pub type ArcSyncFailures = std::sync::Arc<std::sync::RwLock<crate::retry::SyncFailures>>;
+pub type ArcBranchStatus = std::sync::Arc<std::sync::RwLock<crate::status::BranchStatus>>;
+pub type ArcBranchHistory = std::sync::Arc<std::sync::RwLock<crate::history::BranchHistory>>;
+pub type ArcKeyVersions = std::sync::Arc<std::sync::RwLock<crate::versions::KeyVersions>>;
+/// Key -> owners of its file, from CODEOWNERS. Keys without owners are
+/// left out.
+pub type ArcKeyOwners =
+    std::sync::Arc<std::sync::RwLock<std::collections::HashMap<String, Vec<String>>>>;
+pub type ArcLintReport = std::sync::Arc<std::sync::RwLock<Option<crate::lint::LintReport>>>;
+/// Key -> the commit that last touched its file.
+pub type ArcKeyMetadata = std::sync::Arc<
+    std::sync::RwLock<
+        std::collections::HashMap<String, std::sync::Arc<crate::metadata::CommitMetadata>>,
+    >,
+>;
+
+/// Entries whose key starts with `prefix`, in key order.
+pub(crate) fn list_prefix(
+    cache: &Cache,
+    prefix: &str,
+) -> Vec<(String, quickleaf::valu3::prelude::Value)> {
+    let props = quickleaf::ListProps::default()
+        .order(quickleaf::Order::Asc)
+        .filter(quickleaf::Filter::StartWith(prefix.to_string()));
+
+    match cache.list(props) {
+        Ok(entries) => entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Like `list_prefix`, without copying the values.
+pub(crate) fn list_refs<'a>(
+    cache: &'a Cache,
+    prefix: &str,
+) -> Vec<(String, &'a quickleaf::valu3::prelude::Value)> {
+    let props = quickleaf::ListProps::default()
+        .order(quickleaf::Order::Asc)
+        .filter(quickleaf::Filter::StartWith(prefix.to_string()));
+
+    match cache.list(props) {
+        Ok(entries) => entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where branch handlers read the time and wait for retries, so time-based
+/// logic can be driven by a `ManualClock` in tests instead of real sleeps.
+pub trait Clock: Send + Sync {
+    /// Wall-clock time, in milliseconds since the epoch.
+    fn now_millis(&self) -> u128;
+    /// Monotonic time, for intervals.
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// A clock that only moves when told to. Sleeping advances it at once.
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    start_millis: u128,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// Starts at `start_millis` since the epoch.
+    pub fn new(start_millis: u128) -> Self {
+        Self {
+            start: Instant::now(),
+            start_millis,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        if let Ok(mut elapsed) = self.elapsed.lock() {
+            *elapsed += duration;
+        }
+    }
+
+    /// Time advanced since the start, sleeps included.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+            .lock()
+            .map(|elapsed| *elapsed)
+            .unwrap_or_default()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u128 {
+        self.start_millis + self.elapsed().as_millis()
+    }
+
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
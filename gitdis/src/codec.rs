@@ -0,0 +1,683 @@
+//! Wire formats of dumps and replication frames. Every frame starts with a
+//! one line header naming the layout version and the codec of its payload,
+//! `gitdis-wire/1 msgpack\n`, so a reader can pick the codec without being
+//! told and refuse layouts newer than it knows. Files written before the
+//! header existed are read as JSON.
+use crate::dump::DumpDiff;
+use quickleaf::valu3::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+const HEADER_PREFIX: &str = "gitdis-wire/";
+/// Longest header read before giving up on finding its end.
+const MAX_HEADER_LEN: usize = 64;
+/// Version of the frame layout written. Older versions are still read.
+pub const WIRE_VERSION: u32 = 1;
+pub const JSON_CODEC: &str = "json";
+#[cfg(feature = "bincode")]
+pub const BINCODE_CODEC: &str = "bincode";
+#[cfg(feature = "msgpack")]
+pub const MSGPACK_CODEC: &str = "msgpack";
+#[cfg(feature = "protobuf")]
+pub const PROTOBUF_CODEC: &str = "protobuf";
+
+const BRANCH_FIELD: &str = "branch";
+const FROM_FIELD: &str = "from";
+const TO_FIELD: &str = "to";
+const SET_FIELD: &str = "set";
+const REMOVED_FIELD: &str = "removed";
+const CLEARED_FIELD: &str = "cleared";
+
+#[derive(Debug, PartialEq)]
+pub enum CodecError {
+    UnknownCodec(String),
+    UnsupportedVersion(u32),
+    Encode(String),
+    Decode(String),
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CodecError::UnknownCodec(name) => write!(f, "Unknown codec: {}", name),
+            CodecError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported wire version: {}", version)
+            }
+            CodecError::Encode(error) => write!(f, "Encode error: {}", error),
+            CodecError::Decode(error) => write!(f, "Decode error: {}", error),
+        }
+    }
+}
+
+/// The changes of a branch between two sequence numbers, as sent to a
+/// replica or written to a dump.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WireFrame {
+    pub branch_key: String,
+    pub diff: DumpDiff,
+}
+
+/// A value in a form serde codecs can write. Numbers keep their integer
+/// form when they have one.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WireValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String),
+    Array(Vec<WireValue>),
+    Object(Vec<(String, WireValue)>),
+}
+
+impl From<&Value> for WireValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null | Value::Undefined => WireValue::Null,
+            Value::Boolean(boolean) => WireValue::Bool(*boolean),
+            Value::Number(number) => {
+                let number = number.to_string();
+
+                match number.parse::<i64>() {
+                    Ok(integer) => WireValue::Int(integer),
+                    Err(_) => match number.parse::<u64>() {
+                        Ok(integer) => WireValue::UInt(integer),
+                        Err(_) => WireValue::Float(number.parse().unwrap_or(f64::NAN)),
+                    },
+                }
+            }
+            Value::String(string) => WireValue::String(string.to_string()),
+            Value::Array(array) => WireValue::Array(array.values.iter().map(Self::from).collect()),
+            Value::Object(object) => WireValue::Object(
+                object
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), Self::from(value)))
+                    .collect(),
+            ),
+            other => WireValue::String(other.to_string()),
+        }
+    }
+}
+
+impl From<WireValue> for Value {
+    fn from(value: WireValue) -> Self {
+        match value {
+            WireValue::Null => Value::Null,
+            WireValue::Bool(boolean) => Value::from(boolean),
+            WireValue::Int(integer) => Value::from(integer),
+            WireValue::UInt(integer) => Value::from(integer),
+            WireValue::Float(float) => Value::from(float),
+            WireValue::String(string) => Value::from(string),
+            WireValue::Array(values) => {
+                Value::from(values.into_iter().map(Value::from).collect::<Vec<_>>())
+            }
+            WireValue::Object(entries) => Value::from(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, Value::from(value)))
+                    .collect::<BTreeMap<String, Value>>(),
+            ),
+        }
+    }
+}
+
+/// A frame in the shape serde codecs write.
+#[derive(Debug, Serialize, Deserialize)]
+struct SerdeFrame {
+    branch_key: String,
+    from: u64,
+    to: u64,
+    cleared: bool,
+    set: Vec<(String, WireValue)>,
+    removed: Vec<String>,
+}
+
+impl From<&WireFrame> for SerdeFrame {
+    fn from(frame: &WireFrame) -> Self {
+        Self {
+            branch_key: frame.branch_key.clone(),
+            from: frame.diff.from,
+            to: frame.diff.to,
+            cleared: frame.diff.cleared,
+            set: frame
+                .diff
+                .set
+                .iter()
+                .map(|(key, value)| (key.clone(), WireValue::from(value)))
+                .collect(),
+            removed: frame.diff.removed.clone(),
+        }
+    }
+}
+
+impl From<SerdeFrame> for WireFrame {
+    fn from(frame: SerdeFrame) -> Self {
+        Self {
+            branch_key: frame.branch_key,
+            diff: DumpDiff {
+                from: frame.from,
+                to: frame.to,
+                cleared: frame.cleared,
+                set: frame
+                    .set
+                    .into_iter()
+                    .map(|(key, value)| (key, Value::from(value)))
+                    .collect(),
+                removed: frame.removed,
+            },
+        }
+    }
+}
+
+/// Turns frames into payloads and back. The header is handled by `Codecs`,
+/// so a codec only sees its own payload.
+pub trait Codec: Send + Sync {
+    /// Name written in headers and offered when negotiating.
+    fn name(&self) -> &str;
+
+    /// Extension of dump files written with the codec.
+    fn extension(&self) -> &str;
+
+    fn encode(&self, frame: &WireFrame) -> Result<Vec<u8>, CodecError>;
+
+    fn decode(&self, payload: &[u8]) -> Result<WireFrame, CodecError>;
+}
+
+/// Plain JSON, readable by anything. The default.
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &str {
+        JSON_CODEC
+    }
+
+    fn extension(&self) -> &str {
+        "json"
+    }
+
+    fn encode(&self, frame: &WireFrame) -> Result<Vec<u8>, CodecError> {
+        let mut object = serde_json::Map::new();
+
+        object.insert(BRANCH_FIELD.to_string(), frame.branch_key.clone().into());
+        object.insert(FROM_FIELD.to_string(), frame.diff.from.into());
+        object.insert(TO_FIELD.to_string(), frame.diff.to.into());
+        object.insert(
+            SET_FIELD.to_string(),
+            serde_json::Value::Object(
+                frame
+                    .diff
+                    .set
+                    .iter()
+                    .map(|(key, value)| (key.clone(), to_json(WireValue::from(value))))
+                    .collect(),
+            ),
+        );
+        object.insert(REMOVED_FIELD.to_string(), frame.diff.removed.clone().into());
+
+        if frame.diff.cleared {
+            object.insert(CLEARED_FIELD.to_string(), true.into());
+        }
+
+        serde_json::to_vec(&object).map_err(|err| CodecError::Encode(err.to_string()))
+    }
+
+    /// Fields missing from dumps written before frames carried them are
+    /// left empty.
+    fn decode(&self, payload: &[u8]) -> Result<WireFrame, CodecError> {
+        let mut object = match serde_json::from_slice(payload) {
+            Ok(serde_json::Value::Object(object)) => object,
+            Ok(_) => return Err(CodecError::Decode("Frame is not an object".to_string())),
+            Err(err) => return Err(CodecError::Decode(err.to_string())),
+        };
+
+        let set = match object.remove(SET_FIELD) {
+            Some(serde_json::Value::Object(set)) => set
+                .into_iter()
+                .map(|(key, value)| (key, Value::from(from_json(value))))
+                .collect(),
+            _ => return Err(CodecError::Decode(format!("Missing field: {}", SET_FIELD))),
+        };
+
+        let removed = match object.remove(REMOVED_FIELD) {
+            Some(serde_json::Value::Array(removed)) => removed
+                .into_iter()
+                .filter_map(|key| match key {
+                    serde_json::Value::String(key) => Some(key),
+                    _ => None,
+                })
+                .collect(),
+            _ => {
+                return Err(CodecError::Decode(format!(
+                    "Missing field: {}",
+                    REMOVED_FIELD
+                )))
+            }
+        };
+
+        let sequence = |field: &str| object.get(field).and_then(|value| value.as_u64());
+
+        Ok(WireFrame {
+            branch_key: object
+                .get(BRANCH_FIELD)
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            diff: DumpDiff {
+                from: sequence(FROM_FIELD).unwrap_or(0),
+                to: sequence(TO_FIELD).unwrap_or(0),
+                cleared: object.contains_key(CLEARED_FIELD),
+                set,
+                removed,
+            },
+        })
+    }
+}
+
+fn to_json(value: WireValue) -> serde_json::Value {
+    match value {
+        WireValue::Null => serde_json::Value::Null,
+        WireValue::Bool(boolean) => boolean.into(),
+        WireValue::Int(integer) => integer.into(),
+        WireValue::UInt(integer) => integer.into(),
+        WireValue::Float(float) => serde_json::Number::from_f64(float)
+            .map_or(serde_json::Value::Null, serde_json::Value::Number),
+        WireValue::String(string) => string.into(),
+        WireValue::Array(values) => {
+            serde_json::Value::Array(values.into_iter().map(to_json).collect())
+        }
+        WireValue::Object(entries) => serde_json::Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key, to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+fn from_json(value: serde_json::Value) -> WireValue {
+    match value {
+        serde_json::Value::Null => WireValue::Null,
+        serde_json::Value::Bool(boolean) => WireValue::Bool(boolean),
+        serde_json::Value::Number(number) => {
+            if let Some(integer) = number.as_i64() {
+                WireValue::Int(integer)
+            } else if let Some(integer) = number.as_u64() {
+                WireValue::UInt(integer)
+            } else {
+                WireValue::Float(number.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        serde_json::Value::String(string) => WireValue::String(string),
+        serde_json::Value::Array(values) => {
+            WireValue::Array(values.into_iter().map(from_json).collect())
+        }
+        serde_json::Value::Object(entries) => WireValue::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key, from_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Compact, for nodes built from this crate only.
+#[cfg(feature = "bincode")]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl Codec for BincodeCodec {
+    fn name(&self) -> &str {
+        BINCODE_CODEC
+    }
+
+    fn extension(&self) -> &str {
+        "bin"
+    }
+
+    fn encode(&self, frame: &WireFrame) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(&SerdeFrame::from(frame))
+            .map_err(|err| CodecError::Encode(err.to_string()))
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<WireFrame, CodecError> {
+        bincode::deserialize::<SerdeFrame>(payload)
+            .map(WireFrame::from)
+            .map_err(|err| CodecError::Decode(err.to_string()))
+    }
+}
+
+/// Compact and self-describing, with readers in most languages. Values
+/// are written as `WireValue`, with field names.
+#[cfg(feature = "msgpack")]
+pub struct MsgpackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgpackCodec {
+    fn name(&self) -> &str {
+        MSGPACK_CODEC
+    }
+
+    fn extension(&self) -> &str {
+        "msgpack"
+    }
+
+    fn encode(&self, frame: &WireFrame) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec_named(&SerdeFrame::from(frame))
+            .map_err(|err| CodecError::Encode(err.to_string()))
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<WireFrame, CodecError> {
+        rmp_serde::from_slice::<SerdeFrame>(payload)
+            .map(WireFrame::from)
+            .map_err(|err| CodecError::Decode(err.to_string()))
+    }
+}
+
+/// Protocol buffers, with this schema:
+///
+/// ```text
+/// message Frame {
+///   string branch_key = 1;
+///   uint64 from = 2;
+///   uint64 to = 3;
+///   bool cleared = 4;
+///   repeated Entry set = 5;
+///   repeated string removed = 6;
+/// }
+/// message Entry { string key = 1; Value value = 2; }
+/// message List { repeated Value values = 1; }
+/// message Map { repeated Entry entries = 1; }
+/// message Value {
+///   oneof kind {
+///     bool bool = 1; sint64 int = 2; uint64 uint = 3; double float = 4;
+///     string string = 5; List array = 6; Map object = 7;
+///   }
+/// }
+/// ```
+///
+/// A `Value` without a kind is null.
+#[cfg(feature = "protobuf")]
+pub struct ProtobufCodec;
+
+#[cfg(feature = "protobuf")]
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Frame {
+        #[prost(string, tag = "1")]
+        pub branch_key: String,
+        #[prost(uint64, tag = "2")]
+        pub from: u64,
+        #[prost(uint64, tag = "3")]
+        pub to: u64,
+        #[prost(bool, tag = "4")]
+        pub cleared: bool,
+        #[prost(message, repeated, tag = "5")]
+        pub set: Vec<Entry>,
+        #[prost(string, repeated, tag = "6")]
+        pub removed: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Entry {
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(message, optional, tag = "2")]
+        pub value: Option<Value>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct List {
+        #[prost(message, repeated, tag = "1")]
+        pub values: Vec<Value>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Map {
+        #[prost(message, repeated, tag = "1")]
+        pub entries: Vec<Entry>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Value {
+        #[prost(oneof = "Kind", tags = "1, 2, 3, 4, 5, 6, 7")]
+        pub kind: Option<Kind>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Kind {
+        #[prost(bool, tag = "1")]
+        Bool(bool),
+        #[prost(sint64, tag = "2")]
+        Int(i64),
+        #[prost(uint64, tag = "3")]
+        UInt(u64),
+        #[prost(double, tag = "4")]
+        Float(f64),
+        #[prost(string, tag = "5")]
+        String(String),
+        #[prost(message, tag = "6")]
+        Array(List),
+        #[prost(message, tag = "7")]
+        Object(Map),
+    }
+
+    pub fn to_proto(value: super::WireValue) -> Value {
+        use super::WireValue;
+
+        let kind = match value {
+            WireValue::Null => None,
+            WireValue::Bool(boolean) => Some(Kind::Bool(boolean)),
+            WireValue::Int(integer) => Some(Kind::Int(integer)),
+            WireValue::UInt(integer) => Some(Kind::UInt(integer)),
+            WireValue::Float(float) => Some(Kind::Float(float)),
+            WireValue::String(string) => Some(Kind::String(string)),
+            WireValue::Array(values) => Some(Kind::Array(List {
+                values: values.into_iter().map(to_proto).collect(),
+            })),
+            WireValue::Object(entries) => Some(Kind::Object(Map {
+                entries: entries
+                    .into_iter()
+                    .map(|(key, value)| Entry {
+                        key,
+                        value: Some(to_proto(value)),
+                    })
+                    .collect(),
+            })),
+        };
+
+        Value { kind }
+    }
+
+    pub fn from_proto(value: Option<Value>) -> super::WireValue {
+        use super::WireValue;
+
+        match value.and_then(|value| value.kind) {
+            None => WireValue::Null,
+            Some(Kind::Bool(boolean)) => WireValue::Bool(boolean),
+            Some(Kind::Int(integer)) => WireValue::Int(integer),
+            Some(Kind::UInt(integer)) => WireValue::UInt(integer),
+            Some(Kind::Float(float)) => WireValue::Float(float),
+            Some(Kind::String(string)) => WireValue::String(string),
+            Some(Kind::Array(list)) => WireValue::Array(
+                list.values
+                    .into_iter()
+                    .map(|value| from_proto(Some(value)))
+                    .collect(),
+            ),
+            Some(Kind::Object(map)) => WireValue::Object(
+                map.entries
+                    .into_iter()
+                    .map(|entry| (entry.key, from_proto(entry.value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "protobuf")]
+impl Codec for ProtobufCodec {
+    fn name(&self) -> &str {
+        PROTOBUF_CODEC
+    }
+
+    fn extension(&self) -> &str {
+        "pb"
+    }
+
+    fn encode(&self, frame: &WireFrame) -> Result<Vec<u8>, CodecError> {
+        let frame = SerdeFrame::from(frame);
+
+        Ok(prost::Message::encode_to_vec(&proto::Frame {
+            branch_key: frame.branch_key,
+            from: frame.from,
+            to: frame.to,
+            cleared: frame.cleared,
+            set: frame
+                .set
+                .into_iter()
+                .map(|(key, value)| proto::Entry {
+                    key,
+                    value: Some(proto::to_proto(value)),
+                })
+                .collect(),
+            removed: frame.removed,
+        }))
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<WireFrame, CodecError> {
+        let frame = <proto::Frame as prost::Message>::decode(payload)
+            .map_err(|err| CodecError::Decode(err.to_string()))?;
+
+        Ok(WireFrame::from(SerdeFrame {
+            branch_key: frame.branch_key,
+            from: frame.from,
+            to: frame.to,
+            cleared: frame.cleared,
+            set: frame
+                .set
+                .into_iter()
+                .map(|entry| (entry.key, proto::from_proto(entry.value)))
+                .collect(),
+            removed: frame.removed,
+        }))
+    }
+}
+
+/// The codecs a node can read and write, by name. Starts with the built-in
+/// ones compiled in; others can be registered.
+#[derive(Clone)]
+pub struct Codecs {
+    codecs: HashMap<String, Arc<dyn Codec>>,
+}
+
+impl Default for Codecs {
+    fn default() -> Self {
+        let mut codecs = Self {
+            codecs: HashMap::new(),
+        };
+
+        codecs.register(Arc::new(JsonCodec));
+        #[cfg(feature = "bincode")]
+        codecs.register(Arc::new(BincodeCodec));
+        #[cfg(feature = "msgpack")]
+        codecs.register(Arc::new(MsgpackCodec));
+        #[cfg(feature = "protobuf")]
+        codecs.register(Arc::new(ProtobufCodec));
+
+        codecs
+    }
+}
+
+impl Codecs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `codec`, replacing one with the same name.
+    pub fn register(&mut self, codec: Arc<dyn Codec>) {
+        self.codecs.insert(codec.name().to_string(), codec);
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<dyn Codec>, CodecError> {
+        self.codecs
+            .get(name)
+            .cloned()
+            .ok_or(CodecError::UnknownCodec(name.to_string()))
+    }
+
+    pub fn get_names(&self) -> Vec<String> {
+        let mut names = self.codecs.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// The first of `offered`, in the peer's order of preference, this node
+    /// also has.
+    pub fn negotiate<S: AsRef<str>>(&self, offered: &[S]) -> Option<Arc<dyn Codec>> {
+        offered
+            .iter()
+            .find_map(|name| self.codecs.get(name.as_ref().trim()).cloned())
+    }
+
+    /// `frame` with a header naming `codec`.
+    pub fn encode(&self, codec: &str, frame: &WireFrame) -> Result<Vec<u8>, CodecError> {
+        let codec = self.get(codec)?;
+        let mut bytes =
+            format!("{}{} {}\n", HEADER_PREFIX, WIRE_VERSION, codec.name()).into_bytes();
+
+        bytes.extend(codec.encode(frame)?);
+
+        Ok(bytes)
+    }
+
+    /// Reads a frame with the codec its header names, or as JSON without a
+    /// header.
+    pub fn decode(&self, bytes: &[u8]) -> Result<WireFrame, CodecError> {
+        let header = match read_header(bytes)? {
+            Some(header) => header,
+            None => return JsonCodec.decode(bytes),
+        };
+
+        if header.version > WIRE_VERSION {
+            return Err(CodecError::UnsupportedVersion(header.version));
+        }
+
+        self.get(header.codec)?.decode(&bytes[header.len..])
+    }
+}
+
+struct Header<'a> {
+    version: u32,
+    codec: &'a str,
+    /// Bytes up to the payload.
+    len: usize,
+}
+
+fn read_header(bytes: &[u8]) -> Result<Option<Header<'_>>, CodecError> {
+    if !bytes.starts_with(HEADER_PREFIX.as_bytes()) {
+        return Ok(None);
+    }
+
+    let end = bytes
+        .iter()
+        .take(MAX_HEADER_LEN)
+        .position(|byte| *byte == b'\n')
+        .ok_or(CodecError::Decode("Unterminated header".to_string()))?;
+
+    let header = std::str::from_utf8(&bytes[HEADER_PREFIX.len()..end])
+        .map_err(|err| CodecError::Decode(err.to_string()))?;
+
+    match header.split_once(' ') {
+        Some((version, name)) => match version.parse() {
+            Ok(version) => Ok(Some(Header {
+                version,
+                codec: name,
+                len: end + 1,
+            })),
+            Err(_) => Err(CodecError::Decode(format!("Invalid header: {}", header))),
+        },
+        None => Err(CodecError::Decode(format!("Invalid header: {}", header))),
+    }
+}
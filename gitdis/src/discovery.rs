@@ -0,0 +1,125 @@
+use crate::gitdis::BranchSettings;
+use globset::{GlobBuilder, GlobMatcher};
+use quickleaf::valu3::prelude::*;
+use std::collections::{BTreeSet, HashMap};
+
+/// Whether `branch_name` is a pattern rather than a branch, i.e. holds a
+/// glob metacharacter. Git does not allow them in branch names.
+pub fn is_branch_pattern(branch_name: &str) -> bool {
+    branch_name.contains(['*', '?', '[', '{'])
+}
+
+/// A repo registered with a branch pattern, e.g. `release/*`, instead of a
+/// branch. Upstream branches matching it are registered as they appear and
+/// unregistered once they are gone. `*` stops at `/`, `**` does not.
+#[derive(Clone, Debug)]
+pub struct BranchDiscovery {
+    /// Settings every discovered branch is registered with; `branch_name`
+    /// holds the pattern.
+    pub settings: BranchSettings,
+    matcher: GlobMatcher,
+    /// Repo keys of the branches registered for the pattern.
+    branches: BTreeSet<String>,
+}
+
+impl BranchDiscovery {
+    pub fn new(settings: BranchSettings) -> Result<Self, String> {
+        let matcher = GlobBuilder::new(&settings.branch_name)
+            .literal_separator(true)
+            .build()
+            .map_err(|err| err.to_string())?
+            .compile_matcher();
+
+        Ok(Self {
+            settings,
+            matcher,
+            branches: BTreeSet::new(),
+        })
+    }
+
+    /// `owner/repo/pattern`, the key the discovery is registered under.
+    pub fn get_key(&self) -> String {
+        self.settings.get_repo_key()
+    }
+
+    pub fn is_match(&self, branch_name: &str) -> bool {
+        self.matcher.is_match(branch_name)
+    }
+
+    /// Repo keys of the branches registered for the pattern, sorted.
+    pub fn get_branches(&self) -> Vec<String> {
+        self.branches.iter().cloned().collect()
+    }
+
+    pub fn contains(&self, repo_key: &str) -> bool {
+        self.branches.contains(repo_key)
+    }
+
+    /// The settings `branch_name` is registered with.
+    pub fn get_branch_settings(&self, branch_name: &str) -> BranchSettings {
+        BranchSettings {
+            branch_name: branch_name.to_string(),
+            ..self.settings.clone()
+        }
+    }
+
+    pub(crate) fn insert(&mut self, repo_key: String) {
+        self.branches.insert(repo_key);
+    }
+
+    pub(crate) fn remove(&mut self, repo_key: &str) {
+        self.branches.remove(repo_key);
+    }
+}
+
+/// What one discovery pass changed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiscoveryReport {
+    /// Key of the discovery, `owner/repo/pattern`.
+    pub key: String,
+    /// Repo keys of the branches registered and listened to.
+    pub added: Vec<String>,
+    /// Repo keys of the branches gone upstream and unregistered. Their
+    /// clones are kept until the clones are reconciled.
+    pub removed: Vec<String>,
+    /// Matching branches that could not be registered, e.g. as they were
+    /// already registered on their own, and why.
+    pub skipped: HashMap<String, String>,
+    /// Why the branches upstream could not be listed, in which case
+    /// nothing changed.
+    pub error: Option<String>,
+}
+
+impl DiscoveryReport {
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert("key".to_string(), Value::from(self.key.as_str()));
+        object.insert("added".to_string(), strings_to_value(&self.added));
+        object.insert("removed".to_string(), strings_to_value(&self.removed));
+        object.insert(
+            "skipped".to_string(),
+            Value::from(
+                self.skipped
+                    .iter()
+                    .map(|(key, reason)| (key.clone(), Value::from(reason.as_str())))
+                    .collect::<HashMap<String, Value>>(),
+            ),
+        );
+
+        if let Some(error) = &self.error {
+            object.insert("error".to_string(), Value::from(error.as_str()));
+        }
+
+        Value::from(object)
+    }
+}
+
+fn strings_to_value(strings: &[String]) -> Value {
+    Value::from(
+        strings
+            .iter()
+            .map(|string| Value::from(string.as_str()))
+            .collect::<Vec<Value>>(),
+    )
+}
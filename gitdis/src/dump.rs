@@ -0,0 +1,408 @@
+use crate::codec::{Codec, Codecs, JsonCodec, WireFrame};
+use crate::events::{BranchEvent, BranchEventKind};
+use log::debug;
+use quickleaf::valu3::prelude::*;
+use quickleaf::Event;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+const BASE_PREFIX: &str = "base-";
+const DIFF_PREFIX: &str = "diff-";
+const TEMP_EXT: &str = ".tmp";
+
+#[derive(Debug, PartialEq)]
+pub enum DumpError {
+    Io(String),
+    Parse(String),
+    /// A diff does not start where the previous file ended.
+    BrokenChain(String),
+    NoBase(String),
+    Codec(String),
+}
+
+impl std::fmt::Display for DumpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DumpError::Io(error) => write!(f, "Dump I/O error: {}", error),
+            DumpError::Parse(file) => write!(f, "Invalid dump file: {}", file),
+            DumpError::BrokenChain(file) => write!(f, "Dump chain is broken at: {}", file),
+            DumpError::NoBase(branch_key) => write!(f, "No base dump for branch: {}", branch_key),
+            DumpError::Codec(error) => write!(f, "Dump codec error: {}", error),
+        }
+    }
+}
+
+/// The changes of a branch between two sequence numbers. A base dump is a
+/// diff starting from an empty branch.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DumpDiff {
+    pub from: u64,
+    pub to: u64,
+    pub cleared: bool,
+    pub set: BTreeMap<String, Value>,
+    pub removed: Vec<String>,
+}
+
+impl DumpDiff {
+    pub fn is_empty(&self) -> bool {
+        !self.cleared && self.set.is_empty() && self.removed.is_empty()
+    }
+
+    /// Applies the diff on top of `entries`.
+    pub fn apply(&self, entries: &mut BTreeMap<String, Value>) {
+        if self.cleared {
+            entries.clear();
+        }
+
+        for key in &self.removed {
+            entries.remove(key);
+        }
+
+        for (key, value) in &self.set {
+            entries.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+#[derive(Default)]
+struct BranchChanges {
+    /// Key -> sequence of its last change and its value, `None` if removed.
+    keys: HashMap<String, (u64, Option<Value>)>,
+    cleared_at: Option<u64>,
+    last_sequence: u64,
+}
+
+/// Keeps the latest change of every key from the event hub, so a diff can
+/// be cut between any two sequence numbers without reading the whole branch.
+#[derive(Default)]
+pub struct DumpRecorder {
+    branches: RwLock<HashMap<String, BranchChanges>>,
+}
+
+impl DumpRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, event: &BranchEvent) {
+        let mut branches = match self.branches.write() {
+            Ok(branches) => branches,
+            Err(_) => return,
+        };
+
+        let changes = branches.entry(event.branch_key.clone()).or_default();
+        changes.last_sequence = changes.last_sequence.max(event.sequence);
+
+        match &event.event {
+            BranchEventKind::Cache(Event::Insert(data)) => {
+                changes
+                    .keys
+                    .insert(data.key.clone(), (event.sequence, Some(data.value.clone())));
+            }
+            BranchEventKind::Cache(Event::Remove(data)) => {
+                changes
+                    .keys
+                    .insert(data.key.clone(), (event.sequence, None));
+            }
+            // Dumps mirror the cache, so evicted keys are left out as well.
+            BranchEventKind::Evicted { key, .. } => {
+                changes.keys.insert(key.clone(), (event.sequence, None));
+            }
+            BranchEventKind::Cache(Event::Clear) => {
+                changes.keys.clear();
+                changes.cleared_at = Some(event.sequence);
+            }
+            BranchEventKind::Rejected { .. }
+            | BranchEventKind::Invalid { .. }
+            | BranchEventKind::ParseError { .. }
+            | BranchEventKind::Dirty { .. }
+            | BranchEventKind::Archived { .. }
+            | BranchEventKind::Restored
+            | BranchEventKind::Removed
+            | BranchEventKind::SyncCompleted { .. }
+            | BranchEventKind::SyncFailed { .. } => (),
+        }
+    }
+
+    /// Sequence of the last change recorded for `branch_key`.
+    pub fn get_sequence(&self, branch_key: &str) -> u64 {
+        match self.branches.read() {
+            Ok(branches) => branches
+                .get(branch_key)
+                .map(|changes| changes.last_sequence)
+                .unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+
+    /// Changes of `branch_key` after `from`, up to the last one recorded.
+    /// Changes up to `from` are forgotten.
+    pub fn take_diff(&self, branch_key: &str, from: u64) -> DumpDiff {
+        let mut branches = match self.branches.write() {
+            Ok(branches) => branches,
+            Err(_) => return DumpDiff::default(),
+        };
+
+        let changes = branches.entry(branch_key.to_string()).or_default();
+        let to = changes.last_sequence.max(from);
+        let mut diff = DumpDiff {
+            from,
+            to,
+            cleared: changes.cleared_at.is_some_and(|sequence| sequence > from),
+            ..DumpDiff::default()
+        };
+
+        for (key, (sequence, value)) in &changes.keys {
+            if *sequence <= from {
+                continue;
+            }
+
+            match value {
+                Some(value) => {
+                    diff.set.insert(key.clone(), value.clone());
+                }
+                None => diff.removed.push(key.clone()),
+            }
+        }
+
+        diff.removed.sort();
+
+        changes.keys.retain(|_, (sequence, _)| *sequence > to);
+        changes.cleared_at = changes.cleared_at.filter(|sequence| *sequence > to);
+
+        diff
+    }
+}
+
+/// Base and diff dumps of branches under a directory, one subdirectory per
+/// branch. File names carry the sequence range they cover, so a chain is a
+/// base followed by diffs each starting where the previous one ended.
+/// Files are written with one codec but read with the one their header
+/// names, so the codec can change without compacting first.
+pub struct DumpStore {
+    path: String,
+    recorder: DumpRecorder,
+    codecs: Codecs,
+    codec: Arc<dyn Codec>,
+}
+
+impl DumpStore {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            recorder: DumpRecorder::new(),
+            codecs: Codecs::new(),
+            codec: Arc::new(JsonCodec),
+        }
+    }
+
+    /// Writes new files with `codec`, JSON by default.
+    pub fn with_codec(mut self, codec: Arc<dyn Codec>) -> Self {
+        self.codecs.register(codec.clone());
+        self.codec = codec;
+        self
+    }
+
+    /// Reads files written with any of `codecs`.
+    pub fn with_codecs(mut self, codecs: Codecs) -> Self {
+        self.codecs = codecs;
+        self.codecs.register(self.codec.clone());
+        self
+    }
+
+    pub fn get_recorder(&self) -> &DumpRecorder {
+        &self.recorder
+    }
+
+    fn branch_dir(&self, branch_key: &str) -> String {
+        format!("{}/{}", self.path, branch_key.replace('/', "__"))
+    }
+
+    /// Writes a full dump of `entries` as of `sequence`. Later diffs start
+    /// from it.
+    pub fn write_base(
+        &self,
+        branch_key: &str,
+        sequence: u64,
+        entries: Vec<(String, Value)>,
+    ) -> Result<String, DumpError> {
+        // Changes the base already covers are not needed anymore.
+        self.recorder.take_diff(branch_key, sequence);
+
+        let diff = DumpDiff {
+            from: 0,
+            to: sequence,
+            cleared: false,
+            set: entries.into_iter().collect(),
+            removed: Vec::new(),
+        };
+
+        self.write_file(branch_key, BASE_PREFIX, &diff)
+    }
+
+    /// Writes the changes since the last file of the chain. Returns `None`
+    /// when nothing changed.
+    pub fn write_diff(&self, branch_key: &str) -> Result<Option<String>, DumpError> {
+        let files = self.list_files(branch_key)?;
+
+        let from = match files.last() {
+            Some((_, _, to, _)) => *to,
+            None => return Err(DumpError::NoBase(branch_key.to_string())),
+        };
+
+        let diff = self.recorder.take_diff(branch_key, from);
+
+        if diff.is_empty() {
+            return Ok(None);
+        }
+
+        self.write_file(branch_key, DIFF_PREFIX, &diff).map(Some)
+    }
+
+    /// Replays the latest base and the diffs after it.
+    pub fn load(&self, branch_key: &str) -> Result<DumpDiff, DumpError> {
+        let files = self.list_files(branch_key)?;
+
+        let start = match files.iter().rposition(|(is_base, _, _, _)| *is_base) {
+            Some(start) => start,
+            None => return Err(DumpError::NoBase(branch_key.to_string())),
+        };
+
+        let mut entries = BTreeMap::new();
+        let mut sequence = 0;
+
+        for (is_base, from, to, file) in &files[start..] {
+            if !is_base && *from != sequence {
+                return Err(DumpError::BrokenChain(file.clone()));
+            }
+
+            self.read_file(file, *from, *to)?.apply(&mut entries);
+            sequence = *to;
+        }
+
+        Ok(DumpDiff {
+            from: 0,
+            to: sequence,
+            cleared: false,
+            set: entries,
+            removed: Vec::new(),
+        })
+    }
+
+    /// Folds the chain into a single base and deletes the files it replaces.
+    pub fn compact(&self, branch_key: &str) -> Result<String, DumpError> {
+        let files = self.list_files(branch_key)?;
+        let merged = self.load(branch_key)?;
+        let base = self.write_file(branch_key, BASE_PREFIX, &merged)?;
+
+        for (_, _, _, file) in files {
+            if file != base {
+                std::fs::remove_file(&file).map_err(|err| DumpError::Io(err.to_string()))?;
+            }
+        }
+
+        debug!("Compacted dumps of {} into {}", branch_key, base);
+
+        Ok(base)
+    }
+
+    /// Dump files of a branch as `(is_base, from, to, path)`, in chain order.
+    fn list_files(&self, branch_key: &str) -> Result<Vec<(bool, u64, u64, String)>, DumpError> {
+        let dir = self.branch_dir(branch_key);
+
+        if !Path::new(&dir).exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut files = Vec::new();
+
+        for entry in std::fs::read_dir(&dir).map_err(|err| DumpError::Io(err.to_string()))? {
+            let entry = entry.map_err(|err| DumpError::Io(err.to_string()))?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = format!("{}/{}", dir, name);
+
+            if name.ends_with(TEMP_EXT) {
+                continue;
+            }
+
+            // Extensions differ between codecs, the header tells them apart.
+            let (is_base, range) = match name.split_once('.') {
+                Some((stem, _)) => match stem.strip_prefix(BASE_PREFIX) {
+                    Some(range) => (true, range),
+                    None => match stem.strip_prefix(DIFF_PREFIX) {
+                        Some(range) => (false, range),
+                        None => continue,
+                    },
+                },
+                None => continue,
+            };
+
+            let (from, to) = match range.split_once('-') {
+                Some((from, to)) => match (from.parse(), to.parse()) {
+                    (Ok(from), Ok(to)) => (from, to),
+                    _ => return Err(DumpError::Parse(path)),
+                },
+                None => return Err(DumpError::Parse(path)),
+            };
+
+            files.push((is_base, from, to, path));
+        }
+
+        // A base sorts after the diffs it covers.
+        files.sort_by_key(|(is_base, from, to, _)| (*to, *is_base, *from));
+
+        Ok(files)
+    }
+
+    fn write_file(
+        &self,
+        branch_key: &str,
+        prefix: &str,
+        diff: &DumpDiff,
+    ) -> Result<String, DumpError> {
+        let dir = self.branch_dir(branch_key);
+        std::fs::create_dir_all(&dir).map_err(|err| DumpError::Io(err.to_string()))?;
+
+        let file = format!(
+            "{}/{}{:020}-{:020}.{}",
+            dir,
+            prefix,
+            diff.from,
+            diff.to,
+            self.codec.extension()
+        );
+        let temp_file = format!("{}{}", file, TEMP_EXT);
+        let frame = WireFrame {
+            branch_key: branch_key.to_string(),
+            diff: diff.clone(),
+        };
+        let bytes = self
+            .codecs
+            .encode(self.codec.name(), &frame)
+            .map_err(|err| DumpError::Codec(err.to_string()))?;
+
+        // Written aside and renamed, so a crash never leaves half a file.
+        std::fs::write(&temp_file, bytes).map_err(|err| DumpError::Io(err.to_string()))?;
+        std::fs::rename(&temp_file, &file).map_err(|err| DumpError::Io(err.to_string()))?;
+
+        debug!("Wrote dump {}", file);
+
+        Ok(file)
+    }
+
+    fn read_file(&self, file: &str, from: u64, to: u64) -> Result<DumpDiff, DumpError> {
+        let bytes = std::fs::read(file).map_err(|err| DumpError::Io(err.to_string()))?;
+        let frame = self
+            .codecs
+            .decode(&bytes)
+            .map_err(|_| DumpError::Parse(file.to_string()))?;
+
+        // The name is what the chain was checked against.
+        Ok(DumpDiff {
+            from,
+            to,
+            ..frame.diff
+        })
+    }
+}
@@ -0,0 +1,357 @@
+use crate::sync::{AtomicU64, Mutex, Ordering, RwLock};
+use log::debug;
+use quickleaf::Event;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{
+    mpsc::{Receiver, Sender},
+    Arc,
+};
+
+/// Why a key left a branch cache while it still exists in git.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionReason {
+    /// The cache was full and made room for another key.
+    Capacity,
+    /// The key outlived its time to live.
+    Ttl,
+    /// The key was evicted on request.
+    Manual,
+}
+
+impl EvictionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionReason::Capacity => "capacity",
+            EvictionReason::Ttl => "ttl",
+            EvictionReason::Manual => "manual",
+        }
+    }
+}
+
+/// What happened on a branch.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BranchEventKind {
+    /// A change to the branch cache. Removals are keys deleted in git.
+    Cache(Event),
+    /// A key was dropped from the cache but not deleted in git.
+    Evicted { key: String, reason: EvictionReason },
+    /// A fetched commit was not loaded because its signature did not verify,
+    /// the mirror of the branch points elsewhere, or it has lint errors the
+    /// branch blocks on.
+    Rejected { commit: String, reason: String },
+    /// A file failed the schemas applying to it and was not loaded. Its key
+    /// keeps the last valid value it had, if any. `file` is relative to the
+    /// repository.
+    Invalid { file: String, errors: Vec<String> },
+    /// A file of `commit` failed to parse and was not loaded. Its key keeps
+    /// the last value parsed, if any. `file` is relative to the repository.
+    ParseError {
+        file: String,
+        commit: String,
+        error: String,
+    },
+    /// The clone holds local edits or commits, so pulls would fail. Sent
+    /// when they are first found or change.
+    Dirty {
+        files: Vec<String>,
+        local_commits: u64,
+    },
+    /// The branch was moved to cold storage.
+    Archived { reason: String },
+    /// The branch was loaded back from cold storage.
+    Restored,
+    /// The branch was unregistered.
+    Removed,
+    /// A sync loaded a commit other than the one loaded before, the first
+    /// one included.
+    SyncCompleted { commit: String },
+    /// The listener gave up on the branch after running out of retries.
+    SyncFailed { error: String },
+}
+
+impl BranchEventKind {
+    /// The cache key the event is about, if it is about a single one.
+    pub fn get_key(&self) -> Option<&str> {
+        match self {
+            BranchEventKind::Cache(Event::Insert(data))
+            | BranchEventKind::Cache(Event::Remove(data)) => Some(&data.key),
+            BranchEventKind::Evicted { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Whether the event resets the whole branch cache.
+    fn is_branch_wide(&self) -> bool {
+        matches!(
+            self,
+            BranchEventKind::Cache(Event::Clear) | BranchEventKind::Removed
+        )
+    }
+}
+
+/// An event tagged with the branch it happened on. Sequence numbers are
+/// global and strictly increasing.
+///
+/// Listeners see the events of a key, and the clears and removals of its
+/// branch, in increasing sequence order: one published concurrently with a
+/// newer one and reaching listeners after it is stale and dropped. Sync,
+/// write-back and manual evictions of the same key can race this way, while
+/// the newest event always tells the key's current state. Other events
+/// carry no key state and are delivered as published.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BranchEvent {
+    pub branch_key: String,
+    pub sequence: u64,
+    pub event: BranchEventKind,
+}
+
+type PendingRemovals = HashMap<(String, String), VecDeque<Option<EvictionReason>>>;
+
+/// Delivery order of the keyed events of one branch.
+#[derive(Default)]
+struct BranchOrder {
+    /// Sequence of the last clear or removal delivered. Keyed events take
+    /// it shared while delivering, so one never straddles a clear.
+    floor: RwLock<u64>,
+    /// Highest sequence delivered for any key since the last clear.
+    last_keyed: AtomicU64,
+    /// Sequence of the last event delivered for each key.
+    keys: Mutex<HashMap<String, Arc<Mutex<u64>>>>,
+}
+
+pub type EventListener = Arc<dyn Fn(&BranchEvent) + Send + Sync>;
+
+/// Fans branch events out to in-process listeners.
+#[derive(Clone, Default)]
+pub struct EventHub {
+    listeners: Arc<RwLock<Vec<(u64, EventListener)>>>,
+    next_listener_id: Arc<AtomicU64>,
+    sequence: Arc<AtomicU64>,
+    /// Sequence number of the last event published for each branch.
+    branch_sequences: Arc<RwLock<HashMap<String, u64>>>,
+    /// Removals announced before they reach a cache, by branch and key and
+    /// in order, with the eviction reason for those that are not git
+    /// deletions. Any other removal was made by the cache itself to stay
+    /// within capacity.
+    removals: Arc<Mutex<PendingRemovals>>,
+    orders: Arc<Mutex<HashMap<String, Arc<BranchOrder>>>>,
+}
+
+impl EventHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a listener and returns its id for `unsubscribe`.
+    pub fn subscribe(&self, listener: EventListener) -> u64 {
+        let id = self.next_listener_id.fetch_add(1, Ordering::SeqCst);
+
+        if let Ok(mut listeners) = self.listeners.write() {
+            listeners.push((id, listener));
+        }
+
+        id
+    }
+
+    pub fn unsubscribe(&self, id: u64) {
+        if let Ok(mut listeners) = self.listeners.write() {
+            listeners.retain(|(listener_id, _)| *listener_id != id);
+        }
+    }
+
+    /// The sequence number of the last published event.
+    pub fn get_sequence(&self) -> u64 {
+        self.sequence.load(Ordering::SeqCst)
+    }
+
+    /// The sequence number of the last event published for `branch_key`,
+    /// or 0 if there was none. It never goes backwards, so clients keeping
+    /// a copy of the branch can tell when a response is older than theirs.
+    pub fn get_branch_sequence(&self, branch_key: &str) -> u64 {
+        match self.branch_sequences.read() {
+            Ok(sequences) => sequences.get(branch_key).copied().unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+
+    /// Stamps `event` with the next sequence number and hands it to every
+    /// listener, unless it is stale for its key (see `BranchEvent`).
+    /// Listeners must not publish themselves.
+    pub fn publish(&self, branch_key: &str, event: BranchEventKind) -> BranchEvent {
+        // Taken under the lock so per-branch sequences only move forward.
+        let sequence = match self.branch_sequences.write() {
+            Ok(mut sequences) => {
+                let sequence = self.sequence.fetch_add(1, Ordering::SeqCst) + 1;
+                sequences.insert(branch_key.to_string(), sequence);
+                sequence
+            }
+            Err(_) => self.sequence.fetch_add(1, Ordering::SeqCst) + 1,
+        };
+
+        let event = BranchEvent {
+            branch_key: branch_key.to_string(),
+            sequence,
+            event,
+        };
+
+        self.deliver(&event);
+
+        event
+    }
+
+    /// Hands `event` to the listeners, keeping the order of its key.
+    /// Returns whether it was delivered rather than dropped as stale.
+    pub(crate) fn deliver(&self, event: &BranchEvent) -> bool {
+        let listeners = match self.listeners.read() {
+            Ok(listeners) => listeners.clone(),
+            Err(_) => return false,
+        };
+        let notify = || {
+            for (_, listener) in listeners.iter() {
+                listener(event);
+            }
+        };
+
+        let delivered = match (event.event.get_key(), event.event.is_branch_wide()) {
+            (Some(key), _) => self.deliver_keyed(event, key, notify),
+            (None, true) => self.deliver_branch_wide(event, notify),
+            (None, false) => {
+                notify();
+                true
+            }
+        };
+
+        if !delivered {
+            debug!(
+                "Dropping stale event {} of {}: {:?}",
+                event.sequence, event.branch_key, event.event
+            );
+        }
+
+        delivered
+    }
+
+    fn get_order(&self, branch_key: &str) -> Option<Arc<BranchOrder>> {
+        let mut orders = self.orders.lock().ok()?;
+
+        Some(orders.entry(branch_key.to_string()).or_default().clone())
+    }
+
+    fn deliver_keyed(&self, event: &BranchEvent, key: &str, notify: impl Fn()) -> bool {
+        let order = match self.get_order(&event.branch_key) {
+            Some(order) => order,
+            None => return false,
+        };
+        let floor = match order.floor.read() {
+            Ok(floor) => floor,
+            Err(_) => return false,
+        };
+
+        if event.sequence < *floor {
+            return false;
+        }
+
+        let last = match order.keys.lock() {
+            Ok(mut keys) => keys.entry(key.to_string()).or_default().clone(),
+            Err(_) => return false,
+        };
+        let mut last = match last.lock() {
+            Ok(last) => last,
+            Err(_) => return false,
+        };
+
+        if event.sequence < *last {
+            return false;
+        }
+
+        notify();
+        *last = event.sequence;
+        order.last_keyed.fetch_max(event.sequence, Ordering::SeqCst);
+
+        true
+    }
+
+    /// A clear older than an event already delivered for a key would wipe
+    /// that newer state, so it is dropped too.
+    fn deliver_branch_wide(&self, event: &BranchEvent, notify: impl Fn()) -> bool {
+        let order = match self.get_order(&event.branch_key) {
+            Some(order) => order,
+            None => return false,
+        };
+        let mut floor = match order.floor.write() {
+            Ok(floor) => floor,
+            Err(_) => return false,
+        };
+
+        if event.sequence < (*floor).max(order.last_keyed.load(Ordering::SeqCst)) {
+            return false;
+        }
+
+        notify();
+        *floor = event.sequence;
+
+        // Older keyed events now fall under the floor.
+        if let Ok(mut keys) = order.keys.lock() {
+            keys.clear();
+        }
+
+        true
+    }
+
+    /// Announces that `key` is about to be removed from a branch cache, so
+    /// the removal is published as an eviction with `reason`, or as a git
+    /// deletion if there is none. Only call it for keys the cache holds,
+    /// under its write lock.
+    pub fn expect_removal(&self, branch_key: &str, key: &str, reason: Option<EvictionReason>) {
+        if let Ok(mut removals) = self.removals.lock() {
+            removals
+                .entry((branch_key.to_string(), key.to_string()))
+                .or_default()
+                .push_back(reason);
+        }
+    }
+
+    fn take_removal(&self, branch_key: &str, key: &str) -> Option<Option<EvictionReason>> {
+        let mut removals = self.removals.lock().ok()?;
+        let entry = (branch_key.to_string(), key.to_string());
+        let queue = removals.get_mut(&entry)?;
+        let reason = queue.pop_front();
+
+        if queue.is_empty() {
+            removals.remove(&entry);
+        }
+
+        reason
+    }
+
+    /// Forwards the events of one branch cache: publishes them on the hub,
+    /// then hands them to `sender` unchanged. Ends once the cache is dropped.
+    pub fn forward(&self, branch_key: String, receiver: Receiver<Event>, sender: Sender<Event>) {
+        let hub = self.clone();
+
+        std::thread::spawn(move || {
+            for event in receiver.iter() {
+                let kind = match &event {
+                    Event::Remove(data) => match hub.take_removal(&branch_key, &data.key) {
+                        Some(None) => BranchEventKind::Cache(event.clone()),
+                        Some(Some(reason)) => BranchEventKind::Evicted {
+                            key: data.key.clone(),
+                            reason,
+                        },
+                        None => BranchEventKind::Evicted {
+                            key: data.key.clone(),
+                            reason: EvictionReason::Capacity,
+                        },
+                    },
+                    _ => BranchEventKind::Cache(event.clone()),
+                };
+
+                hub.publish(&branch_key, kind);
+
+                if sender.send(event).is_err() {
+                    debug!("Event receiver dropped for {}", branch_key);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,179 @@
+use crate::metadata::CommitMetadata;
+use arrow::array::{ArrayRef, StringBuilder, TimestampMillisecondBuilder};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use quickleaf::valu3::prelude::*;
+use std::io::Write;
+use std::sync::Arc;
+
+/// Rows per record batch. Each batch is written, and a Parquet row group
+/// flushed, before the next one is built.
+pub const EXPORT_BATCH_ROWS: usize = 4096;
+
+#[derive(Debug, PartialEq)]
+pub enum ExportError {
+    Arrow(String),
+    Parquet(String),
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExportError::Arrow(error) => write!(f, "Arrow error: {}", error),
+            ExportError::Parquet(error) => write!(f, "Parquet error: {}", error),
+        }
+    }
+}
+
+impl From<arrow::error::ArrowError> for ExportError {
+    fn from(err: arrow::error::ArrowError) -> Self {
+        ExportError::Arrow(err.to_string())
+    }
+}
+
+impl From<parquet::errors::ParquetError> for ExportError {
+    fn from(err: parquet::errors::ParquetError) -> Self {
+        ExportError::Parquet(err.to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    /// An Arrow IPC stream, readable batch by batch as it arrives.
+    Arrow,
+    /// A Parquet file, compressed with Snappy.
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "arrow" => Some(ExportFormat::Arrow),
+            "parquet" => Some(ExportFormat::Parquet),
+            _ => None,
+        }
+    }
+
+    pub fn get_content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Arrow => "application/vnd.apache.arrow.stream",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn get_extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Arrow => "arrows",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// One exported key, with the commit that last touched its file if known.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportRow {
+    pub key: String,
+    pub value: Value,
+    pub commit: Option<Arc<CommitMetadata>>,
+}
+
+/// Columns of an export: `key`, `value_json`, `type` (`object`, `array`,
+/// `string`, `number`, `boolean` or `null`), `commit` and `updated_at`, the
+/// author date of that commit.
+pub fn get_export_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("key", DataType::Utf8, false),
+        Field::new("value_json", DataType::Utf8, false),
+        Field::new("type", DataType::Utf8, false),
+        Field::new("commit", DataType::Utf8, true),
+        Field::new(
+            "updated_at",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            true,
+        ),
+    ]))
+}
+
+fn get_type(value: &Value) -> &'static str {
+    match value {
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::String(_) => "string",
+        Value::Number(_) => "number",
+        Value::Boolean(_) => "boolean",
+        Value::Null | Value::Undefined => "null",
+        _ => "datetime",
+    }
+}
+
+pub fn build_record_batch(rows: &[ExportRow]) -> Result<RecordBatch, ExportError> {
+    let mut keys = StringBuilder::new();
+    let mut values = StringBuilder::new();
+    let mut types = StringBuilder::new();
+    let mut commits = StringBuilder::new();
+    let mut updated_at = TimestampMillisecondBuilder::new().with_timezone("UTC");
+
+    for row in rows {
+        keys.append_value(&row.key);
+        values.append_value(row.value.to_json(JsonMode::Inline));
+        types.append_value(get_type(&row.value));
+        commits.append_option(row.commit.as_ref().map(|commit| commit.commit.as_str()));
+        updated_at.append_option(
+            row.commit
+                .as_ref()
+                .map(|commit| commit.timestamp as i64 * 1000),
+        );
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(keys.finish()),
+        Arc::new(values.finish()),
+        Arc::new(types.finish()),
+        Arc::new(commits.finish()),
+        Arc::new(updated_at.finish()),
+    ];
+
+    Ok(RecordBatch::try_new(get_export_schema(), columns)?)
+}
+
+/// Writes `rows` to `writer` in `format`, `EXPORT_BATCH_ROWS` at a time, so
+/// a streaming writer sends the first batches before the last are built.
+pub fn write_export<W: Write + Send>(
+    rows: &[ExportRow],
+    format: ExportFormat,
+    writer: W,
+) -> Result<(), ExportError> {
+    let schema = get_export_schema();
+
+    match format {
+        ExportFormat::Arrow => {
+            let mut writer = StreamWriter::try_new(writer, &schema)?;
+
+            for chunk in rows.chunks(EXPORT_BATCH_ROWS) {
+                writer.write(&build_record_batch(chunk)?)?;
+                writer.flush()?;
+            }
+
+            writer.finish()?;
+        }
+        ExportFormat::Parquet => {
+            let properties = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build();
+            let mut writer = ArrowWriter::try_new(writer, schema, Some(properties))?;
+
+            for chunk in rows.chunks(EXPORT_BATCH_ROWS) {
+                writer.write(&build_record_batch(chunk)?)?;
+                writer.flush()?;
+            }
+
+            writer.close()?;
+        }
+    }
+
+    Ok(())
+}
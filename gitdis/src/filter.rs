@@ -0,0 +1,58 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Compiles glob patterns (e.g. `**/test-fixtures/**`, `*.tmp.yaml`) into a
+/// set matched against paths relative to the repository root. `*` also
+/// matches across directories.
+pub fn build_globs(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|err| format!("{}: {}", pattern, err))?;
+        builder.add(glob);
+    }
+
+    builder.build().map_err(|err| err.to_string())
+}
+
+/// Which files of a repository are loaded: those matching `include` (every
+/// file when it is empty) and none matching `exclude`.
+#[derive(Clone, Debug)]
+pub struct FileFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Default for FileFilter {
+    fn default() -> Self {
+        Self {
+            include: None,
+            exclude: GlobSet::empty(),
+        }
+    }
+}
+
+impl FileFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            include: match include.is_empty() {
+                true => None,
+                false => Some(build_globs(include)?),
+            },
+            exclude: build_globs(exclude)?,
+        })
+    }
+
+    /// Only excludes prune directories: an include such as `envs/prod/**`
+    /// still has to walk through `envs`.
+    pub fn is_excluded(&self, path: &str) -> bool {
+        self.exclude.is_match(path)
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        !self.is_excluded(path)
+            && self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(path))
+    }
+}
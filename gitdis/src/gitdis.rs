@@ -1,68 +1,478 @@
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{
         mpsc::{self, SendError},
-        Arc, RwLock,
+        Arc, Condvar, Mutex, RwLock,
     },
+    time::{Duration, Instant},
 };
 
-use branch_handler::BranchHandler;
-use log::debug;
+use branch_handler::{BranchHandler, BranchHandlerError, KeyWrite};
+use log::{debug, error};
+use quickleaf::valu3::prelude::*;
 use quickleaf::{Cache, Event};
 
-use crate::cache::ArcCache;
+use crate::archive::{finish_archive, finish_restore};
+use crate::cache::list_refs;
+use crate::cache::{
+    ArcBranchErrors, ArcBranchHistory, ArcBranchStatus, ArcCache, ArcKeyMetadata, ArcKeyOwners,
+    ArcKeyVersions, ArcLintReport, ArcSyncFailures, ArcSyncReceiver,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::discovery::{BranchDiscovery, DiscoveryReport};
+use crate::events::{BranchEvent, BranchEventKind, EventHub, EvictionReason};
+use crate::filter::{build_globs, FileFilter};
+use crate::history::{BranchHistory, Generation, GenerationSelector};
+use crate::keys::{KeyFormat, KeyFormats};
+use crate::limiter::NetworkLimiter;
+use crate::lint::{LintReport, LintSettings};
+use crate::metadata::{latest_below, CommitMetadata};
+use crate::migration::{LegacyRegistration, MigrationReport};
+use crate::owners::owners_below;
+use crate::payload::{MultiDocument, PayloadParser, PayloadParsers};
+use crate::policy::{ArcBranchPolicy, BranchPolicy, PolicyWebhooks, ResolvedPolicy};
+use crate::process::{ProcessRunner, SystemProcessRunner};
+use crate::reconcile::{reconcile_clones, OrphanPolicy, ReconcileReport};
+use crate::repo_url::RepoUrl;
+use crate::retry::{PollJitter, RetryPolicy, SyncFailures};
+use crate::schema::SchemaRule;
+use crate::status::BranchStatus;
+use crate::sync::{AtomicBool, Ordering};
+use crate::versions::{KeyVersion, KeyVersions};
+use crate::watch::{KeyWatch, KeyWatcher};
 
 use super::branch_handler;
 
 #[derive(Debug, PartialEq)]
 pub enum GitdisError {
     RepoExists,
-    Sender(SendError<BranchSettings>),
+    Sender(Box<SendError<BranchSettings>>),
     BranchNotFound,
     RepoListener,
+    WriteBack(BranchHandlerError),
+    UnknownEncoding(String),
+    SyncTrigger,
+    RemoveClone(String),
+    InvalidGlob(String),
+    InvalidKeyFormat(String),
+    /// Listing the branches of a discovery's remote failed.
+    Discovery(BranchHandlerError),
+    /// The branch gave up syncing, or was removed, before loading data.
+    SyncFailed(String),
+    /// The branch loaded no data within the time given to `wait_ready`.
+    ReadyTimeout,
+    /// A path setting, e.g. `path_target`, climbs out of the repository.
+    InvalidPath(String),
+}
+
+#[derive(Clone, PartialEq)]
+pub enum BranchCredentials {
+    /// Username and personal access token given inline.
+    Token { username: String, token: String },
+    /// Username and the name of an environment variable holding the token.
+    TokenEnv { username: String, token_env: String },
+    /// Delegates to git credential helpers, see `gitcredentials(7)`: those
+    /// configured on the system, e.g. by `gh auth setup-git`, or `helper`
+    /// in their place. Cloud helpers matching on the repository path need
+    /// `use_http_path`.
+    Helper {
+        helper: Option<String>,
+        use_http_path: bool,
+    },
+}
+
+impl BranchCredentials {
+    /// The username, unless a credential helper provides it.
+    pub fn get_username(&self) -> Option<&str> {
+        match self {
+            BranchCredentials::Token { username, .. } => Some(username),
+            BranchCredentials::TokenEnv { username, .. } => Some(username),
+            BranchCredentials::Helper { .. } => None,
+        }
+    }
+
+    /// Resolves the token, reading the environment variable when needed.
+    /// Credential helpers resolve their own.
+    pub fn get_token(&self) -> Option<String> {
+        match self {
+            BranchCredentials::Token { token, .. } => Some(token.clone()),
+            BranchCredentials::TokenEnv { token_env, .. } => std::env::var(token_env).ok(),
+            BranchCredentials::Helper { .. } => None,
+        }
+    }
+}
+
+// Tokens must never end up in logs, so Debug only prints the username.
+impl std::fmt::Debug for BranchCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BranchCredentials::Token { username, .. } => f
+                .debug_struct("Token")
+                .field("username", username)
+                .field("token", &"***")
+                .finish(),
+            BranchCredentials::TokenEnv {
+                username,
+                token_env,
+            } => f
+                .debug_struct("TokenEnv")
+                .field("username", username)
+                .field("token_env", token_env)
+                .finish(),
+            BranchCredentials::Helper {
+                helper,
+                use_http_path,
+            } => f
+                .debug_struct("Helper")
+                .field("helper", helper)
+                .field("use_http_path", use_http_path)
+                .finish(),
+        }
+    }
+}
+
+/// Signals sent to a branch listener.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncSignal {
+    /// Pull now instead of at the next poll.
+    Sync,
+    /// Leave the poll loop.
+    Stop,
+}
+
+/// What `BranchSettings::branch_name` points at.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum RefType {
+    #[default]
+    Branch,
+    Tag,
+    Commit,
+}
+
+impl RefType {
+    /// Tags and commits never move, so they are synced once and not polled.
+    pub fn is_pinned(&self) -> bool {
+        !matches!(self, RefType::Branch)
+    }
+}
+
+/// Keys trusted to sign the commits of a branch.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SigningKeyring {
+    /// A GnuPG home directory holding the trusted public keys.
+    Gpg { home: String },
+    /// An SSH allowed signers file, in the format `ssh-keygen` reads.
+    Ssh { allowed_signers: String },
 }
 
+/// What a branch clone is seeded from before its first clone, so large
+/// repositories are not fetched from scratch on a cold start.
 #[derive(Clone, Debug, PartialEq)]
+pub enum Prewarm {
+    /// A git bundle, downloaded by git itself (`git clone --bundle-uri`,
+    /// git 2.38 or later).
+    Bundle { uri: String },
+    /// A `.tar.gz` of a clone, with `.git` at the archive root, at a local
+    /// path or an http(s) URL. Once extracted, its origin is pointed at the
+    /// branch URL and its checkout reset to the fetched branch, so only its
+    /// objects are reused. Archives with entries outside the clone are
+    /// rejected.
+    Tarball { location: String },
+}
+
+/// Git commands are killed after five minutes unless `git_timeout_millis`
+/// says otherwise, so a stalled remote cannot hold a listener forever.
+pub const DEFAULT_GIT_TIMEOUT_MILLIS: u64 = 300_000;
+
+#[derive(Clone, Debug, PartialEq, Default)]
 pub struct BranchSettings {
     pub url: String,
     pub branch_name: String,
     pub pull_request_interval_millis: u64,
+    /// Spread of the poll interval. Without it every poll waits exactly
+    /// `pull_request_interval_millis`.
+    pub poll_jitter: Option<PollJitter>,
+    pub credentials: Option<BranchCredentials>,
+    /// Environment variable holding the secret push webhooks for this
+    /// branch are signed with, instead of `GITDIS_WEBHOOK_SECRET`.
+    pub webhook_secret_env: Option<String>,
+    pub labels: HashMap<String, String>,
+    /// When set, clones and pulls fetch only the last `clone_depth` commits.
+    pub clone_depth: Option<u32>,
+    /// Clones without file contents (`--filter=blob:none`) and checks out
+    /// only files with a config extension, within `path_target` if set, so
+    /// the blobs of other files are never fetched. Needs a server allowing
+    /// filters; ignored by bare clones, which read every file through git.
+    pub partial_clone: Option<bool>,
+    /// Encoding label (e.g. `latin1`) used for files that are not valid UTF-8.
+    pub encoding: Option<String>,
+    /// Directory inside the repository to load. Only this directory is
+    /// checked out and keys are relative to it.
+    pub path_target: Option<String>,
+    /// How YAML files with several documents are loaded.
+    pub multi_document: Option<MultiDocument>,
+    /// Parse `.json` files leniently (comments, trailing commas). `.jsonc`
+    /// and `.json5` files are always parsed this way.
+    pub lenient_json: Option<bool>,
+    /// Expand `${VAR}` and `${VAR:-default}` placeholders in string values
+    /// from the environment, so one repository can serve several
+    /// deployments. A placeholder without default whose variable is unset
+    /// fails its file. Only variables prefixed with `GITDIS_VAR_` or listed
+    /// in `interpolate_allow` can be read; others fail their file too.
+    pub interpolate_env: Option<bool>,
+    /// Variables `interpolate_env` may read besides the `GITDIS_VAR_` ones.
+    pub interpolate_allow: Option<Vec<String>>,
+    /// How file paths map to keys. Without it, keys end at the first `.`
+    /// of the path and `/` separates them, so `db.example.com.yaml` and
+    /// `db.internal.yaml` collide; set it to keep whole file names.
+    pub key_format: Option<KeyFormat>,
+    /// Data files larger than this are skipped and flagged in the branch
+    /// errors instead of being read, so one huge file cannot exhaust
+    /// memory. Unlimited by default.
+    pub max_file_bytes: Option<u64>,
+    /// Most files one sync may parse. A commit needing more, e.g. one
+    /// adding 100k files, is not loaded until the limits are overridden,
+    /// see `Gitdis::override_sync_limits`. Unlimited by default.
+    pub max_sync_files: Option<usize>,
+    /// Longest one sync may spend parsing files, handled like
+    /// `max_sync_files`. Unlimited by default.
+    pub max_sync_parse_millis: Option<u64>,
+    /// Whether `branch_name` is a branch, a tag or a commit SHA.
+    pub ref_type: Option<RefType>,
+    /// Only load commits whose signature verifies. Others are skipped and
+    /// reported with a `Rejected` event.
+    pub require_signed_commits: Option<bool>,
+    /// Keys signatures are checked against. Without one, git's own GnuPG
+    /// and SSH configuration is used.
+    pub signing_keyring: Option<SigningKeyring>,
+    /// Backoff applied when a sync fails. Defaults to `RetryPolicy::default`.
+    pub retry: Option<RetryPolicy>,
+    /// Proxy for this branch, overriding `GitdisSettings::proxy`.
+    pub proxy: Option<String>,
+    /// Globs of files never loaded, relative to the repository root.
+    pub ignore: Vec<String>,
+    /// Globs of the only files loaded, relative to the repository root. When
+    /// empty every file is; `ignore` applies either way.
+    pub include: Vec<String>,
+    /// Globs of files loaded as binary, e.g. `certs/*.pem`, whatever their
+    /// extension. Each is stored as an object with its `content_type`, an
+    /// `encoding` of `base64` and the encoded `data`; `include` and
+    /// `ignore` still apply.
+    pub binary_files: Vec<String>,
+    /// Seed for the first clone. A failed prewarm falls back to a clone.
+    pub prewarm: Option<Prewarm>,
+    /// Discard local edits and commits found in the clone before a pull.
+    /// Otherwise the sync fails until they are cleaned up.
+    pub reset_dirty: Option<bool>,
+    /// Reuse a clone already in the branch directory, e.g. restored from a
+    /// CI cache, once it is checked to be a clone of the same repository
+    /// and ref. A clone that does not match fails the sync instead of
+    /// being pulled or recloned, and one with local changes is subject to
+    /// `reset_dirty`.
+    pub adopt_existing_clone: Option<bool>,
+    /// Keep the clone as a bare repository and read files from its commits,
+    /// without a working tree. Includes are not expanded, tarball prewarms
+    /// are skipped and write-back is not available.
+    pub bare: Option<bool>,
+    /// Past generations kept in memory, one per loaded commit, so reads can
+    /// go back in time without git. Unchanged values are shared between
+    /// generations. None are kept by default.
+    pub generations: Option<usize>,
+    /// Past values kept in memory for each key, with the commit that set
+    /// them. None are kept by default.
+    pub key_versions: Option<usize>,
+    /// Registered in standby when false: listeners wait, without cloning or
+    /// syncing, until the branch is enabled through `set_enabled` or a
+    /// policy. Enabled by default.
+    pub enabled: Option<bool>,
+    /// Longest a git command may run before it is killed and the sync
+    /// fails with `GitTimeout`. `DEFAULT_GIT_TIMEOUT_MILLIS` by default.
+    pub git_timeout_millis: Option<u64>,
+    /// A second remote serving the same repository, e.g. a mirror on
+    /// another host. Before a commit is loaded, the ref is looked up there
+    /// too and must point at the same commit, whose hash covers the whole
+    /// tree; otherwise the commit is rejected like an unsigned one, so a
+    /// single compromised host cannot publish config. A mirror that lags
+    /// behind holds syncs back until it catches up. Commit SHAs are not
+    /// checked, as they already name the content.
+    pub mirror_url: Option<String>,
+    /// Credentials for `mirror_url`. Those of the branch are never sent to
+    /// the mirror.
+    pub mirror_credentials: Option<BranchCredentials>,
+    /// Lints each commit before it is loaded, see `LintSettings`. The
+    /// report of the last one is kept in the branch. Not linted by default.
+    pub lint: Option<LintSettings>,
+    /// JSON Schemas files are validated against as they are loaded, see
+    /// `SchemaRule`. Invalid files are kept out of the cache, listed in the
+    /// branch errors and reported with an `Invalid` event. Schema files are
+    /// not loaded as config.
+    pub schemas: Vec<SchemaRule>,
 }
 
 impl BranchSettings {
     pub fn get_repo_key(&self) -> String {
-        let url = self.url.clone();
-        let url = url.split('/').collect::<Vec<&str>>();
-        let repo_name = url[url.len() - 1].split('.').collect::<Vec<&str>>()[0];
-        let repo_owner = {
-            let repo_owner = url[url.len() - 2];
+        format!("{}/{}", self.get_repo_name_key(), self.branch_name)
+    }
+
+    /// The `owner/repo` part of the repo key, shared by all its branches.
+    pub fn get_repo_name_key(&self) -> String {
+        canonicalize_repo_url(&self.url)
+    }
+
+    /// The format keys of the branch are in, `KeyFormat::default` for
+    /// legacy keys too.
+    pub fn get_key_format(&self) -> KeyFormat {
+        self.key_format.clone().unwrap_or_default()
+    }
+}
+
+/// Checks a path or key joined onto the repository root cannot climb out
+/// of it: no `..` segment and no NUL byte. Leading slashes are fine, they
+/// are trimmed before joining.
+pub fn validate_relative_path(path: &str) -> Result<(), String> {
+    if path.contains('\0') {
+        return Err(format!("{:?} contains a NUL byte", path));
+    }
+
+    if path.split(['/', '\\']).any(|segment| segment == "..") {
+        return Err(format!("{:?} contains a `..` segment", path));
+    }
+
+    Ok(())
+}
+
+/// The `owner/repo` a remote URL points at, the same for its SSH, HTTPS and
+/// scp-like forms. See `RepoUrl` for how URLs are parsed and keyed; a URL
+/// without a repository name keys as `/`.
+pub fn canonicalize_repo_url(url: &str) -> String {
+    RepoUrl::parse(url).map_or_else(|| "/".to_string(), |url| url.get_key())
+}
+
+/// Whether `url` points at a repository on the local filesystem: a
+/// `file://` URL or a plain path, absolute or relative.
+pub fn is_local_url(url: &str) -> bool {
+    let url = url.trim();
+
+    match url.split_once("://") {
+        Some((scheme, _)) => scheme.eq_ignore_ascii_case("file"),
+        // In scp-like `host:path` URLs the colon comes before any slash.
+        None => match url.find(':') {
+            Some(colon) => url[..colon].contains('/'),
+            None => true,
+        },
+    }
+}
+
+/// The URL git is given for `url`. Plain paths become absolute `file://`
+/// URLs, so they point at the same repository from any directory and
+/// shallow clones work; other URLs are kept as they are.
+pub fn resolve_repo_url(url: &str) -> String {
+    let url = url.trim();
+
+    if url.contains("://") || !is_local_url(url) {
+        return url.to_string();
+    }
+
+    format!("file://{}", normalize_path(url).display())
+}
+
+/// `path` made absolute against the working directory, with `.` and `..`
+/// resolved without touching the disk.
+fn normalize_path(path: &str) -> std::path::PathBuf {
+    let path = std::path::Path::new(path);
+    let path = match path.is_absolute() {
+        true => path.to_path_buf(),
+        false => std::env::current_dir().unwrap_or_default().join(path),
+    };
+    let mut normalized = std::path::PathBuf::new();
 
-            if repo_owner.contains(":") {
-                repo_owner.split(':').collect::<Vec<&str>>()[1]
-            } else {
-                repo_owner
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => (),
+            std::path::Component::ParentDir => {
+                normalized.pop();
             }
-        };
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+/// Checks the settings a branch is registered with.
+fn validate_settings(settings: &BranchSettings) -> Result<(), GitdisError> {
+    if let Some(encoding) = &settings.encoding {
+        if encoding_rs::Encoding::for_label(encoding.as_bytes()).is_none() {
+            debug!("Unknown encoding: {}", encoding);
+            return Err(GitdisError::UnknownEncoding(encoding.clone()));
+        }
+    }
+
+    if let Err(err) = FileFilter::new(&settings.include, &settings.ignore) {
+        debug!("Invalid file glob: {}", err);
+        return Err(GitdisError::InvalidGlob(err));
+    }
+
+    if let Some(Err(err)) = settings.path_target.as_deref().map(validate_relative_path) {
+        debug!("Invalid path target: {}", err);
+        return Err(GitdisError::InvalidPath(err));
+    }
 
-        format!("{}/{}/{}", repo_owner, repo_name, self.branch_name)
+    if let Err(err) = build_globs(&settings.binary_files) {
+        debug!("Invalid binary file glob: {}", err);
+        return Err(GitdisError::InvalidGlob(err));
     }
+
+    if let Some(Err(err)) = settings.key_format.as_ref().map(KeyFormat::validate) {
+        return Err(GitdisError::InvalidKeyFormat(err));
+    }
+
+    Ok(())
 }
 
 pub struct GitdisSettings {
     pub total_branch_items: usize,
     pub local_clone_path: String,
+    /// Proxy for all git traffic over HTTP(S), e.g. `http://proxy:3128` or
+    /// `socks5://proxy:1080`.
+    pub proxy: Option<String>,
+    /// How often each clone is garbage collected and its stale remote refs
+    /// pruned, checked after every sync. `None` disables maintenance.
+    pub maintenance_interval_millis: Option<u64>,
+    /// Clones, fetches, pulls and pushes running at once across every
+    /// branch, see `NetworkLimiter`. Unbounded when `None`.
+    pub max_network_operations: Option<usize>,
 }
 
 #[derive(Clone)]
 pub struct CacheBranch {
     cache: ArcCache,
+    errors: ArcBranchErrors,
+    failures: ArcSyncFailures,
+    status: ArcBranchStatus,
+    history: ArcBranchHistory,
+    metadata: ArcKeyMetadata,
+    versions: ArcKeyVersions,
+    owners: ArcKeyOwners,
+    lint_report: ArcLintReport,
+    sync_sender: Sender<SyncSignal>,
+    sync_receiver: ArcSyncReceiver,
     create_at: u128,
+    archived: Arc<AtomicBool>,
+    settings: BranchSettings,
+    labels: HashMap<String, String>,
+    policy: ArcBranchPolicy,
 }
 
 impl CacheBranch {
-    pub fn new(total_cache_items: usize, sender: Sender<Event>) -> Self {
+    pub fn new(
+        total_cache_items: usize,
+        sender: Sender<Event>,
+        settings: BranchSettings,
+        events: &EventHub,
+    ) -> Self {
         let create_at = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
@@ -70,9 +480,35 @@ impl CacheBranch {
 
         debug!("Creating new cache with {} items", total_cache_items);
 
+        let (sync_sender, sync_receiver) = mpsc::channel();
+        let (cache_sender, cache_receiver) = mpsc::channel();
+        events.forward(settings.get_repo_key(), cache_receiver, sender);
+
         CacheBranch {
-            cache: Arc::new(RwLock::new(Cache::with_sender(total_cache_items, sender))),
+            cache: Arc::new(crate::sync::RwLock::new(Cache::with_sender(
+                total_cache_items,
+                cache_sender,
+            ))),
+            errors: Arc::new(RwLock::new(HashMap::new())),
+            failures: Arc::new(RwLock::new(SyncFailures::default())),
+            status: Arc::new(RwLock::new(BranchStatus::default())),
+            history: Arc::new(RwLock::new(
+                BranchHistory::new(settings.generations.unwrap_or(0))
+                    .with_key_format(settings.get_key_format()),
+            )),
+            metadata: Arc::new(RwLock::new(HashMap::new())),
+            versions: Arc::new(RwLock::new(KeyVersions::new(
+                settings.key_versions.unwrap_or(0),
+            ))),
+            owners: Arc::new(RwLock::new(HashMap::new())),
+            lint_report: Arc::new(RwLock::new(None)),
+            sync_sender,
+            sync_receiver: Arc::new(Mutex::new(sync_receiver)),
             create_at,
+            archived: Arc::new(AtomicBool::new(false)),
+            labels: settings.labels.clone(),
+            settings,
+            policy: Arc::new(RwLock::new(ResolvedPolicy::default())),
         }
     }
 
@@ -80,32 +516,513 @@ impl CacheBranch {
         self.cache.clone()
     }
 
+    pub fn get_errors(&self) -> ArcBranchErrors {
+        self.errors.clone()
+    }
+
+    pub fn get_failures(&self) -> ArcSyncFailures {
+        self.failures.clone()
+    }
+
+    pub fn get_status(&self) -> ArcBranchStatus {
+        self.status.clone()
+    }
+
+    pub fn get_history(&self) -> ArcBranchHistory {
+        self.history.clone()
+    }
+
+    pub fn get_metadata(&self) -> ArcKeyMetadata {
+        self.metadata.clone()
+    }
+
+    pub fn get_versions(&self) -> ArcKeyVersions {
+        self.versions.clone()
+    }
+
+    pub fn get_owners(&self) -> ArcKeyOwners {
+        self.owners.clone()
+    }
+
+    pub fn get_lint_report(&self) -> ArcLintReport {
+        self.lint_report.clone()
+    }
+
     pub fn get_create_at(&self) -> u128 {
         self.create_at
     }
+
+    pub fn get_settings(&self) -> &BranchSettings {
+        &self.settings
+    }
+
+    pub fn get_labels(&self) -> &HashMap<String, String> {
+        &self.labels
+    }
+
+    pub fn get_policy(&self) -> ResolvedPolicy {
+        self.policy
+            .read()
+            .map(|policy| policy.clone())
+            .unwrap_or_default()
+    }
+
+    /// Shares the resolved policy of the branch, kept up to date as
+    /// policies and labels change.
+    pub fn get_policy_handle(&self) -> ArcBranchPolicy {
+        self.policy.clone()
+    }
+
+    fn set_policy(&self, policy: ResolvedPolicy) {
+        if let Ok(mut current) = self.policy.write() {
+            *current = policy;
+        }
+    }
+
+    /// Enabled by its policy if one decides, otherwise by its settings.
+    pub fn is_enabled(&self) -> bool {
+        self.get_policy()
+            .enabled
+            .unwrap_or(self.settings.enabled.unwrap_or(true))
+    }
+
+    /// Puts the branch in or out of standby as `is_enabled` says. Returns
+    /// whether it just left standby, so its listener should be woken.
+    fn apply_standby(&self) -> bool {
+        let enabled = self.is_enabled();
+
+        match self.status.write() {
+            Ok(mut status) => {
+                let activated = status.standby && enabled;
+                status.standby = !enabled;
+                activated
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// A write-back holding what it needs from `Gitdis`, see
+/// `Gitdis::prepare_write_back`.
+pub struct PendingWriteBack {
+    handler: BranchHandler,
+    cache: ArcCache,
+}
+
+impl PendingWriteBack {
+    pub fn run(
+        mut self,
+        object_key: &str,
+        value: Value,
+        expected_commit: Option<&str>,
+    ) -> Result<String, GitdisError> {
+        let commit_hash = self
+            .handler
+            .write_back(object_key, &value, expected_commit)
+            .map_err(GitdisError::WriteBack)?;
+
+        if let Ok(mut cache) = self.cache.write() {
+            cache.insert(object_key, value);
+        }
+
+        Ok(commit_hash)
+    }
+
+    /// Commits `changes` as one commit, see `BranchHandler::commit_changes`,
+    /// and refreshes the cached entries once it has been pushed.
+    pub fn commit(
+        mut self,
+        changes: Vec<KeyWrite>,
+        message: &str,
+        expected_commit: Option<&str>,
+    ) -> Result<String, GitdisError> {
+        let commit_hash = self
+            .handler
+            .commit_changes(&changes, message, expected_commit)
+            .map_err(GitdisError::WriteBack)?;
+
+        if let Ok(mut cache) = self.cache.write() {
+            for change in changes {
+                match change {
+                    KeyWrite::Update { key, value } => cache.insert(key, value),
+                    KeyWrite::Delete { key } => {
+                        let _ = cache.remove(&key);
+                    }
+                }
+            }
+        }
+
+        Ok(commit_hash)
+    }
+}
+
+/// The `git ls-remote` of a discovery, prepared by `prepare_discoveries` to
+/// run without a lock on `Gitdis`.
+pub struct PendingDiscovery {
+    pub key: String,
+    settings: BranchSettings,
+    network: NetworkLimiter,
+    runner: Arc<dyn ProcessRunner>,
+}
+
+impl PendingDiscovery {
+    /// Names of the branches upstream, to hand to `apply_discovery`.
+    pub fn run(&self) -> Result<Vec<String>, GitdisError> {
+        let _permit = self.network.acquire();
+
+        branch_handler::list_remote_branches(
+            &self.settings.url,
+            self.settings.proxy.as_deref(),
+            self.settings.credentials.as_ref(),
+            self.runner.as_ref(),
+            Duration::from_millis(
+                self.settings
+                    .git_timeout_millis
+                    .unwrap_or(DEFAULT_GIT_TIMEOUT_MILLIS),
+            ),
+        )
+        .map_err(GitdisError::Discovery)
+    }
+}
+
+/// Count of running listener threads, so shutdown can wait for them.
+#[derive(Default)]
+struct RunningListeners {
+    count: Mutex<usize>,
+    stopped: Condvar,
+}
+
+/// Held by a listener thread for as long as it runs, panics included.
+struct ListenerGuard(Arc<RunningListeners>);
+
+impl ListenerGuard {
+    fn new(listeners: Arc<RunningListeners>) -> Self {
+        if let Ok(mut count) = listeners.count.lock() {
+            *count += 1;
+        }
+
+        Self(listeners)
+    }
+}
+
+impl Drop for ListenerGuard {
+    fn drop(&mut self) {
+        if let Ok(mut count) = self.0.count.lock() {
+            *count -= 1;
+        }
+
+        self.0.stopped.notify_all();
+    }
+}
+
+/// Worker threads of the sync scheduler unless `set_sync_workers` says
+/// otherwise.
+pub const DEFAULT_SYNC_WORKERS: usize = 4;
+
+/// A branch waiting in the sync scheduler for its next poll.
+struct ScheduledBranch {
+    key: String,
+    handler: BranchHandler,
+    next_at: Instant,
+    /// Whether the setup (first clone and load) succeeded.
+    ready: bool,
+    /// Attempts of the current setup or sync so far, for retries.
+    attempt: u32,
+    _guard: ListenerGuard,
+}
+
+impl ScheduledBranch {
+    /// Runs one attempt of the branch's setup or sync and sets its next
+    /// deadline. Returns `false` once the branch leaves the scheduler.
+    fn run(&mut self) -> bool {
+        if self.handler.is_stop_requested() {
+            debug!("Scheduled branch {} stopped", self.key);
+            return false;
+        }
+
+        if self.handler.is_standby() {
+            debug!("Scheduled branch {} is in standby", self.key);
+            self.next_at = Instant::now() + self.handler.get_interval();
+            return true;
+        }
+
+        self.attempt += 1;
+
+        match self.handler.sync_step(self.ready, self.attempt) {
+            Ok(Some(delay)) => {
+                self.next_at = Instant::now() + delay;
+            }
+            Ok(None) => {
+                self.attempt = 0;
+
+                if !self.ready && self.handler.is_pinned() {
+                    debug!("Scheduled branch {} is pinned, not polling", self.key);
+                    return false;
+                }
+
+                self.ready = true;
+                self.next_at = Instant::now() + self.handler.get_interval();
+            }
+            Err(e) => {
+                error!("Listener for {} failed: {:?}", self.key, e);
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[derive(Default)]
+struct SchedulerQueue {
+    branches: Vec<ScheduledBranch>,
+    /// Branches to run as soon as they are in the queue, e.g. after a
+    /// webhook, whatever their deadline.
+    woken: HashSet<String>,
+    workers: usize,
+    max_workers: usize,
+}
+
+impl SchedulerQueue {
+    /// Index of the branch to run next and when it is due.
+    fn next_due(&self) -> Option<(usize, Instant)> {
+        let now = Instant::now();
+
+        self.branches
+            .iter()
+            .enumerate()
+            .map(|(index, branch)| match self.woken.contains(&branch.key) {
+                true => (index, now),
+                false => (index, branch.next_at),
+            })
+            .min_by_key(|(_, due)| *due)
+    }
+}
+
+/// Multiplexes branch syncs over a bounded pool of worker threads instead
+/// of one thread per branch. A free worker takes the branch whose poll is
+/// the most overdue, runs one sync, or one attempt of it while retrying,
+/// and puts it back with its next deadline. Workers start with the first
+/// branches and end once there are none left.
+#[derive(Clone, Default)]
+struct SyncScheduler {
+    queue: Arc<(Mutex<SchedulerQueue>, Condvar)>,
+}
+
+impl SyncScheduler {
+    fn new(max_workers: usize) -> Self {
+        let scheduler = Self::default();
+        scheduler.set_max_workers(max_workers);
+        scheduler
+    }
+
+    fn set_max_workers(&self, max_workers: usize) {
+        if let Ok(mut queue) = self.queue.0.lock() {
+            queue.max_workers = max_workers.max(1);
+        }
+    }
+
+    fn schedule(&self, branch: ScheduledBranch) {
+        let (lock, condvar) = &*self.queue;
+
+        let mut queue = match lock.lock() {
+            Ok(queue) => queue,
+            Err(_) => return,
+        };
+
+        queue.branches.push(branch);
+
+        if queue.workers < queue.max_workers.min(queue.branches.len()) {
+            queue.workers += 1;
+
+            let scheduler = self.clone();
+            thread::spawn(move || scheduler.work());
+        }
+
+        condvar.notify_one();
+    }
+
+    /// Runs a branch right away instead of at its next deadline.
+    fn wake(&self, repo_key: &str) {
+        let (lock, condvar) = &*self.queue;
+
+        if let Ok(mut queue) = lock.lock() {
+            queue.woken.insert(repo_key.to_string());
+        }
+
+        condvar.notify_all();
+    }
+
+    fn work(&self) {
+        let (lock, condvar) = &*self.queue;
+
+        loop {
+            let mut branch = {
+                let mut queue = match lock.lock() {
+                    Ok(queue) => queue,
+                    Err(_) => return,
+                };
+
+                loop {
+                    let (index, due) = match queue.next_due() {
+                        Some(next) => next,
+                        None => {
+                            queue.workers -= 1;
+                            return;
+                        }
+                    };
+
+                    let now = Instant::now();
+
+                    if due <= now {
+                        let branch = queue.branches.swap_remove(index);
+                        queue.woken.remove(&branch.key);
+                        break branch;
+                    }
+
+                    queue = match condvar.wait_timeout(queue, due - now) {
+                        Ok((queue, _)) => queue,
+                        Err(_) => return,
+                    };
+                }
+            };
+
+            if branch.run() {
+                if let Ok(mut queue) = lock.lock() {
+                    queue.branches.push(branch);
+                }
+
+                condvar.notify_one();
+            }
+        }
+    }
 }
 
 pub struct Gitdis {
     pub settings: GitdisSettings,
     branches: HashMap<String, CacheBranch>,
+    discoveries: HashMap<String, BranchDiscovery>,
+    policies: Vec<BranchPolicy>,
+    watcher: KeyWatcher,
+    webhooks: PolicyWebhooks,
     sender: Sender<Event>,
-    pub receiver: Receiver<Event>,
+    /// Behind a mutex so `Gitdis` can be shared between threads.
+    pub receiver: Mutex<Receiver<Event>>,
+    events: EventHub,
+    key_formats: KeyFormats,
+    listeners: Arc<RunningListeners>,
+    scheduler: SyncScheduler,
+    clock: Arc<dyn Clock>,
+    runner: Arc<dyn ProcessRunner>,
+    network: NetworkLimiter,
+    parsers: PayloadParsers,
 }
 
 impl Gitdis {
     pub fn new(settings: GitdisSettings, sender: Sender<Event>, receiver: Receiver<Event>) -> Self {
+        let network = NetworkLimiter::new(settings.max_network_operations);
+        let events = EventHub::new();
+        let watcher = KeyWatcher::default();
+        watcher.subscribe(&events);
+        let webhooks = PolicyWebhooks::default();
+        webhooks.subscribe(&events);
+
         Self {
             settings,
             branches: HashMap::new(),
+            discoveries: HashMap::new(),
+            policies: Vec::new(),
+            watcher,
+            webhooks,
             sender,
-            receiver,
+            receiver: Mutex::new(receiver),
+            events,
+            key_formats: KeyFormats::new(),
+            listeners: Arc::new(RunningListeners::default()),
+            scheduler: SyncScheduler::new(DEFAULT_SYNC_WORKERS),
+            clock: Arc::new(SystemClock),
+            runner: Arc::new(SystemProcessRunner),
+            network,
+            parsers: PayloadParsers::new(),
         }
     }
 
+    /// Bounds the worker threads `repo_schedule` syncs branches on. Running
+    /// workers above the new bound finish as branches leave.
+    pub fn set_sync_workers(&self, workers: usize) {
+        self.scheduler.set_max_workers(workers);
+    }
+
+    /// Network operations running, and waiting for their turn.
+    pub fn get_network_counts(&self) -> (usize, usize) {
+        self.network.get_counts()
+    }
+
+    /// The clock handlers created from now on read the time from.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// The runner handlers created from now on spawn git through.
+    pub fn set_process_runner(&mut self, runner: Arc<dyn ProcessRunner>) {
+        self.runner = runner;
+    }
+
+    /// Loads the files of `parser`'s format in the branches registered from
+    /// now on, replacing any parser of the same extension, built-in ones
+    /// included.
+    pub fn register_parser(&mut self, parser: Arc<dyn PayloadParser>) {
+        self.parsers.register(parser);
+    }
+
+    /// Branch-aware view of the cache events, for in-process listeners.
+    pub fn get_events(&self) -> EventHub {
+        self.events.clone()
+    }
+
+    /// The key format of every branch, kept up to date as they come and go.
+    pub fn get_key_formats(&self) -> KeyFormats {
+        self.key_formats.clone()
+    }
+
+    pub fn get_key_format(&self, repo_key: &str) -> KeyFormat {
+        self.key_formats.get(repo_key)
+    }
+
     pub fn update_settings(&mut self, settings: GitdisSettings) {
         self.settings = settings;
     }
 
+    pub fn get_local_clone_path(&self) -> &str {
+        &self.settings.local_clone_path
+    }
+
+    /// Settings of every registered branch.
+    pub fn get_branch_settings(&self) -> Vec<BranchSettings> {
+        self.branches
+            .values()
+            .map(|branch| branch.settings.clone())
+            .collect()
+    }
+
+    /// Settings of every registered branch and branch pattern.
+    pub fn get_registered_settings(&self) -> Vec<BranchSettings> {
+        self.branches
+            .values()
+            .map(|branch| &branch.settings)
+            .chain(
+                self.discoveries
+                    .values()
+                    .map(|discovery| &discovery.settings),
+            )
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_branch_keys(&self) -> Vec<String> {
+        self.branches.keys().cloned().collect()
+    }
+
     pub fn get_object_branch(&self, repo_key: &str) -> Option<CacheBranch> {
         match self.branches.get(repo_key) {
             Some(cache) => Some(cache.clone()),
@@ -130,61 +1047,889 @@ impl Gitdis {
 
         debug!("Repo key: {}", repo_key);
 
-        if self.branches.contains_key(&repo_key) {
+        if self.branches.contains_key(&repo_key) || self.discoveries.contains_key(&repo_key) {
             debug!("Repo already exists");
             return Err(GitdisError::RepoExists);
         }
 
+        validate_settings(&settings)?;
+
         let key = settings.get_repo_key();
 
-        self.branches.insert(
-            key.clone(),
-            CacheBranch::new(self.settings.total_branch_items, self.sender.clone()),
+        let branch = CacheBranch::new(
+            self.settings.total_branch_items,
+            self.sender.clone(),
+            settings,
+            &self.events,
         );
+        branch.set_policy(ResolvedPolicy::resolve(&self.policies, &branch.labels));
+        branch.apply_standby();
+        self.watcher.track_owners(&key, branch.get_owners());
+        self.webhooks.track(&key, branch.get_policy_handle());
+        self.key_formats.set(&key, branch.settings.get_key_format());
+
+        self.branches.insert(key.clone(), branch);
 
         debug!("Added new repo: {}", key);
 
         Ok(())
     }
 
-    pub fn create_branch_handler(
-        &self,
-        settings: BranchSettings,
-    ) -> Result<BranchHandler, GitdisError> {
-        let cache = match self.get_data_branch(&settings.get_repo_key()) {
-            Some(cache) => cache,
-            None => {
-                return Err(GitdisError::BranchNotFound);
-            }
-        };
+    /// Registers a repo with a branch pattern in `branch_name`, see
+    /// `BranchDiscovery`. No branch is registered until `apply_discovery`
+    /// is given the branches upstream.
+    pub fn add_discovery(&mut self, settings: BranchSettings) -> Result<(), GitdisError> {
+        let key = settings.get_repo_key();
 
-        Ok(BranchHandler::new(
-            self.settings.local_clone_path.clone(),
-            settings.url,
-            settings.branch_name,
-            cache,
-            settings.pull_request_interval_millis,
-        ))
-    }
+        debug!("Adding discovery: {}", key);
 
-    pub fn repo_listen(
-        &self,
-        settings: BranchSettings,
-    ) -> Result<thread::JoinHandle<()>, GitdisError> {
-        let mut handler = self.create_branch_handler(settings)?;
+        if self.branches.contains_key(&key) || self.discoveries.contains_key(&key) {
+            return Err(GitdisError::RepoExists);
+        }
 
-        Ok(thread::spawn(move || {
-            if let Err(e) = handler.listen() {
-                eprintln!("Error: {:?}", e);
-            }
-        }))
+        if settings.ref_type.unwrap_or_default().is_pinned() {
+            return Err(GitdisError::InvalidGlob(
+                "Branch patterns only match branches".to_string(),
+            ));
+        }
+
+        validate_settings(&settings)?;
+
+        let discovery = BranchDiscovery::new(settings).map_err(GitdisError::InvalidGlob)?;
+        self.discoveries.insert(key, discovery);
+
+        Ok(())
     }
 
-    pub fn listen_events<Callback>(&self, callback: Callback)
+    /// Unregisters a discovery and the branches it registered. Returns
+    /// their repo keys.
+    pub fn remove_discovery(&mut self, key: &str) -> Result<Vec<String>, GitdisError> {
+        debug!("Removing discovery: {}", key);
+
+        let discovery = match self.discoveries.remove(key) {
+            Some(discovery) => discovery,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        let branches = discovery.get_branches();
+
+        for repo_key in branches.iter() {
+            let _ = self.remove_branch(repo_key, false);
+        }
+
+        Ok(branches)
+    }
+
+    pub fn get_discovery(&self, key: &str) -> Option<BranchDiscovery> {
+        self.discoveries.get(key).cloned()
+    }
+
+    pub fn get_discovery_keys(&self) -> Vec<String> {
+        self.discoveries.keys().cloned().collect()
+    }
+
+    /// The `git ls-remote` of every discovery, to run once the lock on
+    /// `Gitdis` is released.
+    pub fn prepare_discoveries(&self) -> Vec<PendingDiscovery> {
+        self.discoveries
+            .iter()
+            .map(|(key, discovery)| {
+                let mut settings = discovery.settings.clone();
+
+                if settings.proxy.is_none() {
+                    settings.proxy = self.settings.proxy.clone();
+                }
+
+                PendingDiscovery {
+                    key: key.clone(),
+                    settings,
+                    network: self.network.clone(),
+                    runner: self.runner.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Brings the branches of a discovery in line with `remote_branches`,
+    /// the branches upstream: matching ones not registered yet are
+    /// registered and scheduled, the ones it registered that are gone are
+    /// unregistered. A branch registered on its own is left alone.
+    pub fn apply_discovery(
+        &mut self,
+        key: &str,
+        remote_branches: &[String],
+    ) -> Result<DiscoveryReport, GitdisError> {
+        let mut discovery = match self.discoveries.get(key) {
+            Some(discovery) => discovery.clone(),
+            None => return Err(GitdisError::BranchNotFound),
+        };
+        let mut report = DiscoveryReport {
+            key: key.to_string(),
+            ..DiscoveryReport::default()
+        };
+
+        let upstream: HashMap<String, BranchSettings> = remote_branches
+            .iter()
+            .filter(|branch_name| discovery.is_match(branch_name))
+            .map(|branch_name| {
+                let settings = discovery.get_branch_settings(branch_name);
+                (settings.get_repo_key(), settings)
+            })
+            .collect();
+
+        for repo_key in discovery.get_branches() {
+            if upstream.contains_key(&repo_key) {
+                continue;
+            }
+
+            // It may have been removed by hand already.
+            let _ = self.remove_branch(&repo_key, false);
+            discovery.remove(&repo_key);
+            report.removed.push(repo_key);
+        }
+
+        for (repo_key, settings) in upstream {
+            if discovery.contains(&repo_key) && self.branches.contains_key(&repo_key) {
+                continue;
+            }
+
+            if let Err(err) = self.add_repo(settings.clone()) {
+                report.skipped.insert(repo_key, format!("{:?}", err));
+                continue;
+            }
+
+            if let Err(err) = self.repo_schedule(settings) {
+                let _ = self.remove_branch(&repo_key, false);
+                report.skipped.insert(repo_key, format!("{:?}", err));
+                continue;
+            }
+
+            discovery.insert(repo_key.clone());
+            report.added.push(repo_key);
+        }
+
+        report.added.sort();
+        report.removed.sort();
+        self.discoveries.insert(key.to_string(), discovery);
+
+        Ok(report)
+    }
+
+    /// Registers branches of an old `gcs` deployment, recording in `report`
+    /// what was registered and what had to be skipped or changed.
+    pub fn migrate_legacy(
+        &mut self,
+        registrations: Vec<LegacyRegistration>,
+        report: &mut MigrationReport,
+    ) {
+        for registration in registrations {
+            let settings = registration.to_settings(report);
+            let key = settings.get_repo_key();
+
+            match self.add_repo(settings.clone()) {
+                Ok(_) => (),
+                Err(GitdisError::RepoExists) => {
+                    report.add_issue(&key, "already registered");
+                    continue;
+                }
+                Err(err) => {
+                    report.add_issue(&key, &format!("{:?}", err));
+                    continue;
+                }
+            }
+
+            // The legacy router listened to every branch it registered.
+            match self.repo_schedule(settings) {
+                Ok(_) => report.migrated.push(key),
+                Err(err) => {
+                    let _ = self.remove_branch(&key, false);
+                    report.add_issue(&key, &format!("{:?}", err));
+                }
+            }
+        }
+    }
+
+    /// Registers a key watch, replacing the one with the same name. Values
+    /// the branches hold for its keys are the baseline changes are
+    /// compared with, see `KeyWatcher`.
+    pub fn add_key_watch(&mut self, watch: KeyWatch) -> Result<(), GitdisError> {
+        debug!("Adding key watch: {}", watch.name);
+
+        self.watcher.add(watch).map_err(GitdisError::InvalidGlob)?;
+
+        for (repo_key, branch) in &self.branches {
+            let cache = match branch.cache.read() {
+                Ok(cache) => cache,
+                Err(_) => continue,
+            };
+
+            for (key, value) in list_refs(&cache, "") {
+                if self.watcher.is_watched(repo_key, &key) {
+                    self.watcher.seed(repo_key, &key, value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_key_watch(&mut self, name: &str) {
+        debug!("Removing key watch: {}", name);
+
+        self.watcher.remove(name);
+    }
+
+    /// The branch key watch `name` is limited to, `Some(None)` if it covers
+    /// every branch, `None` if there is no such watch.
+    pub fn get_key_watch_branch(&self, name: &str) -> Option<Option<String>> {
+        self.watcher.get_branch_key(name)
+    }
+
+    /// Sends watch `name` the changes `events` made to its keys, see
+    /// `KeyWatcher::replay`.
+    pub fn replay_key_watch(&self, name: &str, events: &[BranchEvent]) -> Option<usize> {
+        self.watcher.replay(name, events)
+    }
+
+    /// Names of the key watches, in registration order.
+    pub fn get_key_watches(&self) -> Vec<String> {
+        self.watcher.get_names()
+    }
+
+    /// Registers a policy and re-evaluates it against every known branch.
+    pub fn add_policy(&mut self, policy: BranchPolicy) {
+        debug!("Adding policy: {}", policy.name);
+
+        self.policies.retain(|current| current.name != policy.name);
+        self.policies.push(policy);
+        self.apply_policies();
+    }
+
+    pub fn remove_policy(&mut self, name: &str) {
+        debug!("Removing policy: {}", name);
+
+        self.policies.retain(|current| current.name != name);
+        self.apply_policies();
+    }
+
+    pub fn get_policies(&self) -> &Vec<BranchPolicy> {
+        &self.policies
+    }
+
+    /// Replaces the labels of a branch and recomputes its policy.
+    pub fn set_branch_labels(
+        &mut self,
+        repo_key: &str,
+        labels: HashMap<String, String>,
+    ) -> Result<(), GitdisError> {
+        let branch = match self.branches.get_mut(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        branch.settings.labels = labels.clone();
+        branch.labels = labels;
+        branch.set_policy(ResolvedPolicy::resolve(&self.policies, &branch.labels));
+
+        if branch.apply_standby() {
+            let _ = self.trigger_sync(repo_key);
+        }
+
+        Ok(())
+    }
+
+    /// Enables a branch, or puts it in standby. A policy setting `enabled`
+    /// takes precedence. A branch leaving standby is synced right away;
+    /// one entering it keeps serving its data without syncing.
+    pub fn set_enabled(&mut self, repo_key: &str, enabled: bool) -> Result<(), GitdisError> {
+        debug!("Setting branch {} enabled: {}", repo_key, enabled);
+
+        let branch = match self.branches.get_mut(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        branch.settings.enabled = Some(enabled);
+
+        if branch.apply_standby() {
+            // Without a running listener there is nothing to wake up.
+            let _ = self.trigger_sync(repo_key);
+        }
+
+        Ok(())
+    }
+
+    pub fn is_enabled(&self, repo_key: &str) -> Option<bool> {
+        self.branches.get(repo_key).map(CacheBranch::is_enabled)
+    }
+
+    pub fn get_branch_policy(&self, repo_key: &str) -> Option<ResolvedPolicy> {
+        self.branches.get(repo_key).map(CacheBranch::get_policy)
+    }
+
+    fn apply_policies(&mut self) {
+        let mut activated = Vec::new();
+
+        for (key, branch) in self.branches.iter_mut() {
+            branch.set_policy(ResolvedPolicy::resolve(&self.policies, &branch.labels));
+
+            if branch.apply_standby() {
+                activated.push(key.clone());
+            }
+        }
+
+        for key in activated {
+            let _ = self.trigger_sync(&key);
+        }
+    }
+
+    pub fn get_branch_errors(&self, repo_key: &str) -> Option<HashMap<String, String>> {
+        let branch = self.branches.get(repo_key)?;
+        let errors = branch.errors.read().ok()?;
+
+        Some(errors.clone())
+    }
+
+    /// Failed syncs of a branch, so callers can tell a branch that stopped
+    /// updating from one that has nothing new.
+    pub fn get_sync_failures(&self, repo_key: &str) -> Option<SyncFailures> {
+        let branch = self.branches.get(repo_key)?;
+        let failures = branch.failures.read().ok()?;
+
+        Some(failures.clone())
+    }
+
+    /// A past generation of a branch, if it is still kept.
+    pub fn get_generation(
+        &self,
+        repo_key: &str,
+        selector: &GenerationSelector,
+    ) -> Option<Generation> {
+        let branch = self.branches.get(repo_key)?;
+        let history = branch.history.read().ok()?;
+
+        history.get(selector)
+    }
+
+    /// The commit that last touched `object_key`, or for a prefix the most
+    /// recent commit among the keys below it.
+    pub fn get_metadata(&self, repo_key: &str, object_key: &str) -> Option<CommitMetadata> {
+        let branch = self.branches.get(repo_key)?;
+        let metadata = branch.metadata.read().ok()?;
+        let format = branch.settings.get_key_format();
+        let prefix = format.trim(object_key);
+
+        match metadata.get(prefix) {
+            Some(commit) => Some(commit.as_ref().clone()),
+            None => latest_below(&metadata, prefix, &format).cloned(),
+        }
+    }
+
+    /// Owners of the file of `object_key` from the CODEOWNERS of the
+    /// repository, or for a prefix those of every key below it.
+    pub fn get_key_owners(&self, repo_key: &str, object_key: &str) -> Option<Vec<String>> {
+        let branch = self.branches.get(repo_key)?;
+        let owners = branch.owners.read().ok()?;
+        let format = branch.settings.get_key_format();
+        let prefix = format.trim(object_key);
+
+        match owners.get(prefix) {
+            Some(owners) => Some(owners.clone()),
+            None => Some(owners_below(&owners, prefix, &format)),
+        }
+    }
+
+    /// Versions kept of `object_key`, newest first, see `key_versions`.
+    pub fn get_key_versions(&self, repo_key: &str, object_key: &str) -> Option<Vec<KeyVersion>> {
+        let branch = self.branches.get(repo_key)?;
+        let versions = branch.versions.read().ok()?;
+
+        Some(versions.get(object_key))
+    }
+
+    pub fn get_branch_status(&self, repo_key: &str) -> Option<BranchStatus> {
+        let branch = self.branches.get(repo_key)?;
+        let status = branch.status.read().ok()?;
+
+        Some(status.clone())
+    }
+
+    /// Lint report of the last commit of the branch, `None` inside if it
+    /// has not been linted.
+    pub fn get_lint_report(&self, repo_key: &str) -> Option<Option<LintReport>> {
+        let branch = self.branches.get(repo_key)?;
+        let report = branch.lint_report.read().ok()?;
+
+        Some(report.clone())
+    }
+
+    /// Branches whose clone has edited files or commits missing from the
+    /// remote, sorted, so what they serve may differ from git.
+    pub fn get_dirty_branches(&self) -> Vec<String> {
+        let mut dirty = self
+            .branches
+            .iter()
+            .filter(|(_, branch)| match branch.status.read() {
+                Ok(status) => !status.dirty_files.is_empty() || status.local_commits > 0,
+                Err(_) => false,
+            })
+            .map(|(repo_key, _)| repo_key.clone())
+            .collect::<Vec<String>>();
+
+        dirty.sort();
+        dirty
+    }
+
+    /// Resolves to the commit loaded by the first sync of a branch, at once
+    /// if it already loaded one, otherwise on its `SyncCompleted` event.
+    /// Fails on `SyncFailed`, if the branch is removed meanwhile, or after
+    /// `timeout`. The future holds no borrow of `self`, so it can be
+    /// awaited after a lock on `Gitdis` is released. Needs a tokio runtime.
+    pub fn wait_ready(
+        &self,
+        repo_key: &str,
+        timeout: Duration,
+    ) -> Result<impl std::future::Future<Output = Result<String, GitdisError>> + Send, GitdisError>
+    {
+        let status = match self.branches.get(repo_key) {
+            Some(branch) => branch.get_status(),
+            None => return Err(GitdisError::BranchNotFound),
+        };
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let sender = Mutex::new(Some(sender));
+        let branch_key = repo_key.to_string();
+
+        // Subscribed before the status is read, so a sync finishing in
+        // between is not missed.
+        let id = self.events.subscribe(Arc::new(move |event| {
+            if event.branch_key != branch_key {
+                return;
+            }
+
+            let result = match &event.event {
+                BranchEventKind::SyncCompleted { commit } => Ok(commit.clone()),
+                BranchEventKind::SyncFailed { error } => Err(error.clone()),
+                BranchEventKind::Removed => Err("Branch removed".to_string()),
+                _ => return,
+            };
+
+            if let Some(sender) = sender.lock().ok().and_then(|mut sender| sender.take()) {
+                let _ = sender.send(result);
+            }
+        }));
+
+        let loaded = status
+            .read()
+            .ok()
+            .and_then(|status| status.last_commit.clone());
+        let events = self.events.clone();
+
+        Ok(async move {
+            let result = match loaded {
+                Some(commit) => Ok(commit),
+                None => match tokio::time::timeout(timeout, receiver).await {
+                    Ok(Ok(Ok(commit))) => Ok(commit),
+                    Ok(Ok(Err(error))) => Err(GitdisError::SyncFailed(error)),
+                    Ok(Err(_)) => Err(GitdisError::SyncFailed("Listener dropped".to_string())),
+                    Err(_) => Err(GitdisError::ReadyTimeout),
+                },
+            };
+
+            events.unsubscribe(id);
+
+            result
+        })
+    }
+
+    pub fn create_branch_handler(
+        &self,
+        settings: BranchSettings,
+    ) -> Result<BranchHandler, GitdisError> {
+        let branch = match self.branches.get(&settings.get_repo_key()) {
+            Some(branch) => branch,
+            None => {
+                return Err(GitdisError::BranchNotFound);
+            }
+        };
+
+        let mut settings = settings;
+
+        if settings.proxy.is_none() {
+            settings.proxy = self.settings.proxy.clone();
+        }
+
+        Ok(BranchHandler::new(
+            self.settings.local_clone_path.clone(),
+            settings,
+            branch.get_data(),
+            branch.get_errors(),
+            branch.get_failures(),
+            branch.sync_receiver.clone(),
+            self.events.clone(),
+        )
+        .with_status(branch.get_status())
+        .with_history(branch.get_history())
+        .with_metadata(branch.get_metadata())
+        .with_versions(branch.get_versions())
+        .with_owners(branch.get_owners())
+        .with_lint_report(branch.get_lint_report())
+        .with_policy(branch.get_policy_handle())
+        .with_clock(self.clock.clone())
+        .with_process_runner(self.runner.clone())
+        .with_network_limiter(self.network.clone())
+        .with_parsers(self.parsers.clone())
+        .with_maintenance(self.settings.maintenance_interval_millis))
+    }
+
+    /// Wakes the branch listener so it pulls now instead of at the next poll.
+    pub fn trigger_sync(&self, repo_key: &str) -> Result<(), GitdisError> {
+        debug!("Triggering sync: {}", repo_key);
+
+        let branch = match self.branches.get(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        branch
+            .sync_sender
+            .send(SyncSignal::Sync)
+            .map_err(|_| GitdisError::SyncTrigger)?;
+
+        self.scheduler.wake(repo_key);
+
+        Ok(())
+    }
+
+    /// Asks the branch listener to leave its poll loop. The cache is kept.
+    pub fn stop_listener(&self, repo_key: &str) -> Result<(), GitdisError> {
+        debug!("Stopping listener: {}", repo_key);
+
+        let branch = match self.branches.get(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        branch
+            .sync_sender
+            .send(SyncSignal::Stop)
+            .map_err(|_| GitdisError::SyncTrigger)?;
+
+        self.scheduler.wake(repo_key);
+
+        Ok(())
+    }
+
+    /// Suspends polling of a branch, e.g. during an upstream maintenance
+    /// window. The cache is kept and keeps serving reads.
+    pub fn pause_branch(&self, repo_key: &str) -> Result<(), GitdisError> {
+        debug!("Pausing branch: {}", repo_key);
+
+        self.set_paused(repo_key, true)
+    }
+
+    /// Resumes polling of a paused branch, syncing it right away.
+    pub fn resume_branch(&self, repo_key: &str) -> Result<(), GitdisError> {
+        debug!("Resuming branch: {}", repo_key);
+
+        self.set_paused(repo_key, false)?;
+
+        // Without a running listener there is nothing to wake up.
+        let _ = self.trigger_sync(repo_key);
+
+        Ok(())
+    }
+
+    /// Lets the next sync of a branch go over its sync limits, loading the
+    /// commit they blocked, and syncs it right away.
+    pub fn override_sync_limits(&self, repo_key: &str) -> Result<(), GitdisError> {
+        debug!("Overriding sync limits: {}", repo_key);
+
+        let branch = match self.branches.get(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        if let Ok(mut status) = branch.status.write() {
+            status.override_sync_limits = true;
+        }
+
+        // Without a running listener there is nothing to wake up.
+        let _ = self.trigger_sync(repo_key);
+
+        Ok(())
+    }
+
+    fn set_paused(&self, repo_key: &str, paused: bool) -> Result<(), GitdisError> {
+        let branch = match self.branches.get(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        if let Ok(mut status) = branch.status.write() {
+            status.paused = paused;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_archived(&self, repo_key: &str) -> Option<bool> {
+        self.branches
+            .get(repo_key)
+            .map(|branch| branch.archived.load(Ordering::SeqCst))
+    }
+
+    /// Marks a branch archived or not. Returns `false` if it already was in
+    /// that state, so only one caller archives or restores it.
+    pub fn set_archived(&self, repo_key: &str, archived: bool) -> Result<bool, GitdisError> {
+        let branch = match self.branches.get(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        Ok(branch
+            .archived
+            .compare_exchange(!archived, archived, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok())
+    }
+
+    /// Fills the cache of an archived branch with `restore` and marks it
+    /// restored, see `archive::finish_restore`. Returns `false` if it was
+    /// not archived.
+    pub fn finish_restore(
+        &self,
+        repo_key: &str,
+        restore: impl FnOnce(&mut Cache),
+    ) -> Result<bool, GitdisError> {
+        let branch = match self.branches.get(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        Ok(finish_restore(&branch.cache, &branch.archived, restore))
+    }
+
+    /// Clears the cache of a branch being archived, unless it was restored
+    /// meanwhile. Returns whether it was cleared.
+    pub fn finish_archive(&self, repo_key: &str) -> Result<bool, GitdisError> {
+        let branch = match self.branches.get(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        Ok(finish_archive(&branch.cache, &branch.archived, |cache| {
+            cache.clear()
+        }))
+    }
+
+    /// Unregisters a branch: its listener is stopped and its cache dropped.
+    /// With `remove_clone` its working tree is deleted too.
+    pub fn remove_branch(&mut self, repo_key: &str, remove_clone: bool) -> Result<(), GitdisError> {
+        debug!("Removing branch: {}", repo_key);
+
+        if !self.branches.contains_key(repo_key) {
+            return Err(GitdisError::BranchNotFound);
+        }
+
+        // The listener may already be gone, e.g. after failing for good.
+        let _ = self.stop_listener(repo_key);
+
+        if remove_clone {
+            self.remove_clone(repo_key)?;
+        }
+
+        self.branches.remove(repo_key);
+        self.key_formats.remove(repo_key);
+        self.events.publish(repo_key, BranchEventKind::Removed);
+
+        // Registered again by its discovery if it is still upstream.
+        for discovery in self.discoveries.values_mut() {
+            discovery.remove(repo_key);
+        }
+
+        debug!("Removed branch: {}", repo_key);
+
+        Ok(())
+    }
+
+    /// Reconciles the clone directory with the registered branches, see
+    /// `reconcile_clones`. Run it before the listeners start.
+    pub fn reconcile_clones(&self, policy: OrphanPolicy) -> ReconcileReport {
+        debug!("Reconciling clones in {}", self.settings.local_clone_path);
+
+        let now_millis = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        reconcile_clones(
+            &self.settings.local_clone_path,
+            self.get_registered_settings().iter(),
+            policy,
+            now_millis,
+        )
+    }
+
+    /// Drops a key from the branch cache without touching git; it is loaded
+    /// again once its file changes or the branch is reloaded. Returns
+    /// `false` if the key was not cached.
+    pub fn evict_key(&self, repo_key: &str, object_key: &str) -> Result<bool, GitdisError> {
+        let branch = match self.branches.get(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        let mut cache = match branch.cache.write() {
+            Ok(cache) => cache,
+            Err(_) => return Ok(false),
+        };
+
+        if cache.get(object_key).is_none() {
+            return Ok(false);
+        }
+
+        self.events
+            .expect_removal(repo_key, object_key, Some(EvictionReason::Manual));
+
+        Ok(cache.remove(object_key).is_ok())
+    }
+
+    /// Deletes the local clone of a branch.
+    pub fn remove_clone(&self, repo_key: &str) -> Result<(), GitdisError> {
+        self.get_clone_handler(repo_key)?
+            .remove_clone()
+            .map_err(|err| GitdisError::RemoveClone(err.to_string()))
+    }
+
+    /// A handler for the clone of a branch, so callers holding `Gitdis`
+    /// behind a lock can release it before touching the disk.
+    pub fn get_clone_handler(&self, repo_key: &str) -> Result<BranchHandler, GitdisError> {
+        let branch = match self.branches.get(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        self.create_branch_handler(branch.settings.clone())
+    }
+
+    /// Writes `value` back to the file behind `object_key` and refreshes the
+    /// cached entry once the commit has been pushed.
+    pub fn write_back(
+        &self,
+        repo_key: &str,
+        object_key: &str,
+        value: Value,
+        expected_commit: Option<&str>,
+    ) -> Result<String, GitdisError> {
+        self.prepare_write_back(repo_key)?
+            .run(object_key, value, expected_commit)
+    }
+
+    /// Same as `write_back`, split so the commit and push can run after a
+    /// lock on `Gitdis` is released.
+    pub fn prepare_write_back(&self, repo_key: &str) -> Result<PendingWriteBack, GitdisError> {
+        let branch = match self.branches.get(repo_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisError::BranchNotFound),
+        };
+
+        Ok(PendingWriteBack {
+            handler: self.create_branch_handler(branch.settings.clone())?,
+            cache: branch.cache.clone(),
+        })
+    }
+
+    pub fn repo_listen(
+        &self,
+        settings: BranchSettings,
+    ) -> Result<thread::JoinHandle<()>, GitdisError> {
+        let repo_key = settings.get_repo_key();
+        let mut handler = self.create_branch_handler(settings)?;
+        self.drain_sync_signals(&repo_key);
+
+        let guard = ListenerGuard::new(self.listeners.clone());
+
+        Ok(thread::spawn(move || {
+            let _guard = guard;
+
+            if let Err(e) = handler.listen() {
+                error!("Listener for {} failed: {:?}", repo_key, e);
+            }
+        }))
+    }
+
+    /// Same as `repo_listen`, sharing the scheduler's worker pool with the
+    /// other scheduled branches instead of taking a thread of its own, so
+    /// many branches can be watched. Stopping, pausing, triggering a sync
+    /// and `shutdown` work the same way.
+    pub fn repo_schedule(&self, settings: BranchSettings) -> Result<(), GitdisError> {
+        let repo_key = settings.get_repo_key();
+        let handler = self.create_branch_handler(settings)?;
+        self.drain_sync_signals(&repo_key);
+
+        self.scheduler.schedule(ScheduledBranch {
+            key: repo_key,
+            handler,
+            next_at: Instant::now(),
+            ready: false,
+            attempt: 0,
+            _guard: ListenerGuard::new(self.listeners.clone()),
+        });
+
+        Ok(())
+    }
+
+    /// Signals sent while no listener was running are meant for the
+    /// previous one.
+    fn drain_sync_signals(&self, repo_key: &str) {
+        if let Some(branch) = self.branches.get(repo_key) {
+            if let Ok(receiver) = branch.sync_receiver.lock() {
+                while receiver.try_recv().is_ok() {}
+            }
+        }
+    }
+
+    pub fn get_running_listeners(&self) -> usize {
+        self.listeners.count.lock().map_or(0, |count| *count)
+    }
+
+    /// Stops every branch listener and waits up to `timeout` for their
+    /// threads to finish. A listener stops once its current sync, retries
+    /// included, is over. Returns `false` if some were still running.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        debug!("Shutting down {} listeners", self.get_running_listeners());
+
+        for repo_key in self.branches.keys() {
+            let _ = self.stop_listener(repo_key);
+        }
+
+        let count = match self.listeners.count.lock() {
+            Ok(count) => count,
+            Err(_) => return false,
+        };
+
+        match self
+            .listeners
+            .stopped
+            .wait_timeout_while(count, timeout, |count| *count > 0)
+        {
+            Ok((count, _)) => {
+                debug!("{} listeners still running after shutdown", *count);
+                *count == 0
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn listen_events<Callback>(&self, callback: Callback)
     where
         Callback: Fn(Event) + Send + 'static,
     {
-        for event in self.receiver.iter() {
+        let receiver = match self.receiver.lock() {
+            Ok(receiver) => receiver,
+            Err(_) => return,
+        };
+
+        for event in receiver.iter() {
             match event {
                 Event::Insert(data) => {
                     debug!("Inserting data: {:?}", data);
@@ -203,6 +1948,16 @@ impl Gitdis {
     }
 }
 
+impl Drop for Gitdis {
+    /// Listeners also stop once their branch is dropped, but only after
+    /// their next poll; asking them now ends them sooner.
+    fn drop(&mut self) {
+        for repo_key in self.branches.keys() {
+            let _ = self.stop_listener(repo_key);
+        }
+    }
+}
+
 impl From<GitdisSettings> for Gitdis {
     fn from(settings: GitdisSettings) -> Self {
         let (sender, receiver) = mpsc::channel();
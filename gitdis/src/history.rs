@@ -0,0 +1,206 @@
+use super::keys::KeyFormat;
+use super::memo::build_subtree;
+use quickleaf::valu3::prelude::*;
+use quickleaf::Cache;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+
+/// How a past generation of a branch is addressed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GenerationSelector {
+    /// The newest generation recorded at or before this branch sequence.
+    Sequence(u64),
+    /// The generation loaded from this commit, or from the only one
+    /// starting with these characters.
+    Commit(String),
+}
+
+impl GenerationSelector {
+    /// A number is a sequence, anything else a commit. An abbreviated
+    /// commit made only of digits reads as a sequence; the full commit
+    /// never does.
+    pub fn parse(selector: &str) -> Self {
+        match selector.parse() {
+            Ok(sequence) => GenerationSelector::Sequence(sequence),
+            Err(_) => GenerationSelector::Commit(selector.to_lowercase()),
+        }
+    }
+}
+
+/// The entries of a branch as loaded from one commit. Values that did not
+/// change between generations are shared rather than copied.
+#[derive(Clone, Debug)]
+pub struct Generation {
+    /// Sequence of the branch when the generation was recorded. Events of
+    /// the load itself may still be in flight then and come after it.
+    pub sequence: u64,
+    pub commit: String,
+    pub recorded_at_millis: u128,
+    entries: Arc<BTreeMap<String, Arc<Value>>>,
+    key_format: KeyFormat,
+}
+
+impl Generation {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries.get(key).map(|value| value.as_ref())
+    }
+
+    /// Entries whose key starts with `prefix`, in key order.
+    pub fn list_refs(&self, prefix: &str) -> Vec<(String, &Value)> {
+        self.entries
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.as_ref()))
+            .collect()
+    }
+
+    pub fn list_prefix(&self, prefix: &str) -> Vec<(String, Value)> {
+        self.list_refs(prefix)
+            .into_iter()
+            .map(|(key, value)| (key, value.clone()))
+            .collect()
+    }
+
+    /// Reads `object_key` like `GitdisService::get_object`: the entry with
+    /// that key, or the entries below it rebuilt into a nested object.
+    pub fn get_object(&self, object_key: &str) -> Option<Value> {
+        let prefix = self.key_format.trim(object_key);
+
+        if let Some(value) = self.get(prefix) {
+            return Some(value.clone());
+        }
+
+        let entries = self.list_prefix(&self.key_format.below(prefix));
+
+        build_subtree(prefix, entries, &self.key_format)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert("sequence".to_string(), Value::from(self.sequence));
+        object.insert("commit".to_string(), Value::from(self.commit.as_str()));
+        object.insert(
+            "recorded_at_millis".to_string(),
+            Value::from(self.recorded_at_millis),
+        );
+        object.insert("entries".to_string(), Value::from(self.entries.len()));
+
+        Value::from(object)
+    }
+}
+
+/// The last generations of a branch, oldest first, up to a capacity.
+#[derive(Clone, Debug, Default)]
+pub struct BranchHistory {
+    capacity: usize,
+    generations: VecDeque<Generation>,
+    key_format: KeyFormat,
+}
+
+impl BranchHistory {
+    /// Keeps up to `capacity` generations; none when it is 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            generations: VecDeque::new(),
+            key_format: KeyFormat::default(),
+        }
+    }
+
+    /// The format generations nest keys by in `Generation::get_object`.
+    pub fn with_key_format(mut self, key_format: KeyFormat) -> Self {
+        self.key_format = key_format;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get_last_commit(&self) -> Option<&str> {
+        self.generations
+            .back()
+            .map(|generation| generation.commit.as_str())
+    }
+
+    /// Records the entries of `cache` as the generation of `commit`, sharing
+    /// the values the previous generation already holds. The oldest
+    /// generation is dropped past the capacity.
+    pub fn record(&mut self, cache: &Cache, commit: &str, sequence: u64, now_millis: u128) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let previous = self
+            .generations
+            .back()
+            .map(|generation| &generation.entries);
+        let entries = crate::cache::list_refs(cache, "")
+            .into_iter()
+            .map(|(key, value)| {
+                let shared = previous
+                    .and_then(|entries| entries.get(&key))
+                    .filter(|previous| previous.as_ref() == value)
+                    .cloned();
+
+                let value = shared.unwrap_or_else(|| Arc::new(value.clone()));
+                (key, value)
+            })
+            .collect();
+
+        self.generations.push_back(Generation {
+            sequence,
+            commit: commit.to_string(),
+            recorded_at_millis: now_millis,
+            entries: Arc::new(entries),
+            key_format: self.key_format.clone(),
+        });
+
+        while self.generations.len() > self.capacity {
+            self.generations.pop_front();
+        }
+    }
+
+    pub fn get(&self, selector: &GenerationSelector) -> Option<Generation> {
+        match selector {
+            GenerationSelector::Sequence(sequence) => self
+                .generations
+                .iter()
+                .rev()
+                .find(|generation| generation.sequence <= *sequence)
+                .cloned(),
+            GenerationSelector::Commit(commit) => {
+                let mut found = self
+                    .generations
+                    .iter()
+                    .rev()
+                    .filter(|generation| generation.commit.starts_with(commit.as_str()));
+                let first = found.next()?;
+
+                // A prefix of several commits addresses none of them.
+                match first.commit == *commit || found.all(|other| other.commit == first.commit) {
+                    true => Some(first.clone()),
+                    false => None,
+                }
+            }
+        }
+    }
+
+    /// The generations kept, oldest first.
+    pub fn list(&self) -> Vec<Generation> {
+        self.generations.iter().cloned().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.generations.clear();
+    }
+}
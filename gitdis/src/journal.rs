@@ -0,0 +1,184 @@
+use crate::events::{BranchEvent, BranchEventKind};
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+
+/// Events kept per branch unless `GITDIS_EVENT_JOURNAL_CAPACITY` says
+/// otherwise.
+pub const DEFAULT_JOURNAL_CAPACITY: usize = 10_000;
+/// Shortest commit prefix `since` accepts.
+const MIN_COMMIT_PREFIX: usize = 7;
+
+#[derive(Debug, PartialEq)]
+pub enum JournalError {
+    /// Events after the position were already dropped; the oldest sequence
+    /// still replayable from is given.
+    Truncated(u64),
+    /// No sync of the commit is in the journal.
+    CommitNotFound(String),
+}
+
+impl std::fmt::Display for JournalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JournalError::Truncated(oldest) => write!(
+                f,
+                "Events were dropped from the journal, replay from {} or later",
+                oldest
+            ),
+            JournalError::CommitNotFound(commit) => {
+                write!(f, "No sync of commit {} in the journal", commit)
+            }
+        }
+    }
+}
+
+/// Where a replay starts: after a sequence number, or after the sync that
+/// loaded a commit.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JournalPosition {
+    Sequence(u64),
+    Commit(String),
+}
+
+impl JournalPosition {
+    /// A number is a sequence, anything else a commit. A hash prefix made
+    /// of digits only is read as a number, so give one long enough to hold
+    /// a letter.
+    pub fn parse(since: &str) -> Self {
+        match since.parse() {
+            Ok(sequence) => JournalPosition::Sequence(sequence),
+            Err(_) => JournalPosition::Commit(since.to_string()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct BranchJournal {
+    events: VecDeque<BranchEvent>,
+    /// Sequence of the newest event dropped to stay within capacity.
+    dropped: u64,
+}
+
+/// The latest events of each branch, so subscribers added later can be
+/// caught up. Removing a branch drops its events.
+pub struct EventJournal {
+    capacity: usize,
+    branches: RwLock<HashMap<String, BranchJournal>>,
+}
+
+impl EventJournal {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            branches: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, event: &BranchEvent) {
+        let mut branches = match self.branches.write() {
+            Ok(branches) => branches,
+            Err(_) => return,
+        };
+
+        if let BranchEventKind::Removed = event.event {
+            branches.remove(&event.branch_key);
+            return;
+        }
+
+        let journal = branches.entry(event.branch_key.clone()).or_default();
+        journal.events.push_back(event.clone());
+
+        while journal.events.len() > self.capacity {
+            if let Some(dropped) = journal.events.pop_front() {
+                journal.dropped = dropped.sequence;
+            }
+        }
+    }
+
+    /// Sequence of the last sync of `commit` in the journal, matched by
+    /// prefix for abbreviated hashes.
+    pub fn resolve_commit(&self, branch_key: &str, commit: &str) -> Option<u64> {
+        if commit.len() < MIN_COMMIT_PREFIX {
+            return None;
+        }
+
+        let branches = self.branches.read().ok()?;
+
+        branches
+            .get(branch_key)?
+            .events
+            .iter()
+            .rev()
+            .find_map(|event| match &event.event {
+                BranchEventKind::SyncCompleted { commit: synced } if synced.starts_with(commit) => {
+                    Some(event.sequence)
+                }
+                _ => None,
+            })
+    }
+
+    /// Events of every branch after `since`, oldest first.
+    pub fn replay_all(&self, since: u64) -> Result<Vec<BranchEvent>, JournalError> {
+        let branches = match self.branches.read() {
+            Ok(branches) => branches,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let mut events = Vec::new();
+
+        for journal in branches.values() {
+            if since < journal.dropped {
+                return Err(JournalError::Truncated(journal.dropped));
+            }
+
+            events.extend(
+                journal
+                    .events
+                    .iter()
+                    .filter(|event| event.sequence > since)
+                    .cloned(),
+            );
+        }
+
+        events.sort_by_key(|event| event.sequence);
+
+        Ok(events)
+    }
+
+    /// Events of `branch_key` after `position` and up to `until`, oldest
+    /// first.
+    pub fn replay(
+        &self,
+        branch_key: &str,
+        position: &JournalPosition,
+        until: u64,
+    ) -> Result<Vec<BranchEvent>, JournalError> {
+        let since = match position {
+            JournalPosition::Sequence(sequence) => *sequence,
+            JournalPosition::Commit(commit) => self
+                .resolve_commit(branch_key, commit)
+                .ok_or(JournalError::CommitNotFound(commit.clone()))?,
+        };
+
+        let branches = match self.branches.read() {
+            Ok(branches) => branches,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let journal = match branches.get(branch_key) {
+            Some(journal) => journal,
+            None => return Ok(Vec::new()),
+        };
+
+        if since < journal.dropped {
+            return Err(JournalError::Truncated(journal.dropped));
+        }
+
+        Ok(journal
+            .events
+            .iter()
+            .filter(|event| event.sequence > since && event.sequence <= until)
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// How the path of a file maps to its cache key. Directories and the file
+/// name, without its extension, become segments joined by `separator`.
+/// Within a segment, `separator` and `escape` are prefixed with `escape`,
+/// so `hosts/db.example.com.yaml` is `hosts.db\.example\.com` with `.` as
+/// separator and the segments can always be told apart again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyFormat {
+    pub separator: char,
+    pub escape: char,
+}
+
+impl Default for KeyFormat {
+    fn default() -> Self {
+        Self {
+            separator: '/',
+            escape: '\\',
+        }
+    }
+}
+
+impl KeyFormat {
+    /// Checks that the separator and escape are distinct.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.separator == self.escape {
+            return Err("Key separator and escape must differ".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn escape_segment(&self, segment: &str) -> String {
+        let mut escaped = String::with_capacity(segment.len());
+
+        for char in segment.chars() {
+            if char == self.separator || char == self.escape {
+                escaped.push(self.escape);
+            }
+
+            escaped.push(char);
+        }
+
+        escaped
+    }
+
+    pub fn join<S: AsRef<str>>(&self, segments: &[S]) -> String {
+        segments
+            .iter()
+            .map(|segment| self.escape_segment(segment.as_ref()))
+            .collect::<Vec<String>>()
+            .join(&self.separator.to_string())
+    }
+
+    /// The key of `path`, relative to the data root with `/` between
+    /// directories. Only the extension of the file name is dropped.
+    pub fn from_path(&self, path: &str) -> String {
+        let mut segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<&str>>();
+
+        if let Some(name) = segments.last_mut() {
+            if let Some((stem, _)) = name.rsplit_once('.').filter(|(stem, _)| !stem.is_empty()) {
+                *name = stem;
+            }
+        }
+
+        self.join(&segments)
+    }
+
+    /// The unescaped segments of `key`.
+    pub fn split(&self, key: &str) -> Vec<String> {
+        if key.is_empty() {
+            return Vec::new();
+        }
+
+        let mut segments = vec![String::new()];
+        let mut escaped = false;
+
+        for char in key.chars() {
+            match (escaped, char) {
+                (false, char) if char == self.escape => escaped = true,
+                (false, char) if char == self.separator => segments.push(String::new()),
+                (_, char) => {
+                    escaped = false;
+                    segments.last_mut().unwrap().push(char);
+                }
+            }
+        }
+
+        segments
+    }
+
+    /// The path `key` was read from, without the extension of its file.
+    pub fn to_path(&self, key: &str) -> String {
+        self.split(key).join("/")
+    }
+
+    /// `key` without trailing separators, escaped ones kept.
+    pub fn trim<'a>(&self, key: &'a str) -> &'a str {
+        let mut key = key;
+
+        while let Some(rest) = key.strip_suffix(self.separator) {
+            if self.ends_escaped(rest) {
+                break;
+            }
+
+            key = rest;
+        }
+
+        key
+    }
+
+    /// Whether `key` ends with an escape that applies to what follows it.
+    fn ends_escaped(&self, key: &str) -> bool {
+        key.chars()
+            .rev()
+            .take_while(|char| *char == self.escape)
+            .count()
+            % 2
+            == 1
+    }
+
+    /// Whether `key` is `prefix` itself or nested below it.
+    pub fn is_within(&self, key: &str, prefix: &str) -> bool {
+        prefix.is_empty()
+            || key == prefix
+            || key
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with(self.separator) && !self.ends_escaped(prefix))
+    }
+
+    /// The prefix listing the keys nested below `prefix`.
+    pub fn below(&self, prefix: &str) -> String {
+        match prefix.is_empty() {
+            true => String::new(),
+            false => format!("{}{}", prefix, self.separator),
+        }
+    }
+
+    /// The key `key` is nested in, `""` at the top.
+    pub fn parent<'a>(&self, key: &'a str) -> &'a str {
+        let mut escaped = false;
+        let mut parent = "";
+
+        for (index, char) in key.char_indices() {
+            match (escaped, char) {
+                (false, char) if char == self.escape => escaped = true,
+                (false, char) if char == self.separator => parent = &key[..index],
+                _ => escaped = false,
+            }
+        }
+
+        parent
+    }
+}
+
+/// The key format of each branch, for code keyed by branch rather than
+/// holding one. Branches not found use the default format.
+#[derive(Clone, Default)]
+pub struct KeyFormats {
+    formats: Arc<RwLock<HashMap<String, KeyFormat>>>,
+}
+
+impl KeyFormats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, branch_key: &str) -> KeyFormat {
+        self.formats
+            .read()
+            .ok()
+            .and_then(|formats| formats.get(branch_key).cloned())
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, branch_key: &str, format: KeyFormat) {
+        if let Ok(mut formats) = self.formats.write() {
+            formats.insert(branch_key.to_string(), format);
+        }
+    }
+
+    pub fn remove(&self, branch_key: &str) {
+        if let Ok(mut formats) = self.formats.write() {
+            formats.remove(branch_key);
+        }
+    }
+}
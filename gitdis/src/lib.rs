@@ -1,7 +1,46 @@
+mod archive;
 pub mod branch_handler;
 mod cache;
+pub mod clock;
+pub mod codec;
+pub mod discovery;
+pub mod dump;
+pub mod events;
+#[cfg(feature = "export")]
+pub mod export;
+pub mod filter;
 pub mod gitdis;
+pub mod history;
+pub mod journal;
+pub mod keys;
+pub mod limiter;
+pub mod lint;
+pub mod matrix;
+pub mod memo;
+pub mod metadata;
+pub mod migration;
+pub mod owners;
+pub mod patch;
+pub mod payload;
+pub mod policy;
 pub mod prelude;
+pub mod process;
+pub mod publish;
+pub mod query;
+pub mod reconcile;
+pub mod repo_url;
+pub mod retry;
+pub mod schema;
 pub mod services;
+#[cfg(feature = "sql")]
+pub mod sql;
+pub mod status;
+mod sync;
+pub mod template;
 #[cfg(test)]
 mod tests;
+pub mod usage;
+pub mod versions;
+pub mod view;
+pub mod walk;
+pub mod watch;
@@ -0,0 +1,86 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Default)]
+struct LimiterState {
+    limit: Option<usize>,
+    running: usize,
+    waiting: usize,
+}
+
+/// Bounds the git network operations (clones, fetches, pulls, pushes and
+/// tarball downloads) running at once across every branch, so registering
+/// many branches together neither saturates the network nor trips the rate
+/// limits of the git host. Operations past the limit wait for a permit.
+#[derive(Clone, Default)]
+pub struct NetworkLimiter {
+    state: Arc<(Mutex<LimiterState>, Condvar)>,
+}
+
+/// Held while a network operation runs; dropping it lets the next one in.
+pub struct NetworkPermit {
+    limiter: NetworkLimiter,
+}
+
+impl NetworkLimiter {
+    /// At most `limit` operations at once, any number without one.
+    pub fn new(limit: Option<usize>) -> Self {
+        let limiter = Self::default();
+        limiter.set_limit(limit);
+        limiter
+    }
+
+    /// Operations running above a lowered limit finish, then new ones wait
+    /// until the count is back under it.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        let (lock, condvar) = &*self.state;
+
+        if let Ok(mut state) = lock.lock() {
+            state.limit = limit.map(|limit| limit.max(1));
+        }
+
+        condvar.notify_all();
+    }
+
+    /// Blocks until an operation may start.
+    pub fn acquire(&self) -> NetworkPermit {
+        let (lock, condvar) = &*self.state;
+
+        if let Ok(mut state) = lock.lock() {
+            state.waiting += 1;
+
+            while state.limit.is_some_and(|limit| state.running >= limit) {
+                state = match condvar.wait(state) {
+                    Ok(state) => state,
+                    Err(err) => err.into_inner(),
+                };
+            }
+
+            state.waiting -= 1;
+            state.running += 1;
+        }
+
+        NetworkPermit {
+            limiter: self.clone(),
+        }
+    }
+
+    /// Operations running, and waiting for a permit.
+    pub fn get_counts(&self) -> (usize, usize) {
+        match self.state.0.lock() {
+            Ok(state) => (state.running, state.waiting),
+            Err(_) => (0, 0),
+        }
+    }
+}
+
+impl Drop for NetworkPermit {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.limiter.state;
+
+        if let Ok(mut state) = lock.lock() {
+            state.running = state.running.saturating_sub(1);
+        }
+
+        condvar.notify_one();
+    }
+}
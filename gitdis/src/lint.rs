@@ -0,0 +1,284 @@
+use crate::query::ConditionQuery;
+use quickleaf::valu3::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Strings spelling a boolean that are flagged by `non_normalized_boolean`,
+/// compared case-insensitively. `true` and `false` themselves are only
+/// flagged when not lowercase.
+const BOOLEAN_SPELLINGS: [&str; 4] = ["true", "false", "yes", "no"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LintLevel {
+    Warning,
+    /// Blocks the commit when the branch lints with `block_on_error`.
+    Error,
+}
+
+impl LintLevel {
+    pub fn parse(level: &str) -> Option<Self> {
+        match level {
+            "warning" => Some(LintLevel::Warning),
+            "error" => Some(LintLevel::Error),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LintLevel::Warning => "warning",
+            LintLevel::Error => "error",
+        }
+    }
+}
+
+/// A custom rule: every key under `query.prefix` whose value matches all
+/// of `query.conditions` is reported with `message`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LintRule {
+    pub name: String,
+    pub level: LintLevel,
+    pub query: ConditionQuery,
+    pub message: String,
+}
+
+/// Levels of the built-in rules, `None` turning one off, and the custom
+/// rules run on each commit of a branch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LintSettings {
+    /// Several files, e.g. `app.yaml` and `app.json`, giving the same key.
+    pub duplicate_key: Option<LintLevel>,
+    /// Null values and empty strings, objects and arrays.
+    pub empty_value: Option<LintLevel>,
+    /// Strings longer than `max_string_length` characters.
+    pub long_string: Option<LintLevel>,
+    pub max_string_length: usize,
+    /// Strings such as `"True"` or `"yes"` where a boolean was likely meant.
+    pub non_normalized_boolean: Option<LintLevel>,
+    pub rules: Vec<LintRule>,
+    /// Do not load a commit with error-level findings. It is reported as
+    /// rejected and the branch keeps serving the last one loaded.
+    pub block_on_error: bool,
+}
+
+impl Default for LintSettings {
+    fn default() -> Self {
+        Self {
+            duplicate_key: Some(LintLevel::Error),
+            empty_value: Some(LintLevel::Warning),
+            long_string: Some(LintLevel::Warning),
+            max_string_length: 4096,
+            non_normalized_boolean: Some(LintLevel::Warning),
+            rules: Vec::new(),
+            block_on_error: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LintFinding {
+    pub rule: String,
+    pub level: LintLevel,
+    pub key: String,
+    /// JSON pointer to the offending value inside the key, empty for the
+    /// whole value.
+    pub path: String,
+    pub message: String,
+}
+
+impl LintFinding {
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert("rule".to_string(), Value::from(self.rule.as_str()));
+        object.insert("level".to_string(), Value::from(self.level.as_str()));
+        object.insert("key".to_string(), Value::from(self.key.as_str()));
+        object.insert("path".to_string(), Value::from(self.path.as_str()));
+        object.insert("message".to_string(), Value::from(self.message.as_str()));
+
+        Value::from(object)
+    }
+}
+
+/// Findings of the last commit linted on a branch.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct LintReport {
+    pub commit: String,
+    pub findings: Vec<LintFinding>,
+    /// The commit was not loaded because of error-level findings.
+    pub blocked: bool,
+}
+
+impl LintReport {
+    pub fn count(&self, level: LintLevel) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.level == level)
+            .count()
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert("commit".to_string(), Value::from(self.commit.as_str()));
+        object.insert(
+            "findings".to_string(),
+            Value::from(
+                self.findings
+                    .iter()
+                    .map(LintFinding::to_value)
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        object.insert(
+            "errors".to_string(),
+            Value::from(self.count(LintLevel::Error) as u64),
+        );
+        object.insert(
+            "warnings".to_string(),
+            Value::from(self.count(LintLevel::Warning) as u64),
+        );
+        object.insert("blocked".to_string(), Value::from(self.blocked));
+
+        Value::from(object)
+    }
+}
+
+/// Lints the keys of a commit. `sources` lists the files each key was
+/// read from, `data` the value loaded for it. Findings are ordered by key,
+/// then path.
+pub fn lint(
+    sources: &HashMap<String, Vec<String>>,
+    data: &HashMap<String, Value>,
+    settings: &LintSettings,
+) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+
+    if let Some(level) = settings.duplicate_key {
+        for (key, files) in sources.iter().filter(|(_, files)| files.len() > 1) {
+            let mut files = files.clone();
+            files.sort();
+
+            findings.push(LintFinding {
+                rule: "duplicate_key".to_string(),
+                level,
+                key: key.clone(),
+                path: String::new(),
+                message: format!("Defined by several files: {}", files.join(", ")),
+            });
+        }
+    }
+
+    let entries = data.iter().collect::<BTreeMap<&String, &Value>>();
+
+    for (key, value) in entries {
+        lint_value(key, "", value, settings, &mut findings);
+
+        for rule in &settings.rules {
+            if rule.query.matches(key, value) {
+                findings.push(LintFinding {
+                    rule: rule.name.clone(),
+                    level: rule.level,
+                    key: key.clone(),
+                    path: String::new(),
+                    message: rule.message.clone(),
+                });
+            }
+        }
+    }
+
+    findings.sort_by(|a, b| (&a.key, &a.path).cmp(&(&b.key, &b.path)));
+    findings
+}
+
+fn lint_value(
+    key: &str,
+    path: &str,
+    value: &Value,
+    settings: &LintSettings,
+    findings: &mut Vec<LintFinding>,
+) {
+    let mut report = |rule: &str, level: Option<LintLevel>, message: String| {
+        if let Some(level) = level {
+            findings.push(LintFinding {
+                rule: rule.to_string(),
+                level,
+                key: key.to_string(),
+                path: path.to_string(),
+                message,
+            });
+        }
+    };
+
+    match value {
+        Value::Null => report(
+            "empty_value",
+            settings.empty_value,
+            "Null value".to_string(),
+        ),
+        Value::String(string) => {
+            let string = string.to_string();
+            let length = string.chars().count();
+
+            if string.is_empty() {
+                report(
+                    "empty_value",
+                    settings.empty_value,
+                    "Empty string".to_string(),
+                );
+            } else if length > settings.max_string_length {
+                report(
+                    "long_string",
+                    settings.long_string,
+                    format!(
+                        "String of {} characters, over {}",
+                        length, settings.max_string_length
+                    ),
+                );
+            } else if BOOLEAN_SPELLINGS.contains(&string.to_ascii_lowercase().as_str())
+                && string != "true"
+                && string != "false"
+            {
+                report(
+                    "non_normalized_boolean",
+                    settings.non_normalized_boolean,
+                    format!("String {:?} looks like a boolean", string),
+                );
+            }
+        }
+        Value::Array(array) => {
+            if array.values.is_empty() {
+                report(
+                    "empty_value",
+                    settings.empty_value,
+                    "Empty array".to_string(),
+                );
+            }
+
+            for (index, item) in array.values.iter().enumerate() {
+                lint_value(
+                    key,
+                    &format!("{}/{}", path, index),
+                    item,
+                    settings,
+                    findings,
+                );
+            }
+        }
+        Value::Object(object) => {
+            if object.iter().next().is_none() {
+                report(
+                    "empty_value",
+                    settings.empty_value,
+                    "Empty object".to_string(),
+                );
+            }
+
+            for (name, item) in object.iter() {
+                let name = name.to_string().replace('~', "~0").replace('/', "~1");
+
+                lint_value(key, &format!("{}/{}", path, name), item, settings, findings);
+            }
+        }
+        _ => (),
+    }
+}
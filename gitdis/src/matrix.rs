@@ -0,0 +1,66 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::payload::PayloadError;
+
+/// Location of the matrix file, relative to the repository root.
+pub const MATRIX_FILE: &str = ".gitdis/matrix.yaml";
+/// Prefix of the keys published for each matrix entry.
+pub const MATRIX_KEY_PREFIX: &str = "env";
+
+/// One environment of the matrix and the variables substituted into the
+/// templates for it.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct MatrixEntry {
+    pub name: String,
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+}
+
+/// Content of `.gitdis/matrix.yaml`:
+///
+/// ```yaml
+/// environments:
+///   - name: staging
+///     vars: { region: eu-west-1 }
+///   - name: production
+///     vars: { region: us-east-1 }
+/// templates:
+///   - services/api.yaml
+/// ```
+///
+/// Every template is rendered once per environment, replacing `${var}`
+/// with the entry's value (and `${name}` with the entry name), and published
+/// under `env.<name>.<template key>`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Matrix {
+    pub environments: Vec<MatrixEntry>,
+    #[serde(default)]
+    pub templates: Vec<String>,
+}
+
+impl Matrix {
+    pub fn parse(content: &str) -> Result<Self, PayloadError> {
+        serde_yaml::from_str(content).map_err(|err| PayloadError::Parse(err.to_string()))
+    }
+
+    pub fn render(&self, template: &str, entry: &MatrixEntry) -> String {
+        let mut rendered = template.replace("${name}", &entry.name);
+
+        for (name, value) in &entry.vars {
+            rendered = rendered.replace(&format!("${{{}}}", name), value);
+        }
+
+        rendered
+    }
+
+    pub fn get_key(entry: &MatrixEntry, template_key: &str) -> String {
+        format!("{}.{}.{}", MATRIX_KEY_PREFIX, entry.name, template_key)
+    }
+
+    /// Whether a change to `file` (relative to the repository) affects the
+    /// expanded keys.
+    pub fn is_affected_by(&self, file: &str) -> bool {
+        file == MATRIX_FILE || self.templates.iter().any(|template| template == file)
+    }
+}
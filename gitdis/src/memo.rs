@@ -0,0 +1,193 @@
+use crate::keys::{KeyFormat, KeyFormats};
+use crate::sync::{AtomicU64, Ordering, RwLock};
+use quickleaf::valu3::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Key separator of `KeyFormat::default`.
+pub const KEY_SEPARATOR: char = '/';
+
+#[derive(Clone, Debug, PartialEq, ToValue, ToJson)]
+pub struct MemoStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+impl MemoStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+
+        if total == 0 {
+            return 0.0;
+        }
+
+        self.hits as f64 / total as f64
+    }
+}
+
+/// Memoizes subtrees rebuilt from the flat keys of a branch, or anything
+/// derived from them such as their serialized bytes. Entries are dropped
+/// when a key at or below their prefix changes.
+pub struct SubtreeMemo<T = Value> {
+    entries: RwLock<HashMap<String, HashMap<String, T>>>,
+    // Sequence of the last invalidation per branch, so a subtree computed
+    // before a change is not stored after it.
+    invalidated_at: RwLock<HashMap<String, u64>>,
+    /// How the keys of each branch nest.
+    formats: KeyFormats,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<T> Default for SubtreeMemo<T> {
+    fn default() -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            invalidated_at: RwLock::new(HashMap::new()),
+            formats: KeyFormats::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<T: Clone> SubtreeMemo<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Nests the keys of each branch as `formats` says rather than with
+    /// `KeyFormat::default`.
+    pub fn with_formats(mut self, formats: KeyFormats) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    pub fn get(&self, branch_key: &str, prefix: &str) -> Option<T> {
+        let value = self
+            .entries
+            .read()
+            .ok()
+            .and_then(|entries| entries.get(branch_key)?.get(prefix).cloned());
+
+        match value {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        value
+    }
+
+    /// Stores a subtree computed after event `sequence` was observed.
+    pub fn insert(&self, branch_key: &str, prefix: &str, value: T, sequence: u64) {
+        // Checked under the entries lock, which invalidations also hold, so
+        // an invalidation cannot slip in between the check and the insert.
+        let mut entries = match self.entries.write() {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        let invalidated_at = match self.invalidated_at.read() {
+            Ok(invalidated_at) => invalidated_at.get(branch_key).cloned().unwrap_or(0),
+            Err(_) => return,
+        };
+
+        if invalidated_at > sequence {
+            return;
+        }
+
+        entries
+            .entry(branch_key.to_string())
+            .or_default()
+            .insert(prefix.to_string(), value);
+    }
+
+    /// Drops every memoized subtree containing `key`.
+    pub fn invalidate(&self, branch_key: &str, key: &str, sequence: u64) {
+        if let Ok(mut entries) = self.entries.write() {
+            self.mark_invalidated(branch_key, sequence);
+
+            if let Some(prefixes) = entries.get_mut(branch_key) {
+                let format = self.formats.get(branch_key);
+                prefixes.retain(|prefix, _| !format.is_within(key, prefix));
+            }
+        }
+    }
+
+    pub fn invalidate_branch(&self, branch_key: &str, sequence: u64) {
+        if let Ok(mut entries) = self.entries.write() {
+            self.mark_invalidated(branch_key, sequence);
+            entries.remove(branch_key);
+        }
+    }
+
+    /// Call under the entries lock.
+    fn mark_invalidated(&self, branch_key: &str, sequence: u64) {
+        if let Ok(mut invalidated_at) = self.invalidated_at.write() {
+            let current = invalidated_at.entry(branch_key.to_string()).or_insert(0);
+            *current = (*current).max(sequence);
+        }
+    }
+
+    pub fn get_stats(&self) -> MemoStats {
+        MemoStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self
+                .entries
+                .read()
+                .map(|entries| entries.values().map(|prefixes| prefixes.len()).sum())
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Rebuilds the nested object for `prefix` from flat `entries`, whose keys
+/// all sit below it. Object keys are the unescaped segments of the keys.
+/// Returns `None` when there are no entries.
+pub fn build_subtree(
+    prefix: &str,
+    entries: Vec<(String, Value)>,
+    format: &KeyFormat,
+) -> Option<Value> {
+    if entries.is_empty() {
+        return None;
+    }
+
+    let mut root = Node::default();
+
+    for (key, value) in entries {
+        let relative = key[prefix.len()..].trim_start_matches(format.separator);
+        let mut node = &mut root;
+
+        for part in format.split(relative) {
+            node = node.children.entry(part).or_default();
+        }
+
+        node.value = Some(value);
+    }
+
+    Some(root.into_value())
+}
+
+#[derive(Default)]
+struct Node {
+    value: Option<Value>,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn into_value(self) -> Value {
+        if self.children.is_empty() {
+            return self.value.unwrap_or(Value::Null);
+        }
+
+        let children = self
+            .children
+            .into_iter()
+            .map(|(key, child)| (key, child.into_value()))
+            .collect::<BTreeMap<String, Value>>();
+
+        Value::from(children)
+    }
+}
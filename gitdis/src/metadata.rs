@@ -0,0 +1,74 @@
+use crate::keys::KeyFormat;
+use quickleaf::valu3::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Starts each commit in the output of `git log --format=LOG_FORMAT`.
+pub(crate) const RECORD_SEPARATOR: u8 = 0x1e;
+/// Ends each field of a commit; the files it touched come after the last.
+const FIELD_SEPARATOR: char = '\u{1f}';
+/// Hash, author name and email, author date and message of a commit.
+pub(crate) const LOG_FORMAT: &str = "%x1e%H%x1f%an%x1f%ae%x1f%at%x1f%B%x1f";
+
+/// The commit that last touched the file a key was loaded from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommitMetadata {
+    pub commit: String,
+    pub author: String,
+    pub author_email: String,
+    pub message: String,
+    /// Author date, in seconds since the epoch.
+    pub timestamp: u64,
+}
+
+impl CommitMetadata {
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert("commit".to_string(), Value::from(self.commit.as_str()));
+        object.insert("author".to_string(), Value::from(self.author.as_str()));
+        object.insert(
+            "author_email".to_string(),
+            Value::from(self.author_email.as_str()),
+        );
+        object.insert("message".to_string(), Value::from(self.message.as_str()));
+        object.insert("timestamp".to_string(), Value::from(self.timestamp));
+
+        Value::from(object)
+    }
+}
+
+/// Parses one commit of `git log --name-only --format=LOG_FORMAT`, without
+/// its leading separator, into the commit and the files it touched.
+pub(crate) fn parse_log_record(record: &str) -> Option<(Arc<CommitMetadata>, Vec<String>)> {
+    let mut fields = record.splitn(6, FIELD_SEPARATOR);
+
+    let commit = CommitMetadata {
+        commit: fields.next()?.trim().to_string(),
+        author: fields.next()?.to_string(),
+        author_email: fields.next()?.to_string(),
+        timestamp: fields.next()?.trim().parse().ok()?,
+        message: fields.next()?.trim().to_string(),
+    };
+    let files = fields
+        .next()?
+        .lines()
+        .filter(|file| !file.is_empty())
+        .map(String::from)
+        .collect();
+
+    Some((Arc::new(commit), files))
+}
+
+/// Of the metadata of the keys at or below `prefix`, the most recent one.
+pub fn latest_below<'a>(
+    metadata: &'a HashMap<String, Arc<CommitMetadata>>,
+    prefix: &str,
+    format: &KeyFormat,
+) -> Option<&'a CommitMetadata> {
+    metadata
+        .iter()
+        .filter(|(key, _)| format.is_within(key, prefix))
+        .map(|(_, commit)| commit.as_ref())
+        .max_by_key(|commit| commit.timestamp)
+}
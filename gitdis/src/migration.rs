@@ -0,0 +1,186 @@
+use crate::gitdis::BranchSettings;
+use log::debug;
+use quickleaf::valu3::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+/// Poll interval given to registrations that did not record one.
+pub const DEFAULT_PULL_INTERVAL_MILLIS: u64 = 3000;
+
+/// A branch as registered on the old `gcs` server: only a remote and a
+/// branch, with no path target or ref metadata.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LegacyRegistration {
+    pub url: String,
+    pub branch_name: String,
+    pub pull_request_interval_millis: Option<u64>,
+}
+
+/// Something that could not be migrated as-is, with where it came from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationIssue {
+    pub source: String,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MigrationReport {
+    /// Branch keys registered.
+    pub migrated: Vec<String>,
+    pub issues: Vec<MigrationIssue>,
+}
+
+impl MigrationReport {
+    pub fn add_issue(&mut self, source: &str, reason: &str) {
+        debug!("Migration issue: {}: {}", source, reason);
+
+        self.issues.push(MigrationIssue {
+            source: source.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert(
+            "migrated".to_string(),
+            Value::from(
+                self.migrated
+                    .iter()
+                    .map(|key| Value::from(key.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        object.insert(
+            "issues".to_string(),
+            Value::from(
+                self.issues
+                    .iter()
+                    .map(|issue| {
+                        let mut object = HashMap::new();
+                        object.insert("source".to_string(), Value::from(issue.source.as_str()));
+                        object.insert("reason".to_string(), Value::from(issue.reason.as_str()));
+                        Value::from(object)
+                    })
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+
+        Value::from(object)
+    }
+}
+
+impl LegacyRegistration {
+    /// Converts to the current settings. Credentials embedded in the URL
+    /// are dropped, as they would otherwise end up in keys and logs.
+    pub fn to_settings(&self, report: &mut MigrationReport) -> BranchSettings {
+        let url = match strip_url_credentials(&self.url) {
+            Some(url) => {
+                report.add_issue(
+                    &self.url_without_credentials(),
+                    "credentials in the URL were dropped, configure them on the branch",
+                );
+                url
+            }
+            None => self.url.clone(),
+        };
+
+        BranchSettings {
+            url,
+            branch_name: self.branch_name.clone(),
+            pull_request_interval_millis: self
+                .pull_request_interval_millis
+                .unwrap_or(DEFAULT_PULL_INTERVAL_MILLIS),
+            ..BranchSettings::default()
+        }
+    }
+
+    fn url_without_credentials(&self) -> String {
+        strip_url_credentials(&self.url).unwrap_or(self.url.clone())
+    }
+}
+
+/// Returns the URL without its `user:password@` part, if it has one.
+fn strip_url_credentials(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let (authority, path) = match rest.split_once('/') {
+        Some((authority, path)) => (authority, Some(path)),
+        None => (rest, None),
+    };
+    let (_, host) = authority.rsplit_once('@')?;
+
+    Some(match path {
+        Some(path) => format!("{}://{}/{}", scheme, host, path),
+        None => format!("{}://{}", scheme, host),
+    })
+}
+
+/// Reads the registrations implied by an old clone directory, where each
+/// repository was cloned straight into `<clone_path>/<repo>`.
+pub fn scan_legacy_clones(
+    clone_path: &str,
+    report: &mut MigrationReport,
+) -> Vec<LegacyRegistration> {
+    let mut registrations = Vec::new();
+
+    let entries = match std::fs::read_dir(clone_path) {
+        Ok(entries) => entries,
+        Err(err) => {
+            report.add_issue(clone_path, &err.to_string());
+            return registrations;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let source = path.to_string_lossy().to_string();
+
+        // Directories of the current layout hold owners, not clones.
+        if !Path::new(&format!("{}/.git", source)).is_dir() {
+            continue;
+        }
+
+        let url = match git_read(&source, &["config", "--get", "remote.origin.url"]) {
+            Some(url) => url,
+            None => {
+                report.add_issue(&source, "clone has no origin remote");
+                continue;
+            }
+        };
+
+        let branch_name = match git_read(&source, &["rev-parse", "--abbrev-ref", "HEAD"]) {
+            Some(branch_name) if branch_name != "HEAD" => branch_name,
+            _ => {
+                report.add_issue(
+                    &source,
+                    "detached HEAD, register it with a commit or tag ref type",
+                );
+                continue;
+            }
+        };
+
+        registrations.push(LegacyRegistration {
+            url,
+            branch_name,
+            pull_request_interval_millis: None,
+        });
+    }
+
+    registrations
+}
+
+pub(crate) fn git_read(path: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(path)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -0,0 +1,111 @@
+use crate::keys::KeyFormat;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::collections::HashMap;
+
+/// Where CODEOWNERS is looked up, relative to the repository. The first
+/// one found is used, as on GitHub.
+pub const CODEOWNERS_FILES: [&str; 3] = [".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"];
+
+struct OwnerRule {
+    pattern: GlobSet,
+    owners: Vec<String>,
+}
+
+/// The rules of a CODEOWNERS file. As in git, the last rule matching a
+/// path decides its owners, so a rule without owners disowns it.
+#[derive(Default)]
+pub struct CodeOwners {
+    rules: Vec<OwnerRule>,
+}
+
+impl std::fmt::Debug for CodeOwners {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CodeOwners({} rules)", self.rules.len())
+    }
+}
+
+impl CodeOwners {
+    /// Lines whose pattern does not compile are skipped.
+    pub fn parse(content: &str) -> Self {
+        let rules = content
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let pattern = build_pattern(fields.next()?)?;
+
+                Some(OwnerRule {
+                    pattern,
+                    owners: fields.map(String::from).collect(),
+                })
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    /// Owners of `path`, relative to the repository; empty if none.
+    pub fn get_owners(&self, path: &str) -> Vec<String> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.pattern.is_match(path))
+            .map(|rule| rule.owners.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Compiles a gitignore-style pattern. Patterns with a leading or inner `/`
+/// are anchored at the root, others match at any depth, and a pattern also
+/// matches everything below a directory it names, except `dir/*` which only
+/// matches the files directly in `dir`.
+fn build_pattern(pattern: &str) -> Option<GlobSet> {
+    let anchored = pattern.trim_end_matches('/').contains('/');
+    let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+
+    if pattern.is_empty() {
+        return None;
+    }
+
+    let pattern = match anchored {
+        true => pattern.to_string(),
+        false => format!("**/{}", pattern),
+    };
+
+    let mut patterns = vec![pattern.clone()];
+
+    if !pattern.ends_with("/*") {
+        patterns.push(format!("{}/**", pattern));
+    }
+
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        builder.add(
+            GlobBuilder::new(&pattern)
+                .literal_separator(true)
+                .build()
+                .ok()?,
+        );
+    }
+
+    builder.build().ok()
+}
+
+/// Of the owners of the keys at or below `prefix`, each once, sorted.
+pub fn owners_below(
+    owners: &HashMap<String, Vec<String>>,
+    prefix: &str,
+    format: &KeyFormat,
+) -> Vec<String> {
+    let mut found = owners
+        .iter()
+        .filter(|(key, _)| format.is_within(key, prefix))
+        .flat_map(|(_, owners)| owners.iter().cloned())
+        .collect::<Vec<String>>();
+
+    found.sort();
+    found.dedup();
+    found
+}
@@ -0,0 +1,266 @@
+use quickleaf::valu3::prelude::*;
+use std::collections::BTreeMap;
+
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    InvalidPatch(String),
+    PathNotFound(String),
+    TestFailed(String),
+}
+
+impl std::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PatchError::InvalidPatch(message) => write!(f, "Invalid patch: {}", message),
+            PatchError::PathNotFound(path) => write!(f, "Path not found: {}", path),
+            PatchError::TestFailed(path) => write!(f, "Test failed at path: {}", path),
+        }
+    }
+}
+
+/// A structured change to apply to a document before writing it back.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ObjectPatch {
+    /// RFC 7396 JSON Merge Patch (`application/merge-patch+json`).
+    Merge(Value),
+    /// RFC 6902 JSON Patch (`application/json-patch+json`).
+    Json(Value),
+}
+
+impl ObjectPatch {
+    pub fn apply(&self, target: &mut Value) -> Result<(), PatchError> {
+        match self {
+            ObjectPatch::Merge(patch) => {
+                merge_patch(target, patch);
+                Ok(())
+            }
+            ObjectPatch::Json(operations) => json_patch(target, operations),
+        }
+    }
+}
+
+fn empty_object() -> Value {
+    Value::from(BTreeMap::<String, Value>::new())
+}
+
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch_object = match patch {
+        Value::Object(patch_object) => patch_object,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+
+    if !target.is_object() {
+        *target = empty_object();
+    }
+
+    if let Value::Object(target_object) = target {
+        for (key, value) in patch_object.iter() {
+            let key = key.to_string();
+
+            if value.is_null() {
+                target_object.remove(&key);
+                continue;
+            }
+
+            match target_object.get_mut(&key) {
+                Some(current) => merge_patch(current, value),
+                None => {
+                    let mut current = Value::Undefined;
+                    merge_patch(&mut current, value);
+                    target_object.insert(key, current);
+                }
+            }
+        }
+    }
+}
+
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, PatchError> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if !pointer.starts_with('/') {
+        return Err(PatchError::InvalidPatch(format!(
+            "pointer must start with '/': {}",
+            pointer
+        )));
+    }
+
+    Ok(pointer[1..]
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+fn get_field<'a>(operation: &'a Value, field: &str) -> Result<&'a Value, PatchError> {
+    match operation.get(field) {
+        Some(value) => Ok(value),
+        None => Err(PatchError::InvalidPatch(format!(
+            "missing field '{}'",
+            field
+        ))),
+    }
+}
+
+fn get_pointer_field(operation: &Value, field: &str) -> Result<String, PatchError> {
+    match get_field(operation, field)? {
+        Value::String(pointer) => Ok(pointer.to_string()),
+        _ => Err(PatchError::InvalidPatch(format!(
+            "field '{}' must be a string",
+            field
+        ))),
+    }
+}
+
+fn resolve<'a>(target: &'a Value, tokens: &[String]) -> Option<&'a Value> {
+    let mut current = target;
+
+    for token in tokens {
+        current = match current {
+            Value::Object(object) => object.get(token.as_str())?,
+            Value::Array(array) => array.values.get(token.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    Some(current)
+}
+
+fn resolve_mut<'a>(target: &'a mut Value, tokens: &[String]) -> Option<&'a mut Value> {
+    let mut current = target;
+
+    for token in tokens {
+        current = match current {
+            Value::Object(object) => object.get_mut(token.as_str())?,
+            Value::Array(array) => array.values.get_mut(token.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    Some(current)
+}
+
+fn add(target: &mut Value, pointer: &str, value: Value) -> Result<(), PatchError> {
+    let tokens = parse_pointer(pointer)?;
+
+    let (last, parent) = match tokens.split_last() {
+        Some(split) => split,
+        None => {
+            *target = value;
+            return Ok(());
+        }
+    };
+
+    match resolve_mut(target, parent) {
+        Some(Value::Object(object)) => {
+            object.insert(last.clone(), value);
+            Ok(())
+        }
+        Some(Value::Array(array)) => {
+            if last == "-" {
+                array.values.push(value);
+                return Ok(());
+            }
+
+            match last.parse::<usize>() {
+                Ok(index) if index <= array.values.len() => {
+                    array.values.insert(index, value);
+                    Ok(())
+                }
+                _ => Err(PatchError::PathNotFound(pointer.to_string())),
+            }
+        }
+        _ => Err(PatchError::PathNotFound(pointer.to_string())),
+    }
+}
+
+fn remove(target: &mut Value, pointer: &str) -> Result<Value, PatchError> {
+    let tokens = parse_pointer(pointer)?;
+
+    let (last, parent) = match tokens.split_last() {
+        Some(split) => split,
+        None => {
+            return Err(PatchError::InvalidPatch(
+                "cannot remove the root".to_string(),
+            ))
+        }
+    };
+
+    let removed = match resolve_mut(target, parent) {
+        Some(Value::Object(object)) => object.remove(last.as_str()),
+        Some(Value::Array(array)) => match last.parse::<usize>() {
+            Ok(index) if index < array.values.len() => Some(array.values.remove(index)),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    match removed {
+        Some(value) => Ok(value),
+        None => Err(PatchError::PathNotFound(pointer.to_string())),
+    }
+}
+
+pub fn json_patch(target: &mut Value, operations: &Value) -> Result<(), PatchError> {
+    let operations = match operations {
+        Value::Array(operations) => operations,
+        _ => {
+            return Err(PatchError::InvalidPatch(
+                "JSON Patch must be an array of operations".to_string(),
+            ))
+        }
+    };
+
+    // Operations are applied to a copy so a failing one leaves the target untouched.
+    let mut document = target.clone();
+
+    for operation in operations.values.iter() {
+        let op = get_pointer_field(operation, "op")?;
+        let path = get_pointer_field(operation, "path")?;
+
+        match op.as_str() {
+            "add" => add(&mut document, &path, get_field(operation, "value")?.clone())?,
+            "remove" => {
+                remove(&mut document, &path)?;
+            }
+            "replace" => {
+                remove(&mut document, &path)?;
+                add(&mut document, &path, get_field(operation, "value")?.clone())?;
+            }
+            "move" => {
+                let from = get_pointer_field(operation, "from")?;
+                let value = remove(&mut document, &from)?;
+                add(&mut document, &path, value)?;
+            }
+            "copy" => {
+                let from = get_pointer_field(operation, "from")?;
+                let value = match resolve(&document, &parse_pointer(&from)?) {
+                    Some(value) => value.clone(),
+                    None => return Err(PatchError::PathNotFound(from)),
+                };
+                add(&mut document, &path, value)?;
+            }
+            "test" => {
+                let expected = get_field(operation, "value")?;
+
+                match resolve(&document, &parse_pointer(&path)?) {
+                    Some(value) if value == expected => (),
+                    _ => return Err(PatchError::TestFailed(path)),
+                }
+            }
+            _ => {
+                return Err(PatchError::InvalidPatch(format!(
+                    "unknown operation '{}'",
+                    op
+                )))
+            }
+        }
+    }
+
+    *target = document;
+
+    Ok(())
+}
@@ -0,0 +1,1030 @@
+use base64::Engine;
+use quickleaf::valu3::prelude::*;
+use serde::de::{DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Deepest nesting accepted once anchors have been expanded.
+pub const MAX_DEPTH: usize = 128;
+/// Maximum number of nodes a single file may expand to. Guards against
+/// alias bombs ("billion laughs") that stay small on disk.
+pub const MAX_NODES: usize = 1_000_000;
+
+/// Key of the directive that splices another file into a document.
+pub const INCLUDE_DIRECTIVE: &str = "$include";
+/// JSON Reference style alias of `$include`. References local to the
+/// document (`#/...`) or to URLs are left as they are, so documents such
+/// as OpenAPI specs keep loading unchanged.
+pub const REF_DIRECTIVE: &str = "$ref";
+/// Maximum chain of nested includes.
+pub const MAX_INCLUDE_DEPTH: usize = 16;
+/// JSON files at least this large are parsed with simd-json when the `simd`
+/// feature is enabled. Below it, the copy simd-json parses in place costs
+/// about what it saves.
+pub const SIMD_MIN_BYTES: usize = 256 * 1024;
+/// Files at least this large are parsed as they are read from disk, when
+/// their format allows, rather than read into memory first.
+pub const STREAM_MIN_BYTES: u64 = 16 * 1024 * 1024;
+/// Environment variables placeholders may always read. Others have to be
+/// listed in `ParseOptions::interpolate_allow`, so a repository cannot
+/// pull secrets such as credentials out of the process environment.
+pub const ENV_VAR_PREFIX: &str = "GITDIS_VAR_";
+
+const EXT_JSON: &str = ".json";
+const EXT_JSONC: &str = ".jsonc";
+const EXT_JSON5: &str = ".json5";
+const EXT_NDJSON: &str = ".ndjson";
+const EXT_JSONL: &str = ".jsonl";
+const EXT_CSV: &str = ".csv";
+const EXT_YML: &str = ".yml";
+const EXT_YAML: &str = ".yaml";
+/// Content types of binary files by extension. Others are
+/// `application/octet-stream`.
+const CONTENT_TYPES: [(&str, &str); 14] = [
+    (".pem", "application/x-pem-file"),
+    (".crt", "application/x-x509-ca-cert"),
+    (".cer", "application/pkix-cert"),
+    (".der", "application/x-x509-ca-cert"),
+    (".p12", "application/x-pkcs12"),
+    (".png", "image/png"),
+    (".jpg", "image/jpeg"),
+    (".jpeg", "image/jpeg"),
+    (".gif", "image/gif"),
+    (".webp", "image/webp"),
+    (".ico", "image/x-icon"),
+    (".svg", "image/svg+xml"),
+    (".pdf", "application/pdf"),
+    (".wasm", "application/wasm"),
+];
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// How YAML files holding several `---` separated documents are loaded.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum MultiDocument {
+    /// Documents become an array, indexed in file order. Files with a
+    /// single document keep loading as that document.
+    #[default]
+    Indexed,
+    /// Documents are deep-merged, later documents winning.
+    Merged,
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct ParseOptions {
+    pub multi_document: MultiDocument,
+    /// Parse plain `.json` files with the JSON5 parser too, accepting
+    /// comments and trailing commas.
+    pub lenient_json: bool,
+    /// Expand `${VAR}` and `${VAR:-default}` in string values from the
+    /// environment of the process. See `interpolate_env`.
+    pub interpolate_env: bool,
+    /// Variables placeholders may read besides those named with
+    /// `ENV_VAR_PREFIX`.
+    pub interpolate_allow: Vec<String>,
+    /// Files larger than this are skipped rather than read.
+    pub max_file_bytes: Option<u64>,
+    /// Formats besides the built-in ones, see `PayloadParser`.
+    pub parsers: PayloadParsers,
+}
+
+/// A file format loaded alongside the built-in ones, e.g. protobuf text or
+/// an in-house DSL, registered with `Gitdis::register_parser`. Values it
+/// returns are stored as they are, so it has to keep them within its own
+/// limits.
+pub trait PayloadParser: Send + Sync {
+    /// Extension of the files it parses, with the dot, e.g. `.txtpb`.
+    fn extension(&self) -> &str;
+
+    fn parse(&self, path: &str, content: &str) -> Result<Value, PayloadError>;
+}
+
+/// The registered parsers, by extension. They take precedence over the
+/// built-in formats.
+#[derive(Clone, Default)]
+pub struct PayloadParsers {
+    parsers: Vec<Arc<dyn PayloadParser>>,
+}
+
+impl PayloadParsers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `parser`, replacing one for the same extension.
+    pub fn register(&mut self, parser: Arc<dyn PayloadParser>) {
+        self.parsers
+            .retain(|registered| registered.extension() != parser.extension());
+        self.parsers.push(parser);
+    }
+
+    /// The parser for `path`, if one was registered for its extension.
+    pub fn find(&self, path: &str) -> Option<&Arc<dyn PayloadParser>> {
+        self.parsers
+            .iter()
+            .find(|parser| path.ends_with(parser.extension()))
+    }
+
+    pub fn get_extensions(&self) -> Vec<&str> {
+        self.parsers
+            .iter()
+            .map(|parser| parser.extension())
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for PayloadParsers {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list().entries(self.get_extensions()).finish()
+    }
+}
+
+impl PartialEq for PayloadParsers {
+    fn eq(&self, other: &Self) -> bool {
+        self.parsers.len() == other.parsers.len()
+            && self
+                .parsers
+                .iter()
+                .zip(&other.parsers)
+                .all(|(parser, other)| Arc::ptr_eq(parser, other))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PayloadError {
+    Parse(String),
+    TooDeep,
+    TooLarge,
+    IncludeNotFound(String),
+    IncludeEscape(String),
+    IncludeCycle(String),
+    UnsetVariable(String),
+    /// The variable is neither prefixed with `ENV_VAR_PREFIX` nor allowed.
+    ForbiddenVariable(String),
+    /// The file is over the size limit given, so it was not read.
+    FileTooLarge(u64),
+}
+
+impl std::fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PayloadError::Parse(error) => write!(f, "Parse error: {}", error),
+            PayloadError::TooDeep => write!(f, "Document nested deeper than {}", MAX_DEPTH),
+            PayloadError::TooLarge => {
+                write!(f, "Document expands to more than {} nodes", MAX_NODES)
+            }
+            PayloadError::IncludeNotFound(path) => write!(f, "Included file not found: {}", path),
+            PayloadError::IncludeEscape(path) => {
+                write!(f, "Included file is outside the repository: {}", path)
+            }
+            PayloadError::IncludeCycle(path) => write!(f, "Include cycle through: {}", path),
+            PayloadError::UnsetVariable(name) => {
+                write!(f, "Environment variable not set: {}", name)
+            }
+            PayloadError::ForbiddenVariable(name) => {
+                write!(f, "Environment variable not allowed: {}", name)
+            }
+            PayloadError::FileTooLarge(limit) => {
+                write!(f, "File is larger than {} bytes, skipped", limit)
+            }
+        }
+    }
+}
+
+pub fn is_yaml(path: &str) -> bool {
+    path.ends_with(EXT_YML) || path.ends_with(EXT_YAML)
+}
+
+pub fn is_json5(path: &str) -> bool {
+    path.ends_with(EXT_JSONC) || path.ends_with(EXT_JSON5)
+}
+
+pub fn is_ndjson(path: &str) -> bool {
+    path.ends_with(EXT_NDJSON) || path.ends_with(EXT_JSONL)
+}
+
+pub fn is_csv(path: &str) -> bool {
+    path.ends_with(EXT_CSV)
+}
+
+/// Whether `content` is plain JSON, without the comments, trailing commas
+/// and other JSON5 syntax `lenient_json` accepts.
+pub fn is_strict_json(content: &str) -> bool {
+    serde_json::from_str::<serde::de::IgnoredAny>(content).is_ok()
+}
+
+/// Parses the content of `path` into a value according to its extension.
+pub fn parse_value(
+    path: &str,
+    content: &str,
+    options: &ParseOptions,
+) -> Result<Value, PayloadError> {
+    let value = parse_format(path, content, options)?;
+
+    interpolate_if_enabled(value, options)
+}
+
+/// Content type of a binary file, guessed from its extension.
+pub fn content_type(path: &str) -> &'static str {
+    let path = path.to_ascii_lowercase();
+
+    CONTENT_TYPES
+        .iter()
+        .find(|(ext, _)| path.ends_with(ext))
+        .map(|(_, content_type)| *content_type)
+        .unwrap_or(DEFAULT_CONTENT_TYPE)
+}
+
+/// Value of a file loaded as binary: an object with its `content_type`,
+/// `encoding` (always `base64`) and the encoded `data`.
+pub fn binary_value(path: &str, bytes: &[u8]) -> Value {
+    let mut object = BTreeMap::new();
+    object.insert("content_type".to_string(), Value::from(content_type(path)));
+    object.insert("encoding".to_string(), Value::from("base64"));
+    object.insert(
+        "data".to_string(),
+        Value::from(base64::engine::general_purpose::STANDARD.encode(bytes)),
+    );
+
+    Value::from(object)
+}
+
+/// Whether `path` can be parsed with `parse_reader`.
+pub fn can_stream(path: &str, options: &ParseOptions) -> bool {
+    options.parsers.find(path).is_none()
+        && (is_ndjson(path) || (path.ends_with(EXT_JSON) && !options.lenient_json))
+}
+
+/// Parses the content of `path` as it is read from `reader`, so a large
+/// file never sits in memory as text next to its value. Large arrays are
+/// built an element at a time. Only for the formats of `can_stream`, in
+/// UTF-8, within the same limits as `parse_value`.
+pub fn parse_reader<R: Read>(
+    path: &str,
+    reader: R,
+    options: &ParseOptions,
+) -> Result<Value, PayloadError> {
+    let reader = std::io::BufReader::new(reader);
+    let value = match is_ndjson(path) {
+        true => parse_ndjson_lines(
+            reader
+                .lines()
+                .map(|line| line.map_err(|err| PayloadError::Parse(err.to_string()))),
+        )?,
+        false => stream_json(reader)?,
+    };
+
+    interpolate_if_enabled(value, options)
+}
+
+fn interpolate_if_enabled(value: Value, options: &ParseOptions) -> Result<Value, PayloadError> {
+    match options.interpolate_env {
+        true => interpolate_env(value, &options.interpolate_allow),
+        false => Ok(value),
+    }
+}
+
+/// Expands the placeholders of `value` from the environment of the process,
+/// failing on variables neither prefixed with `ENV_VAR_PREFIX` nor in
+/// `allow`, defaults included.
+pub fn interpolate_env(value: Value, allow: &[String]) -> Result<Value, PayloadError> {
+    expand(value, &|name| match name.starts_with(ENV_VAR_PREFIX)
+        || allow.iter().any(|allowed| allowed == name)
+    {
+        true => Ok(std::env::var(name).ok()),
+        false => Err(PayloadError::ForbiddenVariable(name.to_string())),
+    })
+}
+
+fn parse_format(path: &str, content: &str, options: &ParseOptions) -> Result<Value, PayloadError> {
+    if let Some(parser) = options.parsers.find(path) {
+        return parser.parse(path, content);
+    }
+
+    if is_yaml(path) {
+        return parse_yaml(content, &options.multi_document);
+    }
+
+    if is_json5(path) || (options.lenient_json && path.ends_with(EXT_JSON)) {
+        return parse_json5(content);
+    }
+
+    if is_ndjson(path) {
+        return parse_ndjson(content);
+    }
+
+    if is_csv(path) {
+        return parse_csv(content);
+    }
+
+    #[cfg(all(feature = "simd", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    if content.len() >= SIMD_MIN_BYTES {
+        // Failures are left to the regular parser, so errors and limits
+        // stay the same with and without the feature.
+        if let Ok(value) = parse_json_simd(content) {
+            return Ok(value);
+        }
+    }
+
+    Value::payload_to_value(content).map_err(|err| PayloadError::Parse(format!("{:?}", err)))
+}
+
+/// Expands the placeholders of the strings in `value`, keys excluded,
+/// looking variables up with `lookup`. `${VAR}` fails when `VAR` is unset,
+/// `${VAR:-default}` falls back to `default` when it is unset or empty, and
+/// `$${` is a literal `${`. Expanded values stay strings.
+pub fn interpolate(
+    value: Value,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<Value, PayloadError> {
+    expand(value, &|name| Ok(lookup(name)))
+}
+
+type Lookup<'a> = dyn Fn(&str) -> Result<Option<String>, PayloadError> + 'a;
+
+fn expand(value: Value, lookup: &Lookup) -> Result<Value, PayloadError> {
+    match value {
+        Value::String(string) => {
+            let string = string.to_string();
+
+            match string.contains("${") {
+                true => Ok(Value::from(interpolate_str(&string, lookup)?)),
+                false => Ok(Value::from(string)),
+            }
+        }
+        Value::Object(object) => {
+            let mut expanded = BTreeMap::new();
+
+            for (key, item) in object.iter() {
+                expanded.insert(key.to_string(), expand(item.clone(), lookup)?);
+            }
+
+            Ok(Value::from(expanded))
+        }
+        Value::Array(array) => {
+            let mut values = Vec::with_capacity(array.values.len());
+
+            for item in array.values {
+                values.push(expand(item, lookup)?);
+            }
+
+            Ok(Value::from(values))
+        }
+        value => Ok(value),
+    }
+}
+
+/// Whether `content` holds a placeholder `interpolate` would expand.
+pub fn has_placeholders(content: &str) -> bool {
+    content.replace("$${", "").contains("${")
+}
+
+fn interpolate_str(string: &str, lookup: &Lookup) -> Result<String, PayloadError> {
+    let mut expanded = String::with_capacity(string.len());
+    let mut rest = string;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        expanded.push_str(&rest[..start]);
+
+        let end = match rest[start + 2..].find('}') {
+            Some(end) => start + 2 + end,
+            None => {
+                return Err(PayloadError::Parse(format!(
+                    "unterminated placeholder in {:?}",
+                    string
+                )))
+            }
+        };
+        let placeholder = &rest[start + 2..end];
+
+        let value = match placeholder.split_once(":-") {
+            Some((name, default)) => lookup(name)?
+                .filter(|value| !value.is_empty())
+                .unwrap_or_else(|| default.to_string()),
+            None => lookup(placeholder)?
+                .ok_or_else(|| PayloadError::UnsetVariable(placeholder.to_string()))?,
+        };
+
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Replaces every `{"$include": "relative/path"}` or `{"$ref": ...}` object
+/// in `value` with the parsed content of that file. Sibling keys of the
+/// directive are merged on top of the included document. Paths are
+/// relative to the including file, or to `root` with a leading `/`, and
+/// must stay inside `root`. A `#/json/pointer` fragment includes only that
+/// part of the file. Returns the composed value and the files it pulled
+/// in, so callers can re-parse it when one of them changes.
+pub fn resolve_includes(
+    value: Value,
+    path: &str,
+    root: &str,
+    options: &ParseOptions,
+) -> Result<(Value, Vec<String>), PayloadError> {
+    let root = canonicalize(root)?;
+    let mut stack = vec![canonicalize(path)?];
+    let mut included = Vec::new();
+
+    let value = expand_includes(value, &root, options, &mut stack, &mut included)?;
+
+    Ok((value, included))
+}
+
+fn canonicalize(path: &str) -> Result<PathBuf, PayloadError> {
+    Path::new(path)
+        .canonicalize()
+        .map_err(|_| PayloadError::IncludeNotFound(path.to_string()))
+}
+
+fn expand_includes(
+    value: Value,
+    root: &Path,
+    options: &ParseOptions,
+    stack: &mut Vec<PathBuf>,
+    included: &mut Vec<String>,
+) -> Result<Value, PayloadError> {
+    match value {
+        Value::Object(object) => {
+            let directive = [INCLUDE_DIRECTIVE, REF_DIRECTIVE]
+                .into_iter()
+                .find_map(|directive| match object.get(directive) {
+                    Some(Value::String(target))
+                        if is_file_reference(directive, target.as_str()) =>
+                    {
+                        Some((directive, target.to_string()))
+                    }
+                    _ => None,
+                });
+
+            let mut expanded = BTreeMap::new();
+
+            for (key, item) in object.iter() {
+                let key = key.to_string();
+
+                if directive
+                    .as_ref()
+                    .is_some_and(|(directive, _)| key == *directive)
+                {
+                    continue;
+                }
+
+                expanded.insert(
+                    key,
+                    expand_includes(item.clone(), root, options, stack, included)?,
+                );
+            }
+
+            match directive {
+                Some((_, target)) => {
+                    let mut document = include_file(&target, root, options, stack, included)?;
+
+                    if !expanded.is_empty() {
+                        merge(&mut document, Value::from(expanded));
+                    }
+
+                    Ok(document)
+                }
+                None => Ok(Value::from(expanded)),
+            }
+        }
+        Value::Array(array) => {
+            let mut values = Vec::with_capacity(array.values.len());
+
+            for item in array.values {
+                values.push(expand_includes(item, root, options, stack, included)?);
+            }
+
+            Ok(Value::from(values))
+        }
+        value => Ok(value),
+    }
+}
+
+fn is_file_reference(directive: &str, target: &str) -> bool {
+    directive == INCLUDE_DIRECTIVE || !(target.starts_with('#') || target.contains("://"))
+}
+
+fn include_file(
+    target: &str,
+    root: &Path,
+    options: &ParseOptions,
+    stack: &mut Vec<PathBuf>,
+    included: &mut Vec<String>,
+) -> Result<Value, PayloadError> {
+    if stack.len() > MAX_INCLUDE_DEPTH {
+        return Err(PayloadError::TooDeep);
+    }
+
+    let (file_target, pointer) = match target.split_once('#') {
+        Some((file_target, pointer)) => (file_target, Some(pointer)),
+        None => (target, None),
+    };
+
+    let parent = match stack.last().and_then(|current| current.parent()) {
+        Some(parent) if !file_target.starts_with('/') => parent.to_path_buf(),
+        _ => root.to_path_buf(),
+    };
+
+    let file = parent
+        .join(file_target.trim_start_matches('/'))
+        .canonicalize()
+        .map_err(|_| PayloadError::IncludeNotFound(target.to_string()))?;
+
+    if !file.starts_with(root) {
+        return Err(PayloadError::IncludeEscape(target.to_string()));
+    }
+
+    if stack.contains(&file) {
+        return Err(PayloadError::IncludeCycle(target.to_string()));
+    }
+
+    let file_name = file.to_string_lossy().to_string();
+    let content =
+        std::fs::read(&file).map_err(|_| PayloadError::IncludeNotFound(file_name.clone()))?;
+    let value = parse_value(&file_name, &String::from_utf8_lossy(&content), options)?;
+
+    if !included.contains(&file_name) {
+        included.push(file_name);
+    }
+
+    stack.push(file);
+    let value = expand_includes(value, root, options, stack, included);
+    stack.pop();
+
+    match pointer {
+        Some(pointer) => select_pointer(value?, pointer)
+            .ok_or_else(|| PayloadError::IncludeNotFound(target.to_string())),
+        None => value,
+    }
+}
+
+/// The part of `value` at a JSON pointer (RFC 6901), e.g. `/servers/0`.
+fn select_pointer(value: Value, pointer: &str) -> Option<Value> {
+    if pointer.is_empty() {
+        return Some(value);
+    }
+
+    let mut current = value;
+
+    for token in pointer.strip_prefix('/')?.split('/') {
+        let token = token.replace("~1", "/").replace("~0", "~");
+
+        current = match current {
+            Value::Object(object) => object.get(token.as_str())?.clone(),
+            Value::Array(array) => array.values.into_iter().nth(token.parse().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    Some(current)
+}
+
+fn parse_json5(content: &str) -> Result<Value, PayloadError> {
+    let json = json5::from_str::<serde_json::Value>(content)
+        .map_err(|err| PayloadError::Parse(err.to_string()))?;
+    let mut budget = MAX_NODES;
+
+    json_to_value(json, 0, &mut budget)
+}
+
+/// One array element per line, each a JSON document. Blank lines are
+/// skipped; errors name the line.
+fn parse_ndjson(content: &str) -> Result<Value, PayloadError> {
+    parse_ndjson_lines(content.lines().map(Ok))
+}
+
+fn parse_ndjson_lines<S: AsRef<str>>(
+    lines: impl Iterator<Item = Result<S, PayloadError>>,
+) -> Result<Value, PayloadError> {
+    let mut budget = MAX_NODES;
+    let mut values = Vec::new();
+
+    for (index, line) in lines.enumerate() {
+        let line = line?;
+        let line = line.as_ref().trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let json = serde_json::from_str::<serde_json::Value>(line)
+            .map_err(|err| PayloadError::Parse(format!("line {}: {}", index + 1, err)))?;
+
+        values.push(json_to_value(json, 1, &mut budget)?);
+    }
+
+    Ok(Value::from(values))
+}
+
+/// One object per row, keyed by the fields of the header row. Cells stay
+/// strings, so codes such as `007` keep their leading zeros. Every row must
+/// have as many cells as the header.
+fn parse_csv(content: &str) -> Result<Value, PayloadError> {
+    let mut budget = MAX_NODES;
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::Headers)
+        .from_reader(content.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|err| PayloadError::Parse(err.to_string()))?
+        .clone();
+
+    let mut fields = std::collections::HashSet::new();
+
+    if let Some(field) = headers.iter().find(|field| !fields.insert(*field)) {
+        return Err(PayloadError::Parse(format!(
+            "duplicate header field {:?}",
+            field
+        )));
+    }
+
+    let mut rows = Vec::new();
+
+    for record in reader.records() {
+        let record = record.map_err(|err| PayloadError::Parse(err.to_string()))?;
+
+        if budget <= record.len() {
+            return Err(PayloadError::TooLarge);
+        }
+        budget -= record.len() + 1;
+
+        let row = headers
+            .iter()
+            .zip(record.iter())
+            .map(|(field, cell)| (field.to_string(), Value::from(cell)))
+            .collect::<BTreeMap<String, Value>>();
+
+        rows.push(Value::from(row));
+    }
+
+    Ok(Value::from(rows))
+}
+
+fn stream_json<R: Read>(reader: R) -> Result<Value, PayloadError> {
+    let mut budget = StreamBudget {
+        nodes: MAX_NODES,
+        exceeded: None,
+    };
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    // Depth is limited by `ValueSeed`, to `MAX_DEPTH` as everywhere else.
+    deserializer.disable_recursion_limit();
+    let value = ValueSeed {
+        depth: 0,
+        budget: &mut budget,
+    }
+    .deserialize(&mut deserializer)
+    .and_then(|value| deserializer.end().map(|_| value));
+
+    match (value, budget.exceeded) {
+        (_, Some(err)) => Err(err),
+        (Ok(value), None) => Ok(value),
+        (Err(err), None) => Err(PayloadError::Parse(err.to_string())),
+    }
+}
+
+struct StreamBudget {
+    nodes: usize,
+    /// The limit a parse stopped at, told apart from syntax errors.
+    exceeded: Option<PayloadError>,
+}
+
+/// Builds a value straight from a deserializer, counting nodes and depth
+/// as `json_to_value` does.
+struct ValueSeed<'a> {
+    depth: usize,
+    budget: &'a mut StreamBudget,
+}
+
+impl ValueSeed<'_> {
+    fn enter<E: serde::de::Error>(&mut self) -> Result<(), E> {
+        let exceeded = if self.depth > MAX_DEPTH {
+            PayloadError::TooDeep
+        } else if self.budget.nodes == 0 {
+            PayloadError::TooLarge
+        } else {
+            self.budget.nodes -= 1;
+            return Ok(());
+        };
+
+        let err = E::custom(&exceeded);
+        self.budget.exceeded = Some(exceeded);
+
+        Err(err)
+    }
+
+    fn child(&mut self) -> ValueSeed<'_> {
+        ValueSeed {
+            depth: self.depth + 1,
+            budget: self.budget,
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ValueSeed<'_> {
+    type Value = Value;
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ValueSeed<'_> {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a JSON value")
+    }
+
+    fn visit_unit<E: serde::de::Error>(mut self) -> Result<Value, E> {
+        self.enter()?;
+        Ok(Value::Null)
+    }
+
+    fn visit_bool<E: serde::de::Error>(mut self, value: bool) -> Result<Value, E> {
+        self.enter()?;
+        Ok(Value::from(value))
+    }
+
+    fn visit_i64<E: serde::de::Error>(mut self, value: i64) -> Result<Value, E> {
+        self.enter()?;
+        Ok(Value::from(value))
+    }
+
+    /// Integers that fit are kept as `i64`, as `json_to_value` does.
+    fn visit_u64<E: serde::de::Error>(mut self, value: u64) -> Result<Value, E> {
+        self.enter()?;
+        Ok(match i64::try_from(value) {
+            Ok(value) => Value::from(value),
+            Err(_) => Value::from(value),
+        })
+    }
+
+    fn visit_f64<E: serde::de::Error>(mut self, value: f64) -> Result<Value, E> {
+        self.enter()?;
+        Ok(Value::from(value))
+    }
+
+    fn visit_str<E: serde::de::Error>(mut self, value: &str) -> Result<Value, E> {
+        self.enter()?;
+        Ok(Value::from(value))
+    }
+
+    fn visit_string<E: serde::de::Error>(mut self, value: String) -> Result<Value, E> {
+        self.enter()?;
+        Ok(Value::from(value))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<Value, A::Error> {
+        self.enter()?;
+        let mut values = Vec::new();
+
+        while let Some(value) = seq.next_element_seed(self.child())? {
+            values.push(value);
+        }
+
+        Ok(Value::from(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<Value, A::Error> {
+        self.enter()?;
+        let mut object = BTreeMap::new();
+
+        while let Some(key) = map.next_key::<String>()? {
+            let value = map.next_value_seed(self.child())?;
+            object.insert(key, value);
+        }
+
+        Ok(Value::from(object))
+    }
+}
+
+fn json_to_value(
+    json: serde_json::Value,
+    depth: usize,
+    budget: &mut usize,
+) -> Result<Value, PayloadError> {
+    if depth > MAX_DEPTH {
+        return Err(PayloadError::TooDeep);
+    }
+
+    if *budget == 0 {
+        return Err(PayloadError::TooLarge);
+    }
+    *budget -= 1;
+
+    let value = match json {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(value) => Value::from(value),
+        serde_json::Value::Number(number) => {
+            if let Some(number) = number.as_i64() {
+                Value::from(number)
+            } else if let Some(number) = number.as_u64() {
+                Value::from(number)
+            } else {
+                Value::from(number.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        serde_json::Value::String(value) => Value::from(value),
+        serde_json::Value::Array(array) => {
+            let mut values = Vec::with_capacity(array.len());
+
+            for item in array {
+                values.push(json_to_value(item, depth + 1, budget)?);
+            }
+
+            Value::from(values)
+        }
+        serde_json::Value::Object(map) => {
+            let mut object = BTreeMap::new();
+
+            for (key, item) in map {
+                object.insert(key, json_to_value(item, depth + 1, budget)?);
+            }
+
+            Value::from(object)
+        }
+    };
+
+    Ok(value)
+}
+
+#[cfg(all(feature = "simd", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn parse_json_simd(content: &str) -> Result<Value, PayloadError> {
+    let mut bytes = content.as_bytes().to_vec();
+    let json = simd_json::to_owned_value(&mut bytes)
+        .map_err(|err| PayloadError::Parse(err.to_string()))?;
+    let mut budget = MAX_NODES;
+
+    simd_to_value(json, 0, &mut budget)
+}
+
+#[cfg(all(feature = "simd", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn simd_to_value(
+    json: simd_json::OwnedValue,
+    depth: usize,
+    budget: &mut usize,
+) -> Result<Value, PayloadError> {
+    use simd_json::{OwnedValue, StaticNode};
+
+    if depth > MAX_DEPTH {
+        return Err(PayloadError::TooDeep);
+    }
+
+    if *budget == 0 {
+        return Err(PayloadError::TooLarge);
+    }
+    *budget -= 1;
+
+    let value = match json {
+        OwnedValue::Static(StaticNode::Null) => Value::Null,
+        OwnedValue::Static(StaticNode::Bool(value)) => Value::from(value),
+        OwnedValue::Static(StaticNode::I64(number)) => Value::from(number),
+        OwnedValue::Static(StaticNode::U64(number)) => Value::from(number),
+        OwnedValue::Static(StaticNode::F64(number)) => Value::from(number),
+        OwnedValue::String(value) => Value::from(value),
+        OwnedValue::Array(array) => {
+            let mut values = Vec::with_capacity(array.len());
+
+            for item in array {
+                values.push(simd_to_value(item, depth + 1, budget)?);
+            }
+
+            Value::from(values)
+        }
+        OwnedValue::Object(map) => {
+            let mut object = BTreeMap::new();
+
+            for (key, item) in *map {
+                object.insert(key, simd_to_value(item, depth + 1, budget)?);
+            }
+
+            Value::from(object)
+        }
+    };
+
+    Ok(value)
+}
+
+fn parse_yaml(content: &str, multi_document: &MultiDocument) -> Result<Value, PayloadError> {
+    let mut budget = MAX_NODES;
+    let mut documents = Vec::new();
+
+    for document in serde_yaml::Deserializer::from_str(content) {
+        let mut yaml = serde_yaml::Value::deserialize(document)
+            .map_err(|err| PayloadError::Parse(err.to_string()))?;
+        yaml.apply_merge()
+            .map_err(|err| PayloadError::Parse(err.to_string()))?;
+
+        documents.push(yaml_to_value(yaml, 0, &mut budget)?);
+    }
+
+    if documents.len() <= 1 {
+        return Ok(documents.pop().unwrap_or(Value::Null));
+    }
+
+    match multi_document {
+        MultiDocument::Indexed => Ok(Value::from(documents)),
+        MultiDocument::Merged => {
+            let mut merged = Value::Null;
+
+            for document in documents {
+                merge(&mut merged, document);
+            }
+
+            Ok(merged)
+        }
+    }
+}
+
+fn yaml_to_value(
+    yaml: serde_yaml::Value,
+    depth: usize,
+    budget: &mut usize,
+) -> Result<Value, PayloadError> {
+    if depth > MAX_DEPTH {
+        return Err(PayloadError::TooDeep);
+    }
+
+    if *budget == 0 {
+        return Err(PayloadError::TooLarge);
+    }
+    *budget -= 1;
+
+    let value = match yaml {
+        serde_yaml::Value::Null => Value::Null,
+        serde_yaml::Value::Bool(value) => Value::from(value),
+        serde_yaml::Value::Number(number) => {
+            if let Some(number) = number.as_i64() {
+                Value::from(number)
+            } else if let Some(number) = number.as_u64() {
+                Value::from(number)
+            } else {
+                Value::from(number.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        serde_yaml::Value::String(value) => Value::from(value),
+        serde_yaml::Value::Sequence(sequence) => {
+            let mut values = Vec::with_capacity(sequence.len());
+
+            for item in sequence {
+                values.push(yaml_to_value(item, depth + 1, budget)?);
+            }
+
+            Value::from(values)
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            let mut object = BTreeMap::new();
+
+            for (key, item) in mapping {
+                let key = match key {
+                    serde_yaml::Value::String(key) => key,
+                    key => serde_yaml::to_string(&key)
+                        .map_err(|err| PayloadError::Parse(err.to_string()))?
+                        .trim()
+                        .to_string(),
+                };
+
+                object.insert(key, yaml_to_value(item, depth + 1, budget)?);
+            }
+
+            Value::from(object)
+        }
+        serde_yaml::Value::Tagged(tagged) => yaml_to_value(tagged.value, depth, budget)?,
+    };
+
+    Ok(value)
+}
+
+/// Deep-merges `source` into `target`, `source` winning on conflicts.
+pub fn merge(target: &mut Value, source: Value) {
+    match (target, source) {
+        (Value::Object(target), Value::Object(source)) => {
+            for (key, value) in source.iter() {
+                let key = key.to_string();
+
+                match target.get_mut(&key) {
+                    Some(current) => merge(current, value.clone()),
+                    None => {
+                        target.insert(key, value.clone());
+                    }
+                }
+            }
+        }
+        (target, source) => *target = source,
+    }
+}
@@ -0,0 +1,243 @@
+use crate::events::{BranchEvent, BranchEventKind, EventHub};
+use crate::watch::post_json;
+use log::debug;
+use quickleaf::valu3::prelude::*;
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+
+pub type ArcBranchPolicy = Arc<RwLock<ResolvedPolicy>>;
+
+/// What a redacted key reads as.
+pub const REDACTED: &str = "[redacted]";
+
+/// Matches branches whose labels contain every key/value pair listed.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct LabelSelector {
+    pub match_labels: HashMap<String, String>,
+}
+
+impl LabelSelector {
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.match_labels
+            .iter()
+            .all(|(key, value)| labels.get(key) == Some(value))
+    }
+}
+
+/// Time range, in unix millis, during which updates must not be applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FreezeWindow {
+    pub start_millis: u128,
+    pub end_millis: u128,
+}
+
+impl FreezeWindow {
+    pub fn contains(&self, now_millis: u128) -> bool {
+        now_millis >= self.start_millis && now_millis < self.end_millis
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct BranchPolicy {
+    pub name: String,
+    pub selector: LabelSelector,
+    /// Matching branches skip their syncs while in one of these windows.
+    /// The initial load is not held back.
+    pub freeze_windows: Vec<FreezeWindow>,
+    /// URLs the sync outcomes of matching branches are posted to, see
+    /// `PolicyWebhooks`.
+    pub webhook_targets: Vec<String>,
+    /// Most syncs matching branches run in a minute. Those over it are
+    /// skipped until the next poll or trigger.
+    pub rate_limit_per_minute: Option<u32>,
+    /// Keys read as `REDACTED` through `GitdisService`.
+    pub redact_keys: Vec<String>,
+    /// Archive matching branches once they have not been read for this long.
+    pub archive_after_idle_millis: Option<u64>,
+    /// Activate, or put in standby, matching branches whatever their own
+    /// `enabled` setting says.
+    pub enabled: Option<bool>,
+}
+
+/// The result of merging every policy whose selector matches a branch.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct ResolvedPolicy {
+    pub policy_names: Vec<String>,
+    pub freeze_windows: Vec<FreezeWindow>,
+    pub webhook_targets: Vec<String>,
+    pub rate_limit_per_minute: Option<u32>,
+    pub redact_keys: Vec<String>,
+    pub archive_after_idle_millis: Option<u64>,
+    pub enabled: Option<bool>,
+}
+
+impl ResolvedPolicy {
+    /// Policies are merged in registration order. Lists are concatenated
+    /// without duplicates and the strictest rate limit wins. The idle
+    /// archival delay and `enabled` of the last policy setting them win, so
+    /// a catch-all policy can be overridden by a label-specific one added
+    /// after it.
+    pub fn resolve(policies: &[BranchPolicy], labels: &HashMap<String, String>) -> Self {
+        let mut resolved = ResolvedPolicy::default();
+
+        for policy in policies {
+            if !policy.selector.matches(labels) {
+                continue;
+            }
+
+            resolved.policy_names.push(policy.name.clone());
+
+            for window in &policy.freeze_windows {
+                if !resolved.freeze_windows.contains(window) {
+                    resolved.freeze_windows.push(window.clone());
+                }
+            }
+
+            for target in &policy.webhook_targets {
+                if !resolved.webhook_targets.contains(target) {
+                    resolved.webhook_targets.push(target.clone());
+                }
+            }
+
+            for key in &policy.redact_keys {
+                if !resolved.redact_keys.contains(key) {
+                    resolved.redact_keys.push(key.clone());
+                }
+            }
+
+            resolved.rate_limit_per_minute =
+                match (resolved.rate_limit_per_minute, policy.rate_limit_per_minute) {
+                    (Some(current), Some(limit)) => Some(current.min(limit)),
+                    (current, limit) => current.or(limit),
+                };
+
+            if policy.archive_after_idle_millis.is_some() {
+                resolved.archive_after_idle_millis = policy.archive_after_idle_millis;
+            }
+
+            if policy.enabled.is_some() {
+                resolved.enabled = policy.enabled;
+            }
+        }
+
+        resolved
+    }
+
+    pub fn is_frozen(&self, now_millis: u128) -> bool {
+        self.freeze_windows
+            .iter()
+            .any(|window| window.contains(now_millis))
+    }
+
+    pub fn is_redacted(&self, key: &str) -> bool {
+        self.redact_keys.iter().any(|redact| redact == key)
+    }
+}
+
+type Delivery = (String, String);
+
+/// Posts the sync outcomes of each branch, `SyncCompleted`, `SyncFailed`
+/// and `Rejected`, to the webhook targets of its policy, on a thread of
+/// its own so slow targets never hold a sync back. The body is a JSON
+/// object with `branch_key`, `sequence`, `event` and the fields of the
+/// event.
+#[derive(Clone, Default)]
+pub struct PolicyWebhooks {
+    /// Policy of each branch, see `track`.
+    policies: Arc<RwLock<HashMap<String, ArcBranchPolicy>>>,
+    sender: Arc<Mutex<Option<Sender<Delivery>>>>,
+}
+
+impl PolicyWebhooks {
+    /// Posts the events published on `events`.
+    pub fn subscribe(&self, events: &EventHub) -> u64 {
+        let webhooks = self.clone();
+
+        events.subscribe(Arc::new(move |event: &BranchEvent| webhooks.handle(event)))
+    }
+
+    /// Reads the webhook targets of `branch_key` from `policy`.
+    pub fn track(&self, branch_key: &str, policy: ArcBranchPolicy) {
+        if let Ok(mut policies) = self.policies.write() {
+            policies.insert(branch_key.to_string(), policy);
+        }
+    }
+
+    fn handle(&self, event: &BranchEvent) {
+        let mut body = HashMap::from([
+            (
+                "branch_key".to_string(),
+                Value::from(event.branch_key.as_str()),
+            ),
+            ("sequence".to_string(), Value::from(event.sequence)),
+        ]);
+
+        let (name, fields) = match &event.event {
+            BranchEventKind::SyncCompleted { commit } => {
+                ("sync_completed", vec![("commit", commit)])
+            }
+            BranchEventKind::SyncFailed { error } => ("sync_failed", vec![("error", error)]),
+            BranchEventKind::Rejected { commit, reason } => {
+                ("rejected", vec![("commit", commit), ("reason", reason)])
+            }
+            BranchEventKind::Removed => {
+                if let Ok(mut policies) = self.policies.write() {
+                    policies.remove(&event.branch_key);
+                }
+                return;
+            }
+            _ => return,
+        };
+
+        let targets = match self.policies.read() {
+            Ok(policies) => match policies.get(&event.branch_key) {
+                Some(policy) => policy
+                    .read()
+                    .map(|policy| policy.webhook_targets.clone())
+                    .unwrap_or_default(),
+                None => return,
+            },
+            Err(_) => return,
+        };
+
+        if targets.is_empty() {
+            return;
+        }
+
+        body.insert("event".to_string(), Value::from(name));
+
+        for (field, value) in fields {
+            body.insert(field.to_string(), Value::from(value.as_str()));
+        }
+
+        let body = Value::from(body).to_json(JsonMode::Inline);
+
+        for target in targets {
+            self.deliver(target, body.clone());
+        }
+    }
+
+    fn deliver(&self, url: String, body: String) {
+        let mut sender = match self.sender.lock() {
+            Ok(sender) => sender,
+            Err(_) => return,
+        };
+
+        let sender = sender.get_or_insert_with(|| {
+            let (sender, receiver) = channel::<Delivery>();
+
+            std::thread::spawn(move || {
+                for (url, body) in receiver.iter() {
+                    if let Err(err) = post_json(&url, &body) {
+                        debug!("Failed to post to policy webhook {}: {}", url, err);
+                    }
+                }
+            });
+
+            sender
+        });
+
+        let _ = sender.send((url, body));
+    }
+}
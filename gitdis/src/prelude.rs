@@ -1,5 +1,41 @@
 pub use crate::branch_handler::*;
+pub use crate::clock::*;
+pub use crate::codec::*;
+pub use crate::discovery::*;
+pub use crate::dump::*;
+pub use crate::events::*;
+#[cfg(feature = "export")]
+pub use crate::export::*;
+pub use crate::filter::*;
 pub use crate::gitdis::*;
+pub use crate::history::*;
+pub use crate::journal::*;
+pub use crate::keys::*;
+pub use crate::limiter::*;
+pub use crate::lint::*;
+pub use crate::matrix::*;
+pub use crate::memo::*;
+pub use crate::metadata::*;
+pub use crate::migration::*;
+pub use crate::owners::*;
+pub use crate::patch::*;
+pub use crate::payload::*;
+pub use crate::policy::*;
+pub use crate::process::*;
+pub use crate::publish::*;
+pub use crate::query::*;
+pub use crate::reconcile::*;
+pub use crate::repo_url::*;
+pub use crate::retry::*;
+pub use crate::schema::*;
 pub use crate::services::*;
+#[cfg(feature = "sql")]
+pub use crate::sql::*;
+pub use crate::status::*;
+pub use crate::template::*;
+pub use crate::usage::*;
+pub use crate::versions::*;
+pub use crate::view::*;
+pub use crate::watch::*;
 pub use quickleaf::prelude::*;
 pub use quickleaf::*;
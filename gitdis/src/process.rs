@@ -0,0 +1,86 @@
+use std::io::Read;
+use std::process::{Child, Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+/// Longest wait between two checks of a process run with a timeout.
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Runs the processes of a branch handler: git, and curl and tar for
+/// tarball prewarms. Tests swap in a runner answering without spawning
+/// anything, so git interactions need no real repository.
+pub trait ProcessRunner: Send + Sync {
+    /// Runs `command` to completion, as `Command::output` does.
+    fn output(&self, command: &mut Command) -> std::io::Result<Output>;
+
+    /// Starts `command` with its output streamed, as `Command::spawn` does.
+    fn spawn(&self, command: &mut Command) -> std::io::Result<Child> {
+        command.spawn()
+    }
+
+    /// Runs `command` to completion, killing it once it ran for `timeout`.
+    /// Fails with `ErrorKind::TimedOut` then. Only the process itself is
+    /// killed; helpers it started, such as `git-remote-https`, end when
+    /// they next write to it.
+    fn output_timeout(&self, command: &mut Command, timeout: Duration) -> std::io::Result<Output> {
+        let mut child = self.spawn(
+            command
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )?;
+
+        // Drained as the process runs, so that it never blocks on a full pipe.
+        let stdout = read_pipe(child.stdout.take());
+        let stderr = read_pipe(child.stderr.take());
+
+        let deadline = Instant::now() + timeout;
+        let mut interval = Duration::from_millis(1);
+
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Output {
+                    status,
+                    stdout: stdout.join().unwrap_or_default(),
+                    stderr: stderr.join().unwrap_or_default(),
+                });
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("Killed after {:?}", timeout),
+                ));
+            }
+
+            std::thread::sleep(interval.min(deadline - now));
+            interval = (interval * 2).min(MAX_POLL_INTERVAL);
+        }
+    }
+}
+
+fn read_pipe(pipe: Option<impl Read + Send + 'static>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+
+        buffer
+    })
+}
+
+/// Spawns real processes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemProcessRunner;
+
+impl ProcessRunner for SystemProcessRunner {
+    fn output(&self, command: &mut Command) -> std::io::Result<Output> {
+        command.output()
+    }
+}
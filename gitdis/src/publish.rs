@@ -0,0 +1,393 @@
+use crate::template::TemplateSource;
+use crate::watch::pipe_to;
+use quickleaf::valu3::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// Longest an upload may take, in seconds.
+pub const PUBLISH_TIMEOUT_SECS: u64 = 30;
+/// Shortest wait between two runs of a job, unless it sets its own.
+pub const DEFAULT_JOB_INTERVAL_MILLIS: u64 = 10_000;
+
+/// Tells apart the temporary files of concurrent uploads.
+static NEXT_UPLOAD: AtomicU64 = AtomicU64::new(0);
+
+/// Where the output of a render job is published.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PublishTarget {
+    /// A file on disk, replaced atomically through a temporary file next to
+    /// it. Missing directories are created.
+    File { path: String },
+    /// An S3 object, or one of an S3-compatible store at `endpoint`, signed
+    /// with the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and, if set,
+    /// `AWS_SESSION_TOKEN` of the process. Uploaded with curl, 7.75 or later.
+    S3 {
+        bucket: String,
+        key: String,
+        region: String,
+        endpoint: Option<String>,
+    },
+    /// An HTTP PUT of the output as text.
+    HttpPut { url: String },
+}
+
+impl PublishTarget {
+    pub fn publish(&self, content: &str) -> Result<(), String> {
+        match self {
+            PublishTarget::File { path } => write_file(path, content),
+            PublishTarget::S3 {
+                bucket,
+                key,
+                region,
+                endpoint,
+            } => put_s3_object(bucket, key, region, endpoint.as_deref(), content),
+            PublishTarget::HttpPut { url } => pipe_to(
+                Command::new("curl")
+                    .arg("-sS")
+                    .arg("--fail")
+                    .arg("--max-time")
+                    .arg(PUBLISH_TIMEOUT_SECS.to_string())
+                    .arg("-X")
+                    .arg("PUT")
+                    .arg("-H")
+                    .arg("Content-Type: text/plain; charset=utf-8")
+                    .arg("--data-binary")
+                    .arg("@-")
+                    .arg(url),
+                content,
+            ),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        match self {
+            PublishTarget::File { path } => {
+                object.insert("type".to_string(), Value::from("file"));
+                object.insert("path".to_string(), Value::from(path.as_str()));
+            }
+            PublishTarget::S3 {
+                bucket,
+                key,
+                region,
+                endpoint,
+            } => {
+                object.insert("type".to_string(), Value::from("s3"));
+                object.insert("bucket".to_string(), Value::from(bucket.as_str()));
+                object.insert("key".to_string(), Value::from(key.as_str()));
+                object.insert("region".to_string(), Value::from(region.as_str()));
+
+                if let Some(endpoint) = endpoint {
+                    object.insert("endpoint".to_string(), Value::from(endpoint.as_str()));
+                }
+            }
+            PublishTarget::HttpPut { url } => {
+                object.insert("type".to_string(), Value::from("http_put"));
+                object.insert("url".to_string(), Value::from(url.as_str()));
+            }
+        }
+
+        Value::from(object)
+    }
+}
+
+fn write_file(path: &str, content: &str) -> Result<(), String> {
+    let path = std::path::Path::new(path);
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+    }
+
+    let temporary = path.with_extension("gitdis-tmp");
+
+    std::fs::write(&temporary, content).map_err(|err| err.to_string())?;
+    std::fs::rename(&temporary, path).map_err(|err| {
+        let _ = std::fs::remove_file(&temporary);
+        err.to_string()
+    })
+}
+
+/// Credentials go to curl through a config on its stdin, so they never show
+/// in the process list, and the content through a temporary file.
+fn put_s3_object(
+    bucket: &str,
+    key: &str,
+    region: &str,
+    endpoint: Option<&str>,
+    content: &str,
+) -> Result<(), String> {
+    let access_key = std::env::var("AWS_ACCESS_KEY_ID")
+        .map_err(|_| "AWS_ACCESS_KEY_ID is not set".to_string())?;
+    let secret_key = std::env::var("AWS_SECRET_ACCESS_KEY")
+        .map_err(|_| "AWS_SECRET_ACCESS_KEY is not set".to_string())?;
+    let key = key.trim_start_matches('/');
+    let url = match endpoint {
+        Some(endpoint) => format!("{}/{}/{}", endpoint.trim_end_matches('/'), bucket, key),
+        None => format!("https://{}.s3.{}.amazonaws.com/{}", bucket, region, key),
+    };
+    let mut config = format!("user = \"{}:{}\"\n", access_key, secret_key);
+
+    if let Ok(token) = std::env::var("AWS_SESSION_TOKEN") {
+        config.push_str(&format!("header = \"x-amz-security-token: {}\"\n", token));
+    }
+
+    let upload = std::env::temp_dir().join(format!(
+        "gitdis-publish-{}-{}",
+        std::process::id(),
+        NEXT_UPLOAD.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    std::fs::write(&upload, content).map_err(|err| err.to_string())?;
+
+    let result = pipe_to(
+        Command::new("curl")
+            .arg("-sS")
+            .arg("--fail")
+            .arg("--max-time")
+            .arg(PUBLISH_TIMEOUT_SECS.to_string())
+            .arg("--aws-sigv4")
+            .arg(format!("aws:amz:{}:s3", region))
+            .arg("--config")
+            .arg("-")
+            .arg("--upload-file")
+            .arg(&upload)
+            .arg(url),
+        &config,
+    );
+
+    let _ = std::fs::remove_file(&upload);
+
+    result
+}
+
+/// A template rendered with the data of a branch, or of `prefix` in it,
+/// and published to `target` each time the branch loads a new commit, at
+/// most once per `interval_millis`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderJob {
+    pub template: TemplateSource,
+    pub prefix: String,
+    pub target: PublishTarget,
+    pub interval_millis: u64,
+}
+
+impl RenderJob {
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        match &self.template {
+            TemplateSource::Inline(template) => {
+                object.insert("template".to_string(), Value::from(template.as_str()))
+            }
+            TemplateSource::File(path) => {
+                object.insert("path".to_string(), Value::from(path.as_str()))
+            }
+        };
+        object.insert("prefix".to_string(), Value::from(self.prefix.as_str()));
+        object.insert("target".to_string(), self.target.to_value());
+        object.insert(
+            "interval_millis".to_string(),
+            Value::from(self.interval_millis),
+        );
+
+        Value::from(object)
+    }
+}
+
+/// Outcome of the runs of a render job.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct RenderJobStatus {
+    pub runs: u64,
+    pub failures: u64,
+    pub last_run_millis: Option<u128>,
+    /// The last time the output changed and was published.
+    pub last_published_millis: Option<u128>,
+    /// Commit of the branch at the last run.
+    pub last_commit: Option<String>,
+    /// Error of the last run, if it failed. Failed jobs run again at their
+    /// next interval even if the branch did not move.
+    pub last_error: Option<String>,
+    /// Hash of the last output published. An output identical to it is not
+    /// published again.
+    pub output_hash: Option<u64>,
+}
+
+impl RenderJobStatus {
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert("runs".to_string(), Value::from(self.runs));
+        object.insert("failures".to_string(), Value::from(self.failures));
+        object.insert(
+            "last_run_millis".to_string(),
+            self.last_run_millis.map_or(Value::Null, Value::from),
+        );
+        object.insert(
+            "last_published_millis".to_string(),
+            self.last_published_millis.map_or(Value::Null, Value::from),
+        );
+        object.insert(
+            "last_commit".to_string(),
+            self.last_commit.as_deref().map_or(Value::Null, Value::from),
+        );
+        object.insert(
+            "last_error".to_string(),
+            self.last_error.as_deref().map_or(Value::Null, Value::from),
+        );
+
+        Value::from(object)
+    }
+}
+
+pub fn hash_output(output: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    output.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The render jobs of a branch by name.
+type BranchRenderJobs = BTreeMap<String, (RenderJob, RenderJobStatus)>;
+
+/// Render jobs by branch and name, with the status of each.
+#[derive(Default)]
+pub struct RenderJobStore {
+    branches: RwLock<HashMap<String, BranchRenderJobs>>,
+}
+
+impl RenderJobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether a job of that name was replaced. Its status starts
+    /// over, so it runs at the next check.
+    pub fn save(&self, branch_key: &str, name: &str, job: RenderJob) -> bool {
+        match self.branches.write() {
+            Ok(mut branches) => branches
+                .entry(branch_key.to_string())
+                .or_default()
+                .insert(name.to_string(), (job, RenderJobStatus::default()))
+                .is_some(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn get(&self, branch_key: &str, name: &str) -> Option<(RenderJob, RenderJobStatus)> {
+        match self.branches.read() {
+            Ok(branches) => branches.get(branch_key)?.get(name).cloned(),
+            Err(_) => None,
+        }
+    }
+
+    /// Every job as `(branch_key, name, job, status)`, by branch and name.
+    pub fn list(&self) -> Vec<(String, String, RenderJob, RenderJobStatus)> {
+        let mut jobs = match self.branches.read() {
+            Ok(branches) => branches
+                .iter()
+                .flat_map(|(branch_key, jobs)| {
+                    jobs.iter().map(|(name, (job, status))| {
+                        (
+                            branch_key.clone(),
+                            name.clone(),
+                            job.clone(),
+                            status.clone(),
+                        )
+                    })
+                })
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+
+        jobs.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        jobs
+    }
+
+    pub fn remove(&self, branch_key: &str, name: &str) -> bool {
+        match self.branches.write() {
+            Ok(mut branches) => branches
+                .get_mut(branch_key)
+                .is_some_and(|jobs| jobs.remove(name).is_some()),
+            Err(_) => false,
+        }
+    }
+
+    pub fn remove_branch(&self, branch_key: &str) {
+        if let Ok(mut branches) = self.branches.write() {
+            branches.remove(branch_key);
+        }
+    }
+
+    /// Jobs, as `(branch_key, name)`, whose interval has passed and whose
+    /// branch moved since their last run, or whose last run failed.
+    /// `get_commit` gives the commit a branch is at, `None` for branches
+    /// that have not loaded yet.
+    pub fn get_due(
+        &self,
+        now_millis: u128,
+        get_commit: impl Fn(&str) -> Option<String>,
+    ) -> Vec<(String, String)> {
+        let branches = match self.branches.read() {
+            Ok(branches) => branches,
+            Err(_) => return Vec::new(),
+        };
+        let mut due = Vec::new();
+
+        for (branch_key, jobs) in branches.iter() {
+            let commit = match get_commit(branch_key) {
+                Some(commit) => commit,
+                None => continue,
+            };
+
+            for (name, (job, status)) in jobs {
+                let waited = status
+                    .last_run_millis
+                    .is_none_or(|last| now_millis >= last + job.interval_millis as u128);
+                let pending = status.last_error.is_some()
+                    || status.last_commit.as_deref() != Some(commit.as_str());
+
+                if waited && pending {
+                    due.push((branch_key.clone(), name.clone()));
+                }
+            }
+        }
+
+        due
+    }
+
+    /// Records a run at `commit`: the hash of the output published, `None`
+    /// if it was unchanged, or the error that failed it.
+    pub fn record(
+        &self,
+        branch_key: &str,
+        name: &str,
+        now_millis: u128,
+        commit: Option<String>,
+        result: Result<Option<u64>, String>,
+    ) -> Option<RenderJobStatus> {
+        let mut branches = self.branches.write().ok()?;
+        let (_, status) = branches.get_mut(branch_key)?.get_mut(name)?;
+
+        status.runs += 1;
+        status.last_run_millis = Some(now_millis);
+        status.last_commit = commit;
+
+        match result {
+            Ok(Some(hash)) => {
+                status.output_hash = Some(hash);
+                status.last_published_millis = Some(now_millis);
+                status.last_error = None;
+            }
+            Ok(None) => status.last_error = None,
+            Err(err) => {
+                status.failures += 1;
+                status.last_error = Some(err);
+            }
+        }
+
+        Some(status.clone())
+    }
+}
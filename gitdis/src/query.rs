@@ -0,0 +1,732 @@
+use super::cache::list_refs;
+use super::history::Generation;
+use quickleaf::valu3::prelude::*;
+use quickleaf::Cache;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// Path of a condition on the cache key instead of the value. Conditions on
+/// it narrow the key range scanned.
+pub const KEY_PATH: &str = "$key";
+
+#[derive(Debug, PartialEq)]
+pub enum QueryError {
+    InvalidOperator(String),
+    InvalidPath(String),
+    MissingParam(String),
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueryError::InvalidOperator(op) => write!(f, "Unknown operator: {}", op),
+            QueryError::InvalidPath(path) => {
+                write!(f, "Path must be {} or a JSON pointer: {}", KEY_PATH, path)
+            }
+            QueryError::MissingParam(name) => write!(f, "Missing parameter: {}", name),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConditionOp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    /// The path resolves, whatever its value.
+    Exists,
+    /// An array holding the value, or a string holding it as a substring.
+    Contains,
+    StartsWith,
+}
+
+impl ConditionOp {
+    pub fn parse(op: &str) -> Result<Self, QueryError> {
+        match op {
+            "eq" => Ok(ConditionOp::Eq),
+            "ne" => Ok(ConditionOp::Ne),
+            "gt" => Ok(ConditionOp::Gt),
+            "gte" => Ok(ConditionOp::Gte),
+            "lt" => Ok(ConditionOp::Lt),
+            "lte" => Ok(ConditionOp::Lte),
+            "exists" => Ok(ConditionOp::Exists),
+            "contains" => Ok(ConditionOp::Contains),
+            "starts_with" => Ok(ConditionOp::StartsWith),
+            _ => Err(QueryError::InvalidOperator(op.to_string())),
+        }
+    }
+
+    /// Relative cost of checking the operator on one entry.
+    fn get_weight(&self) -> u64 {
+        match self {
+            ConditionOp::Exists => 1,
+            ConditionOp::Contains => 4,
+            _ => 2,
+        }
+    }
+}
+
+/// `path` compared to `value` with `op`. `path` is a JSON pointer into the
+/// entry, e.g. `/spec/replicas`, or `KEY_PATH`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub path: String,
+    pub op: ConditionOp,
+    pub value: Value,
+}
+
+impl Condition {
+    pub fn new(path: &str, op: ConditionOp, value: Value) -> Result<Self, QueryError> {
+        check_path(path)?;
+
+        Ok(Self {
+            path: path.to_string(),
+            op,
+            value,
+        })
+    }
+
+    fn get_weight(&self) -> u64 {
+        self.op.get_weight() + self.path.matches('/').count() as u64
+    }
+
+    pub(crate) fn matches(&self, key: &str, entry: &Value) -> bool {
+        let key_value = Value::from(key);
+
+        let target = match self.path.as_str() {
+            KEY_PATH => Some(&key_value),
+            path => resolve_pointer(entry, path),
+        };
+
+        let target = match target {
+            Some(target) => target,
+            None => return false,
+        };
+
+        match self.op {
+            ConditionOp::Exists => true,
+            ConditionOp::Eq => target == &self.value,
+            ConditionOp::Ne => target != &self.value,
+            ConditionOp::Gt => compare(target, &self.value).is_some_and(|order| order.is_gt()),
+            ConditionOp::Gte => compare(target, &self.value).is_some_and(|order| order.is_ge()),
+            ConditionOp::Lt => compare(target, &self.value).is_some_and(|order| order.is_lt()),
+            ConditionOp::Lte => compare(target, &self.value).is_some_and(|order| order.is_le()),
+            ConditionOp::Contains => match (target, &self.value) {
+                (Value::Array(array), value) => array.values.iter().any(|item| item == value),
+                (Value::String(target), Value::String(value)) => {
+                    target.to_string().contains(&value.to_string())
+                }
+                _ => false,
+            },
+            ConditionOp::StartsWith => match (target, &self.value) {
+                (Value::String(target), Value::String(value)) => {
+                    target.to_string().starts_with(&value.to_string())
+                }
+                _ => false,
+            },
+        }
+    }
+
+    /// The key prefix the condition restricts keys to, if it does.
+    fn get_key_prefix(&self) -> Option<String> {
+        match (self.path.as_str(), self.op, &self.value) {
+            (KEY_PATH, ConditionOp::Eq | ConditionOp::StartsWith, Value::String(value)) => {
+                Some(value.to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Entries of a branch under `prefix` matching every condition.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConditionQuery {
+    pub prefix: String,
+    pub conditions: Vec<Condition>,
+    /// Matches returned at most.
+    pub limit: Option<usize>,
+}
+
+/// Bounds on the work of one query. Past them the query stops and returns
+/// what it matched so far, marked truncated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueryLimits {
+    pub max_scanned: usize,
+    /// Entries scanned times the cost of checking the conditions on one.
+    pub max_cost: u64,
+    pub max_duration: Duration,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            max_scanned: 10_000,
+            max_cost: 100_000,
+            max_duration: Duration::from_secs(1),
+        }
+    }
+}
+
+/// How the entries of a query are found.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryIndex {
+    /// A single key, from an `eq` condition on `KEY_PATH`.
+    Key(String),
+    /// The keys starting with a prefix, the query's own or a longer one
+    /// from a `starts_with` condition on `KEY_PATH`.
+    Prefix(String),
+    /// The key conditions contradict each other.
+    Empty,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryPlan {
+    pub index: QueryIndex,
+    pub estimated_entries: usize,
+    pub cost_per_entry: u64,
+    pub estimated_cost: u64,
+    /// Entries scanned at most, within both the scan and the cost limits.
+    pub scan_budget: usize,
+}
+
+impl QueryPlan {
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        let (index, key) = match &self.index {
+            QueryIndex::Key(key) => ("key", Value::from(key.as_str())),
+            QueryIndex::Prefix(prefix) => ("prefix", Value::from(prefix.as_str())),
+            QueryIndex::Empty => ("empty", Value::Null),
+        };
+
+        object.insert("index".to_string(), Value::from(index));
+        object.insert("index_key".to_string(), key);
+        object.insert(
+            "estimated_entries".to_string(),
+            Value::from(self.estimated_entries),
+        );
+        object.insert(
+            "cost_per_entry".to_string(),
+            Value::from(self.cost_per_entry),
+        );
+        object.insert(
+            "estimated_cost".to_string(),
+            Value::from(self.estimated_cost),
+        );
+        object.insert("scan_budget".to_string(), Value::from(self.scan_budget));
+
+        Value::from(object)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryResult {
+    pub plan: QueryPlan,
+    pub matches: Vec<(String, Value)>,
+    pub scanned: usize,
+    /// The scan stopped before the last entry, on a limit or on the
+    /// query's own `limit`, so more entries may match.
+    pub truncated: bool,
+}
+
+impl QueryResult {
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert(
+            "matches".to_string(),
+            Value::from(
+                self.matches
+                    .iter()
+                    .map(|(key, value)| {
+                        let mut object = HashMap::new();
+                        object.insert("key".to_string(), Value::from(key.as_str()));
+                        object.insert("value".to_string(), value.clone());
+                        Value::from(object)
+                    })
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        object.insert("scanned".to_string(), Value::from(self.scanned));
+        object.insert("truncated".to_string(), Value::from(self.truncated));
+        object.insert("plan".to_string(), self.plan.to_value());
+
+        Value::from(object)
+    }
+}
+
+impl ConditionQuery {
+    /// The index the query scans and what scanning it should cost.
+    pub fn plan(&self, cache: &Cache, limits: &QueryLimits) -> QueryPlan {
+        let index = self.get_index();
+        let estimated_entries = match &index {
+            QueryIndex::Key(key) => cache.get(key).map_or(0, |_| 1),
+            QueryIndex::Prefix(prefix) => list_refs(cache, prefix).len(),
+            QueryIndex::Empty => 0,
+        };
+
+        self.get_plan(index, estimated_entries, limits)
+    }
+
+    pub fn execute(&self, cache: &Cache, limits: &QueryLimits) -> QueryResult {
+        let started_at = Instant::now();
+        let index = self.get_index();
+
+        let entries = match &index {
+            QueryIndex::Key(key) => match cache.get(key) {
+                Some(value) => vec![(key.clone(), value)],
+                None => Vec::new(),
+            },
+            QueryIndex::Prefix(prefix) => list_refs(cache, prefix),
+            QueryIndex::Empty => Vec::new(),
+        };
+
+        self.scan(index, entries, limits, started_at)
+    }
+
+    /// Same as `execute`, over a past generation of the branch.
+    pub fn execute_generation(&self, generation: &Generation, limits: &QueryLimits) -> QueryResult {
+        let started_at = Instant::now();
+        let index = self.get_index();
+
+        let entries = match &index {
+            QueryIndex::Key(key) => match generation.get(key) {
+                Some(value) => vec![(key.clone(), value)],
+                None => Vec::new(),
+            },
+            QueryIndex::Prefix(prefix) => generation.list_refs(prefix),
+            QueryIndex::Empty => Vec::new(),
+        };
+
+        self.scan(index, entries, limits, started_at)
+    }
+
+    fn scan(
+        &self,
+        index: QueryIndex,
+        entries: Vec<(String, &Value)>,
+        limits: &QueryLimits,
+        started_at: Instant,
+    ) -> QueryResult {
+        let plan = self.get_plan(index, entries.len(), limits);
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let mut matches = Vec::new();
+        let mut scanned = 0;
+
+        for (key, value) in &entries {
+            if scanned >= plan.scan_budget
+                || matches.len() >= limit
+                || started_at.elapsed() >= limits.max_duration
+            {
+                break;
+            }
+
+            scanned += 1;
+
+            if self
+                .conditions
+                .iter()
+                .all(|condition| condition.matches(key, value))
+            {
+                matches.push((key.clone(), (*value).clone()));
+            }
+        }
+
+        QueryResult {
+            truncated: scanned < entries.len(),
+            plan,
+            matches,
+            scanned,
+        }
+    }
+
+    /// Whether the entry at `key` is in the query's index and matches every
+    /// condition.
+    pub fn matches(&self, key: &str, value: &Value) -> bool {
+        let indexed = match self.get_index() {
+            QueryIndex::Key(index_key) => index_key == key,
+            QueryIndex::Prefix(prefix) => key.starts_with(&prefix),
+            QueryIndex::Empty => false,
+        };
+
+        indexed
+            && self
+                .conditions
+                .iter()
+                .all(|condition| condition.matches(key, value))
+    }
+
+    /// Narrows the query prefix with the key conditions.
+    fn get_index(&self) -> QueryIndex {
+        let mut prefix = self.prefix.clone();
+
+        for condition in &self.conditions {
+            let key_prefix = match condition.get_key_prefix() {
+                Some(key_prefix) => key_prefix,
+                None => continue,
+            };
+
+            if key_prefix.starts_with(&prefix) {
+                prefix = key_prefix;
+            } else if !prefix.starts_with(&key_prefix) {
+                return QueryIndex::Empty;
+            }
+        }
+
+        let key = self.conditions.iter().find_map(|condition| {
+            match (condition.path.as_str(), condition.op, &condition.value) {
+                (KEY_PATH, ConditionOp::Eq, Value::String(key)) => Some(key.to_string()),
+                _ => None,
+            }
+        });
+
+        match key {
+            Some(key) if key == prefix => QueryIndex::Key(key),
+            Some(_) => QueryIndex::Empty,
+            None => QueryIndex::Prefix(prefix),
+        }
+    }
+
+    fn get_plan(&self, index: QueryIndex, entries: usize, limits: &QueryLimits) -> QueryPlan {
+        let cost_per_entry = 1 + self
+            .conditions
+            .iter()
+            .map(|condition| condition.get_weight())
+            .sum::<u64>();
+        let affordable = (limits.max_cost / cost_per_entry) as usize;
+
+        QueryPlan {
+            index,
+            estimated_entries: entries,
+            cost_per_entry,
+            estimated_cost: cost_per_entry.saturating_mul(entries as u64),
+            scan_budget: limits.max_scanned.min(affordable),
+        }
+    }
+}
+
+/// A change to the set of entries matching a continuous query.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MatchChange {
+    Entered {
+        key: String,
+        value: Value,
+    },
+    Left {
+        key: String,
+    },
+    /// A matching entry changed and still matches.
+    Updated {
+        key: String,
+        value: Value,
+    },
+}
+
+/// The entries matching a query, kept up to date one changed key at a time
+/// instead of rescanning the branch.
+#[derive(Clone, Debug)]
+pub struct ContinuousQuery {
+    query: ConditionQuery,
+    matched: HashSet<String>,
+}
+
+impl ContinuousQuery {
+    pub fn new(query: ConditionQuery) -> Self {
+        Self {
+            query,
+            matched: HashSet::new(),
+        }
+    }
+
+    pub fn get_query(&self) -> &ConditionQuery {
+        &self.query
+    }
+
+    /// Starts over from `entries`, returning those that match.
+    pub fn seed(&mut self, entries: Vec<(String, Value)>) -> Vec<(String, Value)> {
+        let matches = entries
+            .into_iter()
+            .filter(|(key, value)| self.query.matches(key, value))
+            .collect::<Vec<(String, Value)>>();
+
+        self.matched = matches.iter().map(|(key, _)| key.clone()).collect();
+        matches
+    }
+
+    /// Re-evaluates `key` after it was set to `value`, or removed when
+    /// `None`.
+    pub fn apply(&mut self, key: &str, value: Option<&Value>) -> Option<MatchChange> {
+        let matches = value.is_some_and(|value| self.query.matches(key, value));
+        let matched = self.matched.contains(key);
+
+        match (matched, matches, value) {
+            (false, true, Some(value)) => {
+                self.matched.insert(key.to_string());
+                Some(MatchChange::Entered {
+                    key: key.to_string(),
+                    value: value.clone(),
+                })
+            }
+            (true, true, Some(value)) => Some(MatchChange::Updated {
+                key: key.to_string(),
+                value: value.clone(),
+            }),
+            (true, false, _) => {
+                self.matched.remove(key);
+                Some(MatchChange::Left {
+                    key: key.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Every matching entry leaves, in key order.
+    pub fn clear(&mut self) -> Vec<MatchChange> {
+        let mut keys = self.matched.drain().collect::<Vec<String>>();
+        keys.sort();
+
+        keys.into_iter()
+            .map(|key| MatchChange::Left { key })
+            .collect()
+    }
+}
+
+/// Value of a saved condition: fixed, or bound from a parameter on each run.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryArg {
+    Value(Value),
+    Param(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedCondition {
+    pub path: String,
+    pub op: ConditionOp,
+    pub arg: QueryArg,
+}
+
+impl SavedCondition {
+    pub fn new(path: &str, op: ConditionOp, arg: QueryArg) -> Result<Self, QueryError> {
+        check_path(path)?;
+
+        Ok(Self {
+            path: path.to_string(),
+            op,
+            arg,
+        })
+    }
+
+    fn bind(&self, params: &HashMap<String, Value>) -> Result<Condition, QueryError> {
+        let value = match &self.arg {
+            QueryArg::Value(value) => value.clone(),
+            QueryArg::Param(name) => match params.get(name) {
+                Some(value) => value.clone(),
+                None => return Err(QueryError::MissingParam(name.clone())),
+            },
+        };
+
+        Condition::new(&self.path, self.op, value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryOrder {
+    /// A JSON pointer into the entry, or `KEY_PATH`. Entries without it
+    /// come last.
+    pub path: String,
+    pub descending: bool,
+}
+
+/// A query stored under a name, run by handle with its parameters bound.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SavedQuery {
+    pub prefix: String,
+    pub conditions: Vec<SavedCondition>,
+    /// JSON pointers returned of each match; the whole entry when empty.
+    pub projection: Vec<String>,
+    pub order_by: Option<QueryOrder>,
+    pub limit: Option<usize>,
+}
+
+impl SavedQuery {
+    /// The query with its parameters bound. Ordered queries leave the limit
+    /// out: it applies to the sorted matches, see `execute`.
+    pub fn bind(&self, params: &HashMap<String, Value>) -> Result<ConditionQuery, QueryError> {
+        Ok(ConditionQuery {
+            prefix: self.prefix.clone(),
+            conditions: self
+                .conditions
+                .iter()
+                .map(|condition| condition.bind(params))
+                .collect::<Result<Vec<Condition>, QueryError>>()?,
+            limit: match self.order_by {
+                Some(_) => None,
+                None => self.limit,
+            },
+        })
+    }
+
+    pub fn execute(
+        &self,
+        cache: &Cache,
+        params: &HashMap<String, Value>,
+        limits: &QueryLimits,
+    ) -> Result<QueryResult, QueryError> {
+        let mut result = self.bind(params)?.execute(cache, limits);
+
+        if self.shape(&mut result.matches) {
+            result.truncated = true;
+        }
+
+        Ok(result)
+    }
+
+    /// Orders, limits and projects `matches`, returning whether the limit
+    /// left some out.
+    pub fn shape(&self, matches: &mut Vec<(String, Value)>) -> bool {
+        if let Some(order) = &self.order_by {
+            matches.sort_by(|(left_key, left), (right_key, right)| {
+                let left = resolve_order(left_key, left, &order.path);
+                let right = resolve_order(right_key, right, &order.path);
+
+                match (left, right) {
+                    (Some(left), Some(right)) => {
+                        let ordering = compare(&left, &right).unwrap_or(std::cmp::Ordering::Equal);
+
+                        match order.descending {
+                            true => ordering.reverse(),
+                            false => ordering,
+                        }
+                    }
+                    (Some(_), None) => std::cmp::Ordering::Less,
+                    (None, Some(_)) => std::cmp::Ordering::Greater,
+                    (None, None) => std::cmp::Ordering::Equal,
+                }
+            });
+        }
+
+        let truncated = match self.limit {
+            Some(limit) if matches.len() > limit => {
+                matches.truncate(limit);
+                true
+            }
+            _ => false,
+        };
+
+        if !self.projection.is_empty() {
+            for (_, value) in matches.iter_mut() {
+                *value = self.project(value);
+            }
+        }
+
+        truncated
+    }
+
+    fn project(&self, value: &Value) -> Value {
+        let mut object = HashMap::new();
+
+        for path in &self.projection {
+            if let Some(field) = resolve_pointer(value, path) {
+                object.insert(path.clone(), field.clone());
+            }
+        }
+
+        Value::from(object)
+    }
+}
+
+/// Saved queries per branch and name.
+#[derive(Default)]
+pub struct SavedQueryStore {
+    branches: RwLock<HashMap<String, HashMap<String, SavedQuery>>>,
+}
+
+impl SavedQueryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether a query of that name was replaced.
+    pub fn save(&self, branch_key: &str, name: &str, query: SavedQuery) -> bool {
+        match self.branches.write() {
+            Ok(mut branches) => branches
+                .entry(branch_key.to_string())
+                .or_default()
+                .insert(name.to_string(), query)
+                .is_some(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn get(&self, branch_key: &str, name: &str) -> Option<SavedQuery> {
+        match self.branches.read() {
+            Ok(branches) => branches.get(branch_key)?.get(name).cloned(),
+            Err(_) => None,
+        }
+    }
+
+    pub fn remove(&self, branch_key: &str, name: &str) -> bool {
+        match self.branches.write() {
+            Ok(mut branches) => branches
+                .get_mut(branch_key)
+                .is_some_and(|queries| queries.remove(name).is_some()),
+            Err(_) => false,
+        }
+    }
+
+    pub fn remove_branch(&self, branch_key: &str) {
+        if let Ok(mut branches) = self.branches.write() {
+            branches.remove(branch_key);
+        }
+    }
+}
+
+fn resolve_order(key: &str, value: &Value, path: &str) -> Option<Value> {
+    match path {
+        KEY_PATH => Some(Value::from(key)),
+        path => resolve_pointer(value, path).cloned(),
+    }
+}
+
+fn check_path(path: &str) -> Result<(), QueryError> {
+    match path == KEY_PATH || path.is_empty() || path.starts_with('/') {
+        true => Ok(()),
+        false => Err(QueryError::InvalidPath(path.to_string())),
+    }
+}
+
+fn resolve_pointer<'a>(value: &'a Value, pointer: &str) -> Option<&'a Value> {
+    let mut current = value;
+
+    for token in pointer.split('/').skip(1) {
+        let token = token.replace("~1", "/").replace("~0", "~");
+
+        current = match current {
+            Value::Object(object) => object.get(token.as_str())?,
+            Value::Array(array) => array.values.get(token.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    Some(current)
+}
+
+/// Orders two numbers or two strings; other values do not compare.
+fn compare(left: &Value, right: &Value) -> Option<std::cmp::Ordering> {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => {
+            let left = left.to_string().parse::<f64>().ok()?;
+            let right = right.to_string().parse::<f64>().ok()?;
+            left.partial_cmp(&right)
+        }
+        (Value::String(left), Value::String(right)) => {
+            Some(left.to_string().cmp(&right.to_string()))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,291 @@
+use crate::branch_handler::{get_branch_dir, STORE_DIR};
+use crate::discovery::is_branch_pattern;
+use crate::gitdis::{canonicalize_repo_url, BranchSettings};
+use crate::migration::git_read;
+use log::debug;
+use quickleaf::valu3::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Directory of the clone path holding quarantined directories, one
+/// subdirectory per reconciliation.
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+/// What to do with directories no registered branch owns.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum OrphanPolicy {
+    /// Move them under `QUARANTINE_DIR`, to be inspected and deleted by hand.
+    #[default]
+    Quarantine,
+    Delete,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReconcileAction {
+    /// A valid clone of a registered branch, reused instead of re-cloned.
+    Adopted {
+        path: String,
+    },
+    Quarantined {
+        path: String,
+        to: String,
+        reason: String,
+    },
+    Deleted {
+        path: String,
+        reason: String,
+    },
+    Failed {
+        path: String,
+        error: String,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReconcileReport {
+    pub actions: Vec<ReconcileAction>,
+}
+
+impl ReconcileReport {
+    pub fn to_value(&self) -> Value {
+        let actions = self
+            .actions
+            .iter()
+            .map(|action| {
+                let mut object = HashMap::new();
+                let mut insert = |key: &str, value: &str| {
+                    object.insert(key.to_string(), Value::from(value));
+                };
+
+                match action {
+                    ReconcileAction::Adopted { path } => {
+                        insert("action", "adopted");
+                        insert("path", path);
+                    }
+                    ReconcileAction::Quarantined { path, to, reason } => {
+                        insert("action", "quarantined");
+                        insert("path", path);
+                        insert("to", to);
+                        insert("reason", reason);
+                    }
+                    ReconcileAction::Deleted { path, reason } => {
+                        insert("action", "deleted");
+                        insert("path", path);
+                        insert("reason", reason);
+                    }
+                    ReconcileAction::Failed { path, error } => {
+                        insert("action", "failed");
+                        insert("path", path);
+                        insert("error", error);
+                    }
+                }
+
+                Value::from(object)
+            })
+            .collect::<Vec<Value>>();
+
+        let mut object = HashMap::new();
+        object.insert("actions".to_string(), Value::from(actions));
+
+        Value::from(object)
+    }
+}
+
+/// Checks the `owner/repo/branch` clone directory against the registered
+/// branches. Clones of registered branches whose remote matches are kept;
+/// the rest, left by removed branches or interrupted runs, is quarantined
+/// or deleted so it is never picked up as another branch's clone. Legacy
+/// `data/<repo>` clones of a registered repository are kept for migration.
+/// Settings with a branch pattern keep every clone of their repository
+/// whose remote matches, as their branches are only registered once
+/// discovery lists them.
+pub fn reconcile_clones<'a>(
+    clone_path: &str,
+    branches: impl Iterator<Item = &'a BranchSettings>,
+    policy: OrphanPolicy,
+    now_millis: u128,
+) -> ReconcileReport {
+    Reconciler::new(clone_path, branches, policy, now_millis).run()
+}
+
+struct Reconciler<'a> {
+    clone_path: &'a str,
+    policy: OrphanPolicy,
+    quarantine_path: PathBuf,
+    /// `owner/repo` -> branch directories of its registered branches.
+    repos: HashMap<String, HashSet<String>>,
+    /// `owner/repo` of the repositories registered with a branch pattern.
+    discovered: HashSet<String>,
+    report: ReconcileReport,
+}
+
+impl<'a> Reconciler<'a> {
+    fn new<'b>(
+        clone_path: &'a str,
+        branches: impl Iterator<Item = &'b BranchSettings>,
+        policy: OrphanPolicy,
+        now_millis: u128,
+    ) -> Self {
+        let mut repos: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut discovered = HashSet::new();
+
+        for settings in branches {
+            let branch_dirs = repos.entry(settings.get_repo_name_key()).or_default();
+
+            match is_branch_pattern(&settings.branch_name) {
+                true => {
+                    discovered.insert(settings.get_repo_name_key());
+                }
+                false => {
+                    branch_dirs.insert(get_branch_dir(&settings.branch_name));
+                }
+            }
+        }
+
+        Self {
+            clone_path,
+            policy,
+            quarantine_path: Path::new(clone_path)
+                .join(QUARANTINE_DIR)
+                .join(now_millis.to_string()),
+            repos,
+            discovered,
+            report: ReconcileReport::default(),
+        }
+    }
+
+    fn run(mut self) -> ReconcileReport {
+        for owner in list_dirs(Path::new(self.clone_path)) {
+            let owner_name = get_name(&owner);
+
+            if owner_name == QUARANTINE_DIR {
+                continue;
+            }
+
+            if owner.join(".git").is_dir() {
+                self.reconcile_legacy_clone(&owner);
+                continue;
+            }
+
+            for repo in list_dirs(&owner) {
+                let repo_key = format!("{}/{}", owner_name, get_name(&repo));
+
+                match self.repos.get(&repo_key).cloned() {
+                    Some(branch_dirs) => self.reconcile_repo(&repo, &repo_key, &branch_dirs),
+                    None => self.remove_orphan(&repo, "repository not registered"),
+                }
+            }
+
+            remove_if_empty(&owner);
+        }
+
+        self.report
+    }
+
+    fn reconcile_repo(&mut self, repo: &Path, repo_key: &str, branch_dirs: &HashSet<String>) {
+        for branch in list_dirs(repo) {
+            let branch_dir = get_name(&branch);
+
+            if branch_dir == STORE_DIR {
+                continue;
+            }
+
+            if !branch_dirs.contains(&branch_dir) && !self.discovered.contains(repo_key) {
+                self.remove_orphan(&branch, "branch not registered");
+                continue;
+            }
+
+            let remote = git_read(
+                &branch.to_string_lossy(),
+                &["config", "--get", "remote.origin.url"],
+            );
+
+            match remote {
+                Some(url) if canonicalize_repo_url(&url) == repo_key => {
+                    self.report.actions.push(ReconcileAction::Adopted {
+                        path: branch.to_string_lossy().to_string(),
+                    });
+                }
+                Some(url) => self.remove_orphan(&branch, &format!("clone of {}", url)),
+                None => self.remove_orphan(&branch, "not a git clone"),
+            }
+        }
+    }
+
+    fn reconcile_legacy_clone(&mut self, path: &Path) {
+        let remote = git_read(
+            &path.to_string_lossy(),
+            &["config", "--get", "remote.origin.url"],
+        );
+
+        match remote {
+            Some(url) if self.repos.contains_key(&canonicalize_repo_url(&url)) => {
+                self.report.actions.push(ReconcileAction::Adopted {
+                    path: path.to_string_lossy().to_string(),
+                });
+            }
+            _ => self.remove_orphan(path, "legacy clone of an unregistered repository"),
+        }
+    }
+
+    fn remove_orphan(&mut self, path: &Path, reason: &str) {
+        let path_str = path.to_string_lossy().to_string();
+
+        debug!("Orphaned clone directory {}: {}", path_str, reason);
+
+        let result = match self.policy {
+            OrphanPolicy::Delete => {
+                std::fs::remove_dir_all(path).map(|_| ReconcileAction::Deleted {
+                    path: path_str.clone(),
+                    reason: reason.to_string(),
+                })
+            }
+            OrphanPolicy::Quarantine => {
+                let relative = path.strip_prefix(self.clone_path).unwrap_or(path);
+                let to = self.quarantine_path.join(relative);
+
+                to.parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|_| std::fs::rename(path, &to))
+                    .map(|_| ReconcileAction::Quarantined {
+                        path: path_str.clone(),
+                        to: to.to_string_lossy().to_string(),
+                        reason: reason.to_string(),
+                    })
+            }
+        };
+
+        self.report
+            .actions
+            .push(result.unwrap_or_else(|err| ReconcileAction::Failed {
+                path: path_str,
+                error: err.to_string(),
+            }));
+    }
+}
+
+fn list_dirs(path: &Path) -> Vec<PathBuf> {
+    let mut dirs = match std::fs::read_dir(path) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect::<Vec<PathBuf>>(),
+        Err(_) => Vec::new(),
+    };
+    dirs.sort();
+
+    dirs
+}
+
+fn get_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn remove_if_empty(path: &Path) {
+    if std::fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_none()) {
+        let _ = std::fs::remove_dir(path);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::gitdis::{is_local_url, resolve_repo_url};
+
+/// Joins the host and the groups of a nested namespace in repo keys, so
+/// every key keeps the `owner/repo` shape of routes and clone directories.
+/// Hosts do not allow it in group or repository names, so keys cannot
+/// collide.
+pub const NAMESPACE_SEPARATOR: char = '~';
+
+/// A remote URL split into the parts that name a repository.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RepoUrl {
+    /// Host without user or port; empty for local repositories.
+    pub host: String,
+    /// Groups the repository sits in, outermost first: the owner on most
+    /// hosts, nested groups on GitLab, the organization and project on
+    /// Azure DevOps.
+    pub namespace: Vec<String>,
+    pub name: String,
+    pub local: bool,
+}
+
+impl RepoUrl {
+    /// Parses scheme URLs (`https://`, `ssh://`, `git://`, `file://`...),
+    /// scp-like `user@host:path` shorthands and plain paths. Path segments
+    /// that only belong to a host's URL layout are dropped, so the forms a
+    /// host offers for a repository parse alike:
+    ///
+    /// - `scm/` in front of Bitbucket Server HTTP paths;
+    /// - `_git/` in Azure DevOps paths and `v3/` in front of their SSH ones,
+    ///   with the organization of `org.visualstudio.com` hosts moved into
+    ///   the path as `dev.azure.com` has it;
+    /// - `~` in front of a user home in `ssh://host/~user/repo`.
+    ///
+    /// Local repositories keep only the directory holding them as their
+    /// namespace, as the rest of their path is specific to the machine.
+    pub fn parse(url: &str) -> Option<Self> {
+        let local = is_local_url(url);
+        let url = resolve_repo_url(url);
+
+        let (authority, path) = match url.split_once("://") {
+            Some((_, rest)) => {
+                // Paths may hold `?` and `#`, remote URLs end before them.
+                let rest = match local {
+                    true => rest,
+                    false => rest.split(['?', '#']).next().unwrap_or_default(),
+                };
+
+                rest.split_once('/').unwrap_or((rest, ""))
+            }
+            None => url.split_once(':')?,
+        };
+
+        let host = match local {
+            true => String::new(),
+            false => get_host(authority).to_lowercase(),
+        };
+        let path = path.trim_matches('/');
+        let path = match path.len().checked_sub(4) {
+            Some(end) if path.is_char_boundary(end) && path[end..].eq_ignore_ascii_case(".git") => {
+                path[..end].trim_end_matches('/')
+            }
+            _ => path,
+        };
+
+        let mut segments: Vec<&str> = path
+            .split('/')
+            .map(|segment| segment.trim_start_matches(NAMESPACE_SEPARATOR))
+            .filter(|segment| !segment.is_empty())
+            .collect();
+
+        if !local {
+            segments.retain(|segment| *segment != "_git");
+
+            let layout_prefix = match segments.first() {
+                Some(&"v3") => {
+                    host == "ssh.dev.azure.com" || host.ends_with("vs-ssh.visualstudio.com")
+                }
+                Some(segment) => segment.eq_ignore_ascii_case("scm") && segments.len() > 2,
+                None => false,
+            };
+
+            if layout_prefix {
+                segments.remove(0);
+            }
+
+            if let Some(organization) = host.strip_suffix(".visualstudio.com") {
+                if !organization.ends_with("vs-ssh") {
+                    segments.insert(0, organization);
+                }
+            }
+        }
+
+        let name = segments.pop()?.to_string();
+        let namespace = match local {
+            true => segments.pop().into_iter().map(String::from).collect(),
+            false => segments.into_iter().map(String::from).collect(),
+        };
+
+        Some(Self {
+            host,
+            namespace,
+            name,
+            local,
+        })
+    }
+
+    /// The owner part of the key: the host, then the nested groups, joined
+    /// with `NAMESPACE_SEPARATOR`, e.g. `gitlab.com~group~subgroup`. Local
+    /// repositories have no host, so theirs is the directory holding them.
+    pub fn get_owner(&self) -> String {
+        let host = Some(get_key_host(&self.host)).filter(|host| !host.is_empty());
+
+        host.into_iter()
+            .chain(self.namespace.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(&NAMESPACE_SEPARATOR.to_string())
+    }
+
+    /// The `owner/repo` key of the repository, see `get_owner`, lowercased
+    /// as hosts treat names case-insensitively. The host keeps `owner/repo`
+    /// on two hosts apart, e.g. GitHub and a GitHub Enterprise server.
+    pub fn get_key(&self) -> String {
+        format!("{}/{}", self.get_owner(), self.name).to_lowercase()
+    }
+}
+
+/// The key of `branch` in the `owner/repo` repository, as in
+/// `BranchSettings::get_repo_key`: the repository part lowercased like
+/// `RepoUrl::get_key`, the branch kept as it is. Keys built from request
+/// paths or webhook payloads go through it, so `Owner/Repo` finds the
+/// branch registered from `owner/repo`.
+pub fn get_branch_key(owner: &str, repo: &str, branch: &str) -> String {
+    format!(
+        "{}/{}/{}",
+        owner.to_lowercase(),
+        repo.to_lowercase(),
+        branch
+    )
+}
+
+/// The host a repository is keyed under, the same for the SSH and HTTP
+/// hosts of a service: every Azure DevOps host is `dev.azure.com`, and
+/// `ssh.` is dropped, e.g. from GitHub's `ssh.github.com`.
+fn get_key_host(host: &str) -> &str {
+    if host == "ssh.dev.azure.com" || host.ends_with(".visualstudio.com") {
+        return "dev.azure.com";
+    }
+
+    host.strip_prefix("ssh.").unwrap_or(host)
+}
+
+/// The host of a URL authority, without the user, password or port.
+fn get_host(authority: &str) -> &str {
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+
+    match host.strip_prefix('[') {
+        // An IPv6 address, with colons of its own.
+        Some(address) => address
+            .split_once(']')
+            .map_or(address, |(address, _)| address),
+        None => host.split_once(':').map_or(host, |(host, _)| host),
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// How a branch listener retries a failed sync before giving up.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Retries after the first failure. Once exceeded the listener stops.
+    pub max_retries: u32,
+    pub initial_delay_millis: u64,
+    pub max_delay_millis: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 8,
+            initial_delay_millis: 500,
+            max_delay_millis: 60_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (starting at 1): doubles on each
+    /// attempt up to `max_delay_millis`, then a random half of it is taken
+    /// off so branches failing together do not retry together.
+    pub fn get_delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(32);
+        let delay = self
+            .initial_delay_millis
+            .saturating_mul(1u64 << exponent)
+            .min(self.max_delay_millis);
+        let half = delay / 2;
+
+        Duration::from_millis(half + random_below(half + 1))
+    }
+}
+
+/// Random spread of a branch's poll interval, drawn again before every
+/// poll, so branches sharing an interval do not all fetch at once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PollJitter {
+    /// Up to this percentage of the interval, either way.
+    Percent(u8),
+    /// Up to this many milliseconds, either way, at most the interval.
+    Millis(u64),
+}
+
+impl PollJitter {
+    pub fn apply(&self, interval: Duration) -> Duration {
+        let interval_millis = interval.as_millis() as u64;
+        let spread = match self {
+            PollJitter::Percent(percent) => {
+                interval_millis.saturating_mul((*percent).min(100) as u64) / 100
+            }
+            PollJitter::Millis(millis) => *millis,
+        }
+        .min(interval_millis);
+        let offset = random_below(spread.saturating_mul(2).saturating_add(1));
+
+        Duration::from_millis(
+            interval_millis
+                .saturating_sub(spread)
+                .saturating_add(offset),
+        )
+    }
+}
+
+/// Failed syncs of a branch. `consecutive` is reset by the next success.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncFailures {
+    pub consecutive: u32,
+    pub total: u64,
+    pub last_error: Option<String>,
+}
+
+fn random_below(bound: u64) -> u64 {
+    // Every `RandomState` is seeded with fresh random keys.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(bound);
+
+    hasher.finish() % bound.max(1)
+}
@@ -0,0 +1,139 @@
+use crate::filter::build_globs;
+use globset::GlobSet;
+use jsonschema::Validator;
+use quickleaf::valu3::prelude::*;
+
+/// Errors reported for one invalid file, past which the rest are dropped.
+pub const MAX_SCHEMA_ERRORS: usize = 10;
+
+/// A JSON Schema applied to config files, both given relative to the
+/// repository root: `schema` names the schema file, e.g.
+/// `schema/services.schema.json`, and `files` are globs of the files it
+/// validates. A file matched by several rules must pass all of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SchemaRule {
+    pub schema: String,
+    pub files: Vec<String>,
+}
+
+struct CompiledRule {
+    schema: String,
+    files: GlobSet,
+    /// The error of a schema file that is missing, not JSON or not a valid
+    /// schema. Every file it applies to is then invalid.
+    validator: Result<Validator, String>,
+}
+
+/// The schema rules of a branch, compiled from the schema files of the
+/// loaded commit.
+#[derive(Default)]
+pub struct BranchSchemas {
+    rules: Vec<CompiledRule>,
+}
+
+impl std::fmt::Debug for BranchSchemas {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "BranchSchemas({} rules)", self.rules.len())
+    }
+}
+
+impl BranchSchemas {
+    /// Compiles `rules`, reading each schema file through `read`, which
+    /// returns `None` for a missing file. Rules whose globs do not compile
+    /// match no file.
+    pub fn load(rules: &[SchemaRule], read: impl Fn(&str) -> Option<String>) -> Self {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let schema = rule.schema.trim_start_matches('/').to_string();
+                let validator = read(&schema)
+                    .ok_or_else(|| "not found".to_string())
+                    .and_then(|content| {
+                        serde_json::from_str::<serde_json::Value>(&content)
+                            .map_err(|err| err.to_string())
+                    })
+                    .and_then(|schema| {
+                        jsonschema::validator_for(&schema).map_err(|err| err.to_string())
+                    });
+
+                CompiledRule {
+                    schema,
+                    files: build_globs(&rule.files).unwrap_or_else(|_| GlobSet::empty()),
+                    validator,
+                }
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    /// Whether `path`, relative to the repository, is the schema file of a
+    /// rule. Schema files are not loaded as config.
+    pub fn is_schema(&self, path: &str) -> bool {
+        self.rules.iter().any(|rule| rule.schema == path)
+    }
+
+    /// Validates `value`, read from `path` relative to the repository,
+    /// against the schemas applying to it. Returns the errors found, each
+    /// prefixed with its schema and the JSON pointer of the offending
+    /// value; none if the file is valid or no schema applies.
+    pub fn validate(&self, path: &str, value: &Value) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut instance = None;
+
+        for rule in self.rules.iter().filter(|rule| rule.files.is_match(path)) {
+            let validator = match &rule.validator {
+                Ok(validator) => validator,
+                Err(err) => {
+                    errors.push(format!("{}: invalid schema: {}", rule.schema, err));
+                    continue;
+                }
+            };
+            let instance = instance.get_or_insert_with(|| to_json_value(value));
+
+            errors.extend(
+                validator
+                    .iter_errors(instance)
+                    .map(|err| format!("{}: {}: {}", rule.schema, err.instance_path, err)),
+            );
+        }
+
+        errors.truncate(MAX_SCHEMA_ERRORS);
+        errors
+    }
+}
+
+/// Builds the JSON instance validated from a value. Numbers keep their
+/// integer form when they have one.
+fn to_json_value(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Null | Value::Undefined => serde_json::Value::Null,
+        Value::Boolean(boolean) => serde_json::Value::Bool(*boolean),
+        Value::Number(number) => {
+            let number = number.to_string();
+
+            match number.parse::<i64>() {
+                Ok(integer) => serde_json::Value::from(integer),
+                Err(_) => match number.parse::<u64>() {
+                    Ok(integer) => serde_json::Value::from(integer),
+                    Err(_) => number
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(serde_json::Number::from_f64)
+                        .map_or(serde_json::Value::Null, serde_json::Value::Number),
+                },
+            }
+        }
+        Value::String(string) => serde_json::Value::String(string.to_string()),
+        Value::Array(array) => {
+            serde_json::Value::Array(array.values.iter().map(to_json_value).collect())
+        }
+        Value::Object(object) => serde_json::Value::Object(
+            object
+                .iter()
+                .map(|(key, value)| (key.to_string(), to_json_value(value)))
+                .collect(),
+        ),
+        other => serde_json::Value::String(other.to_string()),
+    }
+}
@@ -1,7 +1,46 @@
+use super::branch_handler::{BranchHandlerError, KeyWrite};
+use super::cache::{list_prefix, list_refs};
+use super::codec::{Codecs, WireFrame};
+use super::discovery::DiscoveryReport;
+use super::dump::{DumpDiff, DumpError, DumpStore};
+use super::events::{BranchEvent, BranchEventKind};
+#[cfg(feature = "export")]
+use super::export::ExportRow;
 use super::gitdis::{BranchSettings, Gitdis, GitdisError};
+use super::history::{Generation, GenerationSelector};
+use super::journal::{EventJournal, JournalError, JournalPosition};
+use super::keys::KeyFormat;
+use super::lint::LintReport;
+use super::memo::{build_subtree, MemoStats, SubtreeMemo};
+use super::metadata::CommitMetadata;
+use super::migration::{scan_legacy_clones, LegacyRegistration, MigrationReport};
+use super::patch::ObjectPatch;
+use super::policy::REDACTED;
+use super::publish::{hash_output, RenderJob, RenderJobStatus, RenderJobStore};
+use super::query::{
+    ConditionQuery, ContinuousQuery, MatchChange, QueryLimits, QueryResult, SavedQuery,
+    SavedQueryStore,
+};
+use super::reconcile::{reconcile_clones, OrphanPolicy, ReconcileReport};
+#[cfg(feature = "sql")]
+use super::sql::{SqlLimits, SqlMirrors, SqlResult};
+use super::status::BranchStatus;
+use super::template::{render, TemplateSource};
+use super::usage::{BranchUsage, ClientUsage, UnusedKeysReport, UsageTracker};
+use super::versions::KeyVersion;
+use super::view::{MaterializedView, ViewHandle, ViewResult, ViewStore};
+use super::watch::post_json;
+use bytes::Bytes;
 use log::debug;
 use quickleaf::valu3::prelude::*;
-use std::sync::{Arc, RwLock};
+use quickleaf::Event;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast;
+
+/// Changes a subscriber may fall behind by before it misses some, see
+/// `broadcast::Receiver::recv`.
+pub const SUBSCRIPTION_CAPACITY: usize = 1024;
 
 #[derive(Debug, PartialEq)]
 pub enum GitdisServiceError {
@@ -9,11 +48,204 @@ pub enum GitdisServiceError {
     BranchNotFound,
     InternalError(String),
     RepoNotCreated,
+    ObjectNotFound,
+    InvalidPatch(String),
+    PreconditionFailed(String),
+    InvalidSettings(String),
+    Dump(String),
+    QueryNotFound,
+    InvalidQuery(String),
+    /// The template of a render does not parse, render or exist.
+    InvalidTemplate(String),
+    RenderJobNotFound,
+    ViewNotFound,
+    GenerationNotFound,
+    SyncFailed(String),
+    /// The branch has not loaded any data yet.
+    NotReady,
+    /// A git command was killed after running past the branch's timeout.
+    GitTimeout(String),
+    SubscriberNotFound,
+    /// The events a backfill asked for are no longer journaled.
+    BackfillUnavailable(String),
+    /// The key would resolve to a file outside the repository.
+    InvalidKey(String),
+    /// The file cannot be rewritten without losing content, e.g. comments.
+    UnsupportedWriteBack(String),
+}
+
+impl From<GitdisError> for GitdisServiceError {
+    fn from(err: GitdisError) -> Self {
+        match err {
+            GitdisError::RepoExists => GitdisServiceError::RepoAlreadyExists,
+            GitdisError::Sender(err) => GitdisServiceError::InternalError(err.to_string()),
+            GitdisError::BranchNotFound => GitdisServiceError::BranchNotFound,
+            GitdisError::RepoListener => {
+                GitdisServiceError::InternalError("Error creating repo listener".to_string())
+            }
+            GitdisError::WriteBack(err @ BranchHandlerError::GitTimeout(_)) => {
+                GitdisServiceError::GitTimeout(err.to_string())
+            }
+            GitdisError::WriteBack(err) => GitdisServiceError::InternalError(err.to_string()),
+            GitdisError::UnknownEncoding(encoding) => {
+                GitdisServiceError::InvalidSettings(format!("Unknown encoding: {}", encoding))
+            }
+            GitdisError::SyncTrigger => {
+                GitdisServiceError::InternalError("Error triggering sync".to_string())
+            }
+            GitdisError::InvalidGlob(err) => {
+                GitdisServiceError::InvalidSettings(format!("Invalid glob: {}", err))
+            }
+            GitdisError::InvalidKeyFormat(err) => GitdisServiceError::InvalidSettings(err),
+            GitdisError::Discovery(err) => GitdisServiceError::InternalError(err.to_string()),
+            GitdisError::SyncFailed(err) => GitdisServiceError::SyncFailed(err),
+            GitdisError::ReadyTimeout => GitdisServiceError::NotReady,
+            GitdisError::InvalidPath(err) => {
+                GitdisServiceError::InvalidSettings(format!("Invalid path: {}", err))
+            }
+            GitdisError::RemoveClone(err) => {
+                GitdisServiceError::InternalError(format!("Error removing clone: {}", err))
+            }
+        }
+    }
+}
+
+fn from_journal_error(err: JournalError) -> GitdisServiceError {
+    match err {
+        JournalError::Truncated(_) => GitdisServiceError::BackfillUnavailable(err.to_string()),
+        JournalError::CommitNotFound(_) => GitdisServiceError::InvalidQuery(err.to_string()),
+    }
+}
+
+/// The message of an error recorded in a job status.
+fn describe_error(err: GitdisServiceError) -> String {
+    match err {
+        GitdisServiceError::InternalError(err)
+        | GitdisServiceError::InvalidTemplate(err)
+        | GitdisServiceError::SyncFailed(err)
+        | GitdisServiceError::GitTimeout(err) => err,
+        GitdisServiceError::BranchNotFound => "Branch not found".to_string(),
+        GitdisServiceError::ObjectNotFound => "Prefix not found".to_string(),
+        err => format!("{:?}", err),
+    }
+}
+
+/// Errors of a write-back a client can act on, the rest as in `From`.
+fn from_write_back_error(err: GitdisError) -> GitdisServiceError {
+    match err {
+        GitdisError::BranchNotFound => GitdisServiceError::BranchNotFound,
+        GitdisError::WriteBack(BranchHandlerError::CommitMismatch(commit)) => {
+            GitdisServiceError::PreconditionFailed(commit)
+        }
+        GitdisError::WriteBack(BranchHandlerError::FileNotFound(_)) => {
+            GitdisServiceError::ObjectNotFound
+        }
+        GitdisError::WriteBack(err @ BranchHandlerError::BareClone(_)) => {
+            GitdisServiceError::InvalidSettings(err.to_string())
+        }
+        GitdisError::WriteBack(err @ BranchHandlerError::PathEscape(_)) => {
+            GitdisServiceError::InvalidKey(err.to_string())
+        }
+        GitdisError::WriteBack(err @ BranchHandlerError::UnsupportedWriteBack(_)) => {
+            GitdisServiceError::UnsupportedWriteBack(err.to_string())
+        }
+        err => err.into(),
+    }
 }
 
 #[derive(Clone)]
 pub struct GitdisService {
     pub gitdis: Arc<RwLock<Gitdis>>,
+    memo: Arc<SubtreeMemo>,
+    serialized: Arc<SubtreeMemo<Bytes>>,
+    dumps: Option<Arc<DumpStore>>,
+    journal: Option<Arc<EventJournal>>,
+    /// Stream subscriptions events can be backfilled to, by id.
+    backfill_targets: Arc<RwLock<HashMap<u64, BackfillTarget>>>,
+    codecs: Codecs,
+    usage: Arc<UsageTracker>,
+    saved_queries: Arc<SavedQueryStore>,
+    views: Arc<ViewStore>,
+    render_jobs: Arc<RenderJobStore>,
+    #[cfg(feature = "sql")]
+    sql: Arc<SqlMirrors>,
+}
+
+/// Where the backfill of a stream subscription goes: the channel of its
+/// live events, for the events up to its snapshot.
+#[derive(Clone)]
+struct BackfillTarget {
+    branch_key: String,
+    prefix: String,
+    sequence: u64,
+    sender: broadcast::Sender<BranchEvent>,
+}
+
+type EventSink = Box<dyn FnMut(BranchEvent) + Send>;
+
+/// What the listener of a subscription does with an event: events up to
+/// `floor` are already in its snapshot and dropped, later ones wait in
+/// `pending` until the snapshot is read and `sink` set from it.
+struct Delivery {
+    floor: u64,
+    pending: Vec<BranchEvent>,
+    sink: Option<EventSink>,
+}
+
+impl Delivery {
+    fn accept(&mut self, event: &BranchEvent) {
+        if event.sequence <= self.floor {
+            return;
+        }
+
+        match &mut self.sink {
+            Some(sink) => sink(event.clone()),
+            None => self.pending.push(event.clone()),
+        }
+    }
+
+    fn start(&mut self, mut sink: EventSink) {
+        for event in self.pending.drain(..) {
+            sink(event);
+        }
+
+        self.sink = Some(sink);
+    }
+}
+
+/// A subscription whose snapshot is read but whose events are held until
+/// its delivery is started.
+struct PendingSubscription {
+    id: u64,
+    sequence: u64,
+    snapshot: Vec<(String, Value)>,
+    delivery: Arc<Mutex<Delivery>>,
+}
+
+/// Whether a subscription to `prefix` gets `event`.
+fn is_in_prefix(event: &BranchEvent, prefix: &str) -> bool {
+    match &event.event {
+        BranchEventKind::Cache(Event::Insert(data))
+        | BranchEventKind::Cache(Event::Remove(data)) => data.key.starts_with(prefix),
+        BranchEventKind::Evicted { key, .. } => key.starts_with(prefix),
+        BranchEventKind::Cache(Event::Clear)
+        | BranchEventKind::Rejected { .. }
+        | BranchEventKind::Invalid { .. }
+        | BranchEventKind::ParseError { .. }
+        | BranchEventKind::Dirty { .. }
+        | BranchEventKind::Archived { .. }
+        | BranchEventKind::Restored
+        | BranchEventKind::Removed
+        | BranchEventKind::SyncCompleted { .. }
+        | BranchEventKind::SyncFailed { .. } => true,
+    }
+}
+
+fn now_millis() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
 }
 
 #[derive(ToValue, ToJson)]
@@ -22,64 +254,2025 @@ pub struct BranchInfo {
     create_at: u128,
 }
 
+impl BranchInfo {
+    pub fn get_key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn get_create_at(&self) -> u128 {
+        self.create_at
+    }
+}
+
+/// A live view of the entries of a branch under a prefix. `snapshot` holds
+/// the entries as of `sequence`; `receiver` yields the changes after it,
+/// missing the oldest once `SUBSCRIPTION_CAPACITY` are waiting.
+pub struct Subscription {
+    pub id: u64,
+    pub sequence: u64,
+    pub snapshot: Vec<(String, Value)>,
+    pub receiver: broadcast::Receiver<BranchEvent>,
+}
+
+/// A live view of the entries of a branch matching a query. `matches` holds
+/// them as of `sequence`; `receiver` yields each entry entering, leaving or
+/// updated in the set after it, with the sequence of its change.
+pub struct QuerySubscription {
+    pub id: u64,
+    pub sequence: u64,
+    pub matches: Vec<(String, Value)>,
+    pub receiver: broadcast::Receiver<(u64, MatchChange)>,
+}
+
 impl GitdisService {
     pub fn new(gitdis: Arc<RwLock<Gitdis>>) -> Self {
-        Self { gitdis }
+        let key_formats = gitdis
+            .read()
+            .map(|gitdis| gitdis.get_key_formats())
+            .unwrap_or_default();
+        let memo = Arc::new(SubtreeMemo::new().with_formats(key_formats.clone()));
+        let serialized = Arc::new(SubtreeMemo::new().with_formats(key_formats.clone()));
+        #[cfg(feature = "sql")]
+        let sql = Arc::new(SqlMirrors::new());
+
+        #[cfg(feature = "sql")]
+        if let Ok(gitdis) = gitdis.read() {
+            let listener_sql = sql.clone();
+
+            gitdis
+                .get_events()
+                .subscribe(Arc::new(move |event| listener_sql.record(event)));
+        }
+
+        if let Ok(gitdis) = gitdis.read() {
+            let listener_memo = memo.clone();
+            let listener_serialized = serialized.clone();
+
+            gitdis
+                .get_events()
+                .subscribe(Arc::new(move |event| match &event.event {
+                    BranchEventKind::Cache(Event::Insert(data))
+                    | BranchEventKind::Cache(Event::Remove(data)) => {
+                        listener_memo.invalidate(&event.branch_key, &data.key, event.sequence);
+                        listener_serialized.invalidate(&event.branch_key, &data.key, event.sequence)
+                    }
+                    BranchEventKind::Evicted { key, .. } => {
+                        listener_memo.invalidate(&event.branch_key, key, event.sequence);
+                        listener_serialized.invalidate(&event.branch_key, key, event.sequence)
+                    }
+                    BranchEventKind::Cache(Event::Clear) | BranchEventKind::Removed => {
+                        listener_memo.invalidate_branch(&event.branch_key, event.sequence);
+                        listener_serialized.invalidate_branch(&event.branch_key, event.sequence)
+                    }
+                    BranchEventKind::Rejected { .. }
+                    | BranchEventKind::Invalid { .. }
+                    | BranchEventKind::ParseError { .. }
+                    | BranchEventKind::Dirty { .. }
+                    | BranchEventKind::Archived { .. }
+                    | BranchEventKind::Restored
+                    | BranchEventKind::SyncCompleted { .. }
+                    | BranchEventKind::SyncFailed { .. } => (),
+                }));
+        }
+
+        Self {
+            gitdis,
+            memo,
+            serialized,
+            dumps: None,
+            journal: None,
+            backfill_targets: Arc::new(RwLock::new(HashMap::new())),
+            codecs: Codecs::new(),
+            usage: Arc::new(UsageTracker::new().with_formats(key_formats)),
+            saved_queries: Arc::new(SavedQueryStore::new()),
+            views: Arc::new(ViewStore::new()),
+            render_jobs: Arc::new(RenderJobStore::new()),
+            #[cfg(feature = "sql")]
+            sql,
+        }
     }
 
-    pub fn add_repo(&mut self, settings: BranchSettings) -> Result<BranchInfo, GitdisServiceError> {
-        debug!("Creating new repo");
+    /// Snapshots are offered in `codecs` and dumps read with them. Set
+    /// before the dumps are enabled.
+    pub fn with_codecs(mut self, codecs: Codecs) -> Self {
+        self.codecs = codecs;
+        self
+    }
 
-        let mut gitdis = match self.gitdis.write() {
-            Ok(gitdis) => gitdis,
+    pub fn get_codecs(&self) -> &Codecs {
+        &self.codecs
+    }
+
+    /// Enables base and diff dumps of branches under `path`, as JSON.
+    pub fn with_dumps(self, path: String) -> Self {
+        self.with_dump_store(DumpStore::new(path))
+    }
+
+    /// Enables base and diff dumps of branches in `dumps`.
+    pub fn with_dump_store(mut self, dumps: DumpStore) -> Self {
+        let dumps = Arc::new(dumps.with_codecs(self.codecs.clone()));
+
+        if let Ok(gitdis) = self.gitdis.read() {
+            let listener_dumps = dumps.clone();
+
+            gitdis.get_events().subscribe(Arc::new(move |event| {
+                listener_dumps.get_recorder().record(event)
+            }));
+        }
+
+        self.dumps = Some(dumps);
+        self
+    }
+
+    fn get_dumps(&self) -> Result<&DumpStore, GitdisServiceError> {
+        match &self.dumps {
+            Some(dumps) => Ok(dumps),
+            None => Err(GitdisServiceError::InvalidSettings(
+                "Dumps are not enabled".to_string(),
+            )),
+        }
+    }
+
+    /// Dumps the changes of a branch since its last dump, or the whole
+    /// branch on the first call. Returns the file written, if any.
+    pub fn dump_branch(&self, branch_key: &str) -> Result<Option<String>, GitdisServiceError> {
+        let dumps = self.get_dumps()?;
+
+        match dumps.write_diff(branch_key) {
+            Ok(file) => return Ok(file),
+            Err(DumpError::NoBase(_)) => (),
+            Err(err) => return Err(GitdisServiceError::Dump(err.to_string())),
+        }
+
+        let (sequence, entries) = {
+            let gitdis = match self.gitdis.read() {
+                Ok(gitdis) => gitdis,
+                Err(_) => {
+                    return Err(GitdisServiceError::InternalError(
+                        "Error reading gitdis".to_string(),
+                    ))
+                }
+            };
+
+            let branch = match gitdis.get_data_branch(branch_key) {
+                Some(branch) => branch,
+                None => return Err(GitdisServiceError::BranchNotFound),
+            };
+
+            let sequence = gitdis.get_events().get_branch_sequence(branch_key);
+
+            let entries = match branch.read() {
+                Ok(branch) => list_prefix(&branch, ""),
+                Err(_) => {
+                    return Err(GitdisServiceError::InternalError(
+                        "Error reading branch".to_string(),
+                    ))
+                }
+            };
+
+            (sequence, entries)
+        };
+
+        // Written without the lock, which writers of `Gitdis` wait on.
+        dumps
+            .write_base(branch_key, sequence, entries)
+            .map(Some)
+            .map_err(|err| GitdisServiceError::Dump(err.to_string()))
+    }
+
+    /// Journals the last `capacity` events of each branch, so subscribers
+    /// can be backfilled.
+    pub fn with_journal(mut self, capacity: usize) -> Self {
+        let journal = Arc::new(EventJournal::new(capacity));
+
+        if let Ok(gitdis) = self.gitdis.read() {
+            let listener_journal = journal.clone();
+
+            gitdis
+                .get_events()
+                .subscribe(Arc::new(move |event| listener_journal.record(event)));
+        }
+
+        self.journal = Some(journal);
+        self
+    }
+
+    /// The whole branch as a frame from sequence 0, for a replica to start
+    /// from, encoded with the first of `offered` this node has. Returns the
+    /// name of the codec with the frame.
+    pub fn snapshot_branch<S: AsRef<str>>(
+        &self,
+        branch_key: &str,
+        offered: &[S],
+    ) -> Result<(String, Vec<u8>), GitdisServiceError> {
+        let codec = match self.codecs.negotiate(offered) {
+            Some(codec) => codec,
+            None => {
+                return Err(GitdisServiceError::InvalidQuery(format!(
+                    "No common codec, available: {}",
+                    self.codecs.get_names().join(", ")
+                )))
+            }
+        };
+
+        // Read before the entries, so a change in between is sent again
+        // after the snapshot rather than missed.
+        let sequence = match self.gitdis.read() {
+            Ok(gitdis) => gitdis.get_events().get_branch_sequence(branch_key),
             Err(_) => {
                 return Err(GitdisServiceError::InternalError(
-                    "Error writing gitdis".to_string(),
+                    "Error reading gitdis".to_string(),
                 ))
             }
         };
 
-        match gitdis.add_repo(settings.clone()) {
-            Ok(_) => {
-                let repo_key = settings.get_repo_key();
-                let object = gitdis.get_object_branch(&repo_key);
+        let entries = self.read_branch(branch_key, |branch| list_prefix(branch, ""))?;
 
-                match object {
-                    Some(object) => Ok(BranchInfo {
-                        key: repo_key,
-                        create_at: object.get_create_at(),
-                    }),
-                    None => Err(GitdisServiceError::RepoNotCreated),
+        let frame = WireFrame {
+            branch_key: branch_key.to_string(),
+            diff: DumpDiff {
+                from: 0,
+                to: sequence,
+                set: entries.into_iter().collect(),
+                ..DumpDiff::default()
+            },
+        };
+
+        self.codecs
+            .encode(codec.name(), &frame)
+            .map(|bytes| (codec.name().to_string(), bytes))
+            .map_err(|err| GitdisServiceError::InternalError(err.to_string()))
+    }
+
+    /// Moves a branch to cold storage: stops its listener, dumps it, then
+    /// frees its cache and local clone. It is restored on the next read.
+    pub fn archive_branch(&self, branch_key: &str) -> Result<(), GitdisServiceError> {
+        self.archive(branch_key, "requested".to_string())
+    }
+
+    fn archive(&self, branch_key: &str, reason: String) -> Result<(), GitdisServiceError> {
+        debug!("Archiving branch {}: {}", branch_key, reason);
+
+        self.get_dumps()?;
+
+        {
+            let gitdis = match self.gitdis.read() {
+                Ok(gitdis) => gitdis,
+                Err(_) => {
+                    return Err(GitdisServiceError::InternalError(
+                        "Error reading gitdis".to_string(),
+                    ))
                 }
+            };
+
+            if !gitdis.set_archived(branch_key, true)? {
+                return Ok(());
+            }
+
+            gitdis.stop_listener(branch_key)?;
+        }
+
+        let archived = self
+            .dump_branch(branch_key)
+            .and_then(|_| self.compact_dumps(branch_key));
+
+        let (handler, events) = {
+            let gitdis = match self.gitdis.read() {
+                Ok(gitdis) => gitdis,
+                Err(_) => {
+                    return Err(GitdisServiceError::InternalError(
+                        "Error reading gitdis".to_string(),
+                    ))
+                }
+            };
+
+            // Without a dump the data would be lost, so the branch stays live.
+            if let Err(err) = archived {
+                gitdis.set_archived(branch_key, false)?;
+                return Err(err);
+            }
+
+            // A read may have restored it from the dump just written.
+            if !gitdis.finish_archive(branch_key)? {
+                return Ok(());
+            }
+
+            (gitdis.get_clone_handler(branch_key)?, gitdis.get_events())
+        };
+
+        handler
+            .remove_clone()
+            .map_err(|err| GitdisError::RemoveClone(err.to_string()))?;
+        events.publish(branch_key, BranchEventKind::Archived { reason });
+
+        Ok(())
+    }
+
+    /// Archives every branch whose policy sets an idle delay and that has
+    /// not been read for that long. Returns the keys archived.
+    pub fn archive_idle_branches(&self) -> Result<Vec<String>, GitdisServiceError> {
+        let now = now_millis();
+        let mut idle = Vec::new();
+
+        {
+            let gitdis = match self.gitdis.read() {
+                Ok(gitdis) => gitdis,
+                Err(_) => {
+                    return Err(GitdisServiceError::InternalError(
+                        "Error reading gitdis".to_string(),
+                    ))
+                }
+            };
+
+            for branch_key in gitdis.get_branch_keys() {
+                let branch = match gitdis.get_object_branch(&branch_key) {
+                    Some(branch) => branch,
+                    None => continue,
+                };
+
+                let delay = match branch.get_policy().archive_after_idle_millis {
+                    Some(delay) => delay as u128,
+                    None => continue,
+                };
+
+                if gitdis.is_archived(&branch_key).unwrap_or(false) {
+                    continue;
+                }
+
+                let idle_millis =
+                    self.usage
+                        .get_idle_millis(&branch_key, branch.get_create_at(), now);
+
+                if idle_millis >= delay {
+                    idle.push((branch_key, idle_millis));
+                }
+            }
+        }
+
+        let mut archived = Vec::new();
+
+        for (branch_key, idle_millis) in idle {
+            match self.archive(&branch_key, format!("idle for {} ms", idle_millis)) {
+                Ok(_) => archived.push(branch_key),
+                Err(err) => debug!("Failed to archive {}: {:?}", branch_key, err),
+            }
+        }
+
+        Ok(archived)
+    }
+
+    pub fn reconcile_clones(
+        &self,
+        policy: OrphanPolicy,
+    ) -> Result<ReconcileReport, GitdisServiceError> {
+        let (clone_path, branches) = match self.gitdis.read() {
+            Ok(gitdis) => (
+                gitdis.get_local_clone_path().to_string(),
+                gitdis.get_registered_settings(),
+            ),
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        // Clones are moved or deleted without holding the lock.
+        Ok(reconcile_clones(
+            &clone_path,
+            branches.iter(),
+            policy,
+            now_millis(),
+        ))
+    }
+
+    /// Stops the branch listeners, see `Gitdis::shutdown`.
+    pub fn shutdown(&self, timeout: std::time::Duration) -> bool {
+        match self.gitdis.read() {
+            Ok(gitdis) => gitdis.shutdown(timeout),
+            Err(_) => false,
+        }
+    }
+
+    pub fn pause_branch(&self, branch_key: &str) -> Result<(), GitdisServiceError> {
+        match self.gitdis.read() {
+            Ok(gitdis) => Ok(gitdis.pause_branch(branch_key)?),
+            Err(_) => Err(GitdisServiceError::InternalError(
+                "Error reading gitdis".to_string(),
+            )),
+        }
+    }
+
+    /// Enables a branch registered in standby, or puts one back in it.
+    pub fn set_enabled(&self, branch_key: &str, enabled: bool) -> Result<(), GitdisServiceError> {
+        match self.gitdis.write() {
+            Ok(mut gitdis) => Ok(gitdis.set_enabled(branch_key, enabled)?),
+            Err(_) => Err(GitdisServiceError::InternalError(
+                "Error writing gitdis".to_string(),
+            )),
+        }
+    }
+
+    pub fn evict_key(&self, branch_key: &str, key: &str) -> Result<bool, GitdisServiceError> {
+        match self.gitdis.read() {
+            Ok(gitdis) => Ok(gitdis.evict_key(branch_key, key)?),
+            Err(_) => Err(GitdisServiceError::InternalError(
+                "Error reading gitdis".to_string(),
+            )),
+        }
+    }
+
+    pub fn override_sync_limits(&self, branch_key: &str) -> Result<(), GitdisServiceError> {
+        match self.gitdis.read() {
+            Ok(gitdis) => Ok(gitdis.override_sync_limits(branch_key)?),
+            Err(_) => Err(GitdisServiceError::InternalError(
+                "Error reading gitdis".to_string(),
+            )),
+        }
+    }
+
+    pub fn resume_branch(&self, branch_key: &str) -> Result<(), GitdisServiceError> {
+        match self.gitdis.read() {
+            Ok(gitdis) => Ok(gitdis.resume_branch(branch_key)?),
+            Err(_) => Err(GitdisServiceError::InternalError(
+                "Error reading gitdis".to_string(),
+            )),
+        }
+    }
+
+    /// Unregisters a branch, deleting its local clone when `remove_clone`.
+    pub fn remove_branch(
+        &self,
+        branch_key: &str,
+        remove_clone: bool,
+    ) -> Result<(), GitdisServiceError> {
+        debug!("Removing branch {}", branch_key);
+
+        let handler = {
+            let mut gitdis = match self.gitdis.write() {
+                Ok(gitdis) => gitdis,
+                Err(_) => {
+                    return Err(GitdisServiceError::InternalError(
+                        "Error writing gitdis".to_string(),
+                    ))
+                }
+            };
+
+            let handler = match remove_clone {
+                true => Some(gitdis.get_clone_handler(branch_key)?),
+                false => None,
+            };
+
+            gitdis.remove_branch(branch_key, false)?;
+
+            for handle in self.views.remove_branch(branch_key) {
+                gitdis.get_events().unsubscribe(handle.subscription_id);
+            }
+
+            handler
+        };
+
+        self.usage.remove(branch_key);
+        self.saved_queries.remove_branch(branch_key);
+        self.render_jobs.remove_branch(branch_key);
+
+        // Deleted once the branch is gone, without holding up other requests.
+        if let Some(handler) = handler {
+            handler
+                .remove_clone()
+                .map_err(|err| GitdisError::RemoveClone(err.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_usage(&self, branch_key: &str) -> BranchUsage {
+        self.usage.get_usage(branch_key)
+    }
+
+    /// Keys of a branch no read returned for `window_millis`, see
+    /// `UnusedKeysReport::build`.
+    pub fn get_unused_keys(
+        &self,
+        branch_key: &str,
+        window_millis: u128,
+    ) -> Result<UnusedKeysReport, GitdisServiceError> {
+        let branch = match self.gitdis.read() {
+            Ok(gitdis) => gitdis.get_object_branch(branch_key),
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+        let branch = branch.ok_or(GitdisServiceError::BranchNotFound)?;
+
+        let keys = match branch.get_data().read() {
+            Ok(cache) => list_refs(&cache, "")
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect::<Vec<String>>(),
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading branch".to_string(),
+                ))
+            }
+        };
+        let metadata = branch.get_metadata();
+        let metadata = metadata.read().ok();
+        let keys = keys
+            .into_iter()
+            .map(|key| {
+                let changed_millis = metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.get(&key))
+                    .map(|commit| commit.timestamp as u128 * 1000);
+
+                (key, changed_millis)
+            })
+            .collect();
+
+        let format = match self.gitdis.read() {
+            Ok(gitdis) => gitdis.get_key_format(branch_key),
+            Err(_) => KeyFormat::default(),
+        };
+
+        Ok(UnusedKeysReport::build(
+            branch_key,
+            &self.usage.get_usage(branch_key),
+            &format,
+            keys,
+            branch.get_create_at(),
+            window_millis,
+            now_millis(),
+        ))
+    }
+
+    /// Posts the unused keys report of every branch that has unused keys to
+    /// `url` as JSON, one request per branch. Returns the branches posted.
+    pub fn post_unused_keys(
+        &self,
+        window_millis: u128,
+        url: &str,
+    ) -> Result<Vec<String>, GitdisServiceError> {
+        let branch_keys = match self.gitdis.read() {
+            Ok(gitdis) => gitdis.get_branch_keys(),
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+        let mut posted = Vec::new();
+
+        for branch_key in branch_keys {
+            let report = match self.get_unused_keys(&branch_key, window_millis) {
+                Ok(report) if !report.keys.is_empty() => report,
+                _ => continue,
+            };
+
+            match post_json(url, &report.to_value().to_json(JsonMode::Inline)) {
+                Ok(_) => posted.push(branch_key),
+                Err(err) => debug!("Failed to post the unused keys of {}: {}", branch_key, err),
             }
-            Err(err) => match err {
-                GitdisError::RepoExists => Err(GitdisServiceError::RepoAlreadyExists),
-                GitdisError::Sender(err) => Err(GitdisServiceError::InternalError(err.to_string())),
-                GitdisError::BranchNotFound => Err(GitdisServiceError::BranchNotFound),
-                GitdisError::RepoListener => Err(GitdisServiceError::InternalError(
-                    "Error creating repo listener".to_string(),
-                )),
-            },
         }
+
+        Ok(posted)
+    }
+
+    /// Attributes a read of `object_key` to the client that made it.
+    pub fn record_client_read(&self, branch_key: &str, object_key: &str, client: &str) {
+        self.usage
+            .record_client_read(branch_key, object_key, client, now_millis());
+    }
+
+    /// Which clients read `object_key`, a key below it or a prefix holding
+    /// it, e.g. before deleting it.
+    pub fn get_client_usage(
+        &self,
+        branch_key: &str,
+        object_key: &str,
+    ) -> HashMap<String, ClientUsage> {
+        self.usage.get_clients(branch_key, object_key)
     }
 
-    // pub fn get_data(
-    //     &self,
-    //     branch_key: &str,
-    //     object_key: &str,
-    // ) -> Result<Option<Value>, GitdisServiceError> {
-    //     debug!("Getting data from branch {} {}", branch_key, object_key);
+    /// Loads an archived branch back from its dumps and restarts its
+    /// listener, which then catches up with the remote.
+    pub fn restore_branch(&self, branch_key: &str) -> Result<(), GitdisServiceError> {
+        debug!("Restoring branch {}", branch_key);
+
+        let dumps = self.get_dumps()?;
+
+        match self.gitdis.read() {
+            Ok(gitdis) => match gitdis.is_archived(branch_key) {
+                Some(true) => (),
+                Some(false) => return Ok(()),
+                None => return Err(GitdisServiceError::BranchNotFound),
+            },
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        // Read without the lock, while the branch is still archived, so
+        // reads keep restoring it rather than finding its cache empty.
+        let restored = dumps
+            .load(branch_key)
+            .map_err(|err| GitdisServiceError::Dump(err.to_string()))?;
+
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        // Only the first of concurrent restores fills the cache.
+        let filled = gitdis.finish_restore(branch_key, |cache| {
+            for (key, value) in restored.set {
+                cache.insert(key, value);
+            }
+        })?;
+
+        if !filled {
+            return Ok(());
+        }
+
+        let settings = match gitdis.get_object_branch(branch_key) {
+            Some(branch) => branch.get_settings().clone(),
+            None => return Err(GitdisServiceError::BranchNotFound),
+        };
 
-    //     match self.gitdis.get_data_branch(&branch_key) {
-    //         Some(branch) => {
-    //             let branch = branch.read().unwrap();
+        gitdis.repo_schedule(settings)?;
+        gitdis
+            .get_events()
+            .publish(branch_key, BranchEventKind::Restored);
+        self.usage.mark_active(branch_key, now_millis());
 
-    //             match branch.get(&object_key) {
-    //                 Some(value) => Ok(Some(value.clone())),
-    //                 None => Ok(None),
-    //             }
-    //         }
-    //         None => Err(GitdisServiceError::BranchNotFound),
-    //     }
-    // }
+        Ok(())
+    }
+
+    fn restore_if_archived(&self, branch_key: &str) -> Result<(), GitdisServiceError> {
+        let archived = match self.gitdis.read() {
+            Ok(gitdis) => gitdis.is_archived(branch_key).unwrap_or(false),
+            Err(_) => false,
+        };
+
+        if archived {
+            self.restore_branch(branch_key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Folds the dumps of a branch into a single base.
+    pub fn compact_dumps(&self, branch_key: &str) -> Result<String, GitdisServiceError> {
+        self.get_dumps()?
+            .compact(branch_key)
+            .map_err(|err| GitdisServiceError::Dump(err.to_string()))
+    }
+
+    /// Reads `object_key` from a branch. When no entry has that exact key,
+    /// the entries below it are rebuilt into a nested object, memoized until
+    /// one of them changes. Entries the branch policy redacts read as
+    /// `REDACTED`, and subtrees of branches redacting any are not memoized.
+    pub fn get_object(
+        &self,
+        branch_key: &str,
+        object_key: &str,
+    ) -> Result<Value, GitdisServiceError> {
+        debug!("Getting data from branch {} {}", branch_key, object_key);
+
+        self.restore_if_archived(branch_key)?;
+
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        let branch = match gitdis.get_data_branch(branch_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisServiceError::BranchNotFound),
+        };
+
+        self.usage.record_read(branch_key, object_key, now_millis());
+
+        let format = gitdis.get_key_format(branch_key);
+        let prefix = format.trim(object_key);
+        let sequence = gitdis.get_events().get_sequence();
+        let policy = gitdis.get_branch_policy(branch_key).unwrap_or_default();
+        let redacts = !policy.redact_keys.is_empty();
+
+        let branch = match branch.read() {
+            Ok(branch) => branch,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading branch".to_string(),
+                ))
+            }
+        };
+
+        if let Some(value) = branch.get(prefix) {
+            return match policy.is_redacted(prefix) {
+                true => Ok(Value::from(REDACTED)),
+                false => Ok(value.clone()),
+            };
+        }
+
+        if !redacts {
+            if let Some(value) = self.memo.get(branch_key, prefix) {
+                return Ok(value);
+            }
+        }
+
+        let entries = list_prefix(&branch, &format.below(prefix))
+            .into_iter()
+            .map(|(key, value)| match policy.is_redacted(&key) {
+                true => (key, Value::from(REDACTED)),
+                false => (key, value),
+            })
+            .collect();
+
+        match build_subtree(prefix, entries, &format) {
+            Some(value) => {
+                if !redacts {
+                    self.memo
+                        .insert(branch_key, prefix, value.clone(), sequence);
+                }
+                Ok(value)
+            }
+            None => Err(GitdisServiceError::ObjectNotFound),
+        }
+    }
+
+    /// `get_object` serialized to JSON. The bytes are kept until one of the
+    /// entries they were built from changes, so hot keys are served without
+    /// serializing them again, unless the branch policy redacts keys.
+    pub fn get_object_json(
+        &self,
+        branch_key: &str,
+        object_key: &str,
+    ) -> Result<Bytes, GitdisServiceError> {
+        let (prefix, sequence, redacts) = match self.gitdis.read() {
+            Ok(gitdis) => (
+                gitdis
+                    .get_key_format(branch_key)
+                    .trim(object_key)
+                    .to_string(),
+                gitdis.get_events().get_sequence(),
+                gitdis
+                    .get_branch_policy(branch_key)
+                    .is_some_and(|policy| !policy.redact_keys.is_empty()),
+            ),
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        // Removing the branch drops its bytes, so a hit means it exists.
+        if let Some(bytes) = self
+            .serialized
+            .get(branch_key, &prefix)
+            .filter(|_| !redacts)
+        {
+            self.restore_if_archived(branch_key)?;
+            self.usage.record_read(branch_key, object_key, now_millis());
+            return Ok(bytes);
+        }
+
+        let value = self.get_object(branch_key, object_key)?;
+        let bytes = match serde_json::to_vec(&value) {
+            Ok(bytes) => Bytes::from(bytes),
+            Err(err) => {
+                return Err(GitdisServiceError::InternalError(format!(
+                    "Error serializing object: {}",
+                    err
+                )))
+            }
+        };
+
+        if !redacts {
+            self.serialized
+                .insert(branch_key, &prefix, bytes.clone(), sequence);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Provenance of `object_key`: the commit that last touched its file, or
+    /// for a prefix the most recent commit among the keys below it.
+    pub fn get_metadata(
+        &self,
+        branch_key: &str,
+        object_key: &str,
+    ) -> Result<CommitMetadata, GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        if gitdis.get_object_branch(branch_key).is_none() {
+            return Err(GitdisServiceError::BranchNotFound);
+        }
+
+        gitdis
+            .get_metadata(branch_key, object_key)
+            .ok_or(GitdisServiceError::ObjectNotFound)
+    }
+
+    /// CODEOWNERS owners of the file of `object_key`, or for a prefix of
+    /// every key below it. Empty without CODEOWNERS.
+    pub fn get_owners(
+        &self,
+        branch_key: &str,
+        object_key: &str,
+    ) -> Result<Vec<String>, GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        gitdis
+            .get_key_owners(branch_key, object_key)
+            .ok_or(GitdisServiceError::BranchNotFound)
+    }
+
+    /// The values `object_key` held, newest first, kept when the branch was
+    /// registered with `key_versions`. The second one is what the key was
+    /// before its last change.
+    pub fn get_history(
+        &self,
+        branch_key: &str,
+        object_key: &str,
+    ) -> Result<Vec<KeyVersion>, GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        match gitdis.get_key_versions(branch_key, object_key) {
+            Some(versions) if versions.is_empty() => Err(GitdisServiceError::ObjectNotFound),
+            Some(versions) => Ok(versions),
+            None => Err(GitdisServiceError::BranchNotFound),
+        }
+    }
+
+    /// A past generation of a branch, kept when the branch was registered
+    /// with `generations`. Reads from it never touch git.
+    pub fn get_generation(
+        &self,
+        branch_key: &str,
+        selector: &GenerationSelector,
+    ) -> Result<Generation, GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        if gitdis.get_object_branch(branch_key).is_none() {
+            return Err(GitdisServiceError::BranchNotFound);
+        }
+
+        gitdis
+            .get_generation(branch_key, selector)
+            .ok_or(GitdisServiceError::GenerationNotFound)
+    }
+
+    /// Runs a condition query over a branch within `limits`, see
+    /// `ConditionQuery::execute`.
+    pub fn query(
+        &self,
+        branch_key: &str,
+        query: &ConditionQuery,
+        limits: &QueryLimits,
+    ) -> Result<QueryResult, GitdisServiceError> {
+        debug!("Querying branch {}", branch_key);
+
+        self.read_branch(branch_key, |branch| query.execute(branch, limits))
+    }
+
+    /// The keys of a branch starting with `prefix`, in key order, with the
+    /// commit that last touched each, for `write_export`.
+    #[cfg(feature = "export")]
+    pub fn export_branch(
+        &self,
+        branch_key: &str,
+        prefix: &str,
+    ) -> Result<Vec<ExportRow>, GitdisServiceError> {
+        debug!("Exporting branch {}", branch_key);
+
+        let entries = self.read_branch(branch_key, |branch| list_prefix(branch, prefix))?;
+
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+        let metadata = match gitdis.get_object_branch(branch_key) {
+            Some(branch) => branch.get_metadata(),
+            None => return Err(GitdisServiceError::BranchNotFound),
+        };
+        let metadata = match metadata.read() {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading metadata".to_string(),
+                ))
+            }
+        };
+
+        Ok(entries
+            .into_iter()
+            .map(|(key, value)| ExportRow {
+                commit: metadata.get(&key).cloned(),
+                key,
+                value,
+            })
+            .collect())
+    }
+
+    /// Runs a read-only SQL query over a branch within `limits`, see
+    /// `SqlMirror`. The branch is copied to SQLite on its first query.
+    #[cfg(feature = "sql")]
+    pub fn query_sql(
+        &self,
+        branch_key: &str,
+        sql: &str,
+        limits: &SqlLimits,
+    ) -> Result<SqlResult, GitdisServiceError> {
+        debug!("Querying branch {} with SQL", branch_key);
+
+        let mirror = self
+            .read_branch(branch_key, |branch| {
+                self.sql.get_or_load(branch_key, branch)
+            })?
+            .map_err(|err| GitdisServiceError::InternalError(err.to_string()))?;
+
+        mirror
+            .query(sql, limits)
+            .map_err(|err| GitdisServiceError::InvalidQuery(err.to_string()))
+    }
+
+    /// Stores a query under `name` for the branch, replacing any query of
+    /// that name. Returns whether one was replaced.
+    pub fn save_query(
+        &self,
+        branch_key: &str,
+        name: &str,
+        query: SavedQuery,
+    ) -> Result<bool, GitdisServiceError> {
+        debug!("Saving query {} of branch {}", name, branch_key);
+
+        let exists = match self.gitdis.read() {
+            Ok(gitdis) => gitdis.get_object_branch(branch_key).is_some(),
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        match exists {
+            true => Ok(self.saved_queries.save(branch_key, name, query)),
+            false => Err(GitdisServiceError::BranchNotFound),
+        }
+    }
+
+    pub fn remove_saved_query(
+        &self,
+        branch_key: &str,
+        name: &str,
+    ) -> Result<(), GitdisServiceError> {
+        match self.saved_queries.remove(branch_key, name) {
+            true => Ok(()),
+            false => Err(GitdisServiceError::QueryNotFound),
+        }
+    }
+
+    /// Runs the query saved under `name` with `params` bound.
+    pub fn run_saved_query(
+        &self,
+        branch_key: &str,
+        name: &str,
+        params: &HashMap<String, Value>,
+        limits: &QueryLimits,
+    ) -> Result<QueryResult, GitdisServiceError> {
+        debug!("Running query {} of branch {}", name, branch_key);
+
+        let query = match self.saved_queries.get(branch_key, name) {
+            Some(query) => query,
+            None => return Err(GitdisServiceError::QueryNotFound),
+        };
+
+        self.read_branch(branch_key, |branch| query.execute(branch, params, limits))?
+            .map_err(|err| GitdisServiceError::InvalidQuery(err.to_string()))
+    }
+
+    /// Materializes the query saved under `query_name` as the view `name`,
+    /// with `params` bound. The view is built once from the branch, then
+    /// kept up to date from its change events. Returns whether a view of
+    /// that name was replaced.
+    pub fn materialize_view(
+        &self,
+        branch_key: &str,
+        name: &str,
+        query_name: &str,
+        params: &HashMap<String, Value>,
+    ) -> Result<bool, GitdisServiceError> {
+        debug!("Materializing view {} of branch {}", name, branch_key);
+
+        let query = match self.saved_queries.get(branch_key, query_name) {
+            Some(query) => query,
+            None => return Err(GitdisServiceError::QueryNotFound),
+        };
+
+        self.restore_if_archived(branch_key)?;
+
+        let PendingSubscription {
+            id,
+            sequence,
+            snapshot,
+            delivery,
+        } = self.subscribe_prefix(branch_key, &query.prefix)?;
+        let view = match MaterializedView::new(query, params, sequence, snapshot, now_millis()) {
+            Ok(view) => Arc::new(Mutex::new(view)),
+            Err(err) => {
+                self.unsubscribe(id);
+                return Err(GitdisServiceError::InvalidQuery(err.to_string()));
+            }
+        };
+
+        let listener_view = view.clone();
+
+        if let Ok(mut delivery) = delivery.lock() {
+            delivery.start(Box::new(move |event| {
+                let mut view = match listener_view.lock() {
+                    Ok(view) => view,
+                    Err(_) => return,
+                };
+
+                match &event.event {
+                    BranchEventKind::Cache(Event::Insert(data)) => {
+                        view.apply(event.sequence, &data.key, Some(&data.value), now_millis())
+                    }
+                    BranchEventKind::Cache(Event::Remove(data)) => {
+                        view.apply(event.sequence, &data.key, None, now_millis())
+                    }
+                    BranchEventKind::Evicted { key, .. } => {
+                        view.apply(event.sequence, key, None, now_millis())
+                    }
+                    BranchEventKind::Cache(Event::Clear) | BranchEventKind::Removed => {
+                        view.clear(event.sequence, now_millis())
+                    }
+                    BranchEventKind::Rejected { .. }
+                    | BranchEventKind::Invalid { .. }
+                    | BranchEventKind::ParseError { .. }
+                    | BranchEventKind::Dirty { .. }
+                    | BranchEventKind::Archived { .. }
+                    | BranchEventKind::Restored
+                    | BranchEventKind::SyncCompleted { .. }
+                    | BranchEventKind::SyncFailed { .. } => (),
+                }
+            }));
+        }
+
+        let handle = ViewHandle {
+            subscription_id: id,
+            view,
+        };
+
+        match self.views.insert(branch_key, name, handle) {
+            Some(replaced) => {
+                self.unsubscribe(replaced.subscription_id);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// The current result of a view, without running its query.
+    pub fn get_view(&self, branch_key: &str, name: &str) -> Result<ViewResult, GitdisServiceError> {
+        let handle = match self.views.get(branch_key, name) {
+            Some(handle) => handle,
+            None => return Err(GitdisServiceError::ViewNotFound),
+        };
+
+        let branch_sequence = self.get_branch_sequence(branch_key)?;
+
+        let result = match handle.view.lock() {
+            Ok(view) => view.get_result(branch_sequence, now_millis()),
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading view".to_string(),
+                ))
+            }
+        };
+
+        Ok(result)
+    }
+
+    pub fn remove_view(&self, branch_key: &str, name: &str) -> Result<(), GitdisServiceError> {
+        match self.views.remove(branch_key, name) {
+            Some(handle) => {
+                self.unsubscribe(handle.subscription_id);
+                Ok(())
+            }
+            None => Err(GitdisServiceError::ViewNotFound),
+        }
+    }
+
+    /// Calls `read` with the cache of a branch, restoring it first if it
+    /// was archived.
+    fn read_branch<T>(
+        &self,
+        branch_key: &str,
+        read: impl FnOnce(&quickleaf::Cache) -> T,
+    ) -> Result<T, GitdisServiceError> {
+        self.restore_if_archived(branch_key)?;
+
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        let branch = match gitdis.get_data_branch(branch_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisServiceError::BranchNotFound),
+        };
+
+        let result = match branch.read() {
+            Ok(branch) => read(&branch),
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading branch".to_string(),
+                ))
+            }
+        };
+
+        Ok(result)
+    }
+
+    /// Subscribes to the entries of a branch starting with `prefix`. The
+    /// listener is registered before the snapshot is read, and events already
+    /// covered by the snapshot are filtered out, so no change is lost between
+    /// the two. Call `unsubscribe` with the returned id when done, which is
+    /// also the id `backfill` takes.
+    pub fn subscribe(
+        &self,
+        branch_key: &str,
+        prefix: &str,
+    ) -> Result<Subscription, GitdisServiceError> {
+        let PendingSubscription {
+            id,
+            sequence,
+            snapshot,
+            delivery,
+        } = self.subscribe_prefix(branch_key, prefix)?;
+        let (sender, receiver) = broadcast::channel(SUBSCRIPTION_CAPACITY);
+
+        if let Ok(mut targets) = self.backfill_targets.write() {
+            targets.insert(
+                id,
+                BackfillTarget {
+                    branch_key: branch_key.to_string(),
+                    prefix: prefix.to_string(),
+                    sequence,
+                    sender: sender.clone(),
+                },
+            );
+        }
+
+        if let Ok(mut delivery) = delivery.lock() {
+            delivery.start(Box::new(move |event| {
+                let _ = sender.send(event);
+            }));
+        }
+
+        Ok(Subscription {
+            id,
+            sequence,
+            snapshot,
+            receiver,
+        })
+    }
+
+    /// Registers a listener for the events of a branch under `prefix` and
+    /// reads its snapshot. The listener runs on the thread publishing each
+    /// event, so subscribing holds no thread of its own.
+    fn subscribe_prefix(
+        &self,
+        branch_key: &str,
+        prefix: &str,
+    ) -> Result<PendingSubscription, GitdisServiceError> {
+        debug!("Subscribing to {} with prefix {}", branch_key, prefix);
+
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        let branch = match gitdis.get_data_branch(branch_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisServiceError::BranchNotFound),
+        };
+
+        let events = gitdis.get_events();
+        // Nothing is delivered until the floor is known.
+        let delivery = Arc::new(Mutex::new(Delivery {
+            floor: u64::MAX,
+            pending: Vec::new(),
+            sink: None,
+        }));
+        let listener_delivery = delivery.clone();
+        let listener_branch_key = branch_key.to_string();
+        let listener_prefix = prefix.to_string();
+
+        let id = events.subscribe(Arc::new(move |event: &BranchEvent| {
+            if event.branch_key == listener_branch_key && is_in_prefix(event, &listener_prefix) {
+                if let Ok(mut delivery) = listener_delivery.lock() {
+                    delivery.accept(event);
+                }
+            }
+        }));
+
+        // Taken under the lock of the delivery, so an event delivered before
+        // it was numbered before it and is in the snapshot.
+        let sequence = match delivery.lock() {
+            Ok(mut delivery) => {
+                delivery.floor = events.get_sequence();
+                delivery.floor
+            }
+            Err(_) => {
+                events.unsubscribe(id);
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading subscription".to_string(),
+                ));
+            }
+        };
+
+        let snapshot = match branch.read() {
+            Ok(branch) => list_prefix(&branch, prefix),
+            Err(_) => {
+                events.unsubscribe(id);
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading branch".to_string(),
+                ));
+            }
+        };
+
+        Ok(PendingSubscription {
+            id,
+            sequence,
+            snapshot,
+            delivery,
+        })
+    }
+
+    /// Subscribes to the entries of a branch matching `query`. Each change
+    /// event re-evaluates only the key it touches. Call `unsubscribe` with the
+    /// returned id when done.
+    pub fn subscribe_query(
+        &self,
+        branch_key: &str,
+        query: ConditionQuery,
+    ) -> Result<QuerySubscription, GitdisServiceError> {
+        // Matches follow every change in order, so this is never a backfill
+        // target: old events replayed after the snapshot would corrupt them.
+        let PendingSubscription {
+            id,
+            sequence,
+            snapshot,
+            delivery,
+        } = self.subscribe_prefix(branch_key, &query.prefix)?;
+        let mut continuous = ContinuousQuery::new(query);
+        let matches = continuous.seed(snapshot);
+        let (sender, receiver) = broadcast::channel(SUBSCRIPTION_CAPACITY);
+
+        if let Ok(mut delivery) = delivery.lock() {
+            delivery.start(Box::new(move |event| {
+                let changes = match &event.event {
+                    BranchEventKind::Cache(Event::Insert(data)) => continuous
+                        .apply(&data.key, Some(&data.value))
+                        .into_iter()
+                        .collect(),
+                    BranchEventKind::Cache(Event::Remove(data)) => {
+                        continuous.apply(&data.key, None).into_iter().collect()
+                    }
+                    BranchEventKind::Evicted { key, .. } => {
+                        continuous.apply(key, None).into_iter().collect()
+                    }
+                    BranchEventKind::Cache(Event::Clear) | BranchEventKind::Removed => {
+                        continuous.clear()
+                    }
+                    BranchEventKind::Rejected { .. }
+                    | BranchEventKind::Invalid { .. }
+                    | BranchEventKind::ParseError { .. }
+                    | BranchEventKind::Dirty { .. }
+                    | BranchEventKind::Archived { .. }
+                    | BranchEventKind::Restored
+                    | BranchEventKind::SyncCompleted { .. }
+                    | BranchEventKind::SyncFailed { .. } => Vec::new(),
+                };
+
+                for change in changes {
+                    let _ = sender.send((event.sequence, change));
+                }
+            }));
+        }
+
+        Ok(QuerySubscription {
+            id,
+            sequence,
+            matches,
+            receiver,
+        })
+    }
+
+    pub fn unsubscribe(&self, id: u64) {
+        if let Ok(gitdis) = self.gitdis.read() {
+            gitdis.get_events().unsubscribe(id);
+        }
+
+        if let Ok(mut targets) = self.backfill_targets.write() {
+            targets.remove(&id);
+        }
+    }
+
+    /// Replays the journaled events after `since` to a subscriber: a branch
+    /// stream by its subscription id, or a key watch by name.
+    ///
+    /// A stream gets the events up to its snapshot that its prefix matches,
+    /// after the live events already sent, each with its own sequence
+    /// number. A watch gets the changes of its keys up to now, on every
+    /// branch it covers; those made since it was added may be sent twice.
+    /// Returns how many events or changes were sent.
+    pub fn backfill(
+        &self,
+        subscriber: &str,
+        since: &JournalPosition,
+    ) -> Result<usize, GitdisServiceError> {
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => {
+                return Err(GitdisServiceError::InvalidSettings(
+                    "Event journal is not enabled".to_string(),
+                ))
+            }
+        };
+
+        debug!("Backfilling {} since {:?}", subscriber, since);
+
+        match subscriber.parse::<u64>() {
+            Ok(id) => self.backfill_stream(journal, id, since),
+            Err(_) => self.backfill_key_watch(journal, subscriber, since),
+        }
+    }
+
+    fn backfill_stream(
+        &self,
+        journal: &EventJournal,
+        id: u64,
+        since: &JournalPosition,
+    ) -> Result<usize, GitdisServiceError> {
+        let target = match self.backfill_targets.read() {
+            Ok(targets) => targets.get(&id).cloned(),
+            Err(_) => None,
+        };
+        let target = match target {
+            Some(target) => target,
+            None => return Err(GitdisServiceError::SubscriberNotFound),
+        };
+
+        let events = journal
+            .replay(&target.branch_key, since, target.sequence)
+            .map_err(from_journal_error)?;
+        let mut sent = 0;
+
+        for event in events {
+            if !is_in_prefix(&event, &target.prefix) {
+                continue;
+            }
+
+            if target.sender.send(event).is_err() {
+                return Err(GitdisServiceError::SubscriberNotFound);
+            }
+
+            sent += 1;
+        }
+
+        Ok(sent)
+    }
+
+    fn backfill_key_watch(
+        &self,
+        journal: &EventJournal,
+        name: &str,
+        since: &JournalPosition,
+    ) -> Result<usize, GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        let events = match (gitdis.get_key_watch_branch(name), since) {
+            (None, _) => return Err(GitdisServiceError::SubscriberNotFound),
+            (Some(Some(branch_key)), since) => journal.replay(&branch_key, since, u64::MAX),
+            (Some(None), JournalPosition::Sequence(sequence)) => journal.replay_all(*sequence),
+            (Some(None), JournalPosition::Commit(_)) => {
+                return Err(GitdisServiceError::InvalidQuery(
+                    "A commit only locates a backfill for a watch on one branch".to_string(),
+                ))
+            }
+        }
+        .map_err(from_journal_error)?;
+
+        gitdis
+            .replay_key_watch(name, &events)
+            .ok_or(GitdisServiceError::SubscriberNotFound)
+    }
+
+    /// Sequence number of the last change to a branch, sent with every read
+    /// so clients can detect a response older than what they already hold.
+    /// Matches the sequence numbers of `subscribe`.
+    pub fn get_branch_sequence(&self, branch_key: &str) -> Result<u64, GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        match gitdis.get_object_branch(branch_key) {
+            Some(_) => Ok(gitdis.get_events().get_branch_sequence(branch_key)),
+            None => Err(GitdisServiceError::BranchNotFound),
+        }
+    }
+
+    pub fn get_branch_status(&self, branch_key: &str) -> Result<BranchStatus, GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        gitdis
+            .get_branch_status(branch_key)
+            .ok_or(GitdisServiceError::BranchNotFound)
+    }
+
+    pub fn get_branch_settings(
+        &self,
+        branch_key: &str,
+    ) -> Result<BranchSettings, GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        gitdis
+            .get_object_branch(branch_key)
+            .map(|branch| branch.get_settings().clone())
+            .ok_or(GitdisServiceError::BranchNotFound)
+    }
+
+    /// `None` until the branch has linted a commit, see `LintSettings`.
+    pub fn get_lint_report(
+        &self,
+        branch_key: &str,
+    ) -> Result<Option<LintReport>, GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        gitdis
+            .get_lint_report(branch_key)
+            .ok_or(GitdisServiceError::BranchNotFound)
+    }
+
+    pub fn get_dirty_branches(&self) -> Result<Vec<String>, GitdisServiceError> {
+        match self.gitdis.read() {
+            Ok(gitdis) => Ok(gitdis.get_dirty_branches()),
+            Err(_) => Err(GitdisServiceError::InternalError(
+                "Error reading gitdis".to_string(),
+            )),
+        }
+    }
+
+    pub fn get_memo_stats(&self) -> MemoStats {
+        self.memo.get_stats()
+    }
+
+    /// Waits for the first sync of a branch, see `Gitdis::wait_ready`, and
+    /// returns the commit it loaded.
+    pub async fn wait_ready(
+        &self,
+        branch_key: &str,
+        timeout: std::time::Duration,
+    ) -> Result<String, GitdisServiceError> {
+        let ready = match self.gitdis.read() {
+            Ok(gitdis) => gitdis.wait_ready(branch_key, timeout)?,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        Ok(ready.await?)
+    }
+
+    /// Hits and misses of the JSON bytes kept by `get_object_json`.
+    pub fn get_serialization_stats(&self) -> MemoStats {
+        self.serialized.get_stats()
+    }
+
+    pub fn add_repo(&mut self, settings: BranchSettings) -> Result<BranchInfo, GitdisServiceError> {
+        debug!("Creating new repo");
+
+        let mut gitdis = match self.gitdis.write() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error writing gitdis".to_string(),
+                ))
+            }
+        };
+
+        match gitdis.add_repo(settings.clone()) {
+            Ok(_) => {
+                let repo_key = settings.get_repo_key();
+                let object = gitdis.get_object_branch(&repo_key);
+
+                match object {
+                    Some(object) => Ok(BranchInfo {
+                        key: repo_key,
+                        create_at: object.get_create_at(),
+                    }),
+                    None => Err(GitdisServiceError::RepoNotCreated),
+                }
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Starts listening to a registered branch on the sync scheduler, see
+    /// `Gitdis::set_sync_workers`.
+    pub fn listen_branch(&self, branch_key: &str) -> Result<(), GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        let branch = match gitdis.get_object_branch(branch_key) {
+            Some(branch) => branch,
+            None => return Err(GitdisServiceError::BranchNotFound),
+        };
+
+        gitdis.repo_schedule(branch.get_settings().clone())?;
+
+        Ok(())
+    }
+
+    /// Registers a repo with a branch pattern, see `BranchDiscovery`. Its
+    /// branches are registered by `discover_branches`.
+    pub fn add_discovery(
+        &self,
+        settings: BranchSettings,
+    ) -> Result<BranchInfo, GitdisServiceError> {
+        let mut gitdis = match self.gitdis.write() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error writing gitdis".to_string(),
+                ))
+            }
+        };
+
+        let key = settings.get_repo_key();
+        gitdis.add_discovery(settings)?;
+
+        Ok(BranchInfo {
+            key,
+            create_at: now_millis(),
+        })
+    }
+
+    /// Unregisters a discovery and the branches it registered, returning
+    /// their keys.
+    pub fn remove_discovery(&self, key: &str) -> Result<Vec<String>, GitdisServiceError> {
+        let mut gitdis = match self.gitdis.write() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error writing gitdis".to_string(),
+                ))
+            }
+        };
+
+        Ok(gitdis.remove_discovery(key)?)
+    }
+
+    /// Lists the branches upstream of every discovery and registers or
+    /// unregisters branches to match. The listings run without the lock.
+    pub fn discover_branches(&self) -> Result<Vec<DiscoveryReport>, GitdisServiceError> {
+        let pending = match self.gitdis.read() {
+            Ok(gitdis) => gitdis.prepare_discoveries(),
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        let listings: Vec<_> = pending
+            .into_iter()
+            .map(|discovery| (discovery.key.clone(), discovery.run()))
+            .collect();
+
+        let mut gitdis = match self.gitdis.write() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error writing gitdis".to_string(),
+                ))
+            }
+        };
+
+        let mut reports = Vec::new();
+
+        for (key, listing) in listings {
+            let report = match listing {
+                Ok(remote_branches) => match gitdis.apply_discovery(&key, &remote_branches) {
+                    Ok(report) => report,
+                    // Removed while its branches were being listed.
+                    Err(GitdisError::BranchNotFound) => continue,
+                    Err(err) => return Err(err.into()),
+                },
+                Err(err) => {
+                    debug!("Error listing branches of {}: {:?}", key, err);
+
+                    DiscoveryReport {
+                        key,
+                        error: Some(format!("{:?}", err)),
+                        ..DiscoveryReport::default()
+                    }
+                }
+            };
+
+            reports.push(report);
+        }
+
+        Ok(reports)
+    }
+
+    /// Re-registers the branches of an old `gcs` deployment, read from its
+    /// clone directory and/or its registrations. Their clones are moved into
+    /// the current layout when the branches first sync.
+    pub fn migrate_legacy(
+        &self,
+        clone_path: Option<&str>,
+        mut registrations: Vec<LegacyRegistration>,
+    ) -> Result<MigrationReport, GitdisServiceError> {
+        debug!("Migrating legacy registrations");
+
+        let mut report = MigrationReport::default();
+
+        if let Some(clone_path) = clone_path {
+            for registration in scan_legacy_clones(clone_path, &mut report) {
+                if !registrations.contains(&registration) {
+                    registrations.push(registration);
+                }
+            }
+        }
+
+        let mut gitdis = match self.gitdis.write() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error writing gitdis".to_string(),
+                ))
+            }
+        };
+
+        gitdis.migrate_legacy(registrations, &mut report);
+
+        Ok(report)
+    }
+
+    /// Applies `patch` to the cached document and writes the result back to
+    /// the repository. `if_match` is the commit the client last saw.
+    pub fn patch_object(
+        &self,
+        branch_key: &str,
+        object_key: &str,
+        patch: ObjectPatch,
+        if_match: Option<String>,
+    ) -> Result<Value, GitdisServiceError> {
+        debug!("Patching object {} on branch {}", object_key, branch_key);
+
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        let pending = gitdis.prepare_write_back(branch_key)?;
+        let mut value = match gitdis.get_data_branch(branch_key) {
+            Some(branch) => match branch.read() {
+                Ok(branch) => match branch.get(object_key) {
+                    Some(value) => value.clone(),
+                    None => return Err(GitdisServiceError::ObjectNotFound),
+                },
+                Err(_) => {
+                    return Err(GitdisServiceError::InternalError(
+                        "Error reading branch".to_string(),
+                    ))
+                }
+            },
+            None => return Err(GitdisServiceError::BranchNotFound),
+        };
+
+        // The commit and push run without the lock.
+        drop(gitdis);
+
+        if let Err(err) = patch.apply(&mut value) {
+            return Err(GitdisServiceError::InvalidPatch(err.to_string()));
+        }
+
+        pending
+            .run(object_key, value.clone(), if_match.as_deref())
+            .map(|_| value)
+            .map_err(from_write_back_error)
+    }
+
+    /// Renders a template with `prefix` of a branch, rebuilt as in
+    /// `get_object`, as its context. The whole branch for an empty prefix.
+    pub fn render_template(
+        &self,
+        branch_key: &str,
+        prefix: &str,
+        source: TemplateSource,
+    ) -> Result<String, GitdisServiceError> {
+        let template = match source {
+            TemplateSource::Inline(template) => template,
+            TemplateSource::File(path) => {
+                let handler = match self.gitdis.read() {
+                    Ok(gitdis) => gitdis.get_clone_handler(branch_key)?,
+                    Err(_) => {
+                        return Err(GitdisServiceError::InternalError(
+                            "Error reading gitdis".to_string(),
+                        ))
+                    }
+                };
+
+                handler
+                    .read_repo_file(&path)
+                    .map_err(|err| GitdisServiceError::InvalidTemplate(err.to_string()))?
+            }
+        };
+
+        let context = self.get_object(branch_key, prefix)?;
+
+        render(&template, &context)
+            .map_err(|err| GitdisServiceError::InvalidTemplate(err.to_string()))
+    }
+
+    /// Returns whether a job of that name was replaced.
+    pub fn save_render_job(
+        &self,
+        branch_key: &str,
+        name: &str,
+        job: RenderJob,
+    ) -> Result<bool, GitdisServiceError> {
+        debug!("Saving render job {} of branch {}", name, branch_key);
+
+        let exists = match self.gitdis.read() {
+            Ok(gitdis) => gitdis.get_object_branch(branch_key).is_some(),
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        match exists {
+            true => Ok(self.render_jobs.save(branch_key, name, job)),
+            false => Err(GitdisServiceError::BranchNotFound),
+        }
+    }
+
+    pub fn get_render_job(
+        &self,
+        branch_key: &str,
+        name: &str,
+    ) -> Result<(RenderJob, RenderJobStatus), GitdisServiceError> {
+        self.render_jobs
+            .get(branch_key, name)
+            .ok_or(GitdisServiceError::RenderJobNotFound)
+    }
+
+    /// Every render job as `(branch_key, name, job, status)`.
+    pub fn get_render_jobs(&self) -> Vec<(String, String, RenderJob, RenderJobStatus)> {
+        self.render_jobs.list()
+    }
+
+    pub fn remove_render_job(
+        &self,
+        branch_key: &str,
+        name: &str,
+    ) -> Result<(), GitdisServiceError> {
+        match self.render_jobs.remove(branch_key, name) {
+            true => Ok(()),
+            false => Err(GitdisServiceError::RenderJobNotFound),
+        }
+    }
+
+    /// Renders a job and publishes its output, unless it is the one last
+    /// published. A failed render or upload is recorded in the job status,
+    /// not returned.
+    pub fn run_render_job(
+        &self,
+        branch_key: &str,
+        name: &str,
+    ) -> Result<RenderJobStatus, GitdisServiceError> {
+        let (job, status) = self.get_render_job(branch_key, name)?;
+        let commit = match self.gitdis.read() {
+            Ok(gitdis) => gitdis
+                .get_branch_status(branch_key)
+                .and_then(|status| status.last_commit),
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        debug!("Running render job {} of branch {}", name, branch_key);
+
+        let result = self
+            .render_template(branch_key, &job.prefix, job.template.clone())
+            .map_err(describe_error)
+            .and_then(|output| {
+                let hash = hash_output(&output);
+
+                match status.output_hash == Some(hash) {
+                    true => Ok(None),
+                    false => job.target.publish(&output).map(|_| Some(hash)),
+                }
+            });
+
+        if let Err(err) = &result {
+            debug!("Render job {} of {} failed: {}", name, branch_key, err);
+        }
+
+        self.render_jobs
+            .record(branch_key, name, now_millis(), commit, result)
+            .ok_or(GitdisServiceError::RenderJobNotFound)
+    }
+
+    /// Runs the render jobs whose branch moved since their last run, see
+    /// `RenderJobStore::get_due`. Returns the `(branch_key, name)` of the
+    /// jobs run.
+    pub fn run_due_render_jobs(&self) -> Vec<(String, String)> {
+        let due = match self.gitdis.read() {
+            Ok(gitdis) => self.render_jobs.get_due(now_millis(), |branch_key| {
+                gitdis
+                    .get_branch_status(branch_key)
+                    .and_then(|status| status.last_commit)
+            }),
+            Err(_) => return Vec::new(),
+        };
+
+        for (branch_key, name) in &due {
+            let _ = self.run_render_job(branch_key, name);
+        }
+
+        due
+    }
+
+    /// Writes several keys back as a single commit with `message`, all or
+    /// none of them, see `BranchHandler::commit_changes`. `if_match` is the
+    /// commit the client last saw. Returns the new commit hash.
+    pub fn commit_changes(
+        &self,
+        branch_key: &str,
+        changes: Vec<KeyWrite>,
+        message: &str,
+        if_match: Option<String>,
+    ) -> Result<String, GitdisServiceError> {
+        debug!(
+            "Committing {} changes on branch {}",
+            changes.len(),
+            branch_key
+        );
+
+        let pending = match self.gitdis.read() {
+            Ok(gitdis) => gitdis.prepare_write_back(branch_key)?,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        // The commit and push run without the lock.
+        pending
+            .commit(changes, message, if_match.as_deref())
+            .map_err(from_write_back_error)
+    }
+
+    pub fn trigger_sync(&self, branch_key: &str) -> Result<(), GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        gitdis.trigger_sync(branch_key).map_err(|err| err.into())
+    }
+
+    /// Lists the files of a branch that could not be loaded cleanly.
+    pub fn get_errors(
+        &self,
+        branch_key: &str,
+    ) -> Result<HashMap<String, String>, GitdisServiceError> {
+        let gitdis = match self.gitdis.read() {
+            Ok(gitdis) => gitdis,
+            Err(_) => {
+                return Err(GitdisServiceError::InternalError(
+                    "Error reading gitdis".to_string(),
+                ))
+            }
+        };
+
+        match gitdis.get_branch_errors(branch_key) {
+            Some(errors) => Ok(errors),
+            None => Err(GitdisServiceError::BranchNotFound),
+        }
+    }
 }
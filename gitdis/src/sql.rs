@@ -0,0 +1,327 @@
+use crate::cache::list_refs;
+use crate::events::{BranchEvent, BranchEventKind};
+use log::debug;
+use quickleaf::valu3::prelude::*;
+use quickleaf::{Cache, Event};
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// SQLite virtual machine instructions between two deadline checks.
+const PROGRESS_INTERVAL: i32 = 1000;
+
+#[derive(Debug, PartialEq)]
+pub enum SqlError {
+    /// Only single `SELECT` statements, `WITH` included, are run.
+    NotReadOnly(String),
+    Sqlite(String),
+}
+
+impl std::fmt::Display for SqlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SqlError::NotReadOnly(reason) => write!(f, "Not a read-only query: {}", reason),
+            SqlError::Sqlite(error) => write!(f, "SQL error: {}", error),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for SqlError {
+    fn from(err: rusqlite::Error) -> Self {
+        SqlError::Sqlite(err.to_string())
+    }
+}
+
+/// Bounds on one SQL query. Past them it stops and returns the rows read
+/// so far, marked truncated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SqlLimits {
+    pub max_rows: usize,
+    pub max_duration: Duration,
+}
+
+impl Default for SqlLimits {
+    fn default() -> Self {
+        Self {
+            max_rows: 1000,
+            max_duration: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SqlResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+    /// A limit stopped the query before its last row.
+    pub truncated: bool,
+}
+
+impl SqlResult {
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert(
+            "columns".to_string(),
+            Value::from(
+                self.columns
+                    .iter()
+                    .map(|column| Value::from(column.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        object.insert(
+            "rows".to_string(),
+            Value::from(
+                self.rows
+                    .iter()
+                    .map(|row| Value::from(row.clone()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        object.insert("truncated".to_string(), Value::from(self.truncated));
+
+        Value::from(object)
+    }
+}
+
+fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|now| now.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+fn to_value(value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(integer) => Value::from(integer),
+        ValueRef::Real(real) => Value::from(real),
+        ValueRef::Text(text) | ValueRef::Blob(text) => {
+            Value::from(String::from_utf8_lossy(text).to_string())
+        }
+    }
+}
+
+/// Statements other than a query are refused before SQLite sees them, and
+/// again if SQLite finds they write. SQLite would only run the first of
+/// several statements, so a `;` before the end is refused too, even in a
+/// string literal.
+fn check_read_only(sql: &str) -> Result<(), SqlError> {
+    if sql.trim().trim_end_matches(';').contains(';') {
+        return Err(SqlError::NotReadOnly(
+            "only one statement is run".to_string(),
+        ));
+    }
+
+    let keyword = sql
+        .trim_start()
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match keyword.as_str() {
+        "select" | "with" => Ok(()),
+        _ => Err(SqlError::NotReadOnly(
+            "must start with SELECT or WITH".to_string(),
+        )),
+    }
+}
+
+/// An in-memory SQLite copy of one branch cache, in the table
+///
+///     entries (key TEXT PRIMARY KEY, value TEXT, updated_at INTEGER)
+///
+/// with values as JSON, for `json_extract`, and `updated_at` the unix
+/// millis the row last changed in the copy.
+pub struct SqlMirror {
+    connection: Mutex<Connection>,
+}
+
+impl SqlMirror {
+    pub fn new(cache: &Cache) -> Result<Self, SqlError> {
+        let connection = Connection::open_in_memory()?;
+
+        connection.execute_batch(
+            "CREATE TABLE entries (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+        )?;
+
+        let mirror = Self {
+            connection: Mutex::new(connection),
+        };
+
+        for (key, value) in list_refs(cache, "") {
+            mirror.upsert(&key, value)?;
+        }
+
+        Ok(mirror)
+    }
+
+    fn upsert(&self, key: &str, value: &Value) -> Result<(), SqlError> {
+        let connection = self
+            .connection
+            .lock()
+            .map_err(|err| SqlError::Sqlite(err.to_string()))?;
+
+        connection.execute(
+            "INSERT INTO entries (key, value, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (key) DO UPDATE SET value = ?2, updated_at = ?3",
+            (key, value.to_json(JsonMode::Inline), now_millis()),
+        )?;
+
+        Ok(())
+    }
+
+    fn delete(&self, key: Option<&str>) -> Result<(), SqlError> {
+        let connection = self
+            .connection
+            .lock()
+            .map_err(|err| SqlError::Sqlite(err.to_string()))?;
+
+        match key {
+            Some(key) => connection.execute("DELETE FROM entries WHERE key = ?1", [key])?,
+            None => connection.execute("DELETE FROM entries", [])?,
+        };
+
+        Ok(())
+    }
+
+    /// Runs a single read-only statement within `limits`.
+    pub fn query(&self, sql: &str, limits: &SqlLimits) -> Result<SqlResult, SqlError> {
+        check_read_only(sql)?;
+
+        let connection = self
+            .connection
+            .lock()
+            .map_err(|err| SqlError::Sqlite(err.to_string()))?;
+        let mut statement = connection.prepare(sql)?;
+
+        if !statement.readonly() {
+            return Err(SqlError::NotReadOnly("the statement writes".to_string()));
+        }
+
+        let columns = statement
+            .column_names()
+            .iter()
+            .map(|column| column.to_string())
+            .collect::<Vec<String>>();
+        let deadline = Instant::now() + limits.max_duration;
+
+        connection.progress_handler(PROGRESS_INTERVAL, Some(move || Instant::now() >= deadline));
+
+        let mut result = SqlResult {
+            columns,
+            rows: Vec::new(),
+            truncated: false,
+        };
+        let mut rows = statement.query([])?;
+
+        loop {
+            let row = match rows.next() {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(rusqlite::Error::SqliteFailure(err, _))
+                    if err.code == rusqlite::ErrorCode::OperationInterrupted =>
+                {
+                    result.truncated = true;
+                    break;
+                }
+                Err(err) => {
+                    drop(rows);
+                    connection.progress_handler(0, None::<fn() -> bool>);
+                    return Err(err.into());
+                }
+            };
+
+            if result.rows.len() >= limits.max_rows {
+                result.truncated = true;
+                break;
+            }
+
+            result.rows.push(
+                (0..result.columns.len())
+                    .map(|index| row.get_ref(index).map(to_value).unwrap_or(Value::Null))
+                    .collect(),
+            );
+        }
+
+        drop(rows);
+        connection.progress_handler(0, None::<fn() -> bool>);
+
+        Ok(result)
+    }
+}
+
+/// The SQL mirrors of the branches, made on their first query and kept
+/// fresh from the branch events from then on.
+#[derive(Default)]
+pub struct SqlMirrors {
+    mirrors: RwLock<HashMap<String, Arc<SqlMirror>>>,
+}
+
+impl SqlMirrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The mirror of `branch_key`, copied from `cache` if there is none yet.
+    pub fn get_or_load(&self, branch_key: &str, cache: &Cache) -> Result<Arc<SqlMirror>, SqlError> {
+        if let Some(mirror) = self.get(branch_key) {
+            return Ok(mirror);
+        }
+
+        debug!("Loading the SQL mirror of {}", branch_key);
+
+        let mirror = Arc::new(SqlMirror::new(cache)?);
+
+        if let Ok(mut mirrors) = self.mirrors.write() {
+            return Ok(mirrors
+                .entry(branch_key.to_string())
+                .or_insert(mirror)
+                .clone());
+        }
+
+        Ok(mirror)
+    }
+
+    fn get(&self, branch_key: &str) -> Option<Arc<SqlMirror>> {
+        self.mirrors.read().ok()?.get(branch_key).cloned()
+    }
+
+    /// Applies a branch event to the mirror of its branch, if it has one.
+    /// Evicted keys are kept, as they are still in git.
+    pub fn record(&self, event: &BranchEvent) {
+        if let BranchEventKind::Removed = event.event {
+            if let Ok(mut mirrors) = self.mirrors.write() {
+                mirrors.remove(&event.branch_key);
+            }
+            return;
+        }
+
+        let mirror = match self.get(&event.branch_key) {
+            Some(mirror) => mirror,
+            None => return,
+        };
+
+        let result = match &event.event {
+            BranchEventKind::Cache(Event::Insert(data)) => mirror.upsert(&data.key, &data.value),
+            BranchEventKind::Cache(Event::Remove(data)) => mirror.delete(Some(&data.key)),
+            BranchEventKind::Cache(Event::Clear) => mirror.delete(None),
+            _ => Ok(()),
+        };
+
+        if let Err(err) = result {
+            debug!(
+                "Failed to update the SQL mirror of {}: {}",
+                event.branch_key, err
+            );
+        }
+    }
+}
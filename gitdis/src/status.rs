@@ -0,0 +1,98 @@
+use quickleaf::valu3::prelude::*;
+use std::collections::HashMap;
+
+/// Freshness of a branch, kept by its listener.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BranchStatus {
+    /// Commit the cache was last loaded from.
+    pub last_commit: Option<String>,
+    /// When the last successful sync finished.
+    pub last_sync_at: Option<u128>,
+    /// Error of the last sync, cleared by the next successful one.
+    pub last_error: Option<String>,
+    pub files_loaded: usize,
+    /// Polling is suspended; the cache keeps serving the last loaded data.
+    pub paused: bool,
+    /// The branch is registered but disabled, by its settings or a policy:
+    /// it is not cloned, or no longer synced, until it is enabled.
+    pub standby: bool,
+    /// Tracked files edited inside the clone, found before the last pull.
+    pub dirty_files: Vec<String>,
+    /// Commits made inside the clone that are not on the remote.
+    pub local_commits: u64,
+    /// When the clone was last garbage collected.
+    pub last_maintenance_at: Option<u128>,
+    /// Disk space freed by the last maintenance run.
+    pub reclaimed_bytes: u64,
+    /// Commit whose sync went over the sync limits, left unloaded.
+    pub blocked_commit: Option<String>,
+    /// Which limit `blocked_commit` went over.
+    pub blocked_reason: Option<String>,
+    /// The next sync ignores the sync limits, set by an operator to load
+    /// `blocked_commit`.
+    pub override_sync_limits: bool,
+    /// Files of the loaded commit that failed to parse, by path in the
+    /// repository, with the error. Their keys keep the last value parsed.
+    pub parse_errors: HashMap<String, String>,
+}
+
+impl BranchStatus {
+    pub fn to_value(&self) -> Value {
+        let optional = |value: &Option<String>| match value {
+            Some(value) => Value::from(value.as_str()),
+            None => Value::Null,
+        };
+        let mut object = HashMap::new();
+
+        object.insert("last_commit".to_string(), optional(&self.last_commit));
+        object.insert(
+            "last_sync_at".to_string(),
+            match self.last_sync_at {
+                Some(millis) => Value::from(millis),
+                None => Value::Null,
+            },
+        );
+        object.insert("last_error".to_string(), optional(&self.last_error));
+        object.insert("files_loaded".to_string(), Value::from(self.files_loaded));
+        object.insert("paused".to_string(), Value::from(self.paused));
+        object.insert("standby".to_string(), Value::from(self.standby));
+        object.insert(
+            "dirty_files".to_string(),
+            Value::from(
+                self.dirty_files
+                    .iter()
+                    .map(|file| Value::from(file.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        object.insert("local_commits".to_string(), Value::from(self.local_commits));
+        object.insert(
+            "last_maintenance_at".to_string(),
+            match self.last_maintenance_at {
+                Some(millis) => Value::from(millis),
+                None => Value::Null,
+            },
+        );
+        object.insert(
+            "reclaimed_bytes".to_string(),
+            Value::from(self.reclaimed_bytes),
+        );
+        object.insert("blocked_commit".to_string(), optional(&self.blocked_commit));
+        object.insert("blocked_reason".to_string(), optional(&self.blocked_reason));
+        object.insert(
+            "override_sync_limits".to_string(),
+            Value::from(self.override_sync_limits),
+        );
+        object.insert(
+            "parse_errors".to_string(),
+            Value::from(
+                self.parse_errors
+                    .iter()
+                    .map(|(file, error)| (file.clone(), Value::from(error.as_str())))
+                    .collect::<HashMap<String, Value>>(),
+            ),
+        );
+
+        Value::from(object)
+    }
+}
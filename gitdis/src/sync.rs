@@ -0,0 +1,15 @@
+// Locks and atomics shared between threads, swapped for loom's under
+// `--cfg loom` so the concurrency tests explore every interleaving:
+//
+// `RUSTFLAGS="--cfg loom" cargo test -p gitdis --release loom_`
+
+#[cfg(loom)]
+pub(crate) use loom::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Mutex, RwLock,
+};
+#[cfg(not(loom))]
+pub(crate) use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Mutex, RwLock,
+};
@@ -0,0 +1,91 @@
+use minijinja::{Environment, UndefinedBehavior};
+use quickleaf::valu3::prelude::*;
+use std::collections::BTreeMap;
+
+/// Instructions a render may run before it is stopped, so a template
+/// cannot loop for ever.
+pub const TEMPLATE_FUEL: u64 = 1_000_000;
+/// Name of the variable holding the whole context.
+pub const DATA_VARIABLE: &str = "data";
+
+#[derive(Debug, PartialEq)]
+pub enum TemplateError {
+    Syntax(String),
+    Render(String),
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TemplateError::Syntax(error) => write!(f, "Template syntax error: {}", error),
+            TemplateError::Render(error) => write!(f, "Template render error: {}", error),
+        }
+    }
+}
+
+/// Where the template of a render comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TemplateSource {
+    Inline(String),
+    /// A file of the repository, relative to its root.
+    File(String),
+}
+
+/// Renders a Jinja template, as minijinja reads it, with `context`. Its
+/// top-level keys are variables, and `data` holds all of it, so arrays,
+/// scalars and keys that are not identifiers can be reached too. Undefined
+/// variables are errors rather than empty strings.
+pub fn render(template: &str, context: &Value) -> Result<String, TemplateError> {
+    let mut environment = Environment::new();
+
+    environment.set_fuel(Some(TEMPLATE_FUEL));
+    environment.set_undefined_behavior(UndefinedBehavior::Strict);
+
+    let template = environment
+        .template_from_str(template)
+        .map_err(|err| TemplateError::Syntax(err.to_string()))?;
+
+    let mut variables = match context {
+        Value::Object(object) => object
+            .iter()
+            .map(|(key, value)| (key.to_string(), to_template_value(value)))
+            .collect(),
+        _ => BTreeMap::new(),
+    };
+
+    variables.insert(DATA_VARIABLE.to_string(), to_template_value(context));
+
+    template
+        .render(variables)
+        .map_err(|err| TemplateError::Render(err.to_string()))
+}
+
+fn to_template_value(value: &Value) -> minijinja::Value {
+    match value {
+        Value::Null | Value::Undefined => minijinja::Value::from(()),
+        Value::Boolean(boolean) => minijinja::Value::from(*boolean),
+        Value::Number(number) => {
+            let number = number.to_string();
+
+            match number.parse::<i64>() {
+                Ok(integer) => minijinja::Value::from(integer),
+                Err(_) => minijinja::Value::from(number.parse::<f64>().unwrap_or(f64::NAN)),
+            }
+        }
+        Value::String(string) => minijinja::Value::from(string.to_string()),
+        Value::Array(array) => minijinja::Value::from(
+            array
+                .values
+                .iter()
+                .map(to_template_value)
+                .collect::<Vec<minijinja::Value>>(),
+        ),
+        Value::Object(object) => minijinja::Value::from(
+            object
+                .iter()
+                .map(|(key, value)| (key.to_string(), to_template_value(value)))
+                .collect::<BTreeMap<String, minijinja::Value>>(),
+        ),
+        other => minijinja::Value::from(other.to_string()),
+    }
+}
@@ -0,0 +1,351 @@
+use crate::keys::{KeyFormat, KeyFormats};
+use quickleaf::valu3::prelude::*;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Clients told apart per branch. Reads of any further client are counted
+/// under `OTHER_CLIENTS`, so made-up identifiers cannot grow memory.
+pub const MAX_CLIENTS: usize = 1000;
+pub const OTHER_CLIENTS: &str = "_other";
+/// Days without a read after which a key is reported as unused, unless
+/// asked otherwise.
+pub const DEFAULT_UNUSED_DAYS: u64 = 30;
+pub const DAY_MILLIS: u128 = 24 * 60 * 60 * 1000;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BranchUsage {
+    pub reads: u64,
+    pub last_read_millis: Option<u128>,
+    /// Reads per object key.
+    pub keys: HashMap<String, u64>,
+    /// Last read of each object key, prefixes as they were read.
+    pub last_reads: HashMap<String, u128>,
+    /// Reads by client identifier, for the reads that gave one.
+    pub clients: HashMap<String, ClientUsage>,
+}
+
+impl BranchUsage {
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert("reads".to_string(), Value::from(self.reads));
+        object.insert(
+            "last_read_millis".to_string(),
+            match self.last_read_millis {
+                Some(millis) => Value::from(millis),
+                None => Value::Null,
+            },
+        );
+        object.insert(
+            "keys".to_string(),
+            Value::from(
+                self.keys
+                    .iter()
+                    .map(|(key, reads)| (key.clone(), Value::from(*reads)))
+                    .collect::<HashMap<String, Value>>(),
+            ),
+        );
+
+        Value::from(object)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KeyReads {
+    pub reads: u64,
+    pub last_read_millis: u128,
+}
+
+/// The keys one client read from a branch, prefixes as they were read.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientUsage {
+    pub keys: HashMap<String, KeyReads>,
+}
+
+impl ClientUsage {
+    pub fn get_reads(&self) -> u64 {
+        self.keys.values().map(|key| key.reads).sum()
+    }
+
+    pub fn get_last_read_millis(&self) -> Option<u128> {
+        self.keys.values().map(|key| key.last_read_millis).max()
+    }
+
+    /// The reads that returned `key`: of it, of a key below it, or of a
+    /// prefix holding it.
+    pub fn depending_on(&self, key: &str, format: &KeyFormat) -> ClientUsage {
+        let key = format.trim(key);
+
+        ClientUsage {
+            keys: self
+                .keys
+                .iter()
+                .filter(|(read, _)| format.is_within(read, key) || format.is_within(key, read))
+                .map(|(read, reads)| (read.clone(), *reads))
+                .collect(),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert("reads".to_string(), Value::from(self.get_reads()));
+        object.insert(
+            "last_read_millis".to_string(),
+            match self.get_last_read_millis() {
+                Some(millis) => Value::from(millis),
+                None => Value::Null,
+            },
+        );
+        object.insert(
+            "keys".to_string(),
+            Value::from(
+                self.keys
+                    .iter()
+                    .map(|(key, reads)| {
+                        (
+                            key.clone(),
+                            Value::from(HashMap::from([
+                                ("reads".to_string(), Value::from(reads.reads)),
+                                (
+                                    "last_read_millis".to_string(),
+                                    Value::from(reads.last_read_millis),
+                                ),
+                            ])),
+                        )
+                    })
+                    .collect::<HashMap<String, Value>>(),
+            ),
+        );
+
+        Value::from(object)
+    }
+}
+
+impl BranchUsage {
+    /// Last read that returned `key`: of it, or of a prefix holding it.
+    pub fn get_key_last_read_millis(&self, key: &str, format: &KeyFormat) -> Option<u128> {
+        let mut prefix = key;
+        let mut last_read = self.last_reads.get(prefix).copied();
+
+        while !prefix.is_empty() {
+            prefix = format.parent(prefix);
+            last_read = last_read.max(self.last_reads.get(prefix).copied());
+        }
+
+        last_read
+    }
+}
+
+/// A key no read returned within the window of an `UnusedKeysReport`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnusedKey {
+    pub key: String,
+    pub last_read_millis: Option<u128>,
+    /// When the commit that last touched its file was authored.
+    pub changed_millis: Option<u128>,
+}
+
+impl UnusedKey {
+    pub fn to_value(&self) -> Value {
+        let optional = |millis: Option<u128>| match millis {
+            Some(millis) => Value::from(millis),
+            None => Value::Null,
+        };
+
+        Value::from(HashMap::from([
+            ("key".to_string(), Value::from(self.key.as_str())),
+            (
+                "last_read_millis".to_string(),
+                optional(self.last_read_millis),
+            ),
+            ("changed_millis".to_string(), optional(self.changed_millis)),
+        ]))
+    }
+}
+
+/// The possibly dead config of a branch: keys not read for
+/// `window_millis`, sorted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnusedKeysReport {
+    pub branch_key: String,
+    pub window_millis: u128,
+    pub keys: Vec<UnusedKey>,
+}
+
+impl UnusedKeysReport {
+    /// Keys counted as read at the later of their last read, their last
+    /// change and `since_millis`, e.g. when the branch was registered, so
+    /// keys added or changed within the window are too new to report.
+    pub fn build(
+        branch_key: &str,
+        usage: &BranchUsage,
+        format: &KeyFormat,
+        keys: Vec<(String, Option<u128>)>,
+        since_millis: u128,
+        window_millis: u128,
+        now_millis: u128,
+    ) -> Self {
+        let mut unused = keys
+            .into_iter()
+            .map(|(key, changed_millis)| UnusedKey {
+                last_read_millis: usage.get_key_last_read_millis(&key, format),
+                key,
+                changed_millis,
+            })
+            .filter(|key| {
+                let active = key
+                    .last_read_millis
+                    .max(key.changed_millis)
+                    .unwrap_or_default()
+                    .max(since_millis);
+
+                now_millis.saturating_sub(active) >= window_millis
+            })
+            .collect::<Vec<UnusedKey>>();
+
+        unused.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Self {
+            branch_key: branch_key.to_string(),
+            window_millis,
+            keys: unused,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        Value::from(HashMap::from([
+            (
+                "branch_key".to_string(),
+                Value::from(self.branch_key.as_str()),
+            ),
+            ("window_millis".to_string(), Value::from(self.window_millis)),
+            (
+                "keys".to_string(),
+                Value::from(
+                    self.keys
+                        .iter()
+                        .map(UnusedKey::to_value)
+                        .collect::<Vec<Value>>(),
+                ),
+            ),
+        ]))
+    }
+}
+
+/// Counts reads per branch and key, so idle branches can be found.
+#[derive(Default)]
+pub struct UsageTracker {
+    branches: RwLock<HashMap<String, BranchUsage>>,
+    /// How the keys of each branch nest.
+    formats: KeyFormats,
+}
+
+impl UsageTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Nests the keys of each branch as `formats` says rather than with
+    /// `KeyFormat::default`.
+    pub fn with_formats(mut self, formats: KeyFormats) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    pub fn record_read(&self, branch_key: &str, object_key: &str, now_millis: u128) {
+        if let Ok(mut branches) = self.branches.write() {
+            let usage = branches.entry(branch_key.to_string()).or_default();
+
+            usage.reads += 1;
+            usage.last_read_millis = Some(now_millis);
+            *usage.keys.entry(object_key.to_string()).or_default() += 1;
+            usage.last_reads.insert(
+                self.formats.get(branch_key).trim(object_key).to_string(),
+                now_millis,
+            );
+        }
+    }
+
+    /// Attributes a read, already counted by `record_read`, to `client`.
+    pub fn record_client_read(
+        &self,
+        branch_key: &str,
+        object_key: &str,
+        client: &str,
+        now_millis: u128,
+    ) {
+        if let Ok(mut branches) = self.branches.write() {
+            let clients = &mut branches.entry(branch_key.to_string()).or_default().clients;
+            let client = match clients.contains_key(client) || clients.len() < MAX_CLIENTS {
+                true => client,
+                false => OTHER_CLIENTS,
+            };
+            let reads = clients
+                .entry(client.to_string())
+                .or_default()
+                .keys
+                .entry(object_key.to_string())
+                .or_default();
+
+            reads.reads += 1;
+            reads.last_read_millis = now_millis;
+        }
+    }
+
+    /// The clients that read `key` of a branch, see `ClientUsage::depending_on`.
+    /// With an empty key, every client.
+    pub fn get_clients(&self, branch_key: &str, key: &str) -> HashMap<String, ClientUsage> {
+        let branches = match self.branches.read() {
+            Ok(branches) => branches,
+            Err(_) => return HashMap::new(),
+        };
+
+        let format = self.formats.get(branch_key);
+
+        match branches.get(branch_key) {
+            Some(usage) => usage
+                .clients
+                .iter()
+                .map(|(client, usage)| (client.clone(), usage.depending_on(key, &format)))
+                .filter(|(_, usage)| !usage.keys.is_empty())
+                .collect(),
+            None => HashMap::new(),
+        }
+    }
+
+    /// Restarts the idle time of a branch without counting a read.
+    pub fn mark_active(&self, branch_key: &str, now_millis: u128) {
+        if let Ok(mut branches) = self.branches.write() {
+            branches
+                .entry(branch_key.to_string())
+                .or_default()
+                .last_read_millis = Some(now_millis);
+        }
+    }
+
+    pub fn remove(&self, branch_key: &str) {
+        if let Ok(mut branches) = self.branches.write() {
+            branches.remove(branch_key);
+        }
+    }
+
+    pub fn get_usage(&self, branch_key: &str) -> BranchUsage {
+        match self.branches.read() {
+            Ok(branches) => branches.get(branch_key).cloned().unwrap_or_default(),
+            Err(_) => BranchUsage::default(),
+        }
+    }
+
+    /// Time since the last read of a branch, or since `since_millis` (e.g.
+    /// its creation) if it was never read.
+    pub fn get_idle_millis(&self, branch_key: &str, since_millis: u128, now_millis: u128) -> u128 {
+        let last_read_millis = self
+            .get_usage(branch_key)
+            .last_read_millis
+            .unwrap_or(since_millis)
+            .max(since_millis);
+
+        now_millis.saturating_sub(last_read_millis)
+    }
+}
@@ -0,0 +1,124 @@
+use quickleaf::valu3::prelude::*;
+use quickleaf::Cache;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// A value a key held as of a commit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyVersion {
+    pub commit: String,
+    /// None once the key was deleted in git.
+    pub value: Option<Value>,
+    pub recorded_at_millis: u128,
+}
+
+impl KeyVersion {
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert("commit".to_string(), Value::from(self.commit.as_str()));
+        object.insert("deleted".to_string(), Value::from(self.value.is_none()));
+        object.insert(
+            "value".to_string(),
+            self.value.clone().unwrap_or(Value::Null),
+        );
+        object.insert(
+            "recorded_at_millis".to_string(),
+            Value::from(self.recorded_at_millis),
+        );
+
+        Value::from(object)
+    }
+}
+
+/// The last values of each key of a branch, oldest first, up to a capacity
+/// per key. A version is recorded each time a loaded commit changes the
+/// value of a key, so the one before the newest is what the key was before
+/// its last change.
+#[derive(Clone, Debug, Default)]
+pub struct KeyVersions {
+    capacity: usize,
+    last_commit: Option<String>,
+    keys: HashMap<String, VecDeque<KeyVersion>>,
+    /// Keys deleted in git since the last record.
+    removed: HashSet<String>,
+}
+
+impl KeyVersions {
+    /// Keeps up to `capacity` versions of each key; none when it is 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get_last_commit(&self) -> Option<&str> {
+        self.last_commit.as_deref()
+    }
+
+    /// Versions of `key`, newest first.
+    pub fn get(&self, key: &str) -> Vec<KeyVersion> {
+        match self.keys.get(key) {
+            Some(versions) => versions.iter().rev().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Notes that `key` was deleted in git, so the next record ends its
+    /// versions with a deletion. Keys evicted from the cache are not.
+    pub fn mark_removed(&mut self, key: &str) {
+        if self.is_enabled() {
+            self.removed.insert(key.to_string());
+        }
+    }
+
+    /// Records a version of each key whose value in `cache` differs from its
+    /// newest one, and a deletion for each key removed since, as of
+    /// `commit`. The oldest version of a key is dropped past the capacity.
+    pub fn record(&mut self, cache: &Cache, commit: &str, now_millis: u128) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        for key in std::mem::take(&mut self.removed) {
+            if cache.get(&key).is_none() {
+                self.push(&key, commit, None, now_millis);
+            }
+        }
+
+        for (key, value) in crate::cache::list_refs(cache, "") {
+            self.push(&key, commit, Some(value), now_millis);
+        }
+
+        self.last_commit = Some(commit.to_string());
+    }
+
+    fn push(&mut self, key: &str, commit: &str, value: Option<&Value>, now_millis: u128) {
+        let newest = self
+            .keys
+            .get(key)
+            .and_then(|versions| versions.back())
+            .map(|version| version.value.as_ref());
+
+        // Unchanged, or deleted before any version was recorded.
+        if newest == Some(value) || (newest.is_none() && value.is_none()) {
+            return;
+        }
+
+        let versions = self.keys.entry(key.to_string()).or_default();
+
+        versions.push_back(KeyVersion {
+            commit: commit.to_string(),
+            value: value.cloned(),
+            recorded_at_millis: now_millis,
+        });
+
+        while versions.len() > self.capacity {
+            versions.pop_front();
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use super::query::{ContinuousQuery, MatchChange, QueryError, SavedQuery};
+use quickleaf::valu3::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The result of a saved query, kept up to date from the change events of
+/// its branch. Only keys in the query's index are re-evaluated, so changes
+/// elsewhere in the branch cost nothing.
+pub struct MaterializedView {
+    query: SavedQuery,
+    continuous: ContinuousQuery,
+    /// Matching entries, unshaped, in key order.
+    entries: BTreeMap<String, Value>,
+    sequence: u64,
+    built_at_millis: u128,
+    refreshed_at_millis: u128,
+}
+
+/// A view as served, with how fresh it is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ViewResult {
+    pub matches: Vec<(String, Value)>,
+    pub truncated: bool,
+    /// Sequence of the last change applied to the view.
+    pub sequence: u64,
+    /// Sequence of the last change to the branch. Only changes under the
+    /// query's prefix newer than `sequence` can make the view stale.
+    pub branch_sequence: u64,
+    pub built_at_millis: u128,
+    /// When the matching entries last changed.
+    pub refreshed_at_millis: u128,
+    /// Time since `refreshed_at_millis` when the result was read.
+    pub age_millis: u128,
+}
+
+impl ViewResult {
+    pub fn to_value(&self) -> Value {
+        let mut object = HashMap::new();
+
+        object.insert(
+            "matches".to_string(),
+            Value::from(
+                self.matches
+                    .iter()
+                    .map(|(key, value)| {
+                        let mut object = HashMap::new();
+                        object.insert("key".to_string(), Value::from(key.as_str()));
+                        object.insert("value".to_string(), value.clone());
+                        Value::from(object)
+                    })
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        object.insert("truncated".to_string(), Value::from(self.truncated));
+        object.insert("sequence".to_string(), Value::from(self.sequence));
+        object.insert(
+            "branch_sequence".to_string(),
+            Value::from(self.branch_sequence),
+        );
+        object.insert(
+            "built_at_millis".to_string(),
+            Value::from(self.built_at_millis),
+        );
+        object.insert(
+            "refreshed_at_millis".to_string(),
+            Value::from(self.refreshed_at_millis),
+        );
+        object.insert("age_millis".to_string(), Value::from(self.age_millis));
+
+        Value::from(object)
+    }
+}
+
+impl MaterializedView {
+    /// Builds the view from `entries`, the branch under the query's prefix
+    /// as of `sequence`.
+    pub fn new(
+        query: SavedQuery,
+        params: &HashMap<String, Value>,
+        sequence: u64,
+        entries: Vec<(String, Value)>,
+        now_millis: u128,
+    ) -> Result<Self, QueryError> {
+        let mut continuous = ContinuousQuery::new(query.bind(params)?);
+        let entries = continuous.seed(entries).into_iter().collect();
+
+        Ok(Self {
+            query,
+            continuous,
+            entries,
+            sequence,
+            built_at_millis: now_millis,
+            refreshed_at_millis: now_millis,
+        })
+    }
+
+    /// Applies a change to `key`, removed when `value` is `None`.
+    pub fn apply(&mut self, sequence: u64, key: &str, value: Option<&Value>, now_millis: u128) {
+        self.sequence = sequence;
+
+        let change = match self.continuous.apply(key, value) {
+            Some(change) => change,
+            None => return,
+        };
+
+        match change {
+            MatchChange::Entered { key, value } | MatchChange::Updated { key, value } => {
+                self.entries.insert(key, value);
+            }
+            MatchChange::Left { key } => {
+                self.entries.remove(&key);
+            }
+        }
+
+        self.refreshed_at_millis = now_millis;
+    }
+
+    pub fn clear(&mut self, sequence: u64, now_millis: u128) {
+        self.sequence = sequence;
+
+        if !self.continuous.clear().is_empty() {
+            self.entries.clear();
+            self.refreshed_at_millis = now_millis;
+        }
+    }
+
+    pub fn get_result(&self, branch_sequence: u64, now_millis: u128) -> ViewResult {
+        let mut matches = self
+            .entries
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let truncated = self.query.shape(&mut matches);
+
+        ViewResult {
+            matches,
+            truncated,
+            sequence: self.sequence,
+            branch_sequence,
+            built_at_millis: self.built_at_millis,
+            refreshed_at_millis: self.refreshed_at_millis,
+            age_millis: now_millis.saturating_sub(self.refreshed_at_millis),
+        }
+    }
+}
+
+/// A view and the id of the subscription feeding it.
+#[derive(Clone)]
+pub struct ViewHandle {
+    pub subscription_id: u64,
+    pub view: Arc<Mutex<MaterializedView>>,
+}
+
+/// Materialized views per branch and name.
+#[derive(Default)]
+pub struct ViewStore {
+    branches: RwLock<HashMap<String, HashMap<String, ViewHandle>>>,
+}
+
+impl ViewStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the view replaced, if any, so its subscription can be ended.
+    pub fn insert(&self, branch_key: &str, name: &str, handle: ViewHandle) -> Option<ViewHandle> {
+        match self.branches.write() {
+            Ok(mut branches) => branches
+                .entry(branch_key.to_string())
+                .or_default()
+                .insert(name.to_string(), handle),
+            Err(_) => None,
+        }
+    }
+
+    pub fn get(&self, branch_key: &str, name: &str) -> Option<ViewHandle> {
+        match self.branches.read() {
+            Ok(branches) => branches.get(branch_key)?.get(name).cloned(),
+            Err(_) => None,
+        }
+    }
+
+    pub fn remove(&self, branch_key: &str, name: &str) -> Option<ViewHandle> {
+        match self.branches.write() {
+            Ok(mut branches) => branches.get_mut(branch_key)?.remove(name),
+            Err(_) => None,
+        }
+    }
+
+    pub fn remove_branch(&self, branch_key: &str) -> Vec<ViewHandle> {
+        match self.branches.write() {
+            Ok(mut branches) => branches
+                .remove(branch_key)
+                .map(|views| views.into_values().collect())
+                .unwrap_or_default(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
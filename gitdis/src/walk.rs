@@ -0,0 +1,89 @@
+use ignore::WalkBuilder;
+use log::debug;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+
+/// Paths a walk may find ahead of its consumer before it waits.
+pub const WALK_QUEUE_SIZE: usize = 1024;
+
+/// Files under a directory, found on a background thread while they are
+/// consumed. Dropping the walk stops it.
+pub struct DataWalk {
+    receiver: Receiver<String>,
+}
+
+impl Iterator for DataWalk {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Walks `root` honoring `.gitignore` files, yielding the files `accept`
+/// takes. `accept` is given every path with whether it is a directory, and
+/// directories it rejects are not entered. Symbolic links are followed
+/// unless they point outside `repo_root`; link cycles are skipped.
+pub fn walk<F>(root: &str, repo_root: &str, accept: F) -> DataWalk
+where
+    F: Fn(&str, bool) -> bool + Send + Sync + 'static,
+{
+    let (sender, receiver) = sync_channel(WALK_QUEUE_SIZE);
+    let repo_root = std::fs::canonicalize(repo_root).unwrap_or_else(|_| PathBuf::from(repo_root));
+    let accept = Arc::new(accept);
+    let filter = accept.clone();
+
+    let mut builder = WalkBuilder::new(root);
+    builder
+        .hidden(false)
+        .follow_links(true)
+        .require_git(false)
+        .git_global(false)
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|kind| kind.is_dir());
+
+            if entry.path_is_symlink() && !is_within(entry.path(), &repo_root) {
+                debug!(
+                    "Skipping {}, it links outside the repository",
+                    entry.path().display()
+                );
+                return false;
+            }
+
+            // Only directories are pruned here, files are judged once.
+            !is_dir || entry.path().to_str().is_some_and(|path| filter(path, true))
+        });
+    let walker = builder.build();
+
+    std::thread::spawn(move || {
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    debug!("Skipping a walk entry: {}", err);
+                    continue;
+                }
+            };
+
+            if !entry.file_type().is_some_and(|kind| kind.is_file()) {
+                continue;
+            }
+
+            let path = match entry.path().to_str() {
+                Some(path) => path,
+                None => continue,
+            };
+
+            if accept(path, false) && sender.send(path.to_string()).is_err() {
+                return;
+            }
+        }
+    });
+
+    DataWalk { receiver }
+}
+
+fn is_within(path: &Path, root: &Path) -> bool {
+    std::fs::canonicalize(path).is_ok_and(|path| path.starts_with(root))
+}
@@ -0,0 +1,485 @@
+use crate::cache::ArcKeyOwners;
+use crate::events::{BranchEvent, BranchEventKind, EventHub};
+use crate::filter::build_globs;
+use crate::query::Condition;
+use globset::GlobSet;
+use log::debug;
+use quickleaf::valu3::prelude::*;
+use quickleaf::Event;
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Longest a webhook or Slack delivery may take.
+const DELIVERY_TIMEOUT_SECS: u64 = 10;
+
+/// A watched key that changed value, as handed to notifiers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyChange {
+    /// Name of the watch that matched.
+    pub watch: String,
+    pub branch_key: String,
+    pub key: String,
+    /// None if the key did not exist.
+    pub previous: Option<Value>,
+    /// None if the key was deleted.
+    pub value: Option<Value>,
+    /// Sequence number of the event that made the change.
+    pub sequence: u64,
+    /// Owners of the file of the key from CODEOWNERS, those it had if it
+    /// was deleted.
+    pub owners: Vec<String>,
+}
+
+impl KeyChange {
+    pub fn to_value(&self) -> Value {
+        Value::from(HashMap::from([
+            ("watch".to_string(), Value::from(self.watch.as_str())),
+            (
+                "branch_key".to_string(),
+                Value::from(self.branch_key.as_str()),
+            ),
+            ("key".to_string(), Value::from(self.key.as_str())),
+            (
+                "previous".to_string(),
+                self.previous.clone().unwrap_or(Value::Null),
+            ),
+            (
+                "value".to_string(),
+                self.value.clone().unwrap_or(Value::Null),
+            ),
+            ("sequence".to_string(), Value::from(self.sequence)),
+            (
+                "owners".to_string(),
+                Value::from(
+                    self.owners
+                        .iter()
+                        .map(|owner| Value::from(owner.as_str()))
+                        .collect::<Vec<Value>>(),
+                ),
+            ),
+        ]))
+    }
+
+    /// One line describing the change, for chat and email channels.
+    pub fn get_summary(&self) -> String {
+        let describe = |value: &Option<Value>| match value {
+            Some(value) => value.to_json(JsonMode::Inline),
+            None => "nothing".to_string(),
+        };
+
+        format!(
+            "{}: {} on {} changed from {} to {}",
+            self.watch,
+            self.key,
+            self.branch_key,
+            describe(&self.previous),
+            describe(&self.value)
+        )
+    }
+}
+
+/// Where the changes matched by a watch are sent. Implement it to plug in
+/// other channels; `WebhookNotifier`, `SlackNotifier` and `EmailNotifier`
+/// are built in.
+pub trait Notifier: Send + Sync {
+    fn notify(&self, change: &KeyChange) -> Result<(), String>;
+}
+
+/// Posts each change as JSON, see `KeyChange::to_value`. Sent with `curl`.
+pub struct WebhookNotifier {
+    pub url: String,
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, change: &KeyChange) -> Result<(), String> {
+        post_json(&self.url, &change.to_value().to_json(JsonMode::Inline))
+    }
+}
+
+/// Posts each change to a Slack incoming webhook. Sent with `curl`.
+pub struct SlackNotifier {
+    pub webhook_url: String,
+}
+
+impl Notifier for SlackNotifier {
+    fn notify(&self, change: &KeyChange) -> Result<(), String> {
+        let message = Value::from(HashMap::from([(
+            "text".to_string(),
+            Value::from(change.get_summary()),
+        )]));
+
+        post_json(&self.webhook_url, &message.to_json(JsonMode::Inline))
+    }
+}
+
+/// Mails each change to `to`. Sent with the local `sendmail`.
+pub struct EmailNotifier {
+    pub to: String,
+}
+
+impl Notifier for EmailNotifier {
+    fn notify(&self, change: &KeyChange) -> Result<(), String> {
+        if self.to.contains(['\r', '\n']) {
+            return Err(format!("Invalid recipient: {:?}", self.to));
+        }
+
+        let subject =
+            format!("[gitdis] {}: {} changed", change.watch, change.key).replace(['\r', '\n'], " ");
+        let mail = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            self.to,
+            subject,
+            change.get_summary()
+        );
+
+        pipe_to(Command::new("sendmail").arg("-t").arg("-i"), &mail)
+    }
+}
+
+pub(crate) fn post_json(url: &str, body: &str) -> Result<(), String> {
+    pipe_to(
+        Command::new("curl")
+            .arg("-sS")
+            .arg("--fail")
+            .arg("--max-time")
+            .arg(DELIVERY_TIMEOUT_SECS.to_string())
+            .arg("-H")
+            .arg("Content-Type: application/json")
+            .arg("--data-binary")
+            .arg("@-")
+            .arg(url),
+        body,
+    )
+}
+
+/// Runs `command` with `input` on its stdin.
+pub(crate) fn pipe_to(command: &mut Command, input: &str) -> Result<(), String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| err.to_string())?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(input.as_bytes())
+            .map_err(|err| err.to_string())?;
+    }
+
+    let output = child.wait_with_output().map_err(|err| err.to_string())?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    Ok(())
+}
+
+/// Notifies a channel when keys matching `keys` change value, e.g. only
+/// when `features/risky` gets `/enabled` set to true.
+#[derive(Clone)]
+pub struct KeyWatch {
+    pub name: String,
+    /// Only this branch, or every branch.
+    pub branch_key: Option<String>,
+    /// Exact keys or globs, e.g. `features/*`.
+    pub keys: Vec<String>,
+    /// Notify only when the new value matches the condition and the
+    /// previous one did not. Without one, every change notifies.
+    pub condition: Option<Condition>,
+    /// Only keys whose file this CODEOWNERS owner, e.g. `@org/team`, owns.
+    pub owner: Option<String>,
+    pub notifier: Arc<dyn Notifier>,
+}
+
+struct WatchRule {
+    watch: KeyWatch,
+    keys: GlobSet,
+}
+
+impl WatchRule {
+    fn applies(&self, branch_key: &str, key: &str, owners: &[String]) -> bool {
+        self.watch
+            .branch_key
+            .as_deref()
+            .is_none_or(|watched| watched == branch_key)
+            && self.keys.is_match(key)
+            && self
+                .watch
+                .owner
+                .as_ref()
+                .is_none_or(|owner| owners.contains(owner))
+    }
+
+    fn fires(&self, key: &str, previous: Option<&Value>, value: Option<&Value>) -> bool {
+        match &self.watch.condition {
+            Some(condition) => {
+                value.is_some_and(|value| condition.matches(key, value))
+                    && !previous.is_some_and(|previous| condition.matches(key, previous))
+            }
+            None => true,
+        }
+    }
+}
+
+type Delivery = (Arc<dyn Notifier>, KeyChange);
+/// Last value of each watched key by branch and key, None once deleted,
+/// with the owners of its file then.
+type SeenValues = HashMap<(String, String), (Option<Value>, Vec<String>)>;
+
+/// Evaluates key watches against the events of every branch and delivers
+/// the changes they match on a thread of its own, so slow channels never
+/// hold a sync back.
+///
+/// Values are compared with the last one seen for the key. The first one
+/// seen, from the caches when the watch is added or from the initial load
+/// of a branch, is only recorded, so registering a watch or a branch does
+/// not notify.
+#[derive(Clone, Default)]
+pub struct KeyWatcher {
+    rules: Arc<RwLock<Vec<WatchRule>>>,
+    seen: Arc<Mutex<SeenValues>>,
+    /// Key owners of each branch, see `track_owners`.
+    owners: Arc<RwLock<HashMap<String, ArcKeyOwners>>>,
+    sender: Arc<Mutex<Option<Sender<Delivery>>>>,
+}
+
+impl KeyWatcher {
+    /// Evaluates the watches on every event published on `events`.
+    pub fn subscribe(&self, events: &EventHub) -> u64 {
+        let watcher = self.clone();
+
+        events.subscribe(Arc::new(move |event: &BranchEvent| watcher.handle(event)))
+    }
+
+    /// Adds a watch, replacing the one with the same name.
+    pub fn add(&self, watch: KeyWatch) -> Result<(), String> {
+        let keys = build_globs(&watch.keys)?;
+
+        if let Ok(mut rules) = self.rules.write() {
+            rules.retain(|rule| rule.watch.name != watch.name);
+            rules.push(WatchRule { watch, keys });
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) {
+        if let Ok(mut rules) = self.rules.write() {
+            rules.retain(|rule| rule.watch.name != name);
+        }
+    }
+
+    pub fn get_names(&self) -> Vec<String> {
+        match self.rules.read() {
+            Ok(rules) => rules.iter().map(|rule| rule.watch.name.clone()).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Reads the owners of the keys of `branch_key` from `owners`, for the
+    /// watches limited to an owner.
+    pub fn track_owners(&self, branch_key: &str, owners: ArcKeyOwners) {
+        if let Ok(mut tracked) = self.owners.write() {
+            tracked.insert(branch_key.to_string(), owners);
+        }
+    }
+
+    fn get_key_owners(&self, branch_key: &str, key: &str) -> Vec<String> {
+        let owners = match self.owners.read() {
+            Ok(tracked) => tracked.get(branch_key).cloned(),
+            Err(_) => None,
+        };
+
+        owners
+            .and_then(|owners| owners.read().ok()?.get(key).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Whether some watch covers `key` of `branch_key`.
+    pub fn is_watched(&self, branch_key: &str, key: &str) -> bool {
+        let owners = self.get_key_owners(branch_key, key);
+
+        match self.rules.read() {
+            Ok(rules) => rules
+                .iter()
+                .any(|rule| rule.applies(branch_key, key, &owners)),
+            Err(_) => false,
+        }
+    }
+
+    /// The branch watch `name` is limited to, `Some(None)` for every
+    /// branch, or `None` if there is no such watch.
+    pub fn get_branch_key(&self, name: &str) -> Option<Option<String>> {
+        let rules = self.rules.read().ok()?;
+
+        rules
+            .iter()
+            .find(|rule| rule.watch.name == name)
+            .map(|rule| rule.watch.branch_key.clone())
+    }
+
+    /// Delivers the changes `events` made to the keys of watch `name`, as if
+    /// it had been added before them. Values before the first event of a
+    /// key are unknown, so its first change reads as from nothing. Returns
+    /// how many changes were sent, or `None` if there is no such watch.
+    pub fn replay(&self, name: &str, events: &[BranchEvent]) -> Option<usize> {
+        let rules = self.rules.read().ok()?;
+        let rule = rules.iter().find(|rule| rule.watch.name == name)?;
+        let mut seen: HashMap<(String, String), Option<Value>> = HashMap::new();
+        let mut delivered = 0;
+
+        for event in events {
+            let (key, value) = match &event.event {
+                BranchEventKind::Cache(Event::Insert(data)) => (&data.key, Some(&data.value)),
+                BranchEventKind::Cache(Event::Remove(data)) => (&data.key, None),
+                _ => continue,
+            };
+            let owners = self.get_key_owners(&event.branch_key, key);
+
+            if !rule.applies(&event.branch_key, key, &owners) {
+                continue;
+            }
+
+            let previous = seen
+                .insert((event.branch_key.clone(), key.clone()), value.cloned())
+                .flatten();
+
+            if previous.as_ref() == value || !rule.fires(key, previous.as_ref(), value) {
+                continue;
+            }
+
+            self.deliver(
+                rule.watch.notifier.clone(),
+                KeyChange {
+                    watch: rule.watch.name.clone(),
+                    branch_key: event.branch_key.clone(),
+                    key: key.clone(),
+                    previous,
+                    value: value.cloned(),
+                    sequence: event.sequence,
+                    owners,
+                },
+            );
+            delivered += 1;
+        }
+
+        Some(delivered)
+    }
+
+    /// Records `value` as the baseline of a key not seen yet.
+    pub fn seed(&self, branch_key: &str, key: &str, value: &Value) {
+        let owners = self.get_key_owners(branch_key, key);
+
+        if let Ok(mut seen) = self.seen.lock() {
+            seen.entry((branch_key.to_string(), key.to_string()))
+                .or_insert_with(|| (Some(value.clone()), owners));
+        }
+    }
+
+    fn handle(&self, event: &BranchEvent) {
+        let (key, value) = match &event.event {
+            BranchEventKind::Cache(Event::Insert(data)) => (&data.key, Some(&data.value)),
+            BranchEventKind::Cache(Event::Remove(data)) => (&data.key, None),
+            BranchEventKind::Removed => {
+                if let Ok(mut seen) = self.seen.lock() {
+                    seen.retain(|(branch_key, _), _| *branch_key != event.branch_key);
+                }
+                if let Ok(mut owners) = self.owners.write() {
+                    owners.remove(&event.branch_key);
+                }
+                return;
+            }
+            _ => return,
+        };
+
+        let seen_key = (event.branch_key.clone(), key.clone());
+        let mut owners = self.get_key_owners(&event.branch_key, key);
+
+        // The owners of a deleted file are gone by the time it is seen.
+        if value.is_none() && owners.is_empty() {
+            if let Ok(seen) = self.seen.lock() {
+                if let Some((_, known)) = seen.get(&seen_key) {
+                    owners = known.clone();
+                }
+            }
+        }
+
+        let rules = match self.rules.read() {
+            Ok(rules) => rules,
+            Err(_) => return,
+        };
+
+        if !rules
+            .iter()
+            .any(|rule| rule.applies(&event.branch_key, key, &owners))
+        {
+            return;
+        }
+
+        let previous = match self.seen.lock() {
+            Ok(mut seen) => seen.insert(seen_key, (value.cloned(), owners.clone())),
+            Err(_) => return,
+        };
+
+        let previous = match previous {
+            Some((previous, _)) => previous,
+            None => return,
+        };
+
+        if previous.as_ref() == value {
+            return;
+        }
+
+        for rule in rules.iter() {
+            if !rule.applies(&event.branch_key, key, &owners)
+                || !rule.fires(key, previous.as_ref(), value)
+            {
+                continue;
+            }
+
+            self.deliver(
+                rule.watch.notifier.clone(),
+                KeyChange {
+                    watch: rule.watch.name.clone(),
+                    branch_key: event.branch_key.clone(),
+                    key: key.clone(),
+                    previous: previous.clone(),
+                    value: value.cloned(),
+                    sequence: event.sequence,
+                    owners: owners.clone(),
+                },
+            );
+        }
+    }
+
+    fn deliver(&self, notifier: Arc<dyn Notifier>, change: KeyChange) {
+        let mut sender = match self.sender.lock() {
+            Ok(sender) => sender,
+            Err(_) => return,
+        };
+
+        let sender = sender.get_or_insert_with(|| {
+            let (sender, receiver) = channel::<Delivery>();
+
+            std::thread::spawn(move || {
+                for (notifier, change) in receiver.iter() {
+                    if let Err(err) = notifier.notify(&change) {
+                        debug!(
+                            "Failed to notify {} of {} on {}: {}",
+                            change.watch, change.key, change.branch_key, err
+                        );
+                    }
+                }
+            });
+
+            sender
+        });
+
+        let _ = sender.send((notifier, change));
+    }
+}